@@ -1,10 +1,60 @@
 use std::io;
-use rtmp;
+use std::sync::Arc;
+use media_core::MediaRegistry;
+
+const USAGE: &str = "Usage: vrsdn [publish <file.flv> <stream name> [--loop]]";
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
+    // `vrsdn publish` also serves an FLV file as a live stream, alongside everything else
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let file_publisher = match args.first().map(String::as_str) {
+        None => None,
+        Some("publish") if args.len() >= 3 => {
+            let mut publisher = rtmp::FilePublisher::new(&args[1], &args[2]);
+            publisher.looping = args[3..].iter().any(|arg| arg == "--loop");
+            Some(publisher)
+        }
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE));
+        }
+    };
+
+    // The config file is optional, without one we run with the defaults
+    let config_path = std::env::var("VRSDN_CONFIG").unwrap_or_else(|_| "vrsdn.toml".to_string());
+    let config = if std::path::Path::new(&config_path).exists() {
+        match rtmp::RtmpConfig::load(&config_path) {
+            Ok(config) => config,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    } else {
+        rtmp::RtmpConfig::default()
+    };
+
     println!("Awaiting connection!");
 
-    let server = rtmp::RtmpServer::new();
+    let server = rtmp::RtmpServer::with_config(config);
+
+    // RTSP plays whatever gets published over RTMP. It runs on plain threads of its own.
+    if server.config.rtsp.enabled {
+        let mut rtsp = rtsp::RtspServer::with_registry(server.registry.clone() as Arc<dyn MediaRegistry>);
+        rtsp.listen = server.config.rtsp.listen.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = rtsp.start() {
+                eprintln!("RTSP server stopped: {}", err);
+            }
+        });
+    }
+
+    if let Some(publisher) = file_publisher {
+        let registry = server.registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = publisher.run(registry).await {
+                eprintln!("Error publishing {}: {}", publisher.path.display(), err);
+            }
+        });
+    }
+
     server.start().await
 }
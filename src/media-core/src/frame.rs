@@ -0,0 +1,64 @@
+use bytes::Bytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrackKind {
+    Video,
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    H264,
+    H265,
+    Av1,
+    Vp9,
+    Aac,
+    Mp3,
+    Opus,
+    Flac,
+    Ac3,
+    Eac3,
+    G711ALaw,
+    G711MuLaw,
+}
+
+impl Codec {
+    pub fn kind(&self) -> TrackKind {
+        match self {
+            Codec::H264 | Codec::H265 | Codec::Av1 | Codec::Vp9 => TrackKind::Video,
+            _ => TrackKind::Audio,
+        }
+    }
+
+    // H.264 and H.265 frames are made of NAL units
+    pub fn uses_nalus(&self) -> bool {
+        matches!(self, Codec::H264 | Codec::H265)
+    }
+}
+
+// A single access unit of one track, independent of the protocol it came in over.
+//
+// The payload format depends on the codec:
+//  - H.264/H.265: NAL units in Annex B format, each one behind a 4 byte start code
+//  - AV1: OBUs in the low overhead bitstream format
+//  - AAC: a raw access unit, without an ADTS header
+//  - everything else: a frame as the codec defines it
+//
+// Decoder configuration never travels as a frame, it's in the track's TrackInfo.
+#[derive(Debug, Clone)]
+pub struct MediaFrame {
+    // Matches TrackInfo::id
+    pub track: u32,
+    pub codec: Codec,
+    // Milliseconds on the stream's timeline, which starts at zero
+    pub pts: i64,
+    pub dts: i64,
+    pub keyframe: bool,
+    pub payload: Bytes,
+}
+
+impl MediaFrame {
+    pub fn composition_time(&self) -> i64 {
+        self.pts - self.dts
+    }
+}
@@ -0,0 +1,20 @@
+use std::io::Read;
+
+mod frame;
+mod projection;
+mod track;
+mod registry;
+
+pub use bytes::Bytes;
+pub use frame::{Codec, MediaFrame, TrackKind};
+pub use projection::{Projection, ProjectionKind, StereoMode};
+pub use track::TrackInfo;
+pub use registry::{FRAME_QUEUE_SIZE, FrameEvent, FrameReceiver, FrameSender, MediaRegistry};
+
+pub trait Serializable {
+    fn serialize(&self) -> Result<Vec<u8>, &'static str>;
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str>
+        where
+            R: Read,
+            Self: Sized;
+}
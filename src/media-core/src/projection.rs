@@ -0,0 +1,61 @@
+// How a video frame maps onto the viewer's field of view, for 360°/180° and stereo video. Without
+// one a track is plain flat video.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Projection {
+    pub kind: ProjectionKind,
+    pub stereo: StereoMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectionKind {
+    // The full sphere
+    Equirectangular,
+    // The front half of the sphere, as used for VR180
+    HalfEquirectangular,
+    Cubemap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    Mono,
+    // Left eye on top
+    TopBottom,
+    // Left eye on the left
+    LeftRight,
+}
+
+impl Projection {
+    // The names used for the onMetaData keys, e.g. projection=equirectangular, stereo_mode=top_bottom
+    pub fn from_names(projection: &str, stereo_mode: Option<&str>) -> Option<Self> {
+        let kind = match projection.to_ascii_lowercase().as_str() {
+            "equirectangular" | "equirect" | "360" => ProjectionKind::Equirectangular,
+            "equirectangular_180" | "half_equirectangular" | "180" => ProjectionKind::HalfEquirectangular,
+            "cubemap" => ProjectionKind::Cubemap,
+            _ => return None,
+        };
+
+        let stereo = match stereo_mode.map(|mode| mode.to_ascii_lowercase()) {
+            Some(mode) if mode == "top_bottom" || mode == "top-bottom" => StereoMode::TopBottom,
+            Some(mode) if mode == "left_right" || mode == "left-right" || mode == "side_by_side" => StereoMode::LeftRight,
+            _ => StereoMode::Mono,
+        };
+
+        Some(Projection { kind, stereo })
+    }
+
+    pub fn projection_name(&self) -> &'static str {
+        match self.kind {
+            ProjectionKind::Equirectangular => "equirectangular",
+            ProjectionKind::HalfEquirectangular => "equirectangular_180",
+            ProjectionKind::Cubemap => "cubemap",
+        }
+    }
+
+    pub fn stereo_mode_name(&self) -> &'static str {
+        match self.stereo {
+            StereoMode::Mono => "mono",
+            StereoMode::TopBottom => "top_bottom",
+            StereoMode::LeftRight => "left_right",
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use crate::frame::MediaFrame;
+use crate::track::TrackInfo;
+
+// How many frames can queue up between a registry and a frame subscriber or publisher
+pub const FRAME_QUEUE_SIZE: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum FrameEvent {
+    // The stream's tracks, sent before the first frame and again whenever they change
+    Tracks(Vec<TrackInfo>),
+    Frame(MediaFrame),
+    // No more frames are coming
+    Ended,
+}
+
+pub type FrameReceiver = mpsc::Receiver<FrameEvent>;
+pub type FrameSender = mpsc::Sender<FrameEvent>;
+
+// The streams an ingest protocol knows about, as seen by any other protocol. Publishing and
+// subscribing hand out channels, dropping them is how either side lets go of the stream.
+pub trait MediaRegistry: Send + Sync {
+    // None while nobody is publishing the stream
+    fn tracks(&self, name: &str) -> Option<Vec<TrackInfo>>;
+
+    // Frames start flowing once the stream is published. A subscriber that falls behind misses
+    // frames up to the next keyframe.
+    fn subscribe_frames(self: Arc<Self>, name: &str) -> FrameReceiver;
+
+    // Publishes the stream from frames. Fails the way a publisher of the registry's own protocol
+    // would, e.g. when the stream is already published.
+    fn publish_frames(self: Arc<Self>, name: &str) -> Result<FrameSender, &'static str>;
+}
@@ -0,0 +1,47 @@
+use bytes::Bytes;
+use crate::frame::{Codec, TrackKind};
+use crate::projection::Projection;
+
+// Everything needed to set up a decoder (or a muxer) for a track before its first frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackInfo {
+    pub id: u32,
+    pub codec: Codec,
+    // The decoder configuration as it goes into an MP4 sample entry: the avcC, hvcC, av1C or vpcC
+    // record, the AAC AudioSpecificConfig, the OpusHead or the FLAC STREAMINFO. Empty for codecs
+    // that don't have one.
+    pub config: Bytes,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    // Set for 360°/180° and stereo video
+    pub projection: Option<Projection>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    // RFC 6381 codecs parameter for HLS and DASH manifests
+    pub codecs: Option<String>,
+    // SDP a=fmtp parameters, without the payload type
+    pub fmtp: Option<String>,
+}
+
+impl TrackInfo {
+    pub fn new(id: u32, codec: Codec) -> Self {
+        Self {
+            id,
+            codec,
+            config: Bytes::new(),
+            width: None,
+            height: None,
+            frame_rate: None,
+            projection: None,
+            sample_rate: None,
+            channels: None,
+            codecs: None,
+            fmtp: None,
+        }
+    }
+
+    pub fn kind(&self) -> TrackKind {
+        self.codec.kind()
+    }
+}
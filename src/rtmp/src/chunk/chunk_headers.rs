@@ -1,7 +1,5 @@
-use std::io;
-use tokio::net::TcpStream;
-use tokio::io::AsyncReadExt;
-use crate::Serializable;
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub struct ChunkBasicHeader {
     pub fmt: u8,
@@ -15,14 +13,20 @@ pub struct ChunkHeader {
     pub message_length: u32,
     pub message_type_id: u8,
     pub message_stream_id: u32,
+
+    // What was added to the previous timestamp to get this one. A fmt 3 chunk that starts a new
+    // message applies it again.
+    pub timestamp_delta: u32,
+    // Whether the timestamp didn't fit in 3 bytes and was sent as an extra 4 byte field
+    pub extended_timestamp: bool,
 }
 
 impl ChunkBasicHeader {
-    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+    pub fn serialize(&self) -> Result<Vec<u8>, &'static str> {
         return Ok(vec![self.fmt << 6 | self.csid]);
     }
 
-    pub async fn deserialize(reader: &mut TcpStream) -> Result<Self, &'static str> where Self: Sized {
+    pub async fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: AsyncRead + Unpin, Self: Sized {
         let mut buf = [0; 1];
         match reader.read_exact(&mut buf).await {
             Ok(_) => {}
@@ -53,26 +57,30 @@ impl ChunkBasicHeader {
     }
 }
 
-static mut PREV_CHUNK_HEADER: Option<ChunkHeader> = None;
-
 impl ChunkHeader {
     pub fn serialize(&self) -> Result<Vec<u8>, &'static str> {
         let mut buf = Vec::new();
         buf.extend_from_slice(&self.basic_header.serialize().unwrap());
 
-        // 3 byte timestamp
-        buf.extend_from_slice(&self.timestamp.to_be_bytes()[1..4]);
+        // 3 byte timestamp, anything that doesn't fit goes in the extended timestamp
+        let extended = self.timestamp >= 0xFFFFFF;
+        buf.extend_from_slice(&std::cmp::min(self.timestamp, 0xFFFFFF).to_be_bytes()[1..4]);
         // 3 byte message length
         buf.extend_from_slice(&self.message_length.to_be_bytes()[1..4]);
         // 1 byte message type id
         buf.push(self.message_type_id);
         // 4 byte message stream id
         buf.extend_from_slice(&self.message_stream_id.to_be_bytes());
+        if extended {
+            buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        }
 
         Ok(buf)
     }
 
-    pub async fn deserialize(reader: &mut TcpStream) -> Result<Self, &'static str> where Self: Sized {
+    // Headers with fmt 1-3 only carry what changed since the last header on the same chunk stream,
+    // so the caller keeps track of the previous header for every csid
+    pub async fn deserialize<R>(reader: &mut R, previous_headers: &mut HashMap<u8, ChunkHeader>) -> Result<Self, &'static str> where R: AsyncRead + Unpin, Self: Sized {
         // First we read the basic header to determine the fmt to attempt to read
         let basic_header = match ChunkBasicHeader::deserialize(reader).await {
             Ok(bh) => bh,
@@ -80,8 +88,9 @@ impl ChunkHeader {
         };
 
         // Now depending on the fmt, we read the rest of the header. If the fmt is 0, we read the
-        // timestamp, message length and message type id.
-        let header = match basic_header.fmt {
+        // timestamp, message length and message type id. The timestamp field of fmt 0 is absolute,
+        // for fmt 1 and 2 it's a delta. Both get added on once we know about the extended timestamp.
+        let mut header = match basic_header.fmt {
             0 => {
                 let mut buf = [0; 11];
                 match reader.read_exact(&mut buf).await {
@@ -97,10 +106,12 @@ impl ChunkHeader {
 
                         ChunkHeader {
                             basic_header,
-                            timestamp,
+                            timestamp: 0,
                             message_length,
                             message_type_id,
                             message_stream_id,
+                            timestamp_delta: timestamp,
+                            extended_timestamp: timestamp == 0xFFFFFF,
                         }
                     }
                     _ => Err("Error reading full header")?,
@@ -117,18 +128,19 @@ impl ChunkHeader {
                         // 1 byte message type id
                         let message_type_id = buf[6];
 
-                        let previous_chunk = unsafe {
-                            PREV_CHUNK_HEADER.as_ref().unwrap()
+                        let previous_chunk = match previous_headers.get(&basic_header.csid) {
+                            Some(previous_chunk) => previous_chunk,
+                            None => Err("No previous header for chunk stream")?,
                         };
-                        
-                        let timestamp = previous_chunk.timestamp + timestamp_delta;
 
                         ChunkHeader {
                             basic_header,
-                            timestamp,
+                            timestamp: previous_chunk.timestamp,
                             message_length,
                             message_type_id,
-                            message_stream_id: previous_chunk.message_stream_id
+                            message_stream_id: previous_chunk.message_stream_id,
+                            timestamp_delta,
+                            extended_timestamp: timestamp_delta == 0xFFFFFF,
                         }
                     }
                     _ => Err("Error reading full header")?,
@@ -140,37 +152,55 @@ impl ChunkHeader {
                     Ok(_) => {
                         let timestamp_delta = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
 
-                        let previous_chunk = unsafe {
-                            PREV_CHUNK_HEADER.as_ref().unwrap()
+                        let previous_chunk = match previous_headers.get(&basic_header.csid) {
+                            Some(previous_chunk) => previous_chunk,
+                            None => Err("No previous header for chunk stream")?,
                         };
 
-                        let timestamp = previous_chunk.timestamp + timestamp_delta;
-
                         ChunkHeader {
                             basic_header,
-                            timestamp,
+                            timestamp: previous_chunk.timestamp,
                             message_length: previous_chunk.message_length,
                             message_type_id: previous_chunk.message_type_id,
-                            message_stream_id: previous_chunk.message_stream_id
+                            message_stream_id: previous_chunk.message_stream_id,
+                            timestamp_delta,
+                            extended_timestamp: timestamp_delta == 0xFFFFFF,
                         }
                     }
                     _ => Err("Error reading full header")?,
                 }
             }
             3 => {
-                let previous_chunk = unsafe {
-                    PREV_CHUNK_HEADER.as_ref().unwrap()
-                };
-                previous_chunk.clone()
+                match previous_headers.get(&basic_header.csid) {
+                    Some(previous_chunk) => ChunkHeader {
+                        basic_header,
+                        ..previous_chunk.clone()
+                    },
+                    None => Err("No previous header for chunk stream")?,
+                }
             }
             _ => Err("Unsupported fmt")?,
         };
 
-        // Copy the header and store it as the previous chunk_headers header
-        unsafe {
-            PREV_CHUNK_HEADER = Some(header.clone());
+        // Fmt 3 chunks repeat the extended timestamp of the header they follow
+        if header.extended_timestamp {
+            let mut buf = [0; 4];
+            if reader.read_exact(&mut buf).await.is_err() {
+                Err("Error reading extended timestamp")?
+            }
+            if header.basic_header.fmt != 3 {
+                header.timestamp_delta = u32::from_be_bytes(buf);
+            }
+        }
+
+        // Timestamps wrap around after about 50 days
+        if header.basic_header.fmt != 3 {
+            header.timestamp = header.timestamp.wrapping_add(header.timestamp_delta);
         }
 
+        // Copy the header and store it as the previous chunk_headers header
+        previous_headers.insert(header.basic_header.csid, header.clone());
+
         Ok(header)
     }
 }
@@ -192,6 +222,8 @@ impl Clone for ChunkHeader {
             message_length: self.message_length,
             message_type_id: self.message_type_id,
             message_stream_id: self.message_stream_id,
+            timestamp_delta: self.timestamp_delta,
+            extended_timestamp: self.extended_timestamp,
         }
     }
 }
@@ -1,15 +1,14 @@
 use std::collections::HashMap;
-use std::io::Read;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
 use crate::chunk::chunk_headers::ChunkHeader;
-use crate::Serializable;
-use crate::socket::RtmpSocket;
 
 // Chunk wrangler's job is to collect partial chunks and format them into full chunks
 
 pub struct ChunkWrangler {
     pub max_chunk_size: usize,
     incomplete_chunks: HashMap<u8, Vec<u8>>,
+    previous_headers: HashMap<u8, ChunkHeader>,
 }
 
 impl ChunkWrangler {
@@ -17,15 +16,24 @@ impl ChunkWrangler {
         Self {
             max_chunk_size: 128,
             incomplete_chunks: HashMap::new(),
+            previous_headers: HashMap::new(),
         }
     }
 
-    pub async fn read_chunk(&mut self, socket: &mut RtmpSocket) -> Result<(ChunkHeader, Vec<u8>), &'static str> {
-        let header = match ChunkHeader::deserialize(&mut socket.socket).await {
+    // Returns None when the chunk was only part of a message and more chunks are needed to complete it
+    pub async fn read_chunk<R>(&mut self, reader: &mut R) -> Result<Option<(ChunkHeader, Vec<u8>)>, &'static str> where R: AsyncRead + Unpin {
+        let mut header = match ChunkHeader::deserialize(reader, &mut self.previous_headers).await {
             Ok(header) => header,
             Err(err) => return Err(err),
         };
 
+        // A fmt 3 chunk is either the next piece of the message we're in the middle of, or the start
+        // of a new message which is exactly like the last one, timestamp delta included
+        if header.basic_header.fmt == 3 && !self.incomplete_chunks.contains_key(&header.basic_header.csid) {
+            header.timestamp = header.timestamp.wrapping_add(header.timestamp_delta);
+            self.previous_headers.insert(header.basic_header.csid, header.clone());
+        }
+
         // If this csid exists in the incomplete messages hashmap, we need to get the remaining bytes to read to complete the msg and pass it into the min
         let mut chunk_size = std::cmp::min(self.max_chunk_size, header.message_length as usize);
         if self.incomplete_chunks.contains_key(&header.basic_header.csid) {
@@ -36,7 +44,7 @@ impl ChunkWrangler {
         let mut buf = vec![0; chunk_size];
 
         // Now we read the rest of the message
-        match socket.socket.read_exact(&mut buf).await {
+        match reader.read_exact(&mut buf).await {
             Ok(_) => {
                 // RTMP will sometimes send parts of data in different chunks
                 // We need to make sure we read all of the data and parse it all at once.
@@ -52,7 +60,7 @@ impl ChunkWrangler {
                     let chunk_vec = self.incomplete_chunks.entry(header.basic_header.csid).or_insert_with(Vec::new);
 
                     // Append the received data to the incomplete chunk_headers vector
-                    chunk_vec.extend_from_slice(&mut buf);
+                    chunk_vec.extend_from_slice(&buf);
 
                     // Check if we have a complete message
                     if chunk_vec.len() == header.message_length as usize {
@@ -61,13 +69,41 @@ impl ChunkWrangler {
                         self.incomplete_chunks.remove(&header.basic_header.csid);
                     } else {
                         // Otherwise, we continue reading from the socket
-                        return Err("Incomplete chunk_headers")
+                        return Ok(None)
                     }
                 }
 
-                Ok((header, buf))
+                Ok(Some((header, buf)))
             }
             Err(_) => Err("Error reading chunk_headers"),
         }
     }
-}
\ No newline at end of file
+
+    // Reads whole messages off the socket on a task of their own, so the connection can wait on
+    // the socket and other events at the same time. The channel closes when the socket does.
+    pub fn spawn<R>(mut self, mut reader: R) -> mpsc::Receiver<(ChunkHeader, Vec<u8>)> where R: AsyncRead + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (header, data) = match self.read_chunk(&mut reader).await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        eprintln!("Stopped reading: {}", err);
+                        return;
+                    }
+                };
+
+                // Set Chunk Size changes how we read every chunk after this one, so it has to be applied here
+                if header.message_type_id == 1 && data.len() >= 4 {
+                    self.max_chunk_size = (u32::from_be_bytes(data[0..4].try_into().unwrap()) & 0x7FFFFFFF) as usize;
+                }
+
+                if tx.send((header, data)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+}
@@ -1,3 +1,2 @@
 pub mod chunk_headers;
 pub mod chunk_wrangler;
-pub mod chunk_router;
\ No newline at end of file
@@ -0,0 +1,533 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use amf::Pair;
+use amf::amf0::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use crate::Serializable;
+use crate::chunk::chunk_headers::ChunkHeader;
+use crate::chunk::chunk_wrangler::ChunkWrangler;
+use crate::command_message::{AMFCall, AMFMessage};
+use crate::control_message::{Acknowledgement, SetChunkSize, UserControlMessage, WindowAcknowledgementSize};
+use crate::flv::video::SUPPORTED_FOURCCS;
+use crate::handshake::{CS0, CS1};
+use crate::media::TagParsers;
+use crate::registry::MediaMessage;
+use crate::server::PublishingType;
+use crate::socket::RtmpSocket;
+
+// The other end of what the server does: connects to an RTMP server, then publishes a stream to it
+// or plays one from it. Commands wait for their _result or _error by transaction id, everything
+// else the server sends comes out as events.
+
+const DEFAULT_PORT: u16 = 1935;
+
+// For the handshake and connect, and for every command after that
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What we announce with SetChunkSize, bigger chunks mean less overhead for video
+const CHUNK_SIZE: u32 = 4096;
+
+// Players ask for this much buffering with SetBufferLength
+const BUFFER_LENGTH_MS: u32 = 3000;
+
+// Until the server tells us otherwise
+const DEFAULT_WINDOW: u64 = 2_500_000;
+
+// rtmp://host[:port]/app/stream, where the stream name can have slashes and a query of its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtmpUrl {
+    pub host: String,
+    pub port: u16,
+    pub app: String,
+    pub stream: String,
+}
+
+impl RtmpUrl {
+    pub fn parse(url: &str) -> Result<Self, &'static str> {
+        let rest = match url.split_once("://") {
+            Some(("rtmp", rest)) => rest,
+            Some(_) => Err("Unsupported URL scheme")?,
+            None => Err("Not a URL")?,
+        };
+
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "Invalid port")?),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            Err("URL has no host")?
+        }
+
+        let (app, stream) = path.split_once('/').unwrap_or((path, ""));
+        if app.is_empty() {
+            Err("URL has no app")?
+        }
+
+        Ok(RtmpUrl {
+            host: host.to_string(),
+            port,
+            app: app.to_string(),
+            stream: stream.to_string(),
+        })
+    }
+
+    // What goes in connect, the URL up to the app
+    pub fn tc_url(&self) -> String {
+        format!("rtmp://{}:{}/{}", self.host, self.port, self.app)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    // onStatus and onPlayStatus, e.g. NetStream.Play.Start or NetStream.Play.Complete
+    Status {
+        level: String,
+        code: String,
+        description: String,
+    },
+    // Audio, video and metadata of the stream we're playing
+    Media(MediaMessage),
+    // The server's StreamBegin and StreamEOF user control events
+    StreamBegin,
+    StreamEof,
+}
+
+pub struct RtmpClient {
+    pub url: RtmpUrl,
+    socket: RtmpSocket<OwnedWriteHalf>,
+    incoming: mpsc::Receiver<(ChunkHeader, Vec<u8>)>,
+    // Bytes read off the socket so far, and how many the server wants acknowledged at a time
+    received: Arc<AtomicU64>,
+    acknowledged: u64,
+    window: u64,
+    next_transaction: f64,
+    // The stream we created to publish or play on
+    stream_id: Option<u32>,
+    publishing: bool,
+    parsers: TagParsers,
+    // Events that came in while we were waiting on something else
+    pending: VecDeque<ClientEvent>,
+}
+
+impl RtmpClient {
+    // Connects to the app in the URL. The stream in it is what publish and play go for.
+    pub async fn connect(url: &str) -> Result<Self, &'static str> {
+        let url = RtmpUrl::parse(url)?;
+        match tokio::time::timeout(COMMAND_TIMEOUT, Self::connect_to(url)).await {
+            Ok(result) => result,
+            Err(_) => Err("Timed out connecting"),
+        }
+    }
+
+    async fn connect_to(url: RtmpUrl) -> Result<Self, &'static str> {
+        let mut stream = match TcpStream::connect((url.host.as_str(), url.port)).await {
+            Ok(stream) => stream,
+            Err(_) => Err("Error connecting")?,
+        };
+        let _ = stream.set_nodelay(true);
+        handshake(&mut stream).await?;
+
+        let (reader, writer) = stream.into_split();
+        let received = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader {
+            reader,
+            received: received.clone(),
+        };
+
+        let mut client = RtmpClient {
+            url,
+            socket: RtmpSocket::new(writer),
+            incoming: ChunkWrangler::new().spawn(reader),
+            received,
+            acknowledged: 0,
+            window: DEFAULT_WINDOW,
+            next_transaction: 1.0,
+            stream_id: None,
+            publishing: false,
+            parsers: TagParsers::new(),
+            pending: VecDeque::new(),
+        };
+
+        client.socket.send_message(SetChunkSize { chunk_size: CHUNK_SIZE }, 2, 1, 0).await;
+        client.socket.chunk_size = CHUNK_SIZE as usize;
+
+        let command_object = vec![
+            ("app".to_string(), Value::String(client.url.app.clone())),
+            ("type".to_string(), Value::String("nonprivate".to_string())),
+            ("flashVer".to_string(), Value::String("FMLE/3.0 (compatible; vrsdn)".to_string())),
+            ("tcUrl".to_string(), Value::String(client.url.tc_url())),
+            ("fpad".to_string(), Value::Boolean(false)),
+            ("capabilities".to_string(), Value::Number(15.0)),
+            ("audioCodecs".to_string(), Value::Number(0x0FFF as f64)),
+            ("videoCodecs".to_string(), Value::Number(0x00FF as f64)),
+            ("videoFunction".to_string(), Value::Number(1.0)),
+            ("objectEncoding".to_string(), Value::Number(0.0)),
+            // Enhanced RTMP, so servers know we take the newer codecs
+            ("fourCcList".to_string(), Value::Array { entries: SUPPORTED_FOURCCS.iter().map(|fourcc| Value::String(fourcc.to_string())).collect() }),
+        ];
+        let object = Value::Object { class_name: None, entries: command_object.into_iter().map(|(key, value)| Pair { key, value }).collect() };
+        let transaction = client.call("connect", 3, 0, vec![object]).await?;
+        client.wait_result(transaction).await?;
+        println!("Connected to {}", client.url.tc_url());
+        Ok(client)
+    }
+
+    // Publishes the stream in the URL, returning once the server said NetStream.Publish.Start
+    pub async fn publish(&mut self, publishing_type: PublishingType) -> Result<(), &'static str> {
+        let name = Value::String(self.url.stream.clone());
+        // What encoders send first, some servers insist on it
+        self.call("releaseStream", 3, 0, vec![Value::Null, name.clone()]).await?;
+        self.call("FCPublish", 3, 0, vec![Value::Null, name.clone()]).await?;
+
+        let stream_id = self.create_stream().await?;
+        self.call("publish", 8, stream_id, vec![Value::Null, name, Value::String(publishing_type.name().to_string())]).await?;
+        self.wait_status("NetStream.Publish.Start").await?;
+        self.publishing = true;
+        Ok(())
+    }
+
+    // Plays the stream in the URL, returning once the server said NetStream.Play.Start. What
+    // gets played comes out of next_event.
+    pub async fn play(&mut self) -> Result<(), &'static str> {
+        let stream_id = self.create_stream().await?;
+
+        // Start -2 plays a live stream, or a recorded one if there's no live one
+        let name = Value::String(self.url.stream.clone());
+        self.call("play", 8, stream_id, vec![Value::Null, name, Value::Number(-2.0)]).await?;
+
+        let mut buffer_length = stream_id.to_be_bytes().to_vec();
+        buffer_length.extend_from_slice(&BUFFER_LENGTH_MS.to_be_bytes());
+        self.socket.send_message(UserControlMessage { event_type: 3, event_data: buffer_length }, 2, 4, 0).await;
+
+        self.wait_status("NetStream.Play.Start").await
+    }
+
+    // Sends a message of the stream we're publishing. Metadata gets the @setDataFrame wrapper
+    // servers expect from encoders.
+    pub async fn send_media(&mut self, message: &MediaMessage) -> Result<(), &'static str> {
+        let stream_id = match self.stream_id.filter(|_| self.publishing) {
+            Some(stream_id) => stream_id,
+            None => Err("Not publishing")?,
+        };
+        // Whatever the server sent meanwhile still needs handling, pings especially
+        self.poll_incoming().await?;
+
+        let mut data = message.data.to_vec();
+        if message.message_type_id == 18 && !is_set_data_frame(&data) {
+            let mut wrapped = Vec::new();
+            Value::String("@setDataFrame".to_string()).write_to(&mut wrapped).unwrap();
+            wrapped.extend_from_slice(&data);
+            data = wrapped;
+        }
+
+        let csid = match message.message_type_id {
+            8 => 4,
+            9 => 6,
+            _ => 5,
+        };
+        self.socket.send_bytes_at(data, csid, message.message_type_id, stream_id, message.timestamp).await;
+        match self.socket.broken {
+            true => Err("Connection closed"),
+            false => Ok(()),
+        }
+    }
+
+    // None once the connection is closed
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let (header, data) = self.incoming.recv().await?;
+            self.handle_message(header, data).await;
+        }
+    }
+
+    // What came in so far without waiting for more, e.g. statuses while publishing. None while
+    // there's nothing new.
+    pub async fn try_next_event(&mut self) -> Result<Option<ClientEvent>, &'static str> {
+        self.poll_incoming().await?;
+        Ok(self.pending.pop_front())
+    }
+
+    // Stops publishing or playing and hangs up
+    pub async fn close(mut self) {
+        if let Some(stream_id) = self.stream_id.take() {
+            if self.publishing {
+                let name = Value::String(self.url.stream.clone());
+                let _ = self.call("FCUnpublish", 3, 0, vec![Value::Null, name]).await;
+            }
+            let _ = self.call("deleteStream", 3, 0, vec![Value::Null, Value::Number(stream_id as f64)]).await;
+        }
+        let _ = self.socket.socket.shutdown().await;
+    }
+
+    async fn create_stream(&mut self) -> Result<u32, &'static str> {
+        let transaction = self.call("createStream", 3, 0, vec![Value::Null]).await?;
+        let values = self.wait_result(transaction).await?;
+        // The command object, then the stream id
+        let stream_id = match values.get(1) {
+            Some(Value::Number(stream_id)) => *stream_id as u32,
+            _ => Err("createStream result has no stream id")?,
+        };
+        self.stream_id = Some(stream_id);
+        Ok(stream_id)
+    }
+
+    // Sends a command, returning its transaction id
+    async fn call(&mut self, name: &str, csid: u8, stream_id: u32, args: Vec<Value>) -> Result<f64, &'static str> {
+        let transaction = self.next_transaction;
+        self.next_transaction += 1.0;
+
+        let mut data = AMFMessage {
+            command_name: name.to_string(),
+            transaction_id: transaction,
+        }.serialize()?;
+        for arg in args {
+            arg.write_to(&mut data).unwrap();
+        }
+        self.socket.send_bytes(data, csid, 20, stream_id).await;
+        match self.socket.broken {
+            true => Err("Connection closed"),
+            false => Ok(transaction),
+        }
+    }
+
+    // The values after the transaction id of the _result, or an error for an _error
+    async fn wait_result(&mut self, transaction: f64) -> Result<Vec<Value>, &'static str> {
+        let deadline = tokio::time::Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            let (header, data) = match tokio::time::timeout_at(deadline, self.incoming.recv()).await {
+                Ok(Some(message)) => message,
+                Ok(None) => Err("Connection closed")?,
+                Err(_) => Err("Timed out waiting for the server")?,
+            };
+
+            if header.message_type_id == 20 {
+                let mut cursor = Cursor::new(&data);
+                if let Ok(message) = AMFMessage::deserialize(&mut cursor) {
+                    if message.transaction_id == transaction {
+                        return match message.command_name.as_str() {
+                            "_result" => Ok(read_values(&mut cursor)),
+                            _ => {
+                                eprintln!("Server answered with {}: {:?}", message.command_name, read_values(&mut cursor));
+                                Err("Command failed")
+                            }
+                        };
+                    }
+                }
+            }
+            self.handle_message(header, data).await;
+        }
+    }
+
+    // Waits for the status that says we're good to go. Any error status means we're not.
+    async fn wait_status(&mut self, success: &str) -> Result<(), &'static str> {
+        let deadline = tokio::time::Instant::now() + COMMAND_TIMEOUT;
+        let mut held = Vec::new();
+        let result = loop {
+            let event = match tokio::time::timeout_at(deadline, self.next_event()).await {
+                Ok(Some(event)) => event,
+                Ok(None) => break Err("Connection closed"),
+                Err(_) => break Err("Timed out waiting for the server"),
+            };
+            match &event {
+                ClientEvent::Status { code, .. } if code == success => break Ok(()),
+                ClientEvent::Status { level, code, .. } if level == "error" => {
+                    eprintln!("{} refused: {}", self.url.stream, code);
+                    break Err("Refused by the server");
+                }
+                _ => held.push(event),
+            }
+        };
+        // The rest is still for the caller to see
+        for event in held.into_iter().rev() {
+            self.pending.push_front(event);
+        }
+        result
+    }
+
+    // Handles whatever came in without waiting for more
+    async fn poll_incoming(&mut self) -> Result<(), &'static str> {
+        loop {
+            match self.incoming.try_recv() {
+                Ok((header, data)) => self.handle_message(header, data).await,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => Err("Connection closed")?,
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, header: ChunkHeader, data: Vec<u8>) {
+        self.acknowledge().await;
+
+        match header.message_type_id {
+            // Set Chunk Size is applied by the reader task already
+            4 => match UserControlMessage::deserialize(&mut Cursor::new(&data)) {
+                Ok(message) => self.handle_user_control_msg(message).await,
+                Err(err) => eprintln!("Error deserializing user control message: {}", err),
+            },
+            5 => match WindowAcknowledgementSize::deserialize(&mut Cursor::new(&data)) {
+                Ok(message) => self.window = message.window_acknowledgement_size as u64,
+                Err(err) => eprintln!("Error deserializing window acknowledgement size: {}", err),
+            },
+            8 | 9 => {
+                let message = self.parsers.parse(&self.url.stream, header.message_type_id, header.timestamp, data);
+                self.pending.push_back(ClientEvent::Media(message));
+            }
+            18 => self.handle_data_message(header, data),
+            20 => {
+                let mut cursor = Cursor::new(&data);
+                match AMFMessage::deserialize(&mut cursor) {
+                    Ok(message) if message.command_name == "onStatus" => {
+                        if let Ok(call) = AMFCall::deserialize(&mut cursor) {
+                            self.pending.push_back(status_event(call.additional_args));
+                        }
+                    }
+                    // Results nobody waits for and onBWDone and the like
+                    Ok(_) => {}
+                    Err(err) => eprintln!("Error reading AMF message: {}", err),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_user_control_msg(&mut self, message: UserControlMessage) {
+        let value = match message.event_data.get(0..4) {
+            Some(bytes) => u32::from_be_bytes(bytes.try_into().unwrap()),
+            None => return,
+        };
+        match message.event_type {
+            0 if Some(value) == self.stream_id => self.pending.push_back(ClientEvent::StreamBegin),
+            1 if Some(value) == self.stream_id => self.pending.push_back(ClientEvent::StreamEof),
+            6 => self.socket.send_message(UserControlMessage::ping_response(value), 2, 4, 0).await,
+            _ => {}
+        }
+    }
+
+    // onMetaData is part of the stream, onPlayStatus is a status like any other
+    fn handle_data_message(&mut self, header: ChunkHeader, data: Vec<u8>) {
+        let mut cursor = Cursor::new(&data);
+        let name = match amf::Value::read_from(&mut cursor, amf::Version::Amf0) {
+            Ok(value) => value.try_as_str().map(str::to_string),
+            Err(_) => None,
+        };
+        match name.as_deref() {
+            Some("onMetaData") => {
+                let message = self.parsers.parse(&self.url.stream, 18, header.timestamp, data);
+                self.pending.push_back(ClientEvent::Media(message));
+            }
+            Some("onPlayStatus") => {
+                if let Ok(amf::Value::Amf0(Value::Object { entries, .. })) = amf::Value::read_from(&mut cursor, amf::Version::Amf0) {
+                    self.pending.push_back(status_event(entries.into_iter().map(|pair| (pair.key, pair.value)).collect()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Servers stop sending once a window's worth of bytes goes unacknowledged
+    async fn acknowledge(&mut self) {
+        let received = self.received.load(Ordering::Relaxed);
+        if received - self.acknowledged >= self.window {
+            self.acknowledged = received;
+            // The sequence number wraps around like everything else in RTMP
+            self.socket.send_message(Acknowledgement { sequence_number: received as u32 }, 2, 3, 0).await;
+        }
+    }
+}
+
+// C0 and C1, then S0, S1 and S2 from the server, then C2 which echoes S1
+async fn handshake(stream: &mut TcpStream) -> Result<(), &'static str> {
+    let c1 = CS1 {
+        timestamp: 0,
+        zero: 0,
+        random_bytes: (0..1528).map(|_| rand::random::<u8>()).collect(),
+    };
+    let request = [CS0 { version: 3 }.serialize()?, c1.serialize()?].concat();
+    if stream.write_all(&request).await.is_err() {
+        Err("Error sending C0 and C1")?
+    }
+
+    let mut s0 = [0u8; 1];
+    let mut s1 = vec![0u8; 1536];
+    let mut s2 = vec![0u8; 1536];
+    if stream.read_exact(&mut s0).await.is_err() || stream.read_exact(&mut s1).await.is_err() || stream.read_exact(&mut s2).await.is_err() {
+        Err("Error reading S0, S1 and S2")?
+    }
+    if CS0::deserialize(&mut s0.as_ref())?.version != 3 {
+        Err("Unsupported RTMP version")?
+    }
+
+    let s1 = CS1::deserialize(&mut s1.as_slice())?;
+    let c2 = CS1 {
+        timestamp: s1.timestamp,
+        zero: 0,
+        random_bytes: s1.random_bytes,
+    };
+    if stream.write_all(&c2.serialize()?).await.is_err() {
+        Err("Error sending C2")?
+    }
+    Ok(())
+}
+
+// Everything left in a command, as AMF0 values
+fn read_values(cursor: &mut Cursor<&Vec<u8>>) -> Vec<Value> {
+    let mut values = Vec::new();
+    while let Ok(amf::Value::Amf0(value)) = amf::Value::read_from(&mut *cursor, amf::Version::Amf0) {
+        values.push(value);
+    }
+    values
+}
+
+fn status_event(info: Vec<(String, Value)>) -> ClientEvent {
+    let field = |name: &str| {
+        info.iter()
+            .find(|(key, _)| key == name)
+            .and_then(|(_, value)| match value {
+                Value::String(value) => Some(value.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    };
+    ClientEvent::Status {
+        level: field("level"),
+        code: field("code"),
+        description: field("description"),
+    }
+}
+
+fn is_set_data_frame(data: &[u8]) -> bool {
+    match amf::Value::read_from(&mut Cursor::new(data), amf::Version::Amf0) {
+        Ok(value) => value.try_as_str() == Some("@setDataFrame"),
+        Err(_) => false,
+    }
+}
+
+// Counts what the reader task reads, for acknowledgements
+struct CountingReader {
+    reader: OwnedReadHalf,
+    received: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.received.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
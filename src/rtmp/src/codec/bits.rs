@@ -0,0 +1,171 @@
+// Reads a byte slice MSB first, one bit field at a time, the way codec headers are laid out
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    // Position in bits
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn bits_left(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.position)
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, &'static str> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    // Up to 32 bits at a time
+    pub fn read_bits(&mut self, count: usize) -> Result<u32, &'static str> {
+        if count > 32 {
+            Err("Can't read more than 32 bits at once")?
+        }
+        if count > self.bits_left() {
+            Err("Not enough bits left")?
+        }
+
+        let mut value = 0u32;
+        for _ in 0..count {
+            let byte = self.data[self.position / 8];
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    // Unsigned exp-Golomb code, ue(v) in the H.264 and H.265 specs
+    pub fn read_ue(&mut self) -> Result<u32, &'static str> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                Err("Invalid exp-Golomb code")?
+            }
+        }
+        if leading_zeros == 0 {
+            return Ok(0);
+        }
+        Ok(((1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)? as u64) as u32)
+    }
+
+    // Signed exp-Golomb code, se(v)
+    pub fn read_se(&mut self) -> Result<i32, &'static str> {
+        let code = self.read_ue()? as i64;
+        if code % 2 == 1 {
+            Ok(((code + 1) / 2) as i32)
+        } else {
+            Ok((-(code / 2)) as i32)
+        }
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Result<(), &'static str> {
+        if count > self.bits_left() {
+            Err("Not enough bits left")?
+        }
+        self.position += count;
+        Ok(())
+    }
+}
+
+// NAL unit payloads escape any 00 00 0x sequence as 00 00 03 0x so it can't look like a start code.
+// The bitstream syntax is defined on the unescaped RBSP.
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for byte in data {
+        if zeros >= 2 && *byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(*byte);
+    }
+    rbsp
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Writes the bit fields of test parameter sets
+    #[derive(Default)]
+    pub(crate) struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        pub(crate) fn bits(&mut self, value: u32, count: usize) -> &mut Self {
+            self.bits.extend((0..count).rev().map(|bit| (value >> bit) & 1 == 1));
+            self
+        }
+
+        pub(crate) fn ue(&mut self, value: u32) -> &mut Self {
+            let code = value as u64 + 1;
+            let length = 64 - code.leading_zeros() as usize;
+            self.bits.extend(std::iter::repeat_n(false, length - 1));
+            self.bits.extend((0..length).rev().map(|bit| (code >> bit) & 1 == 1));
+            self
+        }
+
+        // With the RBSP stop bit
+        pub(crate) fn finish(&mut self) -> Vec<u8> {
+            self.bits.push(true);
+            self.bits.chunks(8).map(|byte| byte.iter().enumerate().fold(0, |value, (i, bit)| value | (*bit as u8) << (7 - i))).collect()
+        }
+    }
+
+    #[test]
+    fn reads_exp_golomb_codes() {
+        // 1, 010, 011, 00100, 00111, then 0001000 for 7
+        let data = [0b1010_0110, 0b0100_0011, 0b1000_1000];
+        let mut reader = BitReader::new(&data);
+        let values: Vec<u32> = (0..6).map(|_| reader.read_ue().unwrap()).collect();
+        assert_eq!(values, [0, 1, 2, 3, 6, 7]);
+        assert_eq!(reader.bits_left(), 0);
+    }
+
+    #[test]
+    fn reads_signed_exp_golomb_codes() {
+        // 1, 010, 011, 00100, 00101 as 0, 1, -1, 2, -2
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_0000];
+        let mut reader = BitReader::new(&data);
+        let values: Vec<i32> = (0..5).map(|_| reader.read_se().unwrap()).collect();
+        assert_eq!(values, [0, 1, -1, 2, -2]);
+    }
+
+    #[test]
+    fn reads_the_largest_exp_golomb_code() {
+        // 31 zeros, then a one and 31 ones
+        let data = [0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(BitReader::new(&data).read_ue(), Ok(u32::MAX - 1));
+    }
+
+    #[test]
+    fn rejects_bad_exp_golomb_codes() {
+        // More leading zeros than fit in 32 bits
+        assert!(BitReader::new(&[0, 0, 0, 0, 0xFF]).read_ue().is_err());
+        // Cut off before the value bits
+        assert!(BitReader::new(&[0b0000_1000]).read_ue().is_err());
+        assert!(BitReader::new(&[]).read_ue().is_err());
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let data = BitWriter::default().bits(5, 3).ue(0).ue(41).ue(u32::MAX - 1).bits(1, 1).finish();
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read_bits(3), Ok(5));
+        assert_eq!(reader.read_ue(), Ok(0));
+        assert_eq!(reader.read_ue(), Ok(41));
+        assert_eq!(reader.read_ue(), Ok(u32::MAX - 1));
+        assert_eq!(reader.read_bit(), Ok(true));
+    }
+
+    #[test]
+    fn removes_emulation_prevention() {
+        assert_eq!(remove_emulation_prevention(&[0, 0, 3, 1, 0, 0, 3, 0, 3]), [0, 0, 1, 0, 0, 0, 3]);
+    }
+}
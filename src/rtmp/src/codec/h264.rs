@@ -0,0 +1,312 @@
+use crate::codec::bits::{remove_emulation_prevention, BitReader};
+
+// ITU-T H.264 7.3.2.1.1, the sequence parameter set. We only keep what describes the picture,
+// which is also about as far as it's safe to parse without a full decoder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    // constraint_set0_flag to constraint_set5_flag and the reserved bits, as one byte
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    // 0 monochrome, 1 4:2:0, 2 4:2:2, 3 4:4:4
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub width: u32,
+    pub height: u32,
+    pub interlaced: bool,
+    // From the VUI timing info, if the encoder bothered to write it
+    pub frame_rate: Option<f64>,
+}
+
+impl Sps {
+    // Takes the whole NAL unit, header byte included
+    pub fn parse(nalu: &[u8]) -> Result<Self, &'static str> {
+        if nalu.len() < 4 || nalu[0] & 0x1F != 7 {
+            Err("Not an H.264 SPS")?
+        }
+
+        let rbsp = remove_emulation_prevention(&nalu[1..]);
+        let mut reader = BitReader::new(&rbsp);
+
+        let profile_idc = reader.read_bits(8)? as u8;
+        let constraint_flags = reader.read_bits(8)? as u8;
+        let level_idc = reader.read_bits(8)? as u8;
+        let seq_parameter_set_id = reader.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+            chroma_format_idc = match reader.read_ue()? {
+                chroma_format_idc @ 0..=3 => chroma_format_idc as u8,
+                _ => Err("Invalid chroma format")?,
+            };
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_bit()?;
+            }
+            bit_depth_luma = read_bit_depth(&mut reader)?;
+            bit_depth_chroma = read_bit_depth(&mut reader)?;
+            // qpprime_y_zero_transform_bypass_flag
+            reader.skip_bits(1)?;
+            if reader.read_bit()? {
+                let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..count {
+                    if reader.read_bit()? {
+                        skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        // log2_max_frame_num_minus4
+        reader.read_ue()?;
+        match reader.read_ue()? {
+            0 => {
+                // log2_max_pic_order_cnt_lsb_minus4
+                reader.read_ue()?;
+            }
+            1 => {
+                // delta_pic_order_always_zero_flag, offset_for_non_ref_pic, offset_for_top_to_bottom_field
+                reader.skip_bits(1)?;
+                reader.read_se()?;
+                reader.read_se()?;
+                for _ in 0..reader.read_ue()? {
+                    reader.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+        reader.read_ue()?;
+        reader.skip_bits(1)?;
+
+        let width_in_mbs = reader.read_ue()? + 1;
+        let height_in_map_units = reader.read_ue()? + 1;
+        let frame_mbs_only = reader.read_bit()?;
+        if !frame_mbs_only {
+            // mb_adaptive_frame_field_flag
+            reader.skip_bits(1)?;
+        }
+        // direct_8x8_inference_flag
+        reader.skip_bits(1)?;
+
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+        if reader.read_bit()? {
+            crop_left = reader.read_ue()?;
+            crop_right = reader.read_ue()?;
+            crop_top = reader.read_ue()?;
+            crop_bottom = reader.read_ue()?;
+        }
+
+        // The cropping offsets are in chroma samples, and in field pairs for interlaced video
+        let field_factor = if frame_mbs_only { 1 } else { 2 };
+        let (crop_unit_x, crop_unit_y) = match (separate_colour_plane, chroma_format_idc) {
+            (true, _) | (_, 0) => (1, field_factor),
+            (_, 1) => (2, 2 * field_factor),
+            (_, 2) => (2, field_factor),
+            _ => (1, field_factor),
+        };
+
+        // All of it straight from the publisher, so nothing here may overflow
+        let coded_width = width_in_mbs.checked_mul(16);
+        let coded_height = height_in_map_units.checked_mul(16 * field_factor);
+        let crop_x = crop_left.checked_add(crop_right).and_then(|crop| crop.checked_mul(crop_unit_x));
+        let crop_y = crop_top.checked_add(crop_bottom).and_then(|crop| crop.checked_mul(crop_unit_y));
+        let (width, height) = match (coded_width, coded_height, crop_x, crop_y) {
+            (Some(width), Some(height), Some(crop_x), Some(crop_y)) => match (width.checked_sub(crop_x), height.checked_sub(crop_y)) {
+                (Some(width), Some(height)) => (width, height),
+                _ => Err("Cropping is larger than the picture")?,
+            },
+            _ => Err("Picture size out of range")?,
+        };
+
+        // Anything after this point is optional, a VUI we can't make sense of just means no frame rate
+        let frame_rate = match reader.read_bit() {
+            Ok(true) => read_vui_frame_rate(&mut reader).ok().flatten(),
+            _ => None,
+        };
+
+        Ok(Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            width,
+            height,
+            interlaced: !frame_mbs_only,
+            frame_rate,
+        })
+    }
+
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            66 if self.constraint_flags & 0x40 != 0 => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            44 => "CAVLC 4:4:4 Intra",
+            _ => "Unknown",
+        }
+    }
+
+    // The level as it's usually written, e.g. 3.1. Level 1b shows up as 1.1 with constraint_set3.
+    pub fn level(&self) -> f64 {
+        self.level_idc as f64 / 10.0
+    }
+
+    // RFC 6381 codecs parameter as used in HLS and DASH manifests, e.g. avc1.64001F
+    pub fn codecs(&self) -> String {
+        format!("avc1.{}", self.profile_level_id().to_uppercase())
+    }
+
+    // RFC 6184 profile-level-id
+    pub fn profile_level_id(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.profile_idc, self.constraint_flags, self.level_idc)
+    }
+}
+
+// bit_depth_minus8, which goes up to 6 for 14 bits
+fn read_bit_depth(reader: &mut BitReader) -> Result<u8, &'static str> {
+    match reader.read_ue()? {
+        bit_depth_minus8 @ 0..=6 => Ok(bit_depth_minus8 as u8 + 8),
+        _ => Err("Invalid bit depth"),
+    }
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Result<(), &'static str> {
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                Err("Invalid scaling list")?
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+// Annex E.1.1, up to the timing info
+fn read_vui_frame_rate(reader: &mut BitReader) -> Result<Option<f64>, &'static str> {
+    if reader.read_bit()? {
+        // aspect_ratio_idc, Extended_SAR carries the ratio explicitly
+        if reader.read_bits(8)? == 255 {
+            reader.skip_bits(32)?;
+        }
+    }
+    if reader.read_bit()? {
+        // overscan_appropriate_flag
+        reader.skip_bits(1)?;
+    }
+    if reader.read_bit()? {
+        // video_format, video_full_range_flag
+        reader.skip_bits(4)?;
+        if reader.read_bit()? {
+            // colour_primaries, transfer_characteristics, matrix_coefficients
+            reader.skip_bits(24)?;
+        }
+    }
+    if reader.read_bit()? {
+        // chroma_sample_loc_type_top_field, chroma_sample_loc_type_bottom_field
+        reader.read_ue()?;
+        reader.read_ue()?;
+    }
+    if !reader.read_bit()? {
+        return Ok(None);
+    }
+
+    let num_units_in_tick = reader.read_bits(32)?;
+    let time_scale = reader.read_bits(32)?;
+    if num_units_in_tick == 0 {
+        return Ok(None);
+    }
+    // A tick is a field, so a frame is two of them
+    Ok(Some(time_scale as f64 / (2.0 * num_units_in_tick as f64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::bits::tests::BitWriter;
+
+    // From an x264 encode, High 3.1 1280x720 at 30 fps
+    const SPS_720P: [u8; 26] = [
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x10, 0x00,
+        0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60,
+    ];
+
+    // A High profile SPS without a VUI
+    fn sps(bit_depth_minus8: u32, width_in_mbs_minus1: u32, height_in_map_units_minus1: u32, crop: Option<[u32; 4]>) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.bits(0x67, 8).bits(100, 8).bits(0, 8).bits(40, 8).ue(0);
+        // chroma_format_idc, the bit depths, qpprime_y_zero_transform_bypass_flag and no scaling matrix
+        writer.ue(1).ue(bit_depth_minus8).ue(bit_depth_minus8).bits(0, 2);
+        // log2_max_frame_num_minus4, pic_order_cnt_type, max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+        writer.ue(0).ue(2).ue(1).bits(0, 1);
+        // The size, frame_mbs_only_flag and direct_8x8_inference_flag
+        writer.ue(width_in_mbs_minus1).ue(height_in_map_units_minus1).bits(1, 1).bits(1, 1);
+        match crop {
+            Some(crop) => {
+                writer.bits(1, 1);
+                for offset in crop {
+                    writer.ue(offset);
+                }
+            }
+            None => {
+                writer.bits(0, 1);
+            }
+        }
+        writer.bits(0, 1).finish()
+    }
+
+    #[test]
+    fn parses_a_real_sps() {
+        let sps = Sps::parse(&SPS_720P).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert_eq!(sps.profile_name(), "High");
+        assert_eq!(sps.level(), 3.1);
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.bit_depth_chroma), (1, 8, 8));
+        assert!(!sps.interlaced);
+        assert_eq!(sps.frame_rate, Some(30.0));
+        assert_eq!(sps.codecs(), "avc1.64001F");
+    }
+
+    #[test]
+    fn parses_cropping_and_bit_depth() {
+        // 1920x1088 cropped to 1080 lines, which is 4 in 4:2:0 chroma samples
+        let sps = Sps::parse(&sps(2, 119, 67, Some([0, 0, 0, 4]))).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!(sps.bit_depth_luma, 10);
+        assert_eq!(sps.frame_rate, None);
+    }
+
+    #[test]
+    fn rejects_malformed_sps() {
+        assert!(Sps::parse(&SPS_720P[..8]).is_err());
+        assert!(Sps::parse(&[0x68, 0x64, 0x00, 0x1f]).is_err());
+        // bit_depth_minus8 that would overflow a u8
+        assert!(Sps::parse(&sps(248, 79, 44, None)).is_err());
+        assert!(Sps::parse(&sps(7, 79, 44, None)).is_err());
+        // Sizes that overflow once in pixels
+        assert!(Sps::parse(&sps(0, u32::MAX - 2, 44, None)).is_err());
+        assert!(Sps::parse(&sps(0, 79, u32::MAX - 2, None)).is_err());
+        // Cropping that overflows, or is larger than the picture
+        assert!(Sps::parse(&sps(0, 79, 44, Some([u32::MAX - 1, u32::MAX - 1, 0, 0]))).is_err());
+        assert!(Sps::parse(&sps(0, 79, 44, Some([0, 0, 200, 200]))).is_err());
+    }
+}
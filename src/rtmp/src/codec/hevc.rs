@@ -0,0 +1,464 @@
+use crate::codec::bits::{remove_emulation_prevention, BitReader};
+
+// ITU-T H.265 7.3.3, the general part of profile_tier_level() which both the VPS and SPS carry
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileTierLevel {
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_idc: u8,
+    pub profile_compatibility_flags: u32,
+    // The progressive/interlaced/non packed/frame only flags and the 44 bits after them
+    pub constraint_indicator_flags: u64,
+    pub level_idc: u8,
+}
+
+impl ProfileTierLevel {
+    fn parse(reader: &mut BitReader, max_sub_layers_minus1: u32) -> Result<Self, &'static str> {
+        let profile_space = reader.read_bits(2)? as u8;
+        let tier_flag = reader.read_bit()?;
+        let profile_idc = reader.read_bits(5)? as u8;
+        let profile_compatibility_flags = reader.read_bits(32)?;
+        let constraint_indicator_flags = ((reader.read_bits(16)? as u64) << 32) | reader.read_bits(32)? as u64;
+        let level_idc = reader.read_bits(8)? as u8;
+
+        let mut sub_layers = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            sub_layers.push((reader.read_bit()?, reader.read_bit()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            // reserved_zero_2bits up to 8 sub layers
+            reader.skip_bits(2 * (8 - max_sub_layers_minus1 as usize))?;
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                reader.skip_bits(88)?;
+            }
+            if level_present {
+                reader.skip_bits(8)?;
+            }
+        }
+
+        Ok(ProfileTierLevel {
+            profile_space,
+            tier_flag,
+            profile_idc,
+            profile_compatibility_flags,
+            constraint_indicator_flags,
+            level_idc,
+        })
+    }
+
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            1 => "Main",
+            2 => "Main 10",
+            3 => "Main Still Picture",
+            4 => "Range Extensions",
+            _ => "Unknown",
+        }
+    }
+
+    // The level as it's usually written, e.g. 4.1
+    pub fn level(&self) -> f64 {
+        self.level_idc as f64 / 30.0
+    }
+
+    // ISO/IEC 14496-15 E.3, the codecs parameter as used in HLS and DASH manifests, e.g. hvc1.1.6.L93.B0
+    pub fn codecs(&self) -> String {
+        let profile_space = match self.profile_space {
+            1 => "A",
+            2 => "B",
+            3 => "C",
+            _ => "",
+        };
+        let tier = if self.tier_flag { "H" } else { "L" };
+
+        let mut codecs = format!("hvc1.{}{}.{:X}.{}{}", profile_space, self.profile_idc, self.profile_compatibility_flags.reverse_bits(), tier, self.level_idc);
+
+        // The six constraint bytes, leaving out the trailing zero ones
+        let constraint_bytes = &self.constraint_indicator_flags.to_be_bytes()[2..8];
+        let used = constraint_bytes.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+        for byte in &constraint_bytes[..used] {
+            codecs.push_str(&format!(".{:X}", byte));
+        }
+        codecs
+    }
+}
+
+// 7.3.2.1, the video parameter set. Mostly interesting for its timing info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vps {
+    pub video_parameter_set_id: u8,
+    pub max_sub_layers: u8,
+    pub profile_tier_level: ProfileTierLevel,
+    pub frame_rate: Option<f64>,
+}
+
+impl Vps {
+    // Takes the whole NAL unit, both header bytes included
+    pub fn parse(nalu: &[u8]) -> Result<Self, &'static str> {
+        if nalu.len() < 3 || (nalu[0] >> 1) & 0x3F != 32 {
+            Err("Not an H.265 VPS")?
+        }
+
+        let rbsp = remove_emulation_prevention(&nalu[2..]);
+        let mut reader = BitReader::new(&rbsp);
+
+        let video_parameter_set_id = reader.read_bits(4)? as u8;
+        // vps_base_layer_internal_flag, vps_base_layer_available_flag, vps_max_layers_minus1
+        reader.skip_bits(8)?;
+        let max_sub_layers_minus1 = reader.read_bits(3)?;
+        // vps_temporal_id_nesting_flag, vps_reserved_0xffff_16bits
+        reader.skip_bits(17)?;
+        let profile_tier_level = ProfileTierLevel::parse(&mut reader, max_sub_layers_minus1)?;
+
+        let frame_rate = read_vps_frame_rate(&mut reader, max_sub_layers_minus1).ok().flatten();
+
+        Ok(Vps {
+            video_parameter_set_id,
+            max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+            profile_tier_level,
+            frame_rate,
+        })
+    }
+}
+
+fn read_vps_frame_rate(reader: &mut BitReader, max_sub_layers_minus1: u32) -> Result<Option<f64>, &'static str> {
+    let sub_layer_ordering_info_present = reader.read_bit()?;
+    let first = if sub_layer_ordering_info_present { 0 } else { max_sub_layers_minus1 };
+    for _ in first..=max_sub_layers_minus1 {
+        reader.read_ue()?;
+        reader.read_ue()?;
+        reader.read_ue()?;
+    }
+
+    let max_layer_id = reader.read_bits(6)?;
+    let num_layer_sets_minus1 = reader.read_ue()?;
+    reader.skip_bits(num_layer_sets_minus1 as usize * (max_layer_id as usize + 1))?;
+
+    if !reader.read_bit()? {
+        return Ok(None);
+    }
+    read_timing_info(reader)
+}
+
+// 7.3.2.2, the sequence parameter set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub video_parameter_set_id: u8,
+    pub max_sub_layers: u8,
+    pub profile_tier_level: ProfileTierLevel,
+    pub seq_parameter_set_id: u32,
+    // 0 monochrome, 1 4:2:0, 2 4:2:2, 3 4:4:4
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub width: u32,
+    pub height: u32,
+    // From the VUI timing info, if the encoder bothered to write it
+    pub frame_rate: Option<f64>,
+}
+
+impl Sps {
+    // Takes the whole NAL unit, both header bytes included
+    pub fn parse(nalu: &[u8]) -> Result<Self, &'static str> {
+        if nalu.len() < 3 || (nalu[0] >> 1) & 0x3F != 33 {
+            Err("Not an H.265 SPS")?
+        }
+
+        let rbsp = remove_emulation_prevention(&nalu[2..]);
+        let mut reader = BitReader::new(&rbsp);
+
+        let video_parameter_set_id = reader.read_bits(4)? as u8;
+        let max_sub_layers_minus1 = reader.read_bits(3)?;
+        // sps_temporal_id_nesting_flag
+        reader.skip_bits(1)?;
+        let profile_tier_level = ProfileTierLevel::parse(&mut reader, max_sub_layers_minus1)?;
+        let seq_parameter_set_id = reader.read_ue()?;
+
+        let chroma_format_idc = match reader.read_ue()? {
+            chroma_format_idc @ 0..=3 => chroma_format_idc as u8,
+            _ => Err("Invalid chroma format")?,
+        };
+        let mut separate_colour_plane = false;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = reader.read_bit()?;
+        }
+
+        let mut width = reader.read_ue()?;
+        let mut height = reader.read_ue()?;
+        if reader.read_bit()? {
+            // The conformance window offsets are in chroma samples
+            let (sub_width, sub_height) = match (separate_colour_plane, chroma_format_idc) {
+                (false, 1) => (2, 2),
+                (false, 2) => (2, 1),
+                _ => (1, 1),
+            };
+            let left = reader.read_ue()?;
+            let right = reader.read_ue()?;
+            let top = reader.read_ue()?;
+            let bottom = reader.read_ue()?;
+            // All of it straight from the publisher, so nothing here may overflow
+            let crop_x = left.checked_add(right).and_then(|crop| crop.checked_mul(sub_width));
+            let crop_y = top.checked_add(bottom).and_then(|crop| crop.checked_mul(sub_height));
+            (width, height) = match (crop_x.and_then(|crop_x| width.checked_sub(crop_x)), crop_y.and_then(|crop_y| height.checked_sub(crop_y))) {
+                (Some(width), Some(height)) => (width, height),
+                _ => Err("Conformance window is larger than the picture")?,
+            };
+        }
+
+        let bit_depth_luma = read_bit_depth(&mut reader)?;
+        let bit_depth_chroma = read_bit_depth(&mut reader)?;
+
+        // Everything up to the VUI only matters to decoders, but has to be walked through to get to it.
+        // Not getting there just means no frame rate.
+        let frame_rate = read_sps_frame_rate(&mut reader, max_sub_layers_minus1).ok().flatten();
+
+        Ok(Sps {
+            video_parameter_set_id,
+            max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+            profile_tier_level,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            width,
+            height,
+            frame_rate,
+        })
+    }
+}
+
+// bit_depth_minus8, which goes up to 8 for 16 bits
+fn read_bit_depth(reader: &mut BitReader) -> Result<u8, &'static str> {
+    match reader.read_ue()? {
+        bit_depth_minus8 @ 0..=8 => Ok(bit_depth_minus8 as u8 + 8),
+        _ => Err("Invalid bit depth"),
+    }
+}
+
+fn read_sps_frame_rate(reader: &mut BitReader, max_sub_layers_minus1: u32) -> Result<Option<f64>, &'static str> {
+    let log2_max_pic_order_cnt_lsb = match reader.read_ue()? {
+        log2_max_pic_order_cnt_lsb_minus4 @ 0..=12 => log2_max_pic_order_cnt_lsb_minus4 + 4,
+        _ => Err("Invalid log2_max_pic_order_cnt_lsb")?,
+    };
+
+    let sub_layer_ordering_info_present = reader.read_bit()?;
+    let first = if sub_layer_ordering_info_present { 0 } else { max_sub_layers_minus1 };
+    for _ in first..=max_sub_layers_minus1 {
+        reader.read_ue()?;
+        reader.read_ue()?;
+        reader.read_ue()?;
+    }
+
+    // Coding block and transform sizes and depths
+    for _ in 0..6 {
+        reader.read_ue()?;
+    }
+
+    // scaling_list_enabled_flag, sps_scaling_list_data_present_flag
+    if reader.read_bit()? && reader.read_bit()? {
+        skip_scaling_list_data(reader)?;
+    }
+
+    // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+    reader.skip_bits(2)?;
+    if reader.read_bit()? {
+        // PCM sample bit depths, sizes and pcm_loop_filter_disabled_flag
+        reader.skip_bits(8)?;
+        reader.read_ue()?;
+        reader.read_ue()?;
+        reader.skip_bits(1)?;
+    }
+
+    let num_short_term_ref_pic_sets = reader.read_ue()?;
+    if num_short_term_ref_pic_sets > 64 {
+        Err("Too many short term reference picture sets")?
+    }
+    let mut num_delta_pocs = Vec::new();
+    for index in 0..num_short_term_ref_pic_sets as usize {
+        let count = skip_short_term_ref_pic_set(reader, index, &num_delta_pocs)?;
+        num_delta_pocs.push(count);
+    }
+
+    if reader.read_bit()? {
+        for _ in 0..reader.read_ue()? {
+            // lt_ref_pic_poc_lsb_sps, used_by_curr_pic_lt_sps_flag
+            reader.skip_bits(log2_max_pic_order_cnt_lsb as usize + 1)?;
+        }
+    }
+
+    // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+    reader.skip_bits(2)?;
+    if !reader.read_bit()? {
+        return Ok(None);
+    }
+
+    // E.2.1, the VUI up to the timing info
+    if reader.read_bit()? {
+        // aspect_ratio_idc, EXTENDED_SAR carries the ratio explicitly
+        if reader.read_bits(8)? == 255 {
+            reader.skip_bits(32)?;
+        }
+    }
+    if reader.read_bit()? {
+        // overscan_appropriate_flag
+        reader.skip_bits(1)?;
+    }
+    if reader.read_bit()? {
+        // video_format, video_full_range_flag
+        reader.skip_bits(4)?;
+        if reader.read_bit()? {
+            // colour_primaries, transfer_characteristics, matrix_coeffs
+            reader.skip_bits(24)?;
+        }
+    }
+    if reader.read_bit()? {
+        reader.read_ue()?;
+        reader.read_ue()?;
+    }
+    // neutral_chroma_indication_flag, field_seq_flag, frame_field_info_present_flag
+    reader.skip_bits(3)?;
+    if reader.read_bit()? {
+        // default display window offsets
+        for _ in 0..4 {
+            reader.read_ue()?;
+        }
+    }
+    if !reader.read_bit()? {
+        return Ok(None);
+    }
+    read_timing_info(reader)
+}
+
+// num_units_in_tick and time_scale. Unlike H.264 a tick is a whole picture.
+fn read_timing_info(reader: &mut BitReader) -> Result<Option<f64>, &'static str> {
+    let num_units_in_tick = reader.read_bits(32)?;
+    let time_scale = reader.read_bits(32)?;
+    if num_units_in_tick == 0 {
+        return Ok(None);
+    }
+    Ok(Some(time_scale as f64 / num_units_in_tick as f64))
+}
+
+// 7.3.4
+fn skip_scaling_list_data(reader: &mut BitReader) -> Result<(), &'static str> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(step) {
+            if !reader.read_bit()? {
+                // scaling_list_pred_matrix_id_delta
+                reader.read_ue()?;
+                continue;
+            }
+            let coefficients = std::cmp::min(64, 1 << (4 + (size_id << 1)));
+            if size_id > 1 {
+                // scaling_list_dc_coef_minus8
+                reader.read_se()?;
+            }
+            for _ in 0..coefficients {
+                reader.read_se()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 7.3.7, returns NumDeltaPocs for the set, which later sets predicted from it depend on
+fn skip_short_term_ref_pic_set(reader: &mut BitReader, index: usize, num_delta_pocs: &[u32]) -> Result<u32, &'static str> {
+    if index != 0 && reader.read_bit()? {
+        // Predicted from the previous set. delta_idx_minus1 is only there in slice headers.
+        // delta_rps_sign, abs_delta_rps_minus1
+        reader.skip_bits(1)?;
+        reader.read_ue()?;
+
+        let mut count = 0;
+        for _ in 0..=num_delta_pocs[index - 1] {
+            let used_by_curr_pic = reader.read_bit()?;
+            let use_delta = used_by_curr_pic || reader.read_bit()?;
+            if use_delta {
+                count += 1;
+            }
+        }
+        return Ok(count);
+    }
+
+    let num_negative_pics = reader.read_ue()?;
+    let num_positive_pics = reader.read_ue()?;
+    if num_negative_pics > 16 || num_positive_pics > 16 {
+        Err("Too many pictures in reference picture set")?
+    }
+    for _ in 0..num_negative_pics + num_positive_pics {
+        // delta_poc_minus1, used_by_curr_pic_flag
+        reader.read_ue()?;
+        reader.skip_bits(1)?;
+    }
+    Ok(num_negative_pics + num_positive_pics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::bits::tests::BitWriter;
+
+    // From an x265 encode, Main 4.0 1920x1080 at 30 fps
+    const SPS_1080P: [u8; 42] = [
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+        0x00, 0x03, 0x00, 0x78, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x96, 0x56, 0x69, 0x24,
+        0xca, 0xe0, 0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0xe0, 0x80,
+    ];
+
+    // An SPS up to the bit depths, which is all Sps::parse needs
+    fn sps(width: u32, height: u32, window: Option<[u32; 4]>, bit_depth_minus8: u32) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        // NAL unit header, sps_video_parameter_set_id, sps_max_sub_layers_minus1, sps_temporal_id_nesting_flag
+        writer.bits(0x4201, 16).bits(0, 4).bits(0, 3).bits(1, 1);
+        // profile_tier_level() for Main, level 4
+        writer.bits(1, 8).bits(0x6000_0000, 32).bits(0x9000, 16).bits(0, 32).bits(120, 8);
+        // sps_seq_parameter_set_id, chroma_format_idc
+        writer.ue(0).ue(1).ue(width).ue(height);
+        match window {
+            Some(window) => {
+                writer.bits(1, 1);
+                for offset in window {
+                    writer.ue(offset);
+                }
+            }
+            None => {
+                writer.bits(0, 1);
+            }
+        }
+        writer.ue(bit_depth_minus8).ue(bit_depth_minus8).finish()
+    }
+
+    #[test]
+    fn parses_a_real_sps() {
+        let sps = Sps::parse(&SPS_1080P).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!(sps.profile_tier_level.profile_name(), "Main");
+        assert_eq!(sps.profile_tier_level.level(), 4.0);
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.bit_depth_chroma), (1, 8, 8));
+        assert_eq!(sps.frame_rate, Some(30.0));
+        assert_eq!(sps.profile_tier_level.codecs(), "hvc1.1.6.L120.90");
+    }
+
+    #[test]
+    fn parses_the_conformance_window_and_bit_depth() {
+        // 1920x1088 cropped to 1080 lines, which is 4 in 4:2:0 chroma samples
+        let sps = Sps::parse(&sps(1920, 1088, Some([0, 0, 0, 4]), 2)).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!(sps.bit_depth_luma, 10);
+    }
+
+    #[test]
+    fn rejects_malformed_sps() {
+        assert!(Sps::parse(&SPS_1080P[..12]).is_err());
+        assert!(Sps::parse(&[0x40, 0x01, 0x0c]).is_err());
+        // bit_depth_minus8 that would overflow a u8
+        assert!(Sps::parse(&sps(1920, 1080, None, 248)).is_err());
+        assert!(Sps::parse(&sps(1920, 1080, None, 9)).is_err());
+        // Conformance windows that overflow, or are larger than the picture
+        assert!(Sps::parse(&sps(1920, 1080, Some([u32::MAX - 1, u32::MAX - 1, 0, 0]), 0)).is_err());
+        assert!(Sps::parse(&sps(1920, 1080, Some([0, 0, 300, 300]), 0)).is_err());
+    }
+}
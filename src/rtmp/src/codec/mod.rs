@@ -0,0 +1,166 @@
+use crate::flv::audio::{AudioCodec, AudioConfiguration, AudioPacket, AudioTag};
+use crate::flv::video::{DecoderConfiguration, VideoCodec};
+
+pub mod bits;
+pub mod h264;
+pub mod hevc;
+pub mod sei;
+
+// What the parameter sets say about a video track. Unlike onMetaData this is what the decoder
+// will actually see.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoProperties {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: Option<f64>,
+    pub profile: &'static str,
+    pub level: f64,
+    pub chroma_format_idc: u8,
+    pub bit_depth: u8,
+    // RFC 6381 codecs parameter for HLS and DASH manifests
+    pub codecs: String,
+    // SDP a=fmtp parameters for RTP, without the payload type
+    pub fmtp: String,
+}
+
+impl VideoProperties {
+    // None for codecs we don't read parameter sets for
+    pub fn from_config(config: &DecoderConfiguration) -> Result<Option<Self>, &'static str> {
+        match config {
+            DecoderConfiguration::Avc(record) => {
+                let sps_nalu = match record.sps.first() {
+                    Some(sps) => sps,
+                    None => Err("AVC sequence header has no SPS")?,
+                };
+                let sps = h264::Sps::parse(sps_nalu)?;
+
+                let parameter_sets: Vec<String> = record.sps.iter().chain(record.pps.iter()).map(|nalu| base64_encode(nalu)).collect();
+                Ok(Some(VideoProperties {
+                    codec: VideoCodec::Avc,
+                    width: sps.width,
+                    height: sps.height,
+                    frame_rate: sps.frame_rate,
+                    profile: sps.profile_name(),
+                    level: sps.level(),
+                    chroma_format_idc: sps.chroma_format_idc,
+                    bit_depth: sps.bit_depth_luma,
+                    codecs: sps.codecs(),
+                    fmtp: format!("packetization-mode=1;profile-level-id={};sprop-parameter-sets={}", sps.profile_level_id(), parameter_sets.join(",")),
+                }))
+            }
+            DecoderConfiguration::Hevc(record) => {
+                let sps_nalus = record.sps();
+                let sps_nalu = match sps_nalus.first() {
+                    Some(sps) => sps,
+                    None => Err("HEVC sequence header has no SPS")?,
+                };
+                let sps = hevc::Sps::parse(sps_nalu)?;
+
+                // The VPS is where the timing info usually is
+                let vps = record.vps().first().and_then(|vps| hevc::Vps::parse(vps).ok());
+                let frame_rate = sps.frame_rate.or(vps.and_then(|vps| vps.frame_rate));
+
+                let sprop = |nalus: Vec<Vec<u8>>| nalus.iter().map(|nalu| base64_encode(nalu)).collect::<Vec<String>>().join(",");
+                Ok(Some(VideoProperties {
+                    codec: VideoCodec::Hevc,
+                    width: sps.width,
+                    height: sps.height,
+                    frame_rate,
+                    profile: sps.profile_tier_level.profile_name(),
+                    level: sps.profile_tier_level.level(),
+                    chroma_format_idc: sps.chroma_format_idc,
+                    bit_depth: sps.bit_depth_luma,
+                    codecs: sps.profile_tier_level.codecs(),
+                    fmtp: format!("sprop-vps={};sprop-sps={};sprop-pps={}", sprop(record.vps()), sprop(record.sps()), sprop(record.pps())),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn chroma_format(&self) -> &'static str {
+        match self.chroma_format_idc {
+            0 => "4:0:0",
+            1 => "4:2:0",
+            2 => "4:2:2",
+            _ => "4:4:4",
+        }
+    }
+}
+
+// The same for an audio track, as far as its sequence header or frames tell
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioProperties {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub channels: u8,
+    // RFC 6381 codecs parameter, None for codecs HLS and DASH have no use for
+    pub codecs: Option<String>,
+}
+
+impl AudioProperties {
+    pub fn from_tag(tag: &AudioTag) -> Self {
+        let codecs = match (&tag.codec, &tag.packet) {
+            (AudioCodec::Aac, AudioPacket::SequenceHeader(AudioConfiguration::Aac(config))) => Some(format!("mp4a.40.{}", config.object_type)),
+            (AudioCodec::Aac, _) => Some("mp4a.40.2".to_string()),
+            (AudioCodec::Mp3, _) => Some("mp4a.40.34".to_string()),
+            (AudioCodec::Opus, _) => Some("opus".to_string()),
+            (AudioCodec::Flac, _) => Some("fLaC".to_string()),
+            (AudioCodec::Ac3, _) => Some("ac-3".to_string()),
+            (AudioCodec::Eac3, _) => Some("ec-3".to_string()),
+            _ => None,
+        };
+
+        AudioProperties {
+            codec: tag.codec,
+            sample_rate: tag.sample_rate,
+            channels: tag.channels,
+            codecs,
+        }
+    }
+}
+
+// An SMPTE timecode, HH:MM:SS:FF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timecode {
+    pub hours: u32,
+    pub minutes: u32,
+    pub seconds: u32,
+    pub frames: u32,
+}
+
+impl Timecode {
+    // Drop frame timecodes separate the frames with a semicolon, we treat them the same
+    pub fn parse(text: &str) -> Option<Self> {
+        let fields: Vec<u32> = text.split([':', ';', '.']).map(|field| field.trim().parse().ok()).collect::<Option<Vec<u32>>>()?;
+        match fields[..] {
+            [hours, minutes, seconds, frames] if minutes < 60 && seconds < 60 => Some(Timecode { hours, minutes, seconds, frames }),
+            _ => None,
+        }
+    }
+
+    pub fn to_millis(&self, frame_rate: f64) -> i64 {
+        let seconds = (self.hours as i64 * 60 + self.minutes as i64) * 60 + self.seconds as i64;
+        seconds * 1000 + (self.frames as f64 * 1000.0 / frame_rate) as i64
+    }
+}
+
+// RFC 4648 base64 with padding, as SDP wants parameter sets
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
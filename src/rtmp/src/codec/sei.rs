@@ -0,0 +1,133 @@
+use media_core::{Projection, ProjectionKind, StereoMode};
+use crate::codec::Timecode;
+use crate::codec::bits::{remove_emulation_prevention, BitReader};
+
+// SEI messages that describe how the picture is meant to be viewed. H.264 (D.1) and H.265 (D.2)
+// share the payload types and syntax for all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeiMessage {
+    // frame_packing_arrangement_type, 3 is side by side and 4 top and bottom
+    FramePacking(u8),
+    EquirectangularProjection,
+    CubemapProjection,
+    // The time the picture was shot at, H.265 only
+    TimeCode(Timecode),
+}
+
+const FRAME_PACKING_ARRANGEMENT: u32 = 45;
+const EQUIRECTANGULAR_PROJECTION: u32 = 150;
+const CUBEMAP_PROJECTION: u32 = 151;
+const TIME_CODE: u32 = 136;
+
+// Takes a whole SEI NAL unit, header included. Messages we don't know about or that cancel an
+// earlier one are left out.
+pub fn parse_sei(nalu: &[u8], hevc: bool) -> Vec<SeiMessage> {
+    let header_size = if hevc { 2 } else { 1 };
+    if nalu.len() <= header_size {
+        return Vec::new();
+    }
+    let rbsp = remove_emulation_prevention(&nalu[header_size..]);
+
+    let mut messages = Vec::new();
+    let mut position = 0;
+    // The RBSP ends with the trailing bits 0x80
+    while position + 2 <= rbsp.len() && rbsp[position] != 0x80 {
+        let payload_type = match read_ff_coded(&rbsp, &mut position) {
+            Some(value) => value,
+            None => break,
+        };
+        let payload_size = match read_ff_coded(&rbsp, &mut position) {
+            Some(value) => value as usize,
+            None => break,
+        };
+        if position + payload_size > rbsp.len() {
+            break;
+        }
+
+        let payload = &rbsp[position..position + payload_size];
+        position += payload_size;
+
+        let message = match payload_type {
+            FRAME_PACKING_ARRANGEMENT => parse_frame_packing(payload).ok().flatten(),
+            // Both start with their cancel flag
+            EQUIRECTANGULAR_PROJECTION if payload.first().is_some_and(|byte| byte & 0x80 == 0) => Some(SeiMessage::EquirectangularProjection),
+            CUBEMAP_PROJECTION if payload.first().is_some_and(|byte| byte & 0x80 == 0) => Some(SeiMessage::CubemapProjection),
+            TIME_CODE if hevc => parse_time_code(payload).ok().flatten(),
+            _ => None,
+        };
+        messages.extend(message);
+    }
+    messages
+}
+
+// The projection the SEI messages of a frame add up to, if any
+pub fn projection(messages: &[SeiMessage]) -> Option<Projection> {
+    let kind = messages.iter().find_map(|message| match message {
+        SeiMessage::EquirectangularProjection => Some(ProjectionKind::Equirectangular),
+        SeiMessage::CubemapProjection => Some(ProjectionKind::Cubemap),
+        _ => None,
+    })?;
+
+    let stereo = messages.iter().find_map(|message| match message {
+        SeiMessage::FramePacking(3) => Some(StereoMode::LeftRight),
+        SeiMessage::FramePacking(4) => Some(StereoMode::TopBottom),
+        _ => None,
+    }).unwrap_or(StereoMode::Mono);
+
+    Some(Projection { kind, stereo })
+}
+
+// Payload types and sizes are sums of bytes, each 0xFF meaning another byte follows
+fn read_ff_coded(data: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    loop {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value += byte as u32;
+        if byte != 0xFF {
+            return Some(value);
+        }
+    }
+}
+
+fn parse_frame_packing(payload: &[u8]) -> Result<Option<SeiMessage>, &'static str> {
+    let mut reader = BitReader::new(payload);
+    // frame_packing_arrangement_id
+    reader.read_ue()?;
+    if reader.read_bit()? {
+        return Ok(None);
+    }
+    Ok(Some(SeiMessage::FramePacking(reader.read_bits(7)? as u8)))
+}
+
+// D.2.27, only the first clock timestamp is used
+fn parse_time_code(payload: &[u8]) -> Result<Option<SeiMessage>, &'static str> {
+    let mut reader = BitReader::new(payload);
+    if reader.read_bits(2)? == 0 || !reader.read_bit()? {
+        return Ok(None);
+    }
+
+    // units_field_based_flag, counting_type
+    reader.skip_bits(6)?;
+    let full_timestamp = reader.read_bit()?;
+    // discontinuity_flag, cnt_dropped_flag
+    reader.skip_bits(2)?;
+    let frames = reader.read_bits(9)?;
+
+    let (mut hours, mut minutes, mut seconds) = (0, 0, 0);
+    if full_timestamp {
+        seconds = reader.read_bits(6)?;
+        minutes = reader.read_bits(6)?;
+        hours = reader.read_bits(5)?;
+    } else if reader.read_bit()? {
+        seconds = reader.read_bits(6)?;
+        if reader.read_bit()? {
+            minutes = reader.read_bits(6)?;
+            if reader.read_bit()? {
+                hours = reader.read_bits(5)?;
+            }
+        }
+    }
+
+    Ok(Some(SeiMessage::TimeCode(Timecode { hours, minutes, seconds, frames })))
+}
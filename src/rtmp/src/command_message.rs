@@ -22,6 +22,19 @@ pub struct PlayMessage {
     pub reset: bool,
 }
 
+#[derive(Debug)]
+pub struct SeekMessage {
+    pub milliseconds: f64,
+}
+
+#[derive(Debug)]
+pub struct PauseMessage {
+    // True to pause, false to carry on
+    pub pause: bool,
+    // Where the stream was paused, or where to carry on from
+    pub milliseconds: f64,
+}
+
 fn vec_pair_to_tuple(source: Vec<Pair<String, Value>>) -> Vec<(String, Value)> {
     source.into_iter().map(|p| (p.key, p.value)).collect()
 }
@@ -100,16 +113,72 @@ impl Serializable for PlayMessage {
             _ => Err("Error reading AMF0 Start")?,
         };
 
+        // Duration and reset are optional, fall back to playing until the stream ends
+        let duration: f64 = match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Number(x))) => x,
+            _ => -1.0,
+        };
+
+        let reset: bool = match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Boolean(x))) => x,
+            _ => true,
+        };
 
         Ok(PlayMessage {
             stream_name,
             start,
-            duration: 0.0,
-            reset: false,
+            duration,
+            reset,
         })
     }
 
     fn serialize(&self) -> Result<Vec<u8>, &'static str> {
         Ok(Vec::new())
     }
-}
\ No newline at end of file
+}
+
+impl Serializable for SeekMessage {
+    fn deserialize<R>(mut reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized {
+        match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Null)) => {},
+            _ => Err("Error reading AMF0 Null")?,
+        }
+
+        let milliseconds: f64 = match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Number(x))) => x,
+            _ => Err("Error reading AMF0 Milliseconds")?,
+        };
+
+        Ok(SeekMessage { milliseconds })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        Ok(Vec::new())
+    }
+}
+
+impl Serializable for PauseMessage {
+    fn deserialize<R>(mut reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized {
+        match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Null)) => {},
+            _ => Err("Error reading AMF0 Null")?,
+        }
+
+        let pause: bool = match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Boolean(x))) => x,
+            _ => Err("Error reading AMF0 Pause Flag")?,
+        };
+
+        // Some players leave the position out
+        let milliseconds: f64 = match amf::Value::read_from(&mut reader, Version::Amf0) {
+            Ok(amf::Value::Amf0(Value::Number(x))) => x,
+            _ => -1.0,
+        };
+
+        Ok(PauseMessage { pause, milliseconds })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        Ok(Vec::new())
+    }
+}
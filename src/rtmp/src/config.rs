@@ -0,0 +1,464 @@
+use std::time::Duration;
+use serde::Deserialize;
+
+// Server configuration. Every section is optional in the config file and falls back to
+// the defaults below, so an empty file (or no file at all) gives the stock behaviour.
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtmpConfig {
+    // The plain RTMP listener
+    pub listen: String,
+    pub hooks: HookConfig,
+    pub publish: PublishConfig,
+    pub timeouts: TimeoutConfig,
+    pub rtmps: RtmpsConfig,
+    pub groups: Vec<StreamGroupConfig>,
+    pub record: RecordConfig,
+    pub vod: VodConfig,
+    pub http: HttpConfig,
+    pub websocket: WebSocketConfig,
+    pub rtmpt: RtmptConfig,
+    pub hls: HlsConfig,
+    pub relay: RelayConfig,
+    pub rtsp: RtspConfig,
+}
+
+impl Default for RtmpConfig {
+    fn default() -> Self {
+        RtmpConfig {
+            listen: "127.0.0.1:1935".to_string(),
+            hooks: HookConfig::default(),
+            publish: PublishConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            rtmps: RtmpsConfig::default(),
+            groups: Vec::new(),
+            record: RecordConfig::default(),
+            vod: VodConfig::default(),
+            http: HttpConfig::default(),
+            websocket: WebSocketConfig::default(),
+            rtmpt: RtmptConfig::default(),
+            hls: HlsConfig::default(),
+            relay: RelayConfig::default(),
+            rtsp: RtspConfig::default(),
+        }
+    }
+}
+
+// Used to get rid of dead peers, e.g. a crashed encoder whose TCP connection never got closed.
+// Zero disables the timeout in question.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub handshake_ms: u64,
+    // Publishers that stop sending audio and video for this long are disconnected
+    pub publisher_idle_ms: u64,
+    // Players and everyone else are disconnected after not sending us anything for this long,
+    // not even an acknowledgement or a ping response
+    pub player_idle_ms: u64,
+    // How often we send a ping request, which also measures the round trip time
+    pub ping_interval_ms: u64,
+    // Peers that don't take what we send for this long are disconnected, e.g. a player whose
+    // connection stalled
+    pub write_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            handshake_ms: 10000,
+            publisher_idle_ms: 10000,
+            player_idle_ms: 30000,
+            ping_interval_ms: 5000,
+            write_ms: 10000,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn handshake(&self) -> Option<Duration> {
+        Self::duration(self.handshake_ms)
+    }
+
+    pub fn publisher_idle(&self) -> Option<Duration> {
+        Self::duration(self.publisher_idle_ms)
+    }
+
+    pub fn player_idle(&self) -> Option<Duration> {
+        Self::duration(self.player_idle_ms)
+    }
+
+    pub fn ping_interval(&self) -> Option<Duration> {
+        Self::duration(self.ping_interval_ms)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        Self::duration(self.write_ms)
+    }
+
+    fn duration(ms: u64) -> Option<Duration> {
+        if ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(ms))
+        }
+    }
+}
+
+// RTMPS: RTMP over TLS, on a listener next to the plain one. Certificates and keys are PEM files,
+// checked for changes every reload interval so renewed certificates get picked up without a
+// restart. Zero never reloads.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtmpsConfig {
+    pub enabled: bool,
+    // 1936 sits next to plain RTMP, 443 is what gets through the strictest firewalls
+    pub listen: String,
+    // For clients asking for a name none of the certificates below is for, or none at all
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    // Picked by the name clients ask for with SNI. Configured as [[rtmps.certificates]] tables.
+    pub certificates: Vec<CertificateConfig>,
+    pub reload_interval_ms: u64,
+}
+
+impl Default for RtmpsConfig {
+    fn default() -> Self {
+        RtmpsConfig {
+            enabled: false,
+            listen: "127.0.0.1:1936".to_string(),
+            cert_path: None,
+            key_path: None,
+            certificates: Vec::new(),
+            reload_interval_ms: 60000,
+        }
+    }
+}
+
+// A certificate for a server name, which can start with a *. wildcard
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CertificateConfig {
+    pub server_name: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PublishConfig {
+    pub duplicate_policy: DuplicatePublisherPolicy,
+    // How long players stay attached to a stream after its publisher drops, waiting for it to
+    // reconnect. Zero ends the stream as soon as the publisher is gone.
+    pub reconnect_grace_ms: u64,
+    // Timestamps further than this from the rest of the stream are treated as a discontinuity
+    // and rebased
+    pub timestamp_tolerance_ms: u32,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            duplicate_policy: DuplicatePublisherPolicy::default(),
+            reconnect_grace_ms: 0,
+            timestamp_tolerance_ms: 1000,
+        }
+    }
+}
+
+// What to do when a second encoder publishes a stream name that is already live
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePublisherPolicy {
+    // Refuse the newcomer with NetStream.Publish.BadName
+    #[default]
+    Reject,
+    // The newcomer replaces the current publisher, which gets disconnected
+    Takeover,
+    // The newcomer waits on standby and goes live once the current publisher leaves
+    KeepFirst,
+}
+
+// Streams that were shot together, e.g. the cameras of a VR rig, and get aligned onto one clock.
+// Configured as [[groups]] tables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StreamGroupConfig {
+    pub name: String,
+    pub members: Vec<String>,
+    // Align members by the timecodes in their onMetaData or SEI when they all have them, rather
+    // than by when their first frame came in
+    pub use_timecodes: bool,
+    // Members drifting further than this from the rest of the group get logged
+    pub drift_warning_ms: u64,
+    // How often the drift statistics are logged. Zero only logs warnings.
+    pub stats_interval_ms: u64,
+}
+
+impl Default for StreamGroupConfig {
+    fn default() -> Self {
+        StreamGroupConfig {
+            name: String::new(),
+            members: Vec::new(),
+            use_timecodes: true,
+            drift_warning_ms: 100,
+            stats_interval_ms: 10000,
+        }
+    }
+}
+
+// Streams published as record or append are written to {path}/{name}.flv, record starting the
+// file over and append adding to it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordConfig {
+    pub path: String,
+    // Record live streams too. Each publish gets its own file, named after when it started.
+    pub live: bool,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        RecordConfig {
+            path: "recordings".to_string(),
+            live: false,
+        }
+    }
+}
+
+// Recorded content players can play. Playing a name that matches {path}/{name}.flv plays the
+// file rather than a live stream. Off unless a path is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct VodConfig {
+    pub path: Option<String>,
+}
+
+// The HTTP listener for HTTP-FLV, WebSocket-FLV, HLS and DASH playback. Off unless enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub listen: String,
+    // Sent as Access-Control-Allow-Origin so browser players on other origins can use the
+    // streams, e.g. "*". Empty leaves CORS out.
+    pub allow_origin: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+            allow_origin: String::new(),
+        }
+    }
+}
+
+// WebSocket-FLV on the HTTP listener: the HTTP-FLV URLs, asked for with a WebSocket upgrade
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    // How many tags can wait to be sent to a player before slow_player kicks in
+    pub queue_size: usize,
+    pub slow_player: SlowPlayerPolicy,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            enabled: true,
+            queue_size: 512,
+            slow_player: SlowPlayerPolicy::default(),
+        }
+    }
+}
+
+// RTMPT on the HTTP listener: RTMP tunneled through POSTs, for clients behind proxies that only
+// let HTTP through. Off unless enabled, it's a way in for publishers too.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtmptConfig {
+    pub enabled: bool,
+    // Sessions whose client sent no request for this long are closed
+    pub session_timeout_ms: u64,
+}
+
+impl Default for RtmptConfig {
+    fn default() -> Self {
+        RtmptConfig {
+            enabled: false,
+            session_timeout_ms: 30000,
+        }
+    }
+}
+
+// The RTSP listener, for the same streams as RTMP. Off unless enabled. RTSP publishers don't go
+// through the hooks, only enable it where anybody who can connect may publish.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtspConfig {
+    pub enabled: bool,
+    // 554 is the standard port, which takes root to bind
+    pub listen: String,
+}
+
+impl Default for RtspConfig {
+    fn default() -> Self {
+        RtspConfig {
+            enabled: false,
+            listen: "127.0.0.1:554".to_string(),
+        }
+    }
+}
+
+// What to do with a player whose queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowPlayerPolicy {
+    // Drop tags, then video until the next keyframe, like RTMP and HTTP-FLV players get
+    #[default]
+    DropFrames,
+    // Disconnect the player, e.g. for players that would rather reconnect than skip
+    Disconnect,
+}
+
+// HLS output of every published stream, GET /{app}/{stream}.m3u8 on the HTTP listener, and
+// optionally DASH. Off unless enabled, it keeps every stream's segments in memory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HlsConfig {
+    pub enabled: bool,
+    // Segments are cut at the first keyframe after this long
+    pub segment_duration_ms: u64,
+    // How many segments the playlist offers
+    pub playlist_length: usize,
+    // Low latency HLS: fMP4 segments cut into parts of this long, which players can fetch as
+    // soon as they're done
+    pub low_latency: bool,
+    pub part_duration_ms: u64,
+    // Also offer the segments as MPEG-DASH, GET /{app}/{stream}.mpd. They're fMP4 then, which HLS
+    // and DASH share.
+    pub dash: bool,
+    // Also write playlists and segments to {path}/{stream}/, for a web server or CDN to pick up.
+    // Parts stay in memory, low latency needs the blocking requests only we can answer.
+    pub path: Option<String>,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        HlsConfig {
+            enabled: false,
+            segment_duration_ms: 4000,
+            playlist_length: 6,
+            low_latency: false,
+            part_duration_ms: 500,
+            dash: false,
+            path: None,
+        }
+    }
+}
+
+// Relaying streams to and from other RTMP servers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    // Configured as [[relay.push]] tables
+    pub push: Vec<PushConfig>,
+    // Edge mode: streams players ask for that nobody publishes here get pulled from the first of
+    // these origins that has them. URLs without a stream name get the name that was asked for.
+    pub origins: Vec<String>,
+    // How long a pull keeps going after its last player left
+    pub pull_idle_timeout_ms: u64,
+    // Relays that lose their connection retry after the minimum, doubling up to the maximum
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            push: Vec::new(),
+            origins: Vec::new(),
+            pull_idle_timeout_ms: 10000,
+            reconnect_min_ms: 1000,
+            reconnect_max_ms: 30000,
+        }
+    }
+}
+
+// Streams published over RTMP that get pushed on to other servers, like nginx-rtmp's push. Empty
+// app or stream match any.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    pub app: String,
+    pub stream: String,
+    // Where to push to. URLs without a stream name get the name it was published as.
+    pub urls: Vec<String>,
+}
+
+impl PushConfig {
+    pub fn matches(&self, app: &str, stream: &str) -> bool {
+        (self.app.is_empty() || self.app == app) && (self.stream.is_empty() || self.stream == stream)
+    }
+}
+
+// HTTP callbacks fired on stream lifecycle events, modelled after nginx-rtmp's on_* directives.
+// Each URL receives a JSON POST. Only on_publish and on_play can deny the action, by
+// answering with anything other than a 2xx status.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    pub on_connect: Option<String>,
+    pub on_publish: Option<String>,
+    pub on_publish_done: Option<String>,
+    pub on_play: Option<String>,
+    pub on_play_done: Option<String>,
+    pub on_record_done: Option<String>,
+    pub timeout_ms: u64,
+    // Whether to allow the action when the hook can't be reached or times out
+    pub fail_open: bool,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        HookConfig {
+            on_connect: None,
+            on_publish: None,
+            on_publish_done: None,
+            on_play: None,
+            on_play_done: None,
+            on_record_done: None,
+            timeout_ms: 3000,
+            fail_open: false,
+        }
+    }
+}
+
+impl HookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl RtmpConfig {
+    pub fn load(path: &str) -> Result<Self, &'static str> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Error reading config file {}: {}", path, err);
+                return Err("Error reading config file");
+            }
+        };
+
+        match toml::from_str(&text) {
+            Ok(config) => Ok(config),
+            Err(err) => {
+                eprintln!("Error parsing config file {}: {}", path, err);
+                Err("Error parsing config file")
+            }
+        }
+    }
+}
@@ -28,6 +28,31 @@ impl Serializable for SetChunkSize {
     }
 }
 
+// Message type 3, how many bytes we've received so far, sent every window acknowledgement size
+#[derive(Debug)]
+pub struct Acknowledgement {
+    pub sequence_number: u32,
+}
+
+impl Serializable for Acknowledgement {
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        Ok(self.sequence_number.to_be_bytes().to_vec())
+    }
+
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized
+    {
+        let mut sequence_number_bytes = [0u8; 4];
+        match reader.read_exact(&mut sequence_number_bytes) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading acknowledgement sequence number")?,
+        }
+
+        Ok(Acknowledgement {
+            sequence_number: u32::from_be_bytes(sequence_number_bytes),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct WindowAcknowledgementSize {
     // 32 bit integer
@@ -88,4 +113,71 @@ impl Serializable for SetPeerBandwidth {
             limit_type: limit_type_bytes[0],
         })
     }
-}
\ No newline at end of file
+}
+
+// Message type 4. Each event is a 2 byte event type followed by event specific data.
+#[derive(Debug)]
+pub struct UserControlMessage {
+    pub event_type: u16,
+    pub event_data: Vec<u8>,
+}
+
+impl UserControlMessage {
+    pub fn stream_begin(stream_id: u32) -> Self {
+        UserControlMessage {
+            event_type: 0,
+            event_data: stream_id.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn stream_eof(stream_id: u32) -> Self {
+        UserControlMessage {
+            event_type: 1,
+            event_data: stream_id.to_be_bytes().to_vec(),
+        }
+    }
+
+    // The peer answers with a ping response (event 7) carrying the same timestamp
+    pub fn ping_request(timestamp: u32) -> Self {
+        UserControlMessage {
+            event_type: 6,
+            event_data: timestamp.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn ping_response(timestamp: u32) -> Self {
+        UserControlMessage {
+            event_type: 7,
+            event_data: timestamp.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+impl Serializable for UserControlMessage {
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.event_type.to_be_bytes());
+        buf.extend_from_slice(&self.event_data);
+        Ok(buf)
+    }
+
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized
+    {
+        let mut event_type_bytes = [0u8; 2];
+        match reader.read_exact(&mut event_type_bytes) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading user control event type")?,
+        }
+
+        let mut event_data = Vec::new();
+        match reader.read_to_end(&mut event_data) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading user control event data")?,
+        }
+
+        Ok(UserControlMessage {
+            event_type: u16::from_be_bytes(event_type_bytes),
+            event_data,
+        })
+    }
+}
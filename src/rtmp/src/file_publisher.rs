@@ -0,0 +1,176 @@
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use crate::flv::file::{FlvReader, FlvTag};
+use crate::media::TagParsers;
+use crate::registry::StreamRegistry;
+use crate::server::{wait_for_kick, NEXT_CLIENT_ID};
+
+// Publishes an FLV file as a live stream, as if an encoder were sending it: tags go out when
+// their timestamps say so, through the same parsers and registry calls as RTMP ingest. Looping
+// makes for a stream that never ends, e.g. a test channel or a slate.
+
+// How many tags the reader gets ahead of the pacing
+const READ_AHEAD: usize = 64;
+
+pub struct FilePublisher {
+    pub path: PathBuf,
+    pub name: String,
+    // Start over at the end of the file rather than ending the stream
+    pub looping: bool,
+}
+
+impl FilePublisher {
+    pub fn new(path: &str, name: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            name: name.to_string(),
+            looping: false,
+        }
+    }
+
+    // Returns once the file is done, or when another publisher takes over the stream
+    pub async fn run(&self, registry: Arc<StreamRegistry>) -> Result<(), &'static str> {
+        let publisher_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut kicked = Some(registry.publish(&self.name, publisher_id, None)?);
+        println!("Publishing {} as {}", self.path.display(), self.name);
+
+        // Reading the file blocks, so it happens on a thread of its own
+        let (sender, mut tags) = mpsc::channel(READ_AHEAD);
+        let path = self.path.clone();
+        let looping = self.looping;
+        tokio::task::spawn_blocking(move || read_tags(&path, looping, sender));
+
+        let mut parsers = TagParsers::new();
+        let mut pacing = Pacing::new();
+        let result = loop {
+            let tag = tokio::select! {
+                tag = tags.recv() => tag,
+                _ = wait_for_kick(&mut kicked) => {
+                    println!("Publishing {} was taken over", self.name);
+                    break Ok(());
+                }
+            };
+
+            let tag = match tag {
+                Some(Ok(Some(tag))) => tag,
+                // End of one pass through the file, the next one carries on the timeline
+                Some(Ok(None)) => {
+                    pacing.next_pass();
+                    continue;
+                }
+                Some(Err(err)) => break Err(err),
+                None => break Ok(()),
+            };
+
+            let timestamp = pacing.timestamp(&tag);
+            tokio::time::sleep_until(pacing.deadline(timestamp)).await;
+            registry.send(&self.name, publisher_id, parsers.parse(&self.name, tag.tag_type, timestamp, tag.data));
+        };
+
+        registry.unpublish(&self.name, publisher_id);
+        println!("Stopped publishing {} as {}", self.path.display(), self.name);
+        result
+    }
+}
+
+// Sends Ok(None) after every pass through the file. Stops when the publisher is gone.
+fn read_tags(path: &Path, looping: bool, sender: mpsc::Sender<Result<Option<FlvTag>, &'static str>>) {
+    loop {
+        let mut reader = match std::fs::File::open(path) {
+            Ok(file) => match FlvReader::new(BufReader::new(file)) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    let _ = sender.blocking_send(Err(err));
+                    return;
+                }
+            },
+            Err(_) => {
+                let _ = sender.blocking_send(Err("Error opening FLV file"));
+                return;
+            }
+        };
+
+        let mut tags = 0;
+        loop {
+            let tag = match reader.next_tag() {
+                Ok(Some(tag)) => tag,
+                Ok(None) => break,
+                Err(err) => {
+                    let _ = sender.blocking_send(Err(err));
+                    return;
+                }
+            };
+            if !matches!(tag.tag_type, 8 | 9 | 18) {
+                continue;
+            }
+            tags += 1;
+            if sender.blocking_send(Ok(Some(tag))).is_err() {
+                return;
+            }
+        }
+
+        // Looping a file without anything in it would spin forever
+        if sender.blocking_send(Ok(None)).is_err() || !looping || tags == 0 {
+            return;
+        }
+    }
+}
+
+// Maps the file's timestamps onto one timeline across passes, and that timeline onto the clock
+struct Pacing {
+    started: Instant,
+    // Added to the file's timestamps, the length of the passes before this one
+    offset: u32,
+    first_timestamp: Option<u32>,
+    last_timestamp: u32,
+    // Between the last two video frames, so the next pass doesn't start on top of the last frame
+    frame_duration: u32,
+    last_video: Option<u32>,
+}
+
+impl Pacing {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            offset: 0,
+            first_timestamp: None,
+            last_timestamp: 0,
+            frame_duration: 40,
+            last_video: None,
+        }
+    }
+
+    fn timestamp(&mut self, tag: &FlvTag) -> u32 {
+        // Metadata is usually stamped 0, it goes out straight away with the rest of the stream time
+        if tag.tag_type == 18 {
+            return self.offset.wrapping_add(self.last_timestamp.saturating_sub(self.first_timestamp.unwrap_or(0)));
+        }
+
+        let first = *self.first_timestamp.get_or_insert(tag.timestamp);
+        if tag.tag_type == 9 {
+            if let Some(last_video) = self.last_video.filter(|&last_video| tag.timestamp > last_video) {
+                self.frame_duration = tag.timestamp - last_video;
+            }
+            self.last_video = Some(tag.timestamp);
+        }
+        self.last_timestamp = self.last_timestamp.max(tag.timestamp);
+        self.offset.wrapping_add(tag.timestamp.saturating_sub(first))
+    }
+
+    fn deadline(&self, timestamp: u32) -> Instant {
+        self.started + Duration::from_millis(timestamp as u64)
+    }
+
+    fn next_pass(&mut self) {
+        if let Some(first) = self.first_timestamp.take() {
+            self.offset = self.offset.wrapping_add(self.last_timestamp.saturating_sub(first) + self.frame_duration);
+        }
+        self.last_timestamp = 0;
+        self.last_video = None;
+    }
+}
@@ -0,0 +1,453 @@
+use crate::codec::bits::BitReader;
+
+// AUDIODATA as carried in RTMP audio messages (type 8) and FLV audio tags. The first byte holds
+// the sound format, rate, size and type, AAC adds a packet type on top.
+//
+// The rate/size/type flags are only accurate for the older codecs, everything else describes
+// itself in a sequence header or its frame headers. AudioTag always carries the real values.
+//
+// Enhanced RTMP uses sound format 9 (ExHeader), the low bits become a packet type and a FourCC
+// follows, which is how Opus, FLAC and AC-3 get carried.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioCodec {
+    LinearPcm,
+    Adpcm,
+    Mp3,
+    LinearPcmLittleEndian,
+    Nellymoser,
+    G711ALaw,
+    G711MuLaw,
+    Aac,
+    Speex,
+    DeviceSpecific,
+    Opus,
+    Flac,
+    Ac3,
+    Eac3,
+}
+
+impl AudioCodec {
+    fn from_fourcc(fourcc: &[u8]) -> Result<Self, &'static str> {
+        match fourcc {
+            b"Opus" => Ok(AudioCodec::Opus),
+            b"fLaC" => Ok(AudioCodec::Flac),
+            b"ac-3" => Ok(AudioCodec::Ac3),
+            b"ec-3" => Ok(AudioCodec::Eac3),
+            b"mp4a" => Ok(AudioCodec::Aac),
+            b".mp3" => Ok(AudioCodec::Mp3),
+            _ => Err("Unknown audio FourCC"),
+        }
+    }
+
+    pub fn fourcc(&self) -> Option<&'static str> {
+        match self {
+            AudioCodec::Opus => Some("Opus"),
+            AudioCodec::Flac => Some("fLaC"),
+            AudioCodec::Ac3 => Some("ac-3"),
+            AudioCodec::Eac3 => Some("ec-3"),
+            AudioCodec::Aac => Some("mp4a"),
+            AudioCodec::Mp3 => Some(".mp3"),
+            _ => None,
+        }
+    }
+}
+
+// What the decoder needs to know before the first frame
+#[derive(Debug, Clone)]
+pub enum AudioConfiguration {
+    Aac(AudioSpecificConfig),
+    // The Opus identification header (OpusHead)
+    Opus(Vec<u8>),
+    // The STREAMINFO metadata block, optionally preceded by the fLaC marker
+    Flac(Vec<u8>),
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub enum AudioPacket {
+    SequenceHeader(AudioConfiguration),
+    // One or more encoded frames, raw AAC has no ADTS header
+    Frame(Vec<u8>),
+    EndOfSequence,
+    // Packet types we don't look into are passed along as is
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioTag {
+    pub codec: AudioCodec,
+    // Whether the tag used the enhanced RTMP header
+    pub enhanced: bool,
+    pub sample_rate: u32,
+    pub channels: u8,
+    // Bits per sample, only meaningful for uncompressed formats
+    pub sample_size: u8,
+    pub packet: AudioPacket,
+}
+
+impl AudioTag {
+    pub fn is_sequence_header(&self) -> bool {
+        matches!(self.packet, AudioPacket::SequenceHeader(_))
+    }
+}
+
+// Keeps the format from the last sequence header, frames after it don't describe themselves
+pub struct AudioTagParser {
+    sample_rate: u32,
+    channels: u8,
+    aac_config: Option<AudioSpecificConfig>,
+}
+
+impl Default for AudioTagParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioTagParser {
+    pub fn new() -> Self {
+        Self {
+            sample_rate: 0,
+            channels: 0,
+            aac_config: None,
+        }
+    }
+
+    pub fn parse(&mut self, data: &[u8]) -> Result<AudioTag, &'static str> {
+        let first = match data.first() {
+            Some(first) => *first,
+            None => Err("Empty audio tag")?,
+        };
+
+        let sound_format = first >> 4;
+        if sound_format == 9 {
+            return self.parse_enhanced(data);
+        }
+
+        let flag_rate = [5512, 11025, 22050, 44100][((first >> 2) & 0b11) as usize];
+        let sample_size = if (first >> 1) & 1 == 1 { 16 } else { 8 };
+        let flag_channels = if first & 1 == 1 { 2 } else { 1 };
+        let body = &data[1..];
+
+        let (codec, sample_rate, channels) = match sound_format {
+            0 => (AudioCodec::LinearPcm, flag_rate, flag_channels),
+            1 => (AudioCodec::Adpcm, flag_rate, flag_channels),
+            2 => (AudioCodec::Mp3, flag_rate, flag_channels),
+            3 => (AudioCodec::LinearPcmLittleEndian, flag_rate, flag_channels),
+            4 => (AudioCodec::Nellymoser, 16000, 1),
+            5 => (AudioCodec::Nellymoser, 8000, 1),
+            6 => (AudioCodec::Nellymoser, flag_rate, flag_channels),
+            // G.711 is always 8kHz mono whatever the flags say
+            7 => (AudioCodec::G711ALaw, 8000, 1),
+            8 => (AudioCodec::G711MuLaw, 8000, 1),
+            10 => {
+                if body.is_empty() {
+                    Err("AAC audio tag is too short")?
+                }
+                return match body[0] {
+                    0 => self.aac_sequence_header(false, &body[1..]),
+                    1 => Ok(self.frame(AudioCodec::Aac, false, &body[1..])),
+                    _ => Err("Unknown AAC packet type"),
+                };
+            }
+            11 => (AudioCodec::Speex, 16000, 1),
+            // MP3 8kHz
+            14 => (AudioCodec::Mp3, 8000, flag_channels),
+            15 => (AudioCodec::DeviceSpecific, flag_rate, flag_channels),
+            _ => Err("Unknown sound format")?,
+        };
+
+        // The frame header knows better than the flags, which can't even express 48kHz
+        let (sample_rate, channels) = match codec {
+            AudioCodec::Mp3 => match Mp3FrameHeader::parse(body) {
+                Ok(header) => (header.sample_rate, header.channels),
+                Err(_) => (sample_rate, channels),
+            },
+            _ => (sample_rate, channels),
+        };
+
+        Ok(AudioTag {
+            codec,
+            enhanced: false,
+            sample_rate,
+            channels,
+            sample_size,
+            packet: AudioPacket::Frame(body.to_vec()),
+        })
+    }
+
+    fn parse_enhanced(&mut self, data: &[u8]) -> Result<AudioTag, &'static str> {
+        if data.len() < 5 {
+            Err("Enhanced audio tag is too short")?
+        }
+
+        let packet_type = data[0] & 0x0F;
+        let codec = AudioCodec::from_fourcc(&data[1..5])?;
+        let body = &data[5..];
+
+        match packet_type {
+            // SequenceStart
+            0 => match codec {
+                AudioCodec::Aac => self.aac_sequence_header(true, body),
+                AudioCodec::Opus => {
+                    let (sample_rate, channels) = parse_opus_head(body)?;
+                    Ok(self.sequence_header(codec, sample_rate, channels, AudioConfiguration::Opus(body.to_vec())))
+                }
+                AudioCodec::Flac => {
+                    let (sample_rate, channels) = parse_flac_stream_info(body)?;
+                    Ok(self.sequence_header(codec, sample_rate, channels, AudioConfiguration::Flac(body.to_vec())))
+                }
+                _ => Ok(self.sequence_header(codec, self.sample_rate, self.channels, AudioConfiguration::Other(body.to_vec()))),
+            },
+            // CodedFrames
+            1 => Ok(self.frame(codec, true, body)),
+            // SequenceEnd
+            2 => Ok(AudioTag {
+                codec,
+                enhanced: true,
+                sample_rate: self.sample_rate,
+                channels: self.channels,
+                sample_size: 16,
+                packet: AudioPacket::EndOfSequence,
+            }),
+            // MultichannelConfig, Multitrack and anything newer
+            _ => Ok(AudioTag {
+                codec,
+                enhanced: true,
+                sample_rate: self.sample_rate,
+                channels: self.channels,
+                sample_size: 16,
+                packet: AudioPacket::Other(body.to_vec()),
+            }),
+        }
+    }
+
+    fn aac_sequence_header(&mut self, enhanced: bool, body: &[u8]) -> Result<AudioTag, &'static str> {
+        let config = AudioSpecificConfig::parse(body)?;
+        self.aac_config = Some(config.clone());
+        let mut tag = self.sequence_header(AudioCodec::Aac, config.sample_rate, config.channel_configuration, AudioConfiguration::Aac(config));
+        tag.enhanced = enhanced;
+        Ok(tag)
+    }
+
+    fn sequence_header(&mut self, codec: AudioCodec, sample_rate: u32, channels: u8, config: AudioConfiguration) -> AudioTag {
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        AudioTag {
+            codec,
+            enhanced: true,
+            sample_rate,
+            channels,
+            sample_size: 16,
+            packet: AudioPacket::SequenceHeader(config),
+        }
+    }
+
+    fn frame(&mut self, codec: AudioCodec, enhanced: bool, body: &[u8]) -> AudioTag {
+        // Codecs without a sequence header have a frame header to go by instead
+        let described = match codec {
+            AudioCodec::Mp3 => Mp3FrameHeader::parse(body).ok().map(|header| (header.sample_rate, header.channels)),
+            AudioCodec::Ac3 | AudioCodec::Eac3 => parse_ac3_sync_frame(body).ok(),
+            AudioCodec::Aac => self.aac_config.as_ref().map(|config| (config.sample_rate, config.channel_configuration)),
+            _ => None,
+        };
+        if let Some((sample_rate, channels)) = described {
+            self.sample_rate = sample_rate;
+            self.channels = channels;
+        }
+
+        AudioTag {
+            codec,
+            enhanced,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            sample_size: 16,
+            packet: AudioPacket::Frame(body.to_vec()),
+        }
+    }
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+// ISO/IEC 14496-3 1.6.2.1, the AAC sequence header. Only the leading fields are parsed, the
+// record is kept as a whole for muxers that need to pass it on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSpecificConfig {
+    pub object_type: u8,
+    // 15 means the sample rate was given explicitly
+    pub sample_rate_index: u8,
+    pub sample_rate: u32,
+    pub channel_configuration: u8,
+    pub data: Vec<u8>,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let mut reader = BitReader::new(data);
+
+        let mut object_type = reader.read_bits(5)? as u8;
+        if object_type == 31 {
+            object_type = 32 + reader.read_bits(6)? as u8;
+        }
+
+        let sample_rate_index = reader.read_bits(4)? as u8;
+        let sample_rate = match sample_rate_index {
+            15 => reader.read_bits(24)?,
+            index => match AAC_SAMPLE_RATES.get(index as usize) {
+                Some(sample_rate) => *sample_rate,
+                None => Err("Invalid AAC sample rate index")?,
+            },
+        };
+
+        let channel_configuration = reader.read_bits(4)? as u8;
+
+        Ok(AudioSpecificConfig {
+            object_type,
+            sample_rate_index,
+            sample_rate,
+            channel_configuration,
+            data: data.to_vec(),
+        })
+    }
+}
+
+// The 4 byte header in front of every MPEG audio frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mp3FrameHeader {
+    // 1 for MPEG-1, 2 for MPEG-2 and 25 for MPEG-2.5
+    pub version: u8,
+    pub layer: u8,
+    // In kbit/s, 0 for free format
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl Mp3FrameHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < 4 || data[0] != 0xFF || data[1] & 0xE0 != 0xE0 {
+            Err("Missing MP3 frame sync")?
+        }
+
+        let (version, rate_divisor) = match (data[1] >> 3) & 0b11 {
+            0 => (25, 4),
+            2 => (2, 2),
+            3 => (1, 1),
+            _ => Err("Invalid MPEG audio version")?,
+        };
+        let layer = match (data[1] >> 1) & 0b11 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => Err("Invalid MPEG audio layer")?,
+        };
+
+        let bitrate_index = (data[2] >> 4) as usize;
+        if bitrate_index == 15 {
+            Err("Invalid MP3 bitrate index")?
+        }
+        let bitrate = match (version, layer) {
+            (1, 1) => [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448][bitrate_index],
+            (1, 2) => [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384][bitrate_index],
+            (1, _) => [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320][bitrate_index],
+            (_, 1) => [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256][bitrate_index],
+            _ => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160][bitrate_index],
+        };
+
+        let sample_rate = match (data[2] >> 2) & 0b11 {
+            0 => 44100,
+            1 => 48000,
+            2 => 32000,
+            _ => Err("Invalid MP3 sample rate index")?,
+        } / rate_divisor;
+
+        // Channel mode 3 is single channel
+        let channels = if data[3] >> 6 == 3 { 1 } else { 2 };
+
+        Ok(Mp3FrameHeader {
+            version,
+            layer,
+            bitrate,
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+// RFC 7845 5.1, Opus always decodes at 48kHz whatever the input rate was
+fn parse_opus_head(data: &[u8]) -> Result<(u32, u8), &'static str> {
+    if data.len() < 19 || &data[0..8] != b"OpusHead" {
+        Err("Invalid Opus identification header")?
+    }
+    Ok((48000, data[9]))
+}
+
+// The STREAMINFO metadata block, which comes first in every FLAC stream
+fn parse_flac_stream_info(data: &[u8]) -> Result<(u32, u8), &'static str> {
+    let data = match data.strip_prefix(b"fLaC") {
+        Some(data) => data,
+        None => data,
+    };
+
+    let mut reader = BitReader::new(data);
+    // Metadata block header, STREAMINFO is type 0
+    reader.skip_bits(1)?;
+    if reader.read_bits(7)? != 0 {
+        Err("FLAC sequence header doesn't start with STREAMINFO")?
+    }
+    reader.skip_bits(24)?;
+    // Block and frame sizes
+    reader.skip_bits(16 + 16 + 24 + 24)?;
+
+    let sample_rate = reader.read_bits(20)?;
+    let channels = reader.read_bits(3)? as u8 + 1;
+    Ok((sample_rate, channels))
+}
+
+// ATSC A/52 5.3 and E.1.2, the sync frame headers of AC-3 and E-AC-3
+fn parse_ac3_sync_frame(data: &[u8]) -> Result<(u32, u8), &'static str> {
+    if data.len() < 8 || data[0] != 0x0B || data[1] != 0x77 {
+        Err("Missing AC-3 sync word")?
+    }
+
+    // bsid sits at the same place in both, anything above 10 is E-AC-3
+    let bsid = data[5] >> 3;
+    let mut reader = BitReader::new(&data[4..]);
+
+    let (sample_rate, acmod, lfeon) = if bsid > 10 {
+        let fscod = reader.read_bits(2)?;
+        let fscod2 = reader.read_bits(2)?;
+        let sample_rate = match (fscod, fscod2) {
+            (3, 0) => 24000,
+            (3, 1) => 22050,
+            (3, 2) => 16000,
+            (3, _) => Err("Invalid E-AC-3 sample rate code")?,
+            (fscod, _) => [48000, 44100, 32000][fscod as usize],
+        };
+        let acmod = reader.read_bits(3)?;
+        (sample_rate, acmod, reader.read_bit()?)
+    } else {
+        let sample_rate = match reader.read_bits(2)? {
+            3 => Err("Invalid AC-3 sample rate code")?,
+            fscod => [48000, 44100, 32000][fscod as usize],
+        };
+        // frmsizecod, bsid and bsmod
+        reader.skip_bits(6 + 5 + 3)?;
+        let acmod = reader.read_bits(3)?;
+        // Mix levels that are only there for some channel layouts
+        if acmod & 1 == 1 && acmod != 1 {
+            reader.skip_bits(2)?;
+        }
+        if acmod & 4 == 4 {
+            reader.skip_bits(2)?;
+        }
+        if acmod == 2 {
+            reader.skip_bits(2)?;
+        }
+        (sample_rate, acmod, reader.read_bit()?)
+    };
+
+    let channels = [2, 1, 2, 3, 3, 4, 4, 5][acmod as usize] + lfeon as u8;
+    Ok((sample_rate, channels))
+}
@@ -0,0 +1,195 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use crate::Serializable;
+
+// The FLV file format (Adobe FLV spec, Annex E): a 9 byte header, then tags that each wrap an
+// RTMP audio, video or data message. Every tag is followed by its own size, and the header by a
+// zero, so a file can be walked backwards too.
+
+pub const FLV_HEADER_SIZE: u64 = 9;
+// The header plus the PreviousTagSize0 after it, which is where the first tag starts
+pub const FLV_BODY_OFFSET: u64 = FLV_HEADER_SIZE + 4;
+// Tag header, before the data
+pub const TAG_HEADER_SIZE: u64 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlvHeader {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl Serializable for FlvHeader {
+    // Includes the PreviousTagSize0 that follows the header
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        let flags = ((self.audio as u8) << 2) | self.video as u8;
+        let mut buf = vec![b'F', b'L', b'V', 1, flags];
+        buf.extend_from_slice(&(FLV_HEADER_SIZE as u32).to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        Ok(buf)
+    }
+
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized {
+        let mut header = [0u8; FLV_HEADER_SIZE as usize];
+        if reader.read_exact(&mut header).is_err() {
+            Err("Error reading FLV header")?
+        }
+        if &header[0..3] != b"FLV" {
+            Err("Not an FLV file")?
+        }
+
+        // Later versions could make the header bigger, whatever is in there gets skipped
+        let data_offset = u32::from_be_bytes(header[5..9].try_into().unwrap()) as u64;
+        if data_offset < FLV_HEADER_SIZE {
+            Err("Invalid FLV header size")?
+        }
+        let skip = data_offset - FLV_HEADER_SIZE + 4;
+        match std::io::copy(&mut reader.take(skip), &mut std::io::sink()) {
+            Ok(skipped) if skipped == skip => {}
+            _ => Err("Error reading FLV header")?,
+        }
+
+        Ok(FlvHeader {
+            audio: header[4] & 0x04 != 0,
+            video: header[4] & 0x01 != 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlvTag {
+    // 8 audio, 9 video, 18 script data, the same as the RTMP message type ids
+    pub tag_type: u8,
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+}
+
+impl FlvTag {
+    // What the tag takes up in the file, including the PreviousTagSize after it
+    pub fn size(&self) -> u64 {
+        TAG_HEADER_SIZE + self.data.len() as u64 + 4
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        // Sequence headers are flagged as keyframes too, but there's nothing to seek to in them
+        self.tag_type == 9 && !self.data.is_empty() && (self.data[0] >> 4) & 0x07 == 1 && !self.is_sequence_header()
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        match self.tag_type {
+            // Legacy AVC packet type 0, or enhanced SequenceStart
+            9 => match self.data.first() {
+                Some(byte) if byte & 0x80 != 0 => byte & 0x0F == 0,
+                Some(byte) if byte & 0x0F == 7 => self.data.get(1) == Some(&0),
+                _ => false,
+            },
+            // Legacy AAC packet type 0, or enhanced SequenceStart
+            8 => match self.data.first() {
+                Some(byte) if byte >> 4 == 10 => self.data.get(1) == Some(&0),
+                Some(byte) if byte >> 4 == 9 => byte & 0x0F == 0,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Reads the next tag, None at the end of the file. A tag that got cut off, e.g. because the
+    // recording was interrupted, counts as the end of the file too.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, &'static str> {
+        let mut header = [0u8; TAG_HEADER_SIZE as usize];
+        match read_full(reader, &mut header) {
+            Ok(0) => return Ok(None),
+            Ok(n) if n < header.len() => return Ok(None),
+            Ok(_) => {}
+            Err(_) => Err("Error reading FLV tag")?,
+        }
+
+        // The filter bit is for encrypted tags, which we have no use for
+        let tag_type = header[0] & 0x1F;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        // The timestamp's upper 8 bits come last
+        let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+        let mut data = vec![0u8; size + 4];
+        match read_full(reader, &mut data) {
+            Ok(n) if n < data.len() => return Ok(None),
+            Ok(_) => {}
+            Err(_) => Err("Error reading FLV tag")?,
+        }
+        data.truncate(size);
+
+        Ok(Some(FlvTag { tag_type, timestamp, data }))
+    }
+
+    // Includes the PreviousTagSize after the tag
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let size = (self.data.len() as u32).to_be_bytes();
+        let timestamp = self.timestamp.to_be_bytes();
+        let mut buf = Vec::with_capacity(self.size() as usize);
+        buf.extend_from_slice(&[self.tag_type, size[1], size[2], size[3]]);
+        buf.extend_from_slice(&[timestamp[1], timestamp[2], timestamp[3], timestamp[0]]);
+        // Stream id, always zero
+        buf.extend_from_slice(&[0, 0, 0]);
+        buf.extend_from_slice(&self.data);
+        buf.extend_from_slice(&((TAG_HEADER_SIZE as u32) + self.data.len() as u32).to_be_bytes());
+        buf
+    }
+}
+
+// Walks the tags of an FLV file, keeping track of where in the file it is
+pub struct FlvReader<R: Read> {
+    reader: R,
+    pub header: FlvHeader,
+    // Where the next tag starts
+    position: u64,
+}
+
+impl<R: Read> FlvReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, &'static str> {
+        let header = FlvHeader::deserialize(&mut reader)?;
+        Ok(FlvReader {
+            reader,
+            header,
+            position: FLV_BODY_OFFSET,
+        })
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn next_tag(&mut self) -> Result<Option<FlvTag>, &'static str> {
+        let tag = FlvTag::read_from(&mut self.reader)?;
+        if let Some(tag) = &tag {
+            self.position += tag.size();
+        }
+        Ok(tag)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read + Seek> FlvReader<R> {
+    // Carries on reading at the tag starting at the position, e.g. one from a keyframe index
+    pub fn seek(&mut self, position: u64) -> Result<(), &'static str> {
+        if position < FLV_BODY_OFFSET || self.reader.seek(SeekFrom::Start(position)).is_err() {
+            Err("Error seeking in FLV file")?
+        }
+        self.position = position;
+        Ok(())
+    }
+}
+
+// Like read_exact, but returns how far it got when the data runs out
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
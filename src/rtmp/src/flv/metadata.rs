@@ -0,0 +1,132 @@
+use amf::Pair;
+use amf::amf0::Value;
+use media_core::Projection;
+use crate::codec::Timecode;
+
+// Where the keyframes of an FLV file are, so players can seek without reading the whole file.
+// Stored as the keyframes object yamdi and friends write, with the times in seconds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyframeIndex {
+    pub times: Vec<f64>,
+    pub positions: Vec<u64>,
+}
+
+impl KeyframeIndex {
+    pub fn push(&mut self, time: f64, position: u64) {
+        self.times.push(time);
+        self.positions.push(position);
+    }
+
+    // The last keyframe at or before the time, as (time, position). Before the first keyframe
+    // that's the first one.
+    pub fn seek(&self, time: f64) -> Option<(f64, u64)> {
+        if self.times.is_empty() {
+            return None;
+        }
+        let index = self.times.iter().rposition(|&keyframe| keyframe <= time).unwrap_or(0);
+        Some((self.times[index], self.positions[index]))
+    }
+}
+
+// SCRIPTDATA onMetaData, as sent by encoders (minus the @setDataFrame wrapper) and written at
+// the start of FLV files. Encoders fill in what they feel like, so none of it can be relied on.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub properties: Vec<(String, Value)>,
+}
+
+impl Metadata {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let mut reader = data;
+        match Value::read_from(&mut reader) {
+            Ok(name) if name.try_as_str() == Some("onMetaData") => {}
+            _ => Err("Not an onMetaData message")?,
+        }
+
+        let entries = match Value::read_from(&mut reader) {
+            Ok(Value::EcmaArray { entries }) | Ok(Value::Object { entries, .. }) => entries,
+            _ => Err("Error reading onMetaData properties")?,
+        };
+
+        Ok(Metadata {
+            properties: entries.into_iter().map(|pair| (pair.key, pair.value)).collect(),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.properties.iter().find(|(name, _)| name == key).map(|(_, value)| value)
+    }
+
+    pub fn number(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(|value| value.try_as_f64())
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        match self.properties.iter_mut().find(|(name, _)| name == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.properties.push((key.to_string(), value)),
+        }
+    }
+
+    // VR video as flagged by the custom spherical, projection and stereo_mode keys. spherical on its
+    // own means a mono 360° equirectangular video.
+    pub fn projection(&self) -> Option<Projection> {
+        let stereo_mode = self.get("stereo_mode").and_then(|value| value.try_as_str());
+        match self.get("projection").and_then(|value| value.try_as_str()) {
+            Some(projection) => Projection::from_names(projection, stereo_mode),
+            None if self.get("spherical") == Some(&Value::Boolean(true)) => Projection::from_names("equirectangular", stereo_mode),
+            None => None,
+        }
+    }
+
+    // Custom timecode key, the time the first frame after this metadata was shot at
+    pub fn timecode(&self) -> Option<Timecode> {
+        Timecode::parse(self.get("timecode")?.try_as_str()?)
+    }
+
+    pub fn keyframes(&self) -> Option<KeyframeIndex> {
+        let entries = match self.get("keyframes")? {
+            Value::Object { entries, .. } | Value::EcmaArray { entries } => entries,
+            _ => return None,
+        };
+        let numbers = |key: &str| -> Option<Vec<f64>> {
+            match &entries.iter().find(|pair| pair.key == key)?.value {
+                Value::Array { entries } => entries.iter().map(|value| value.try_as_f64()).collect(),
+                _ => None,
+            }
+        };
+
+        let times = numbers("times")?;
+        let positions: Vec<u64> = numbers("filepositions")?.into_iter().map(|position| position as u64).collect();
+        if times.len() != positions.len() {
+            return None;
+        }
+        Some(KeyframeIndex { times, positions })
+    }
+
+    pub fn set_keyframes(&mut self, keyframes: &KeyframeIndex) {
+        let numbers = |values: Vec<f64>| Value::Array { entries: values.into_iter().map(Value::Number).collect() };
+        self.set("keyframes", Value::Object {
+            class_name: None,
+            entries: vec![
+                Pair { key: "filepositions".to_string(), value: numbers(keyframes.positions.iter().map(|&position| position as f64).collect()) },
+                Pair { key: "times".to_string(), value: numbers(keyframes.times.clone()) },
+            ],
+        });
+    }
+
+    pub fn set_projection(&mut self, projection: &Projection) {
+        self.set("spherical", Value::Boolean(true));
+        self.set("projection", Value::String(projection.projection_name().to_string()));
+        self.set("stereo_mode", Value::String(projection.stereo_mode_name().to_string()));
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Value::String("onMetaData".to_string()).write_to(&mut buf).unwrap();
+        Value::EcmaArray {
+            entries: self.properties.iter().map(|(key, value)| Pair { key: key.clone(), value: value.clone() }).collect(),
+        }.write_to(&mut buf).unwrap();
+        buf
+    }
+}
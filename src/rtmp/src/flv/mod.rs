@@ -0,0 +1,4 @@
+pub mod video;
+pub mod audio;
+pub mod metadata;
+pub mod file;
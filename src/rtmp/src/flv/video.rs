@@ -0,0 +1,605 @@
+use std::io::Read;
+use amf::Version;
+use amf::amf0::Value;
+use crate::Serializable;
+
+// VIDEODATA as carried in RTMP video messages (type 9) and FLV video tags. The first byte holds
+// the frame type and codec id, AVC adds a packet type and a composition time offset on top.
+//
+// Enhanced RTMP (https://github.com/veovera/enhanced-rtmp) sets the top bit of the first byte
+// (IsExHeader). The codec id bits then become a packet type and a FourCC follows, which is how
+// HEVC, AV1 and VP9 get carried.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Keyframe,
+    InterFrame,
+    DisposableInterFrame,
+    GeneratedKeyframe,
+    VideoInfo,
+}
+
+impl FrameType {
+    fn from_id(id: u8) -> Result<Self, &'static str> {
+        match id {
+            1 => Ok(FrameType::Keyframe),
+            2 => Ok(FrameType::InterFrame),
+            3 => Ok(FrameType::DisposableInterFrame),
+            4 => Ok(FrameType::GeneratedKeyframe),
+            5 => Ok(FrameType::VideoInfo),
+            _ => Err("Unknown video frame type"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoCodec {
+    SorensonH263,
+    ScreenVideo,
+    Vp6,
+    Vp6Alpha,
+    ScreenVideo2,
+    Avc,
+    Hevc,
+    Av1,
+    Vp9,
+}
+
+impl VideoCodec {
+    fn from_id(id: u8) -> Result<Self, &'static str> {
+        match id {
+            2 => Ok(VideoCodec::SorensonH263),
+            3 => Ok(VideoCodec::ScreenVideo),
+            4 => Ok(VideoCodec::Vp6),
+            5 => Ok(VideoCodec::Vp6Alpha),
+            6 => Ok(VideoCodec::ScreenVideo2),
+            7 => Ok(VideoCodec::Avc),
+            _ => Err("Unknown video codec id"),
+        }
+    }
+
+    fn from_fourcc(fourcc: &[u8]) -> Result<Self, &'static str> {
+        match fourcc {
+            b"avc1" => Ok(VideoCodec::Avc),
+            b"hvc1" => Ok(VideoCodec::Hevc),
+            b"av01" => Ok(VideoCodec::Av1),
+            b"vp09" => Ok(VideoCodec::Vp9),
+            _ => Err("Unknown video FourCC"),
+        }
+    }
+
+    pub fn fourcc(&self) -> Option<&'static str> {
+        match self {
+            VideoCodec::Avc => Some("avc1"),
+            VideoCodec::Hevc => Some("hvc1"),
+            VideoCodec::Av1 => Some("av01"),
+            VideoCodec::Vp9 => Some("vp09"),
+            _ => None,
+        }
+    }
+
+    // Codecs whose frames are NAL units with a length prefix
+    fn uses_nalus(&self) -> bool {
+        matches!(self, VideoCodec::Avc | VideoCodec::Hevc)
+    }
+}
+
+// The FourCCs we accept over enhanced RTMP, advertised to clients in the connect response
+pub const SUPPORTED_FOURCCS: [&str; 4] = ["avc1", "hvc1", "av01", "vp09"];
+
+#[derive(Debug, Clone)]
+pub enum DecoderConfiguration {
+    Avc(AvcDecoderConfigurationRecord),
+    Hevc(HevcDecoderConfigurationRecord),
+    // AV1CodecConfigurationRecord and VPCodecConfigurationRecord, kept as they are
+    Av1(Vec<u8>),
+    Vp9(Vec<u8>),
+}
+
+impl DecoderConfiguration {
+    // The parameter sets as start code delimited NAL units, empty for codecs without any
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        match self {
+            DecoderConfiguration::Avc(record) => record.to_annex_b(),
+            DecoderConfiguration::Hevc(record) => record.to_annex_b(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn nalu_length_size(&self) -> Option<usize> {
+        match self {
+            DecoderConfiguration::Avc(record) => Some(record.nalu_length_size as usize),
+            DecoderConfiguration::Hevc(record) => Some(record.nalu_length_size as usize),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum VideoPacket {
+    SequenceHeader(DecoderConfiguration),
+    // The NAL units of a single AVC or HEVC frame, without their length prefixes
+    Nalus(Vec<Vec<u8>>),
+    // A single AV1 (low overhead OBUs) or VP9 frame
+    Frame(Vec<u8>),
+    EndOfSequence,
+    // Enhanced RTMP metadata, e.g. colorInfo for HDR. The AMF values as they were sent.
+    Metadata(Vec<Value>),
+    // Codecs and packet types we don't look into are passed along as is
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoTag {
+    pub frame_type: FrameType,
+    pub codec: VideoCodec,
+    // Whether the tag used the enhanced RTMP header
+    pub enhanced: bool,
+    // Milliseconds to add to the message timestamp (the DTS) to get the PTS
+    pub composition_time: i32,
+    pub packet: VideoPacket,
+}
+
+impl VideoTag {
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type == FrameType::Keyframe
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        matches!(self.packet, VideoPacket::SequenceHeader(_))
+    }
+
+    // Start code delimited NAL units, the way MPEG-TS and most decoders want them
+    pub fn to_annex_b(&self) -> Option<Vec<u8>> {
+        match &self.packet {
+            VideoPacket::Nalus(nalus) => Some(to_annex_b(nalus)),
+            VideoPacket::SequenceHeader(config) if self.codec.uses_nalus() => Some(config.to_annex_b()),
+            _ => None,
+        }
+    }
+}
+
+// Keeps the NALU length size from the last sequence header, every frame after it depends on it
+pub struct VideoTagParser {
+    nalu_length_size: usize,
+}
+
+impl Default for VideoTagParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoTagParser {
+    pub fn new() -> Self {
+        Self { nalu_length_size: 4 }
+    }
+
+    pub fn parse(&mut self, data: &[u8]) -> Result<VideoTag, &'static str> {
+        let first = match data.first() {
+            Some(first) => *first,
+            None => Err("Empty video tag")?,
+        };
+
+        if first & 0x80 != 0 {
+            return self.parse_enhanced(data);
+        }
+
+        let frame_type = FrameType::from_id(first >> 4)?;
+        let codec = VideoCodec::from_id(first & 0x0F)?;
+
+        if codec != VideoCodec::Avc {
+            return Ok(VideoTag {
+                frame_type,
+                codec,
+                enhanced: false,
+                composition_time: 0,
+                packet: VideoPacket::Other(data[1..].to_vec()),
+            });
+        }
+
+        if data.len() < 5 {
+            Err("AVC video tag is too short")?
+        }
+
+        // 24 bit signed composition time offset
+        let composition_time = read_composition_time(&data[2..5]);
+        let body = &data[5..];
+
+        let packet = match data[1] {
+            0 => self.sequence_header(codec, body)?,
+            1 => self.coded_frame(codec, body)?,
+            2 => VideoPacket::EndOfSequence,
+            _ => Err("Unknown AVC packet type")?,
+        };
+
+        Ok(VideoTag {
+            frame_type,
+            codec,
+            enhanced: false,
+            composition_time,
+            packet,
+        })
+    }
+
+    fn parse_enhanced(&mut self, data: &[u8]) -> Result<VideoTag, &'static str> {
+        if data.len() < 5 {
+            Err("Enhanced video tag is too short")?
+        }
+
+        let frame_type = FrameType::from_id((data[0] >> 4) & 0b0111)?;
+        let packet_type = data[0] & 0x0F;
+        let codec = VideoCodec::from_fourcc(&data[1..5])?;
+        let body = &data[5..];
+
+        let mut composition_time = 0;
+        let packet = match packet_type {
+            // SequenceStart
+            0 => self.sequence_header(codec, body)?,
+            // CodedFrames, AVC and HEVC carry a composition time offset up front
+            1 if codec.uses_nalus() => {
+                if body.len() < 3 {
+                    Err("Enhanced video tag is too short")?
+                }
+                composition_time = read_composition_time(&body[0..3]);
+                self.coded_frame(codec, &body[3..])?
+            }
+            // CodedFramesX, the composition time offset is implied to be zero
+            1 | 3 => self.coded_frame(codec, body)?,
+            // SequenceEnd
+            2 => VideoPacket::EndOfSequence,
+            // Metadata
+            4 => {
+                let mut reader = body;
+                let mut values = Vec::new();
+                while !reader.is_empty() {
+                    match amf::Value::read_from(&mut reader, Version::Amf0) {
+                        Ok(amf::Value::Amf0(value)) => values.push(value),
+                        _ => Err("Error reading video metadata")?,
+                    }
+                }
+                VideoPacket::Metadata(values)
+            }
+            // MPEG2TSSequenceStart and anything newer
+            _ => VideoPacket::Other(body.to_vec()),
+        };
+
+        Ok(VideoTag {
+            frame_type,
+            codec,
+            enhanced: true,
+            composition_time,
+            packet,
+        })
+    }
+
+    fn sequence_header(&mut self, codec: VideoCodec, body: &[u8]) -> Result<VideoPacket, &'static str> {
+        let config = match codec {
+            VideoCodec::Avc => DecoderConfiguration::Avc(AvcDecoderConfigurationRecord::deserialize(&mut &body[..])?),
+            VideoCodec::Hevc => DecoderConfiguration::Hevc(HevcDecoderConfigurationRecord::deserialize(&mut &body[..])?),
+            VideoCodec::Av1 => DecoderConfiguration::Av1(body.to_vec()),
+            VideoCodec::Vp9 => DecoderConfiguration::Vp9(body.to_vec()),
+            _ => return Ok(VideoPacket::Other(body.to_vec())),
+        };
+
+        if let Some(nalu_length_size) = config.nalu_length_size() {
+            self.nalu_length_size = nalu_length_size;
+        }
+        Ok(VideoPacket::SequenceHeader(config))
+    }
+
+    fn coded_frame(&self, codec: VideoCodec, body: &[u8]) -> Result<VideoPacket, &'static str> {
+        if codec.uses_nalus() {
+            Ok(VideoPacket::Nalus(split_nalus(body, self.nalu_length_size)?))
+        } else {
+            Ok(VideoPacket::Frame(body.to_vec()))
+        }
+    }
+}
+
+fn read_composition_time(bytes: &[u8]) -> i32 {
+    // 24 bit signed, shifting back down sign extends it
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8
+}
+
+// Splits NAL units that are each prefixed with their length, as in MP4 and FLV
+pub fn split_nalus(data: &[u8], nalu_length_size: usize) -> Result<Vec<Vec<u8>>, &'static str> {
+    if !(1..=4).contains(&nalu_length_size) {
+        Err("Invalid NALU length size")?
+    }
+
+    let mut nalus = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        if position + nalu_length_size > data.len() {
+            Err("Truncated NALU length")?
+        }
+
+        let length = data[position..position + nalu_length_size].iter().fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+        position += nalu_length_size;
+
+        if position + length > data.len() {
+            Err("Truncated NALU")?
+        }
+        nalus.push(data[position..position + length].to_vec());
+        position += length;
+    }
+
+    Ok(nalus)
+}
+
+// The other way around, splits NAL units delimited by 3 or 4 byte start codes
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut start = None;
+    let mut position = 0;
+    while position + 3 <= data.len() {
+        if data[position] == 0 && data[position + 1] == 0 && data[position + 2] == 1 {
+            if let Some(start) = start {
+                // A zero before the start code belongs to a 4 byte start code
+                let mut end = position;
+                if end > start && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                nalus.push(&data[start..end]);
+            }
+            position += 3;
+            start = Some(position);
+        } else {
+            position += 1;
+        }
+    }
+    if let Some(start) = start {
+        if start < data.len() {
+            nalus.push(&data[start..]);
+        }
+    }
+    nalus
+}
+
+pub fn to_annex_b(nalus: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for nalu in nalus {
+        buf.extend_from_slice(&[0, 0, 0, 1]);
+        buf.extend_from_slice(nalu);
+    }
+    buf
+}
+
+// ISO/IEC 14496-15 5.2.4.1, the H.264 sequence header
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub version: u8,
+    pub profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    pub nalu_length_size: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let nalus: Vec<Vec<u8>> = self.sps.iter().chain(self.pps.iter()).cloned().collect();
+        to_annex_b(&nalus)
+    }
+}
+
+fn read_parameter_sets<R>(reader: &mut R, count: usize) -> Result<Vec<Vec<u8>>, &'static str> where R: Read {
+    let mut sets = Vec::new();
+    for _ in 0..count {
+        let mut length_bytes = [0u8; 2];
+        match reader.read_exact(&mut length_bytes) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading parameter set length")?,
+        }
+
+        let mut set = vec![0u8; u16::from_be_bytes(length_bytes) as usize];
+        match reader.read_exact(&mut set) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading parameter set")?,
+        }
+        sets.push(set);
+    }
+    Ok(sets)
+}
+
+impl Serializable for AvcDecoderConfigurationRecord {
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        if self.nalu_length_size == 0 || self.sps.len() > 31 || self.pps.len() > 255 {
+            Err("Invalid AVC decoder configuration record")?
+        }
+
+        let mut buf = vec![
+            self.version,
+            self.profile_indication,
+            self.profile_compatibility,
+            self.level_indication,
+            0b11111100 | (self.nalu_length_size - 1),
+            0b11100000 | self.sps.len() as u8,
+        ];
+        for sps in &self.sps {
+            buf.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            buf.extend_from_slice(sps);
+        }
+        buf.push(self.pps.len() as u8);
+        for pps in &self.pps {
+            buf.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            buf.extend_from_slice(pps);
+        }
+        Ok(buf)
+    }
+
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized {
+        let mut header = [0u8; 6];
+        match reader.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading AVC decoder configuration record")?,
+        }
+
+        if header[0] != 1 {
+            Err("Unsupported AVC decoder configuration record version")?
+        }
+
+        let sps = read_parameter_sets(reader, (header[5] & 0b00011111) as usize)?;
+
+        let mut pps_count = [0u8; 1];
+        match reader.read_exact(&mut pps_count) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading PPS count")?,
+        }
+        let pps = read_parameter_sets(reader, pps_count[0] as usize)?;
+
+        Ok(AvcDecoderConfigurationRecord {
+            version: header[0],
+            profile_indication: header[1],
+            profile_compatibility: header[2],
+            level_indication: header[3],
+            nalu_length_size: (header[4] & 0b00000011) + 1,
+            sps,
+            pps,
+        })
+    }
+}
+
+// ISO/IEC 14496-15 8.3.3.1, the H.265 sequence header
+#[derive(Debug, Clone, PartialEq)]
+pub struct HevcDecoderConfigurationRecord {
+    pub version: u8,
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    // 48 bits
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    pub nalu_length_size: u8,
+    // NAL unit arrays keyed by NAL unit type, usually VPS (32), SPS (33) and PPS (34)
+    pub arrays: Vec<HevcNaluArray>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HevcNaluArray {
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    pub nalus: Vec<Vec<u8>>,
+}
+
+impl HevcDecoderConfigurationRecord {
+    pub fn nalus_of_type(&self, nal_unit_type: u8) -> Vec<Vec<u8>> {
+        self.arrays.iter()
+            .filter(|array| array.nal_unit_type == nal_unit_type)
+            .flat_map(|array| array.nalus.iter().cloned())
+            .collect()
+    }
+
+    pub fn vps(&self) -> Vec<Vec<u8>> {
+        self.nalus_of_type(32)
+    }
+
+    pub fn sps(&self) -> Vec<Vec<u8>> {
+        self.nalus_of_type(33)
+    }
+
+    pub fn pps(&self) -> Vec<Vec<u8>> {
+        self.nalus_of_type(34)
+    }
+
+    pub fn to_annex_b(&self) -> Vec<u8> {
+        let nalus: Vec<Vec<u8>> = self.arrays.iter().flat_map(|array| array.nalus.iter().cloned()).collect();
+        to_annex_b(&nalus)
+    }
+}
+
+impl Serializable for HevcDecoderConfigurationRecord {
+    fn serialize(&self) -> Result<Vec<u8>, &'static str> {
+        if self.nalu_length_size == 0 || self.arrays.len() > 255 {
+            Err("Invalid HEVC decoder configuration record")?
+        }
+
+        let mut buf = vec![
+            self.version,
+            (self.general_profile_space << 6) | ((self.general_tier_flag as u8) << 5) | (self.general_profile_idc & 0b00011111),
+        ];
+        buf.extend_from_slice(&self.general_profile_compatibility_flags.to_be_bytes());
+        buf.extend_from_slice(&self.general_constraint_indicator_flags.to_be_bytes()[2..8]);
+        buf.push(self.general_level_idc);
+        buf.extend_from_slice(&(0xF000 | self.min_spatial_segmentation_idc).to_be_bytes());
+        buf.push(0b11111100 | self.parallelism_type);
+        buf.push(0b11111100 | self.chroma_format_idc);
+        buf.push(0b11111000 | (self.bit_depth_luma - 8));
+        buf.push(0b11111000 | (self.bit_depth_chroma - 8));
+        buf.extend_from_slice(&self.avg_frame_rate.to_be_bytes());
+        buf.push((self.constant_frame_rate << 6) | (self.num_temporal_layers << 3) | ((self.temporal_id_nested as u8) << 2) | (self.nalu_length_size - 1));
+        buf.push(self.arrays.len() as u8);
+        for array in &self.arrays {
+            buf.push(((array.array_completeness as u8) << 7) | (array.nal_unit_type & 0b00111111));
+            buf.extend_from_slice(&(array.nalus.len() as u16).to_be_bytes());
+            for nalu in &array.nalus {
+                buf.extend_from_slice(&(nalu.len() as u16).to_be_bytes());
+                buf.extend_from_slice(nalu);
+            }
+        }
+        Ok(buf)
+    }
+
+    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R: Read, Self: Sized {
+        let mut header = [0u8; 23];
+        match reader.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(_) => Err("Error reading HEVC decoder configuration record")?,
+        }
+
+        if header[0] != 1 {
+            Err("Unsupported HEVC decoder configuration record version")?
+        }
+
+        let mut arrays = Vec::new();
+        for _ in 0..header[22] {
+            let mut array_header = [0u8; 3];
+            match reader.read_exact(&mut array_header) {
+                Ok(_) => {}
+                Err(_) => Err("Error reading HEVC NAL unit array")?,
+            }
+
+            arrays.push(HevcNaluArray {
+                array_completeness: array_header[0] & 0b10000000 != 0,
+                nal_unit_type: array_header[0] & 0b00111111,
+                nalus: read_parameter_sets(reader, u16::from_be_bytes([array_header[1], array_header[2]]) as usize)?,
+            });
+        }
+
+        let mut constraint_flags = [0u8; 8];
+        constraint_flags[2..8].copy_from_slice(&header[6..12]);
+
+        Ok(HevcDecoderConfigurationRecord {
+            version: header[0],
+            general_profile_space: header[1] >> 6,
+            general_tier_flag: header[1] & 0b00100000 != 0,
+            general_profile_idc: header[1] & 0b00011111,
+            general_profile_compatibility_flags: u32::from_be_bytes(header[2..6].try_into().unwrap()),
+            general_constraint_indicator_flags: u64::from_be_bytes(constraint_flags),
+            general_level_idc: header[12],
+            min_spatial_segmentation_idc: u16::from_be_bytes([header[13], header[14]]) & 0x0FFF,
+            parallelism_type: header[15] & 0b00000011,
+            chroma_format_idc: header[16] & 0b00000011,
+            bit_depth_luma: (header[17] & 0b00000111) + 8,
+            bit_depth_chroma: (header[18] & 0b00000111) + 8,
+            avg_frame_rate: u16::from_be_bytes([header[19], header[20]]),
+            constant_frame_rate: header[21] >> 6,
+            num_temporal_layers: (header[21] >> 3) & 0b00000111,
+            temporal_id_nested: header[21] & 0b00000100 != 0,
+            nalu_length_size: (header[21] & 0b00000011) + 1,
+            arrays,
+        })
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use media_core::{FrameEvent, MediaFrame, MediaRegistry, TrackInfo, FRAME_QUEUE_SIZE};
+use tokio::sync::mpsc;
+use crate::config::StreamGroupConfig;
+use crate::registry::{StreamRegistry, TimecodeAnchor};
+
+// Stream groups put the streams of a multi-camera rig on one clock. Every member's timeline
+// starts at zero with its own first frame, so on its own it says nothing about how it lines up
+// with the others. The group clock starts when the first member's first frame comes in, and
+// every member gets an offset onto it:
+//  - by default from when the member's first frame came in, relative to the group start
+//  - from timecodes, when both the member and the group's reference member (the first one to
+//    start) have them. The timecodes say when frames were shot rather than when they arrived,
+//    so they aren't thrown off by encoders connecting at different times.
+//
+// Drift is how far a member's frames lag behind the group clock as they come in. It creeps up
+// when an encoder's clock runs slow or its uplink backs up.
+
+#[derive(Debug, Clone)]
+pub enum GroupEvent {
+    Tracks { member: String, tracks: Vec<TrackInfo> },
+    // The frame's timestamps are on the group clock
+    Frame { member: String, frame: MediaFrame },
+    // The member stopped publishing, it may come back later
+    Ended { member: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberStats {
+    pub member: String,
+    // Added to the member's timestamps to put them on the group clock
+    pub offset_ms: i64,
+    pub aligned_by_timecode: bool,
+    // How far the member's latest frame lagged behind the group clock, and the extremes so far
+    pub drift_ms: i64,
+    pub min_drift_ms: i64,
+    pub max_drift_ms: i64,
+    // Drift compared to the group's reference member
+    pub relative_drift_ms: i64,
+    pub frames: u64,
+}
+
+struct MemberClock {
+    // The offset going by arrival time
+    wall_offset: i64,
+    // The time of day at the member's timestamp 0
+    timecode_origin: Option<i64>,
+    offset: i64,
+    aligned_by_timecode: bool,
+    drift: i64,
+    min_drift: i64,
+    max_drift: i64,
+    frames: u64,
+    // Whether we've warned about the member drifting and it hasn't recovered yet
+    drifting: bool,
+}
+
+struct GroupClock {
+    config: StreamGroupConfig,
+    started: Option<Instant>,
+    reference: Option<String>,
+    members: HashMap<String, MemberClock>,
+}
+
+impl GroupClock {
+    fn new(config: StreamGroupConfig) -> Self {
+        Self {
+            config,
+            started: None,
+            reference: None,
+            members: HashMap::new(),
+        }
+    }
+
+    fn ingest(&mut self, member: &str, timestamp: u32, timecode: Option<TimecodeAnchor>, now: Instant) {
+        let started = *self.started.get_or_insert(now);
+        let elapsed = now.duration_since(started).as_millis() as i64;
+        let group = &self.config.name;
+
+        let clock = self.members.entry(member.to_string()).or_insert_with(|| {
+            let wall_offset = elapsed - timestamp as i64;
+            println!("Stream {} joined group {} at {}ms", member, group, wall_offset);
+            MemberClock {
+                wall_offset,
+                timecode_origin: None,
+                offset: wall_offset,
+                aligned_by_timecode: false,
+                drift: 0,
+                min_drift: 0,
+                max_drift: 0,
+                frames: 0,
+                drifting: false,
+            }
+        });
+        let reference = self.reference.get_or_insert_with(|| member.to_string()).clone();
+
+        let timecode_origin = timecode.map(|timecode| timecode.origin());
+        let timecode_changed = clock.timecode_origin != timecode_origin;
+        clock.timecode_origin = timecode_origin;
+
+        let drift = elapsed - (timestamp as i64 + clock.offset);
+        clock.drift = drift;
+        clock.min_drift = if clock.frames == 0 { drift } else { clock.min_drift.min(drift) };
+        clock.max_drift = if clock.frames == 0 { drift } else { clock.max_drift.max(drift) };
+        clock.frames += 1;
+
+        if timecode_changed && self.config.use_timecodes {
+            self.align_by_timecode(&reference);
+        }
+        self.check_drift(member, &reference);
+    }
+
+    // Members with a timecode get lined up with the reference member's, if it has one too
+    fn align_by_timecode(&mut self, reference: &str) {
+        let (reference_offset, reference_origin) = match self.members.get(reference) {
+            Some(MemberClock { wall_offset, timecode_origin: Some(origin), .. }) => (*wall_offset, *origin),
+            _ => return,
+        };
+
+        for (name, clock) in self.members.iter_mut() {
+            let origin = match clock.timecode_origin {
+                Some(origin) => origin,
+                None => continue,
+            };
+            let offset = reference_offset + origin - reference_origin;
+            if offset != clock.offset {
+                println!("Aligning stream {} in group {} by timecode: offset {}ms, was {}ms", name, self.config.name, offset, clock.offset);
+                // The drift so far was measured against the old offset
+                clock.drift += clock.offset - offset;
+                clock.min_drift = clock.drift;
+                clock.max_drift = clock.drift;
+                clock.offset = offset;
+            }
+            clock.aligned_by_timecode = true;
+        }
+    }
+
+    fn check_drift(&mut self, member: &str, reference: &str) {
+        let reference_drift = match self.members.get(reference) {
+            Some(clock) => clock.drift,
+            None => return,
+        };
+        let clock = match self.members.get_mut(member) {
+            Some(clock) => clock,
+            None => return,
+        };
+
+        let relative_drift = clock.drift - reference_drift;
+        let drifting = relative_drift.unsigned_abs() > self.config.drift_warning_ms;
+        if drifting && !clock.drifting {
+            println!("Stream {} is drifting {}ms from {} in group {}", member, relative_drift, reference, self.config.name);
+        } else if !drifting && clock.drifting {
+            println!("Stream {} is back in sync with group {}", member, self.config.name);
+        }
+        clock.drifting = drifting;
+    }
+
+    fn stats(&self) -> Vec<MemberStats> {
+        let reference_drift = self.reference.as_ref().and_then(|reference| self.members.get(reference)).map_or(0, |clock| clock.drift);
+
+        self.config.members.iter()
+            .filter_map(|member| self.members.get(member).map(|clock| (member, clock)))
+            .map(|(member, clock)| MemberStats {
+                member: member.clone(),
+                offset_ms: clock.offset,
+                aligned_by_timecode: clock.aligned_by_timecode,
+                drift_ms: clock.drift,
+                min_drift_ms: clock.min_drift,
+                max_drift_ms: clock.max_drift,
+                relative_drift_ms: clock.drift - reference_drift,
+                frames: clock.frames,
+            })
+            .collect()
+    }
+}
+
+pub struct StreamGroups {
+    groups: Mutex<HashMap<String, GroupClock>>,
+}
+
+impl StreamGroups {
+    pub fn new(configs: Vec<StreamGroupConfig>) -> Self {
+        let groups = configs.into_iter().map(|config| (config.name.clone(), GroupClock::new(config))).collect();
+        Self {
+            groups: Mutex::new(groups),
+        }
+    }
+
+    // Called for every audio and video message of every stream, with its normalized timestamp
+    pub fn ingest(&self, name: &str, timestamp: u32, timecode: Option<TimecodeAnchor>) {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        for group in groups.values_mut().filter(|group| group.config.members.iter().any(|member| member == name)) {
+            group.ingest(name, timestamp, timecode, now);
+        }
+    }
+
+    // The stream is gone, when it's published again its timeline starts over
+    pub fn reset(&self, name: &str) {
+        let mut groups = self.groups.lock().unwrap();
+        for group in groups.values_mut() {
+            if group.members.remove(name).is_some() && group.reference.as_deref() == Some(name) {
+                // The longest running member becomes the reference
+                group.reference = group.members.iter().min_by_key(|(_, clock)| clock.wall_offset).map(|(member, _)| member.clone());
+            }
+            if group.members.is_empty() {
+                group.started = None;
+                group.reference = None;
+            }
+        }
+    }
+
+    fn offset(&self, group: &str, member: &str) -> Option<i64> {
+        self.groups.lock().unwrap().get(group)?.members.get(member).map(|clock| clock.offset)
+    }
+
+    fn members(&self, group: &str) -> Option<Vec<String>> {
+        self.groups.lock().unwrap().get(group).map(|group| group.config.members.clone())
+    }
+
+    pub fn stats(&self, group: &str) -> Option<Vec<MemberStats>> {
+        self.groups.lock().unwrap().get(group).map(|group| group.stats())
+    }
+}
+
+impl StreamRegistry {
+    // Frames of every member of the group, on the group clock. Members that aren't published
+    // yet, or stop and come back, are picked up whenever they're live.
+    pub fn subscribe_group(self: &Arc<Self>, group: &str) -> Result<mpsc::Receiver<GroupEvent>, &'static str> {
+        let members = match self.groups.members(group) {
+            Some(members) => members,
+            None => Err("Unknown stream group")?,
+        };
+
+        let (sender, receiver) = mpsc::channel(FRAME_QUEUE_SIZE * members.len().max(1));
+        for member in members {
+            let registry = self.clone();
+            let sender = sender.clone();
+            let group = group.to_string();
+            tokio::spawn(async move {
+                while !sender.is_closed() {
+                    forward_member(&registry, &group, &member, &sender).await;
+                }
+            });
+        }
+        Ok(receiver)
+    }
+
+    pub fn group_stats(&self, group: &str) -> Option<Vec<MemberStats>> {
+        self.groups.stats(group)
+    }
+
+    // Logs the drift statistics of every group that has them switched on
+    pub fn start_group_reports(self: &Arc<Self>) {
+        let groups: Vec<(String, u64)> = self.groups.groups.lock().unwrap().values()
+            .map(|group| (group.config.name.clone(), group.config.stats_interval_ms))
+            .filter(|(_, interval)| *interval > 0)
+            .collect();
+
+        for (group, interval) in groups {
+            let registry = self.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(interval));
+                loop {
+                    interval.tick().await;
+                    for stats in registry.group_stats(&group).unwrap_or_default() {
+                        println!(
+                            "Group {} member {}: offset {}ms{}, drift {}ms (min {}ms, max {}ms), {}ms relative, {} frames",
+                            group, stats.member, stats.offset_ms, if stats.aligned_by_timecode { " by timecode" } else { "" },
+                            stats.drift_ms, stats.min_drift_ms, stats.max_drift_ms, stats.relative_drift_ms, stats.frames,
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Forwards one publish of the member, returns once it has ended or the group subscriber is gone
+async fn forward_member(registry: &Arc<StreamRegistry>, group: &str, member: &str, sender: &mpsc::Sender<GroupEvent>) {
+    let mut frames = registry.clone().subscribe_frames(member);
+
+    loop {
+        let event = tokio::select! {
+            event = frames.recv() => event,
+            _ = sender.closed() => return,
+        };
+
+        let event = match event {
+            Some(FrameEvent::Tracks(tracks)) => GroupEvent::Tracks { member: member.to_string(), tracks },
+            Some(FrameEvent::Frame(mut frame)) => {
+                // Frames only come in after the member's first frame got ingested, so the offset is known
+                let offset = registry.groups.offset(group, member).unwrap_or(0);
+                frame.pts += offset;
+                frame.dts += offset;
+                GroupEvent::Frame { member: member.to_string(), frame }
+            }
+            Some(FrameEvent::Ended) | None => {
+                let _ = sender.send(GroupEvent::Ended { member: member.to_string() }).await;
+                return;
+            }
+        };
+
+        if sender.send(event).await.is_err() {
+            return;
+        }
+    }
+}
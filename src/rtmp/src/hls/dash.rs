@@ -0,0 +1,217 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use media_core::{ProjectionKind, StereoMode, TrackInfo, TrackKind};
+use crate::hls::playlist::{file_prefix, rendition_name, Init, MediaPlaylist, Segment};
+
+// A dynamic MPEG-DASH manifest (ISO/IEC 23009-1) for the same fMP4 segments the HLS playlist
+// offers, so they're only packaged and stored once. Every init segment starts a period, with an
+// adaptation set for each track's rendition whose segments go by their sequence numbers. Segment
+// times are milliseconds on the stream's timeline, mapped to the clock by when each period
+// started.
+
+// None until there's a segment
+pub(crate) fn render(playlist: &MediaPlaylist, prefix: &str) -> Option<String> {
+    let availability_start = playlist.availability_start()?;
+    let segments: Vec<&Segment> = playlist.segments().collect();
+    if segments.is_empty() {
+        return None;
+    }
+
+    let target_duration = playlist.target_duration();
+    let window: u64 = segments.iter().map(|segment| segment.duration).sum();
+    let mut mpd = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    mpd.push_str(&format!(
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" xmlns:omaf=\"urn:mpeg:mpegI:omaf:2017\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011,urn:mpeg:dash:profile:cmaf:2019\" type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" minBufferTime=\"{}\" timeShiftBufferDepth=\"{}\" suggestedPresentationDelay=\"{}\"",
+        utc_time(availability_start),
+        utc_time(SystemTime::now()),
+        duration(target_duration * 1000),
+        duration(window),
+        duration(target_duration * 3000),
+    ));
+
+    let mut periods = String::new();
+    let mut end = 0;
+    for init in playlist.inits() {
+        let (started, media_start) = match init.started {
+            Some(started) => started,
+            None => continue,
+        };
+        let segments: Vec<&Segment> = segments.iter().copied().filter(|segment| segment.init == Some(init.version)).collect();
+        let last = match segments.last() {
+            Some(last) => last,
+            None => continue,
+        };
+
+        let start = started.duration_since(availability_start).map_or(0, |start| start.as_millis() as u64);
+        end = start + (last.start + last.duration as i64 - media_start).max(0) as u64;
+        periods.push_str(&format!("  <Period id=\"{}\" start=\"{}\">\n", init.version, duration(start)));
+        // Every track is packaged on its own, an adaptation set each
+        for index in 0..init.tracks.len() {
+            render_adaptation_set(&mut periods, prefix, init, index, media_start, &segments);
+        }
+        periods.push_str("  </Period>\n");
+    }
+
+    // An ended stream gets its duration and no more updates
+    if playlist.ended {
+        mpd.push_str(&format!(" mediaPresentationDuration=\"{}\">\n", duration(end)));
+    } else {
+        mpd.push_str(&format!(" minimumUpdatePeriod=\"{}\">\n", duration(target_duration * 1000)));
+    }
+    mpd.push_str(&periods);
+    // Players go by our clock rather than their own
+    mpd.push_str(&format!("  <UTCTiming schemeIdUri=\"urn:mpeg:dash:utc:direct:2014\" value=\"{}\"/>\n", utc_time(SystemTime::now())));
+    mpd.push_str("</MPD>\n");
+    Some(mpd)
+}
+
+fn render_adaptation_set(mpd: &mut String, prefix: &str, init: &Init, index: usize, media_start: i64, segments: &[&Segment]) {
+    let track = &init.tracks[index];
+    let kind = track.kind();
+    let (content_type, mime_type) = match kind {
+        TrackKind::Video => ("video", "video/mp4"),
+        TrackKind::Audio => ("audio", "audio/mp4"),
+    };
+
+    mpd.push_str(&format!(
+        "    <AdaptationSet id=\"{}\" contentType=\"{}\" mimeType=\"{}\"",
+        index,
+        content_type,
+        mime_type,
+    ));
+    if let Some(codecs) = &track.codecs {
+        mpd.push_str(&format!(" codecs=\"{}\"", codecs));
+    }
+    mpd.push_str(" segmentAlignment=\"true\" startWithSAP=\"1\">\n");
+    if kind == TrackKind::Video {
+        render_projection(mpd, track);
+    }
+
+    let file_prefix = file_prefix(Some(kind));
+    mpd.push_str(&format!(
+        "      <SegmentTemplate timescale=\"1000\" presentationTimeOffset=\"{}\" startNumber=\"{}\" initialization=\"{}{}init{}.mp4\" media=\"{}{}$Number$.m4s\">\n",
+        media_start,
+        segments[0].sequence,
+        prefix,
+        file_prefix,
+        init.version,
+        prefix,
+        file_prefix,
+    ));
+    mpd.push_str("        <SegmentTimeline>\n");
+    render_timeline(mpd, segments);
+    mpd.push_str("        </SegmentTimeline>\n");
+    mpd.push_str("      </SegmentTemplate>\n");
+
+    // The peak over the segments there are
+    let bandwidth = segments.iter()
+        .filter(|segment| segment.duration > 0)
+        .filter_map(|segment| Some(segment.data.get(index)?.len() as u64 * 8000 / segment.duration))
+        .max()
+        .unwrap_or(0);
+    mpd.push_str(&format!("      <Representation id=\"{}-{}\" bandwidth=\"{}\"", rendition_name(kind), init.version, bandwidth));
+    match kind {
+        TrackKind::Video => {
+            if let (Some(width), Some(height)) = (track.width, track.height) {
+                mpd.push_str(&format!(" width=\"{}\" height=\"{}\"", width, height));
+            }
+            if let Some(frame_rate) = track.frame_rate.filter(|frame_rate| *frame_rate > 0.0) {
+                mpd.push_str(&format!(" frameRate=\"{}/1000\"", (frame_rate * 1000.0).round() as u64));
+            }
+        }
+        TrackKind::Audio => {
+            if let Some(sample_rate) = track.sample_rate {
+                mpd.push_str(&format!(" audioSamplingRate=\"{}\"", sample_rate));
+            }
+        }
+    }
+    mpd.push_str("/>\n");
+    mpd.push_str("    </AdaptationSet>\n");
+}
+
+// One S per run of segments that follow each other with the same duration
+fn render_timeline(mpd: &mut String, segments: &[&Segment]) {
+    let mut runs: Vec<(i64, u64, u32)> = Vec::new();
+    for segment in segments {
+        match runs.last_mut() {
+            Some((start, duration, repeat)) if *duration == segment.duration && *start + (*duration * (*repeat as u64 + 1)) as i64 == segment.start => {
+                *repeat += 1;
+            }
+            _ => runs.push((segment.start, segment.duration, 0)),
+        }
+    }
+
+    let mut next = None;
+    for (start, duration, repeat) in runs {
+        mpd.push_str("          <S");
+        // t is only needed where there's a gap
+        if next != Some(start) {
+            mpd.push_str(&format!(" t=\"{}\"", start));
+        }
+        mpd.push_str(&format!(" d=\"{}\"", duration));
+        if repeat > 0 {
+            mpd.push_str(&format!(" r=\"{}\"", repeat));
+        }
+        mpd.push_str("/>\n");
+        next = Some(start + (duration * (repeat as u64 + 1)) as i64);
+    }
+}
+
+// VR video, with the OMAF projection format (its type is an attribute in the omaf namespace,
+// not the value) and the frame packing of stereo video. Supplemental so players that don't know
+// them still play the video flat.
+fn render_projection(mpd: &mut String, video: &TrackInfo) {
+    let projection = match &video.projection {
+        Some(projection) => projection,
+        None => return,
+    };
+
+    let format = match projection.kind {
+        ProjectionKind::Equirectangular | ProjectionKind::HalfEquirectangular => 0,
+        ProjectionKind::Cubemap => 1,
+    };
+    mpd.push_str(&format!("      <SupplementalProperty schemeIdUri=\"urn:mpeg:mpegI:omaf:2017:pf\" omaf:projection_type=\"{}\"/>\n", format));
+
+    // ISO/IEC 23001-8 VideoFramePackingType
+    let packing = match projection.stereo {
+        StereoMode::Mono => None,
+        StereoMode::LeftRight => Some(3),
+        StereoMode::TopBottom => Some(4),
+    };
+    if let Some(packing) = packing {
+        mpd.push_str(&format!("      <SupplementalProperty schemeIdUri=\"urn:mpeg:mpegB:cicp:VideoFramePackingType\" value=\"{}\"/>\n", packing));
+    }
+}
+
+// xs:duration from milliseconds
+fn duration(milliseconds: u64) -> String {
+    format!("PT{}.{:03}S", milliseconds / 1000, milliseconds % 1000)
+}
+
+// xs:dateTime in UTC, to the millisecond
+fn utc_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, time_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Civil date from the days since 1970-01-01, counting in 400 year eras starting in March
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
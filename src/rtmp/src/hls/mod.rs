@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use media_core::{FrameEvent, MediaRegistry, TrackKind};
+use tokio::sync::Notify;
+use tokio::sync::broadcast::error::RecvError;
+use crate::config::HlsConfig;
+use crate::recorder::file_name;
+use crate::registry::StreamRegistry;
+
+mod dash;
+mod playlist;
+mod segmenter;
+mod ts;
+
+use playlist::{file_prefix, parse_playlist_name, playlist_name, MediaPlaylist};
+pub(crate) use playlist::{parse_rendition, PlaylistKind};
+use segmenter::{Packaged, Segmenter};
+
+// Packages every published stream as HLS: segments cut at keyframes, offered through a sliding
+// window playlist. Segments are MPEG-TS, or fMP4 with partial segments for low latency HLS.
+// fMP4 segments can be offered through a DASH manifest as well. Everything is kept in memory
+// for the HTTP listener, and segments are written to disk as well when a path is configured.
+
+struct HlsStream {
+    playlist: MediaPlaylist,
+    // Bumped every time the stream gets published, so a stale cleanup leaves a new run alone
+    run: u64,
+    // Whether the stream is being packaged right now
+    active: bool,
+    // Woken whenever the playlist changes, for blocking playlist reloads
+    updated: Arc<Notify>,
+}
+
+// Why a wait didn't get what it was waiting for, each goes with its own HTTP status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WaitError {
+    NoSuchStream,
+    TooFarAhead,
+    TimedOut,
+}
+
+pub(crate) struct HlsStreams {
+    config: HlsConfig,
+    streams: Mutex<HashMap<String, HlsStream>>,
+}
+
+impl HlsStreams {
+    pub(crate) fn new(config: HlsConfig) -> Self {
+        Self {
+            config,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        if self.fmp4() { "m4s" } else { "ts" }
+    }
+
+    fn fmp4(&self) -> bool {
+        self.config.low_latency || self.config.dash
+    }
+
+    // Packages every stream that gets published from now on
+    pub(crate) async fn start(self: Arc<Self>, registry: Arc<StreamRegistry>) {
+        let mut published = registry.subscribe_published();
+        loop {
+            let name = match published.recv().await {
+                Ok(name) => name,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("HLS missed {} published streams", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let run = match self.start_stream(&name) {
+                Some(run) => run,
+                // Still packaging it from before the publisher reconnected
+                None => continue,
+            };
+            let hls = self.clone();
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                hls.package(registry, &name, run).await;
+            });
+        }
+    }
+
+    // None if the stream is being packaged already
+    fn start_stream(&self, name: &str) -> Option<u64> {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.entry(name.to_string()).or_insert_with(|| {
+            let part_target = self.config.low_latency.then_some(self.config.part_duration_ms);
+            HlsStream {
+                playlist: MediaPlaylist::new(self.config.playlist_length, self.config.segment_duration_ms, self.extension(), part_target),
+                run: 0,
+                active: false,
+                updated: Arc::new(Notify::new()),
+            }
+        });
+        if stream.active {
+            return None;
+        }
+        // A stream that comes back carries on with the playlist it had
+        stream.active = true;
+        stream.playlist.ended = false;
+        stream.run += 1;
+        Some(stream.run)
+    }
+
+    // The stream and playlist of a playlist name: {stream}/video and {stream}/audio are the
+    // rendition playlists of fMP4 streams, {stream}/media the one of MPEG-TS streams, anything
+    // else is a stream's multivariant playlist
+    pub(crate) fn playlist_name<'a>(&self, name: &'a str) -> (&'a str, PlaylistKind) {
+        let rendition = name.rsplit_once('/').and_then(|(stream, rendition)| Some((stream, parse_playlist_name(rendition)?)));
+        match rendition {
+            Some((stream, rendition)) if self.streams.lock().unwrap().contains_key(stream) => (stream, PlaylistKind::Media(rendition)),
+            _ => (name, PlaylistKind::Multivariant),
+        }
+    }
+
+    // None until the stream has its first segment, or part for low latency, and for renditions
+    // the stream doesn't have
+    pub(crate) fn playlist(&self, name: &str, kind: PlaylistKind, prefix: &str, skip: bool) -> Option<String> {
+        let streams = self.streams.lock().unwrap();
+        let playlist = &streams.get(name).filter(|stream| !stream.playlist.is_empty())?.playlist;
+        match kind {
+            PlaylistKind::Multivariant => Some(playlist.render_multivariant(prefix)),
+            PlaylistKind::Media(rendition) if playlist.renditions().contains(&rendition) => Some(playlist.render(prefix, rendition, self.config.low_latency, skip)),
+            PlaylistKind::Media(_) => None,
+        }
+    }
+
+    // None unless DASH is on, and until the stream has its first segment
+    pub(crate) fn mpd(&self, name: &str, prefix: &str) -> Option<String> {
+        if !self.config.dash {
+            return None;
+        }
+        dash::render(&self.streams.lock().unwrap().get(name)?.playlist, prefix)
+    }
+
+    pub(crate) fn segment(&self, name: &str, sequence: u64, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        self.streams.lock().unwrap().get(name)?.playlist.segment(sequence, rendition)
+    }
+
+    pub(crate) fn part(&self, name: &str, sequence: u64, index: usize, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        self.streams.lock().unwrap().get(name)?.playlist.part(sequence, index, rendition)
+    }
+
+    pub(crate) fn init(&self, name: &str, version: u64, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        self.streams.lock().unwrap().get(name)?.playlist.init(version, rendition)
+    }
+
+    // Waits until the playlist has the segment, or the part of it, for blocking playlist reloads
+    // and preload hints. Gives up after three target durations, and right away when the request
+    // is more than two segments past the last one in the playlist.
+    pub(crate) async fn wait(&self, name: &str, sequence: u64, part: Option<usize>) -> Result<(), WaitError> {
+        let (updated, target_duration) = {
+            let streams = self.streams.lock().unwrap();
+            let stream = match streams.get(name) {
+                Some(stream) => stream,
+                None => Err(WaitError::NoSuchStream)?,
+            };
+            // The last segment in the playlist is the one before the segment in progress
+            if sequence > stream.playlist.next_sequence() + 1 {
+                Err(WaitError::TooFarAhead)?
+            }
+            (stream.updated.clone(), stream.playlist.target_duration())
+        };
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(target_duration * 3);
+        loop {
+            // Registered before checking, so an update in between isn't missed
+            let notified = updated.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let ready = match self.streams.lock().unwrap().get(name) {
+                Some(stream) => stream.playlist.has(sequence, part),
+                None => Err(WaitError::NoSuchStream)?,
+            };
+            if ready {
+                return Ok(());
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                Err(WaitError::TimedOut)?
+            }
+        }
+    }
+
+    async fn package(self: Arc<Self>, registry: Arc<StreamRegistry>, name: &str, run: u64) {
+        let mut frames = registry.clone().subscribe_frames(name);
+        // It might have ended again before we got to it
+        if registry.stream_tracks(name).is_none() {
+            drop(frames);
+            self.end_stream(name, run, None).await;
+            return;
+        }
+
+        let dir = self.config.path.as_ref().map(|path| Path::new(path).join(file_name(name)));
+        if let Some(dir) = &dir {
+            if let Err(err) = tokio::fs::create_dir_all(dir).await {
+                eprintln!("Error creating HLS directory {}: {}", dir.display(), err);
+            }
+        }
+        println!("Packaging {} as HLS", name);
+
+        let segment_duration = self.config.segment_duration_ms as i64;
+        let part_duration = self.config.low_latency.then_some(self.config.part_duration_ms as i64);
+        let mut segmenter = match self.fmp4() {
+            true => Segmenter::fmp4(segment_duration, part_duration),
+            false => Segmenter::ts(segment_duration),
+        };
+        // The publisher came back to a playlist that's still up, players have to start over
+        segmenter.discontinuity = self.streams.lock().unwrap().get(name).is_some_and(|stream| !stream.playlist.is_empty());
+
+        while let Some(event) = frames.recv().await {
+            let packaged = match event {
+                FrameEvent::Tracks(tracks) => segmenter.set_tracks(name, tracks),
+                FrameEvent::Frame(frame) => segmenter.push(&frame),
+                FrameEvent::Ended => break,
+            };
+            for packaged in packaged {
+                self.add(name, run, packaged, dir.as_deref()).await;
+            }
+        }
+
+        for packaged in segmenter.finish() {
+            self.add(name, run, packaged, dir.as_deref()).await;
+        }
+        self.end_stream(name, run, dir.as_deref()).await;
+        println!("Stopped packaging {} as HLS", name);
+    }
+
+    async fn add(&self, name: &str, run: u64, packaged: Packaged, dir: Option<&Path>) {
+        let extension = self.extension();
+        let mut removed = Vec::new();
+
+        // What has to go to disk: the new files, then the manifests
+        let written = {
+            let mut streams = self.streams.lock().unwrap();
+            let stream = match streams.get_mut(name).filter(|stream| stream.run == run) {
+                Some(stream) => stream,
+                None => return,
+            };
+
+            let playlist = &mut stream.playlist;
+            let files: Vec<(String, Option<Arc<Vec<u8>>>)> = match packaged {
+                Packaged::Tracks(tracks) => {
+                    playlist.set_tracks(tracks);
+                    Vec::new()
+                }
+                Packaged::Init(init) => {
+                    let version = playlist.set_init(init);
+                    playlist.renditions().into_iter()
+                        .map(|rendition| (format!("{}init{}.mp4", file_prefix(rendition), version), playlist.init(version, rendition)))
+                        .collect()
+                }
+                // Files on disk don't get parts, they're only any good with blocking reloads
+                Packaged::Part(part) => {
+                    playlist.push_part(part);
+                    Vec::new()
+                }
+                Packaged::Segment(segment) => {
+                    let sequence;
+                    (sequence, removed) = playlist.push(segment);
+                    playlist.renditions().into_iter()
+                        .map(|rendition| (format!("{}{}.{}", file_prefix(rendition), sequence, extension), playlist.segment(sequence, rendition)))
+                        .collect()
+                }
+            };
+            stream.updated.notify_waiters();
+
+            let files: Vec<(String, Arc<Vec<u8>>)> = files.into_iter().filter_map(|(file, data)| Some((file, data?))).collect();
+            match dir {
+                Some(dir) if !files.is_empty() => Some((dir, files, self.manifests(&stream.playlist))),
+                _ => None,
+            }
+        };
+
+        let (dir, files, manifests) = match written {
+            Some(written) => written,
+            None => return,
+        };
+        for (file, data) in files {
+            if let Err(err) = tokio::fs::write(dir.join(&file), &*data).await {
+                eprintln!("Error writing HLS file {} of {}: {}", file, name, err);
+                return;
+            }
+        }
+        write_manifests(dir, &manifests).await;
+        for sequence in removed {
+            for rendition in [None, Some(TrackKind::Video), Some(TrackKind::Audio)] {
+                let _ = tokio::fs::remove_file(dir.join(format!("{}{}.{}", file_prefix(rendition), sequence, extension))).await;
+            }
+        }
+    }
+
+    // The playlist stays up a while after the stream ended, so players can get to the end of it
+    async fn end_stream(self: &Arc<Self>, name: &str, run: u64, dir: Option<&Path>) {
+        let manifests = {
+            let mut streams = self.streams.lock().unwrap();
+            let stream = match streams.get_mut(name).filter(|stream| stream.run == run) {
+                Some(stream) => stream,
+                None => return,
+            };
+            stream.active = false;
+            stream.playlist.ended = true;
+            stream.updated.notify_waiters();
+            self.manifests(&stream.playlist)
+        };
+        if let Some(dir) = dir {
+            write_manifests(dir, &manifests).await;
+        }
+
+        let window = self.config.segment_duration_ms * self.config.playlist_length as u64;
+        let hls = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(window)).await;
+            let mut streams = hls.streams.lock().unwrap();
+            if streams.get(&name).is_some_and(|stream| stream.run == run && !stream.active) {
+                streams.remove(&name);
+            }
+        });
+    }
+
+    // The files on disk: index.m3u8, with a playlist for every rendition next to it for fMP4, and
+    // index.mpd for DASH
+    fn manifests(&self, playlist: &MediaPlaylist) -> Vec<(String, String)> {
+        let mut manifests = vec![("index.m3u8".to_string(), playlist.render_multivariant(""))];
+        for rendition in playlist.renditions() {
+            manifests.push((format!("{}.m3u8", playlist_name(rendition)), playlist.render("", rendition, false, false)));
+        }
+        if let Some(mpd) = self.config.dash.then(|| dash::render(playlist, "")).flatten() {
+            manifests.push(("index.mpd".to_string(), mpd));
+        }
+        manifests
+    }
+}
+
+// Written next to the manifest first, so readers never see half of one
+async fn write_manifests(dir: &Path, manifests: &[(String, String)]) {
+    for (file, manifest) in manifests {
+        let path = dir.join(file);
+        let temp_path = dir.join(format!("{}.tmp", file));
+        if tokio::fs::write(&temp_path, manifest).await.is_err() || tokio::fs::rename(&temp_path, &path).await.is_err() {
+            eprintln!("Error writing HLS manifest {}", path.display());
+        }
+    }
+}
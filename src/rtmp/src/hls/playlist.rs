@@ -0,0 +1,460 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use media_core::{ProjectionKind, StereoMode, TrackInfo, TrackKind};
+use crate::hls::segmenter::{InitData, PartData, SegmentData};
+
+// The sliding window of segments a live media playlist offers (RFC 8216 section 6.2.2), plus
+// the partial segments of low latency HLS (RFC 8216bis section 4.4.4.9). With fMP4 every track
+// is a rendition with a media playlist of its own, MPEG-TS has just the one, and a multivariant
+// playlist ties them together. Renditions go by the kind of their track, None being the MPEG-TS
+// one.
+
+// Segments at the end of the window that still list their parts
+const SEGMENTS_WITH_PARTS: usize = 3;
+
+// Which of a stream's playlists a request is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PlaylistKind {
+    Multivariant,
+    Media(Option<TrackKind>),
+}
+
+pub(crate) struct Part {
+    // Milliseconds
+    pub duration: u64,
+    pub independent: bool,
+    // For every rendition
+    pub data: Vec<Arc<Vec<u8>>>,
+}
+
+pub(crate) struct Segment {
+    pub sequence: u64,
+    // Milliseconds, start being on the stream's timeline
+    pub start: i64,
+    pub duration: u64,
+    // Set on the first segment after the tracks or the timeline changed
+    pub discontinuity: bool,
+    // For every rendition
+    pub data: Vec<Arc<Vec<u8>>>,
+    pub parts: Vec<Part>,
+    // Which init segment it goes with, for fMP4
+    pub init: Option<u64>,
+}
+
+pub(crate) struct Init {
+    pub version: u64,
+    // The init segment and track of every rendition
+    pub data: Vec<Arc<Vec<u8>>>,
+    pub tracks: Vec<TrackInfo>,
+    // When its first segment started, by the clock and on the stream's timeline, which is what
+    // a DASH period goes by
+    pub started: Option<(SystemTime, i64)>,
+}
+
+pub(crate) struct MediaPlaylist {
+    segments: VecDeque<Segment>,
+    // How many segments the window holds
+    length: usize,
+    // The configured segment duration, the floor for EXT-X-TARGETDURATION
+    target_duration: u64,
+    // ts or m4s
+    extension: &'static str,
+    // The configured part duration, only set for low latency playlists
+    part_target: Option<u64>,
+    next_sequence: u64,
+    // How many discontinuities have left the window
+    discontinuity_sequence: u64,
+    // The parts of the segment in progress, which will get the next sequence number
+    partial: Vec<Part>,
+    partial_discontinuity: bool,
+    // The init segments still in use, newest last
+    inits: Vec<Init>,
+    // The tracks of the MPEG-TS segments, fMP4 has them in its init segments
+    tracks: Vec<TrackInfo>,
+    next_init: u64,
+    // When the first segment started, the clock time everything in DASH is relative to
+    availability_start: Option<SystemTime>,
+    pub ended: bool,
+}
+
+impl MediaPlaylist {
+    pub(crate) fn new(length: usize, target_duration: u64, extension: &'static str, part_target: Option<u64>) -> Self {
+        Self {
+            segments: VecDeque::new(),
+            length: length.max(1),
+            target_duration,
+            extension,
+            part_target,
+            next_sequence: 0,
+            discontinuity_sequence: 0,
+            partial: Vec::new(),
+            partial_discontinuity: false,
+            inits: Vec::new(),
+            tracks: Vec::new(),
+            next_init: 0,
+            availability_start: None,
+            ended: false,
+        }
+    }
+
+    fn current_init(&self) -> Option<u64> {
+        self.inits.last().map(|init| init.version)
+    }
+
+    // Returns the version the init segment goes by
+    pub(crate) fn set_init(&mut self, init: InitData) -> u64 {
+        let version = self.next_init;
+        self.inits.push(Init {
+            version,
+            data: init.data.into_iter().map(Arc::new).collect(),
+            tracks: init.tracks,
+            started: None,
+        });
+        self.next_init += 1;
+        version
+    }
+
+    pub(crate) fn set_tracks(&mut self, tracks: Vec<TrackInfo>) {
+        self.tracks = tracks;
+    }
+
+    pub(crate) fn push_part(&mut self, part: PartData) {
+        if self.partial.is_empty() {
+            self.partial_discontinuity = part.discontinuity;
+        }
+        self.partial.push(Part {
+            duration: part.duration,
+            independent: part.independent,
+            data: part.data.into_iter().map(Arc::new).collect(),
+        });
+    }
+
+    // Adds a segment to the window, along with the parts it was made of. Returns its sequence
+    // number, along with those of the segments that fell out of the window.
+    pub(crate) fn push(&mut self, segment: SegmentData) -> (u64, Vec<u64>) {
+        let sequence = self.next_sequence;
+        // It's only complete now, so it started its duration ago
+        let started = SystemTime::now() - Duration::from_millis(segment.duration);
+        self.availability_start.get_or_insert(started);
+        if let Some(init) = self.inits.last_mut().filter(|init| init.started.is_none()) {
+            init.started = Some((started, segment.start));
+        }
+
+        self.segments.push_back(Segment {
+            sequence,
+            start: segment.start,
+            duration: segment.duration,
+            discontinuity: segment.discontinuity,
+            data: segment.data.into_iter().map(Arc::new).collect(),
+            parts: std::mem::take(&mut self.partial),
+            init: self.current_init(),
+        });
+        self.next_sequence += 1;
+
+        // Only the last few segments need their parts
+        if self.segments.len() > SEGMENTS_WITH_PARTS {
+            let index = self.segments.len() - SEGMENTS_WITH_PARTS - 1;
+            self.segments[index].parts.clear();
+        }
+
+        let mut removed = Vec::new();
+        while self.segments.len() > self.length {
+            let segment = self.segments.pop_front().unwrap();
+            if segment.discontinuity {
+                self.discontinuity_sequence += 1;
+            }
+            removed.push(segment.sequence);
+        }
+
+        // Init segments only go once nothing in the window needs them
+        let oldest = self.segments.front().and_then(|segment| segment.init);
+        if let Some(oldest) = oldest {
+            self.inits.retain(|init| init.version >= oldest);
+        }
+        (sequence, removed)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.segments.is_empty() && self.partial.is_empty()
+    }
+
+    // Where the data of the rendition is in the segments that go with the init segment
+    fn rendition_index(&self, init: Option<u64>, rendition: Option<TrackKind>) -> Option<usize> {
+        match (init, rendition) {
+            (None, None) => Some(0),
+            (Some(version), Some(kind)) => self.inits.iter().find(|init| init.version == version)?.tracks.iter().position(|track| track.kind() == kind),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn segment(&self, sequence: u64, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        let segment = self.segments.iter().find(|segment| segment.sequence == sequence)?;
+        let index = self.rendition_index(segment.init, rendition)?;
+        segment.data.get(index).cloned()
+    }
+
+    pub(crate) fn part(&self, sequence: u64, index: usize, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        let (parts, init) = if sequence == self.next_sequence {
+            (&self.partial, self.current_init())
+        } else {
+            let segment = self.segments.iter().find(|segment| segment.sequence == sequence)?;
+            (&segment.parts, segment.init)
+        };
+        let rendition_index = self.rendition_index(init, rendition)?;
+        parts.get(index)?.data.get(rendition_index).cloned()
+    }
+
+    pub(crate) fn init(&self, version: u64, rendition: Option<TrackKind>) -> Option<Arc<Vec<u8>>> {
+        let index = self.rendition_index(Some(version), rendition)?;
+        self.inits.iter().find(|init| init.version == version)?.data.get(index).cloned()
+    }
+
+    // The renditions of the current init segment, or the MPEG-TS one
+    pub(crate) fn renditions(&self) -> Vec<Option<TrackKind>> {
+        match self.inits.last() {
+            Some(init) => init.tracks.iter().map(|track| Some(track.kind())).collect(),
+            None => vec![None],
+        }
+    }
+
+    pub(crate) fn inits(&self) -> &[Init] {
+        &self.inits
+    }
+
+    pub(crate) fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter()
+    }
+
+    pub(crate) fn availability_start(&self) -> Option<SystemTime> {
+        self.availability_start
+    }
+
+    // Whether the playlist has the segment, or the part of it, a blocking request waits for.
+    // Once the stream is over nothing more is coming, so that counts too.
+    pub(crate) fn has(&self, sequence: u64, part: Option<usize>) -> bool {
+        if self.ended || sequence < self.next_sequence {
+            return true;
+        }
+        match part {
+            Some(part) => sequence == self.next_sequence && part < self.partial.len(),
+            None => false,
+        }
+    }
+
+    // The sequence number the segment in progress is going to get
+    pub(crate) fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub(crate) fn target_duration(&self) -> u64 {
+        let longest = self.segments.iter().map(|segment| segment.duration).max().unwrap_or(0);
+        longest.max(self.target_duration).div_ceil(1000)
+    }
+
+    // The multivariant playlist: the video rendition, with the audio one as its EXT-X-MEDIA audio
+    // group, or the one MPEG-TS rendition. Rendition playlists are at {prefix}{name}.m3u8.
+    pub(crate) fn render_multivariant(&self, prefix: &str) -> String {
+        let tracks = match self.inits.last() {
+            Some(init) => &init.tracks,
+            None => &self.tracks,
+        };
+        let video = tracks.iter().find(|track| track.kind() == TrackKind::Video);
+        let audio = tracks.iter().find(|track| track.kind() == TrackKind::Audio);
+        let fmp4 = !self.inits.is_empty();
+
+        let bandwidth: u64 = self.renditions().into_iter().map(|rendition| self.peak_bandwidth(rendition)).sum();
+        let codecs: Vec<&str> = tracks.iter().filter_map(|track| track.codecs.as_deref()).collect();
+        let mut stream_inf = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", bandwidth.max(1));
+        if !codecs.is_empty() {
+            stream_inf.push_str(&format!(",CODECS=\"{}\"", codecs.join(",")));
+        }
+        let mut layout = None;
+        if let Some(video) = video {
+            if let (Some(width), Some(height)) = (video.width, video.height) {
+                stream_inf.push_str(&format!(",RESOLUTION={}x{}", width, height));
+            }
+            if let Some(frame_rate) = video.frame_rate.filter(|frame_rate| *frame_rate > 0.0) {
+                stream_inf.push_str(&format!(",FRAME-RATE={:.3}", frame_rate));
+            }
+            layout = video_layout(video);
+        }
+        if let Some(layout) = &layout {
+            stream_inf.push_str(&format!(",REQ-VIDEO-LAYOUT=\"{}\"", layout));
+        }
+
+        // Attributes starting with REQ- need version 12
+        let version = if layout.is_some() { 12 } else { 7 };
+        let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-INDEPENDENT-SEGMENTS\n", version);
+        match (video, audio) {
+            _ if !fmp4 => playlist.push_str(&format!("{}\n{}{}.m3u8\n", stream_inf, prefix, playlist_name(None))),
+            (Some(_), Some(_)) => {
+                playlist.push_str(&format!(
+                    "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"audio\",DEFAULT=YES,AUTOSELECT=YES,URI=\"{}audio.m3u8\"\n",
+                    prefix,
+                ));
+                playlist.push_str(&format!("{},AUDIO=\"audio\"\n{}video.m3u8\n", stream_inf, prefix));
+            }
+            (Some(_), None) => playlist.push_str(&format!("{}\n{}video.m3u8\n", stream_inf, prefix)),
+            _ => playlist.push_str(&format!("{}\n{}audio.m3u8\n", stream_inf, prefix)),
+        }
+        playlist
+    }
+
+    // Bits per second of the rendition, the peak over its segments, or its parts while there
+    // are no segments yet
+    fn peak_bandwidth(&self, rendition: Option<TrackKind>) -> u64 {
+        let rate = |size: usize, duration: u64| (size as u64 * 8000).checked_div(duration).unwrap_or(0);
+        let peak = self.segments.iter()
+            .filter_map(|segment| {
+                let data = segment.data.get(self.rendition_index(segment.init, rendition)?)?;
+                Some(rate(data.len(), segment.duration))
+            })
+            .max();
+        peak.unwrap_or_else(|| {
+            let index = self.rendition_index(self.current_init(), rendition);
+            let size = self.partial.iter().filter_map(|part| Some(part.data.get(index?)?.len())).sum();
+            rate(size, self.partial.iter().map(|part| part.duration).sum())
+        })
+    }
+
+    // The media playlist of the rendition. Segment URIs are {prefix}{sequence}.{extension}, parts
+    // {prefix}{sequence}.{part}.m4s and init segments {prefix}init{version}.mp4, the prefix ending
+    // in the rendition's file prefix. Low latency adds the parts, and skip leaves out the older
+    // segments a client that asked for a delta update already has.
+    pub(crate) fn render(&self, prefix: &str, rendition: Option<TrackKind>, low_latency: bool, skip: bool) -> String {
+        let prefix = &format!("{}{}", prefix, file_prefix(rendition));
+        let target_duration = self.target_duration();
+        let part_target = self.part_target.filter(|_| low_latency);
+        // Players can only be sent deltas if the window is long enough
+        let can_skip_until = target_duration * 6;
+
+        let version = match (part_target, self.inits.is_empty()) {
+            (Some(_), _) => 9,
+            (None, false) => 7,
+            (None, true) => 3,
+        };
+        let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n", version, target_duration);
+        if let Some(part_target) = part_target {
+            playlist.push_str(&format!(
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,CAN-SKIP-UNTIL={:.1},PART-HOLD-BACK={:.3}\n",
+                can_skip_until as f64,
+                (part_target * 3) as f64 / 1000.0,
+            ));
+            playlist.push_str(&format!("#EXT-X-PART-INF:PART-TARGET={:.3}\n", part_target as f64 / 1000.0));
+        }
+        // Segments from before the rendition was there are left out
+        let segments: Vec<&Segment> = self.segments.iter().filter(|segment| self.rendition_index(segment.init, rendition).is_some()).collect();
+        let media_sequence = segments.first().map_or(self.next_sequence, |segment| segment.sequence);
+        playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", media_sequence));
+        playlist.push_str(&format!("#EXT-X-DISCONTINUITY-SEQUENCE:{}\n", self.discontinuity_sequence));
+
+        // Segments that start further than CAN-SKIP-UNTIL from the end of the playlist
+        let mut skipped = 0;
+        if skip && part_target.is_some() {
+            let mut remaining: u64 = segments.iter().map(|segment| segment.duration).sum();
+            for segment in &segments {
+                if remaining <= can_skip_until * 1000 {
+                    break;
+                }
+                remaining -= segment.duration;
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            playlist.push_str(&format!("#EXT-X-SKIP:SKIPPED-SEGMENTS={}\n", skipped));
+        }
+
+        let mut init = None;
+        for segment in segments.iter().skip(skipped) {
+            if segment.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            self.render_init(&mut playlist, prefix, &mut init, segment.init);
+            if part_target.is_some() {
+                self.render_parts(&mut playlist, prefix, rendition, segment.sequence, &segment.parts);
+            }
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}{}.{}\n", segment.duration as f64 / 1000.0, prefix, segment.sequence, self.extension));
+        }
+
+        if self.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        } else if part_target.is_some() {
+            if !self.partial.is_empty() {
+                if self.partial_discontinuity {
+                    playlist.push_str("#EXT-X-DISCONTINUITY\n");
+                }
+                self.render_init(&mut playlist, prefix, &mut init, self.current_init());
+                self.render_parts(&mut playlist, prefix, rendition, self.next_sequence, &self.partial);
+            }
+            // The part that comes next, which players can ask for before it's there
+            playlist.push_str(&format!("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}{}.{}.m4s\"\n", prefix, self.next_sequence, self.partial.len()));
+        }
+        playlist
+    }
+
+    // EXT-X-MAP whenever the init segment changes, starting with the first one
+    fn render_init(&self, playlist: &mut String, prefix: &str, current: &mut Option<u64>, init: Option<u64>) {
+        if let Some(version) = init.filter(|&version| *current != Some(version)) {
+            playlist.push_str(&format!("#EXT-X-MAP:URI=\"{}init{}.mp4\"\n", prefix, version));
+            *current = Some(version);
+        }
+    }
+
+    fn render_parts(&self, playlist: &mut String, prefix: &str, rendition: Option<TrackKind>, sequence: u64, parts: &[Part]) {
+        for (index, part) in parts.iter().enumerate() {
+            playlist.push_str(&format!("#EXT-X-PART:DURATION={:.3},URI=\"{}{}.{}.m4s\"", part.duration as f64 / 1000.0, prefix, sequence, index));
+            if part.independent || rendition == Some(TrackKind::Audio) {
+                playlist.push_str(",INDEPENDENT=YES");
+            }
+            playlist.push('\n');
+        }
+    }
+}
+
+// What a rendition's playlist is called, and what the names of its files start with
+pub(crate) fn rendition_name(kind: TrackKind) -> &'static str {
+    match kind {
+        TrackKind::Video => "video",
+        TrackKind::Audio => "audio",
+    }
+}
+
+// The name of the rendition's media playlist
+pub(crate) fn playlist_name(rendition: Option<TrackKind>) -> &'static str {
+    rendition.map_or("media", rendition_name)
+}
+
+pub(crate) fn parse_playlist_name(name: &str) -> Option<Option<TrackKind>> {
+    match name {
+        "media" => Some(None),
+        name => parse_rendition(name).map(Some),
+    }
+}
+
+// Nothing for MPEG-TS
+pub(crate) fn file_prefix(rendition: Option<TrackKind>) -> String {
+    rendition.map_or(String::new(), |kind| format!("{}-", rendition_name(kind)))
+}
+
+pub(crate) fn parse_rendition(name: &str) -> Option<TrackKind> {
+    match name {
+        "video" => Some(TrackKind::Video),
+        "audio" => Some(TrackKind::Audio),
+        _ => None,
+    }
+}
+
+// REQ-VIDEO-LAYOUT for VR video: the channels, and the projection where HLS has one for it
+fn video_layout(video: &TrackInfo) -> Option<String> {
+    let projection = video.projection?;
+    let channels = match projection.stereo {
+        StereoMode::Mono => "CH-MONO",
+        StereoMode::TopBottom | StereoMode::LeftRight => "CH-STEREO",
+    };
+    match projection.kind {
+        ProjectionKind::Equirectangular => Some(format!("{},PROJ-EQUI", channels)),
+        ProjectionKind::HalfEquirectangular => Some(format!("{},PROJ-HEQU", channels)),
+        ProjectionKind::Cubemap => Some(channels.to_string()),
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use media_core::{MediaFrame, TrackInfo, TrackKind};
+use crate::hls::ts::TsMuxer;
+use crate::mp4::{Fmp4Muxer, Sample};
+
+// Cuts a stream's frames into segments. A segment starts on a keyframe once the one before it
+// is long enough, or on any frame for streams without video. Segments are MPEG-TS with all the
+// tracks in them, or fMP4 fragments which can also be cut into partial segments for low latency
+// HLS. With fMP4 every track is a rendition of its own, with its own init segment and fragments,
+// all cut at the same times.
+
+pub(crate) enum Packaged {
+    // The fMP4 init segment for the segments that follow, sent whenever the tracks change
+    Init(InitData),
+    // The tracks of the MPEG-TS segments that follow, which have no init segment to tell
+    Tracks(Vec<TrackInfo>),
+    // A partial segment, one fragment of the segment in progress
+    Part(PartData),
+    Segment(SegmentData),
+}
+
+// Parts and segments have data for every rendition, in the order of the init segment's tracks.
+// MPEG-TS has just the one.
+
+pub(crate) struct InitData {
+    // The init segment of every rendition
+    pub data: Vec<Vec<u8>>,
+    // The track of every rendition
+    pub tracks: Vec<TrackInfo>,
+}
+
+pub(crate) struct PartData {
+    // Milliseconds
+    pub duration: u64,
+    // The video starts with a keyframe, so players can start decoding at it. Audio always can.
+    pub independent: bool,
+    // Set on the first part of a segment that starts a new timeline
+    pub discontinuity: bool,
+    pub data: Vec<Vec<u8>>,
+}
+
+pub(crate) struct SegmentData {
+    // Milliseconds, start being the decode time of its first frame
+    pub start: i64,
+    pub duration: u64,
+    pub discontinuity: bool,
+    // For fMP4 segments, their parts one after the other
+    pub data: Vec<Vec<u8>>,
+}
+
+enum Muxer {
+    Ts(TsMuxer),
+    // One for every track
+    Fmp4(Vec<Fmp4Muxer>),
+}
+
+impl Muxer {
+    fn has_video(&self) -> bool {
+        match self {
+            Muxer::Ts(muxer) => muxer.has_video(),
+            Muxer::Fmp4(muxers) => muxers.iter().any(Fmp4Muxer::has_video),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Muxer::Ts(muxer) => muxer.is_empty(),
+            Muxer::Fmp4(muxers) => muxers.is_empty(),
+        }
+    }
+
+    fn carries(&self, frame: &MediaFrame) -> bool {
+        match self {
+            Muxer::Ts(muxer) => muxer.carries(frame),
+            Muxer::Fmp4(muxers) => muxers.iter().any(|muxer| muxer.carries(frame)),
+        }
+    }
+
+    fn renditions(&self) -> usize {
+        match self {
+            Muxer::Ts(_) => 1,
+            Muxer::Fmp4(muxers) => muxers.len(),
+        }
+    }
+}
+
+struct CurrentSegment {
+    start: i64,
+    // For every rendition
+    data: Vec<Vec<u8>>,
+    discontinuity: bool,
+    part_start: i64,
+    part_independent: bool,
+    // fMP4 samples for the part in progress
+    samples: Vec<Sample>,
+}
+
+pub(crate) struct Segmenter {
+    fmp4: bool,
+    segment_duration: i64,
+    // Only for fMP4, None leaves segments whole
+    part_duration: Option<i64>,
+    tracks: Vec<TrackInfo>,
+    muxer: Option<Muxer>,
+    current: Option<CurrentSegment>,
+    // fMP4 samples need a duration, so the last frame of every track waits for the next one
+    pending: HashMap<u32, MediaFrame>,
+    // Between the last two frames of every track, for the duration of the ones nothing follows
+    frame_durations: HashMap<u32, i64>,
+    last_dts: Option<i64>,
+    // Between the last two frames of the track segments are cut on
+    frame_duration: i64,
+    pub discontinuity: bool,
+}
+
+impl Segmenter {
+    pub(crate) fn ts(segment_duration: i64) -> Self {
+        Self::new(false, segment_duration, None)
+    }
+
+    pub(crate) fn fmp4(segment_duration: i64, part_duration: Option<i64>) -> Self {
+        Self::new(true, segment_duration, part_duration)
+    }
+
+    fn new(fmp4: bool, segment_duration: i64, part_duration: Option<i64>) -> Self {
+        Self {
+            fmp4,
+            segment_duration,
+            part_duration,
+            tracks: Vec::new(),
+            muxer: None,
+            current: None,
+            pending: HashMap::new(),
+            frame_durations: HashMap::new(),
+            last_dts: None,
+            frame_duration: 0,
+            discontinuity: false,
+        }
+    }
+
+    // Tracks get sent again on every metadata update, only a real change starts over
+    pub(crate) fn set_tracks(&mut self, name: &str, tracks: Vec<TrackInfo>) -> Vec<Packaged> {
+        if tracks == self.tracks {
+            return Vec::new();
+        }
+
+        let mut packaged = self.finish();
+        if !packaged.is_empty() {
+            self.discontinuity = true;
+        }
+
+        let muxer = if self.fmp4 {
+            // The first track of each kind
+            let mut muxers: Vec<Fmp4Muxer> = Vec::new();
+            for track in &tracks {
+                if muxers.iter().any(|muxer| muxer.tracks().iter().any(|other| other.kind() == track.kind())) {
+                    continue;
+                }
+                let muxer = Fmp4Muxer::new(name, std::slice::from_ref(track));
+                if !muxer.is_empty() {
+                    muxers.push(muxer);
+                }
+            }
+            if !muxers.is_empty() {
+                packaged.push(Packaged::Init(InitData {
+                    data: muxers.iter().map(Fmp4Muxer::init_segment).collect(),
+                    tracks: muxers.iter().flat_map(Fmp4Muxer::tracks).collect(),
+                }));
+            }
+            Muxer::Fmp4(muxers)
+        } else {
+            let muxer = TsMuxer::new(name, &tracks);
+            if !muxer.is_empty() {
+                packaged.push(Packaged::Tracks(muxer.tracks().to_vec()));
+            }
+            Muxer::Ts(muxer)
+        };
+        self.muxer = (!muxer.is_empty()).then_some(muxer);
+        self.tracks = tracks;
+        packaged
+    }
+
+    pub(crate) fn push(&mut self, frame: &MediaFrame) -> Vec<Packaged> {
+        let mut packaged = Vec::new();
+        let has_video = match &self.muxer {
+            Some(muxer) if muxer.carries(frame) => muxer.has_video(),
+            _ => return packaged,
+        };
+
+        // The frame before this one is complete now
+        if let Some(previous) = self.pending.remove(&frame.track) {
+            let duration = frame.dts - previous.dts;
+            if duration > 0 {
+                self.frame_durations.insert(frame.track, duration);
+            }
+            if let Some(current) = &mut self.current {
+                current.samples.push(Fmp4Muxer::sample(&previous, duration.max(0)));
+            }
+        }
+
+        let timing_track = if has_video { TrackKind::Video } else { TrackKind::Audio };
+        let is_timing_track = frame.codec.kind() == timing_track;
+        let boundary = is_timing_track && (frame.keyframe || timing_track == TrackKind::Audio);
+
+        match &self.current {
+            // Segments have to start on a keyframe
+            None if !boundary => return packaged,
+            Some(current) if boundary && frame.dts - current.start >= self.segment_duration => {
+                self.close_segment(frame.dts, &mut packaged);
+            }
+            // Parts end before the frame that would take them past the part duration
+            Some(current) if is_timing_track && frame.dts > current.part_start
+                && self.part_duration.is_some_and(|part_duration| frame.dts + self.frame_duration - current.part_start > part_duration) => {
+                self.close_part(frame.dts, &mut packaged);
+                if let Some(current) = &mut self.current {
+                    current.part_independent = boundary;
+                }
+            }
+            _ => {}
+        }
+
+        let renditions = self.muxer.as_ref().map_or(0, Muxer::renditions);
+        let current = self.current.get_or_insert_with(|| CurrentSegment {
+            start: frame.dts,
+            data: vec![Vec::new(); renditions],
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            part_start: frame.dts,
+            part_independent: true,
+            samples: Vec::new(),
+        });
+        match &mut self.muxer {
+            Some(Muxer::Ts(muxer)) => {
+                let data = &mut current.data[0];
+                if data.is_empty() {
+                    muxer.write_tables(data);
+                }
+                muxer.write_frame(frame, data);
+            }
+            Some(Muxer::Fmp4(_)) => {
+                self.pending.insert(frame.track, frame.clone());
+            }
+            None => {}
+        }
+
+        if is_timing_track {
+            if let Some(last_dts) = self.last_dts.filter(|&last_dts| frame.dts > last_dts) {
+                self.frame_duration = frame.dts - last_dts;
+            }
+            self.last_dts = Some(self.last_dts.map_or(frame.dts, |last_dts| last_dts.max(frame.dts)));
+        }
+        packaged
+    }
+
+    // Whatever is left, as a last segment
+    pub(crate) fn finish(&mut self) -> Vec<Packaged> {
+        let mut packaged = Vec::new();
+        for (track, frame) in std::mem::take(&mut self.pending) {
+            let duration = self.frame_durations.get(&track).copied().unwrap_or(self.frame_duration);
+            if let Some(current) = &mut self.current {
+                current.samples.push(Fmp4Muxer::sample(&frame, duration));
+            }
+        }
+        let end = self.last_dts.unwrap_or(0) + self.frame_duration;
+        self.close_segment(end, &mut packaged);
+        packaged
+    }
+
+    // Turns the samples so far into a fragment, which is a part when parts are on
+    fn close_part(&mut self, end: i64, packaged: &mut Vec<Packaged>) {
+        let (current, muxers) = match (&mut self.current, &mut self.muxer) {
+            (Some(current), Some(Muxer::Fmp4(muxers))) => (current, muxers),
+            _ => return,
+        };
+        if current.samples.is_empty() {
+            return;
+        }
+
+        let samples = std::mem::take(&mut current.samples);
+        let fragments: Vec<Vec<u8>> = muxers.iter_mut().map(|muxer| muxer.fragment(&samples, current.part_start)).collect();
+        for (data, fragment) in current.data.iter_mut().zip(&fragments) {
+            data.extend_from_slice(fragment);
+        }
+        if self.part_duration.is_some() {
+            packaged.push(Packaged::Part(PartData {
+                duration: (end - current.part_start).max(0) as u64,
+                independent: current.part_independent,
+                discontinuity: current.discontinuity && current.part_start == current.start,
+                data: fragments,
+            }));
+        }
+        current.part_start = end;
+    }
+
+    fn close_segment(&mut self, end: i64, packaged: &mut Vec<Packaged>) {
+        self.close_part(end, packaged);
+        if let Some(current) = self.current.take() {
+            packaged.push(Packaged::Segment(SegmentData {
+                start: current.start,
+                duration: (end - current.start).max(0) as u64,
+                discontinuity: current.discontinuity,
+                data: current.data,
+            }));
+        }
+    }
+}
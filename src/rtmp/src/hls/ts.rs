@@ -0,0 +1,328 @@
+use media_core::{Codec, MediaFrame, TrackInfo, TrackKind};
+use crate::Serializable;
+use crate::flv::audio::AudioSpecificConfig;
+use crate::flv::video::{AvcDecoderConfigurationRecord, HevcDecoderConfigurationRecord, split_annex_b};
+
+// ISO/IEC 13818-1 transport stream muxing, as much as HLS needs: one program with at most one
+// video and one audio stream, a PAT and PMT at the start of every segment, one PES packet per
+// frame and the PCR on the video stream.
+
+const PACKET_SIZE: usize = 188;
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+
+// Access unit delimiters, which HLS wants at the start of every video frame
+const H264_AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xF0];
+const HEVC_AUD: [u8; 7] = [0, 0, 0, 1, 0x46, 0x01, 0x50];
+
+struct TsStream {
+    track: u32,
+    codec: Codec,
+    pid: u16,
+    stream_type: u8,
+    continuity: u8,
+    // Sent in front of every keyframe that doesn't bring its own
+    parameter_sets: Vec<u8>,
+    // Only for AAC, whose frames need an ADTS header
+    adts: Option<AudioSpecificConfig>,
+}
+
+pub(crate) struct TsMuxer {
+    video: Option<TsStream>,
+    audio: Option<TsStream>,
+    // The tracks that made it in, for the playlist to describe
+    tracks: Vec<TrackInfo>,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+}
+
+impl TsMuxer {
+    // Tracks that can't go in a transport stream are left out
+    pub(crate) fn new(name: &str, tracks: &[TrackInfo]) -> Self {
+        let mut muxer = TsMuxer {
+            video: None,
+            audio: None,
+            tracks: Vec::new(),
+            pat_continuity: 0,
+            pmt_continuity: 0,
+        };
+
+        for track in tracks {
+            let stream = match ts_stream(track) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("HLS of {} leaves out track {}: {}", name, track.id, err);
+                    continue;
+                }
+            };
+            let slot = match track.kind() {
+                TrackKind::Video => &mut muxer.video,
+                TrackKind::Audio => &mut muxer.audio,
+            };
+            if slot.is_none() {
+                *slot = Some(stream);
+                muxer.tracks.push(track.clone());
+            }
+        }
+        muxer
+    }
+
+    pub(crate) fn tracks(&self) -> &[TrackInfo] {
+        &self.tracks
+    }
+
+    pub(crate) fn has_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.video.is_none() && self.audio.is_none()
+    }
+
+    pub(crate) fn carries(&self, frame: &MediaFrame) -> bool {
+        self.streams().any(|stream| stream.track == frame.track)
+    }
+
+    fn streams(&self) -> impl Iterator<Item = &TsStream> {
+        self.video.iter().chain(self.audio.iter())
+    }
+
+    fn pcr_pid(&self) -> u16 {
+        match &self.video {
+            Some(video) => video.pid,
+            None => AUDIO_PID,
+        }
+    }
+
+    // The PAT and PMT, which every segment starts with so it can be decoded on its own
+    pub(crate) fn write_tables(&mut self, out: &mut Vec<u8>) {
+        // One program, number 1
+        let mut pat = vec![0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01];
+        pat.extend_from_slice(&(0xE000 | PMT_PID).to_be_bytes());
+        write_section(out, PAT_PID, &mut self.pat_continuity, 0x00, &pat);
+
+        let mut pmt = vec![0x00, 0x01, 0xC1, 0x00, 0x00];
+        pmt.extend_from_slice(&(0xE000 | self.pcr_pid()).to_be_bytes());
+        // No program descriptors
+        pmt.extend_from_slice(&[0xF0, 0x00]);
+        for stream in self.streams() {
+            pmt.push(stream.stream_type);
+            pmt.extend_from_slice(&(0xE000 | stream.pid).to_be_bytes());
+            pmt.extend_from_slice(&[0xF0, 0x00]);
+        }
+        write_section(out, PMT_PID, &mut self.pmt_continuity, 0x02, &pmt);
+    }
+
+    pub(crate) fn write_frame(&mut self, frame: &MediaFrame, out: &mut Vec<u8>) {
+        let pcr_pid = self.pcr_pid();
+        let stream = match self.video.iter_mut().chain(self.audio.iter_mut()).find(|stream| stream.track == frame.track) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let dts = to_90khz(frame.dts);
+        let pts = to_90khz(frame.pts);
+        let (stream_id, payload) = match stream.codec.kind() {
+            TrackKind::Video => (0xE0, video_payload(stream, frame)),
+            TrackKind::Audio => (0xC0, audio_payload(stream, frame)),
+        };
+
+        let mut pes = vec![0x00, 0x00, 0x01, stream_id];
+        let header_size = if pts != dts { 10 } else { 5 };
+        // Zero means unbounded, which only video is allowed to use
+        let length = 3 + header_size + payload.len();
+        let length = if length > u16::MAX as usize { 0 } else { length as u16 };
+        pes.extend_from_slice(&length.to_be_bytes());
+        if pts != dts {
+            pes.extend_from_slice(&[0x80, 0xC0, 10]);
+            write_timestamp(&mut pes, 0b0011, pts);
+            write_timestamp(&mut pes, 0b0001, dts);
+        } else {
+            pes.extend_from_slice(&[0x80, 0x80, 5]);
+            write_timestamp(&mut pes, 0b0010, pts);
+        }
+        pes.extend_from_slice(&payload);
+
+        let pcr = (stream.pid == pcr_pid).then_some(dts);
+        let random_access = frame.keyframe && stream.codec.kind() == TrackKind::Video;
+        write_packets(out, stream.pid, &mut stream.continuity, &pes, pcr, random_access);
+    }
+}
+
+fn ts_stream(track: &TrackInfo) -> Result<TsStream, &'static str> {
+    let (pid, stream_type) = match track.codec {
+        Codec::H264 => (VIDEO_PID, 0x1B),
+        Codec::H265 => (VIDEO_PID, 0x24),
+        Codec::Aac => (AUDIO_PID, 0x0F),
+        Codec::Mp3 => (AUDIO_PID, 0x03),
+        _ => Err("Codec can't be carried in MPEG-TS")?,
+    };
+
+    let parameter_sets = match track.codec {
+        Codec::H264 => AvcDecoderConfigurationRecord::deserialize(&mut &track.config[..])?.to_annex_b(),
+        Codec::H265 => HevcDecoderConfigurationRecord::deserialize(&mut &track.config[..])?.to_annex_b(),
+        _ => Vec::new(),
+    };
+
+    let adts = match track.codec {
+        Codec::Aac => {
+            let config = AudioSpecificConfig::parse(&track.config)?;
+            // ADTS can only give the sample rate as an index
+            if config.sample_rate_index > 12 {
+                Err("AAC sample rate can't be expressed in ADTS")?
+            }
+            Some(config)
+        }
+        _ => None,
+    };
+
+    Ok(TsStream {
+        track: track.id,
+        codec: track.codec,
+        pid,
+        stream_type,
+        continuity: 0,
+        parameter_sets,
+        adts,
+    })
+}
+
+// Milliseconds to the 33 bit, 90kHz clock of PES timestamps and the PCR
+fn to_90khz(milliseconds: i64) -> u64 {
+    (milliseconds * 90) as u64 & 0x1_FFFF_FFFF
+}
+
+// An access unit delimiter first, then the parameter sets on keyframes
+fn video_payload(stream: &TsStream, frame: &MediaFrame) -> Vec<u8> {
+    let nal_type = |nalu: &[u8]| match stream.codec {
+        Codec::H265 => nalu.first().map(|byte| (byte >> 1) & 0x3F),
+        _ => nalu.first().map(|byte| byte & 0x1F),
+    };
+    let (aud, aud_type, sps_type): (&[u8], u8, u8) = match stream.codec {
+        Codec::H265 => (&HEVC_AUD, 35, 33),
+        _ => (&H264_AUD, 9, 7),
+    };
+
+    let nal_types: Vec<u8> = split_annex_b(&frame.payload).into_iter().filter_map(nal_type).collect();
+    let mut payload = Vec::with_capacity(aud.len() + stream.parameter_sets.len() + frame.payload.len());
+    if nal_types.first() != Some(&aud_type) {
+        payload.extend_from_slice(aud);
+    }
+    if frame.keyframe && !nal_types.contains(&sps_type) {
+        payload.extend_from_slice(&stream.parameter_sets);
+    }
+    payload.extend_from_slice(&frame.payload);
+    payload
+}
+
+fn audio_payload(stream: &TsStream, frame: &MediaFrame) -> Vec<u8> {
+    let config = match &stream.adts {
+        Some(config) => config,
+        None => return frame.payload.to_vec(),
+    };
+
+    // ADTS only has profiles for the first four object types, HE-AAC goes as AAC LC
+    let profile = match config.object_type {
+        1..=4 => config.object_type - 1,
+        _ => 1,
+    };
+    let length = 7 + frame.payload.len();
+    let mut payload = vec![
+        0xFF,
+        0xF1,
+        (profile << 6) | (config.sample_rate_index << 2) | ((config.channel_configuration >> 2) & 0x01),
+        ((config.channel_configuration & 0x03) << 6) | ((length >> 11) & 0x03) as u8,
+        (length >> 3) as u8,
+        (((length & 0x07) << 5) as u8) | 0x1F,
+        0xFC,
+    ];
+    payload.extend_from_slice(&frame.payload);
+    payload
+}
+
+fn write_timestamp(out: &mut Vec<u8>, prefix: u8, timestamp: u64) {
+    out.push((prefix << 4) | (((timestamp >> 30) & 0x07) as u8) << 1 | 1);
+    out.extend_from_slice(&((((timestamp >> 15) & 0x7FFF) << 1 | 1) as u16).to_be_bytes());
+    out.extend_from_slice(&(((timestamp & 0x7FFF) << 1 | 1) as u16).to_be_bytes());
+}
+
+// A PSI section in a packet of its own, with the section header and CRC around the body
+fn write_section(out: &mut Vec<u8>, pid: u16, continuity: &mut u8, table_id: u8, body: &[u8]) {
+    let mut section = vec![table_id];
+    // Section syntax indicator plus the length of everything after it, CRC included
+    section.extend_from_slice(&(0xB000 | (body.len() as u16 + 4)).to_be_bytes());
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+
+    let start = out.len();
+    out.extend_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | *continuity]);
+    // Pointer field, the section starts right away
+    out.push(0);
+    out.extend_from_slice(&section);
+    out.resize(start + PACKET_SIZE, 0xFF);
+    *continuity = (*continuity + 1) & 0x0F;
+}
+
+// Splits a PES packet into transport packets. The first one carries the PCR and random access
+// flag, the last one is padded out with adaptation field stuffing.
+fn write_packets(out: &mut Vec<u8>, pid: u16, continuity: &mut u8, pes: &[u8], pcr: Option<u64>, random_access: bool) {
+    let mut offset = 0;
+    while offset < pes.len() {
+        let first = offset == 0;
+
+        // The adaptation field after its length byte
+        let mut adaptation: Option<Vec<u8>> = None;
+        if first && (pcr.is_some() || random_access) {
+            let mut field = vec![if random_access { 0x40 } else { 0x00 }];
+            if let Some(pcr) = pcr {
+                field[0] |= 0x10;
+                // 33 bit base, 6 reserved bits, 9 bit extension
+                field.extend_from_slice(&((pcr << 15) | 0x7E00).to_be_bytes()[2..]);
+            }
+            adaptation = Some(field);
+        }
+
+        let space = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| field.len() + 1);
+        let remaining = pes.len() - offset;
+        if remaining < space {
+            let stuffing = space - remaining;
+            match &mut adaptation {
+                Some(field) => field.resize(field.len() + stuffing, 0xFF),
+                // Just the length byte
+                None if stuffing == 1 => adaptation = Some(Vec::new()),
+                None => {
+                    let mut field = vec![0x00];
+                    field.resize(stuffing - 1, 0xFF);
+                    adaptation = Some(field);
+                }
+            }
+        }
+
+        let control = if adaptation.is_some() { 0x30 } else { 0x10 };
+        let start_indicator = if first { 0x40 } else { 0x00 };
+        out.extend_from_slice(&[0x47, start_indicator | (pid >> 8) as u8, pid as u8, control | *continuity]);
+        if let Some(field) = &adaptation {
+            out.push(field.len() as u8);
+            out.extend_from_slice(field);
+        }
+        let take = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| field.len() + 1);
+        out.extend_from_slice(&pes[offset..offset + take]);
+        offset += take;
+        *continuity = (*continuity + 1) & 0x0F;
+    }
+}
+
+// CRC-32/MPEG-2, as PSI sections use
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
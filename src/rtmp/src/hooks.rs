@@ -0,0 +1,168 @@
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::command_message::PlayMessage;
+use crate::config::HookConfig;
+
+// Details about the connected client that get sent along with every hook call
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: String,
+    pub app: String,
+    pub flash_ver: String,
+    pub tc_url: String,
+}
+
+pub struct Hooks {
+    config: HookConfig,
+}
+
+impl Hooks {
+    pub fn new(config: HookConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn on_connect(&self, client: &ClientInfo) {
+        self.notify(self.config.on_connect.clone(), Self::body("connect", client));
+    }
+
+    pub async fn on_publish(&self, client: &ClientInfo, name: &str, publishing_type: &str) -> bool {
+        let mut body = Self::body("publish", client);
+        body["name"] = json!(name);
+        body["type"] = json!(publishing_type);
+        self.authorize(self.config.on_publish.as_deref(), body).await
+    }
+
+    pub fn on_publish_done(&self, client: &ClientInfo, name: &str) {
+        let mut body = Self::body("publish_done", client);
+        body["name"] = json!(name);
+        self.notify(self.config.on_publish_done.clone(), body);
+    }
+
+    pub async fn on_play(&self, client: &ClientInfo, play: &PlayMessage) -> bool {
+        let mut body = Self::body("play", client);
+        body["name"] = json!(play.stream_name);
+        body["start"] = json!(play.start);
+        body["duration"] = json!(play.duration);
+        body["reset"] = json!(play.reset);
+        self.authorize(self.config.on_play.as_deref(), body).await
+    }
+
+    pub fn on_play_done(&self, client: &ClientInfo, name: &str) {
+        let mut body = Self::body("play_done", client);
+        body["name"] = json!(name);
+        self.notify(self.config.on_play_done.clone(), body);
+    }
+
+    pub fn on_record_done(&self, client: &ClientInfo, name: &str, path: &str) {
+        let mut body = Self::body("record_done", client);
+        body["name"] = json!(name);
+        body["path"] = json!(path);
+        self.notify(self.config.on_record_done.clone(), body);
+    }
+
+    fn body(call: &str, client: &ClientInfo) -> Value {
+        json!({
+            "call": call,
+            "clientid": client.id,
+            "addr": client.addr,
+            "app": client.app,
+            "flashver": client.flash_ver,
+            "tcurl": client.tc_url,
+        })
+    }
+
+    // Fire and forget, nobody waits on the result of a notification
+    fn notify(&self, url: Option<String>, body: Value) {
+        let url = match url {
+            Some(url) => url,
+            None => return,
+        };
+
+        let timeout = self.config.timeout();
+        tokio::spawn(async move {
+            match tokio::time::timeout(timeout, post_json(&url, &body.to_string())).await {
+                Ok(Ok(status)) if (200..300).contains(&status) => {}
+                Ok(Ok(status)) => eprintln!("Hook {} answered with status {}", url, status),
+                Ok(Err(err)) => eprintln!("Hook {} failed: {}", url, err),
+                Err(_) => eprintln!("Hook {} timed out", url),
+            }
+        });
+    }
+
+    // Returns whether the action is allowed to go ahead
+    async fn authorize(&self, url: Option<&str>, body: Value) -> bool {
+        let url = match url {
+            Some(url) => url,
+            None => return true,
+        };
+
+        match tokio::time::timeout(self.config.timeout(), post_json(url, &body.to_string())).await {
+            Ok(Ok(status)) => {
+                if !(200..300).contains(&status) {
+                    println!("Hook {} denied {} with status {}", url, body["call"], status);
+                }
+                (200..300).contains(&status)
+            }
+            Ok(Err(err)) => {
+                eprintln!("Hook {} failed: {}", url, err);
+                self.config.fail_open
+            }
+            Err(_) => {
+                eprintln!("Hook {} timed out", url);
+                self.config.fail_open
+            }
+        }
+    }
+}
+
+// Bare bones HTTP/1.1 client, hooks only ever talk to plain http:// endpoints so this is all we need
+async fn post_json(url: &str, body: &str) -> Result<u16, &'static str> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => Err("Only http:// hook URLs are supported")?,
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = match TcpStream::connect(&address).await {
+        Ok(stream) => stream,
+        Err(_) => Err("Error connecting to hook")?,
+    };
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, authority, body.len(), body
+    );
+
+    if stream.write_all(request.as_bytes()).await.is_err() {
+        Err("Error sending hook request")?
+    }
+
+    // We only care about the status line, "HTTP/1.1 200 OK"
+    let mut response = Vec::new();
+    let mut buf = [0; 512];
+    while !response.contains(&b'\n') {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(_) => Err("Error reading hook response")?,
+        }
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    match status_line.split_whitespace().nth(1).and_then(|code| code.parse().ok()) {
+        Some(status) => Ok(status),
+        None => Err("Malformed hook response"),
+    }
+}
@@ -0,0 +1,148 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::broadcast;
+use crate::Serializable;
+use crate::command_message::PlayMessage;
+use crate::flv::file::{FlvHeader, FlvTag};
+use crate::hooks::ClientInfo;
+use crate::http::HttpServer;
+use crate::registry::{KeyframeGate, MediaMessage, StreamEvent};
+use crate::relay::PullPlayer;
+
+// HTTP-FLV: an FLV file that never ends, sent with chunked encoding. It starts with the header,
+// metadata and sequence headers, then the current GOP so the picture shows up straight away,
+// then the live tags. Players that fall behind get the same treatment as RTMP players.
+
+pub(super) async fn serve(server: &HttpServer, client: &ClientInfo, name: &str, mut reader: BufReader<OwnedReadHalf>, mut writer: OwnedWriteHalf) {
+    let (mut events, messages, _pull) = match subscribe(server, client, name, &mut writer).await {
+        Some(subscription) => subscription,
+        None => return,
+    };
+    println!("HTTP-FLV client {} is playing {}", client.id, name);
+
+    let result = stream(server, &mut events, messages, &mut reader, &mut writer).await;
+    if let Err(err) = result {
+        println!("HTTP-FLV client {} stopped playing {}: {}", client.id, name, err);
+    }
+
+    drop(events);
+    server.registry.unsubscribe(name);
+    server.hooks.on_play_done(client, name);
+}
+
+// The play hook, then a subscription with the current GOP. Whoever isn't allowed to play, or
+// asks for a stream that isn't there, gets an error response instead. On edges a stream that
+// isn't there gets pulled from the origin first, which goes on while the returned player is kept.
+pub(super) async fn subscribe(
+    server: &HttpServer,
+    client: &ClientInfo,
+    name: &str,
+    writer: &mut OwnedWriteHalf,
+) -> Option<(broadcast::Receiver<StreamEvent>, Vec<MediaMessage>, Option<PullPlayer>)> {
+    let play = PlayMessage {
+        stream_name: name.to_string(),
+        start: -1.0,
+        duration: -1.0,
+        reset: true,
+    };
+    if !server.hooks.on_play(client, &play).await {
+        let _ = server.respond(writer, 403, "Forbidden", false).await;
+        return None;
+    }
+
+    let pull = server.pulls.request(name);
+    if pull.is_some() {
+        server.pulls.wait(name).await;
+    }
+    match server.registry.subscribe_with_gop(name) {
+        Some((events, messages)) => Some((events, messages, pull)),
+        None => {
+            let _ = server.respond(writer, 404, "Not Found", false).await;
+            None
+        }
+    }
+}
+
+// Players set up their decoders from the header flags, so only flag what the stream has.
+// Before any headers came in we can't tell.
+pub(super) fn header(messages: &[MediaMessage]) -> Result<Vec<u8>, &'static str> {
+    let audio = messages.iter().any(|message| message.message_type_id == 8);
+    let video = messages.iter().any(|message| message.message_type_id == 9);
+    let header = match (audio, video) {
+        (false, false) => FlvHeader { audio: true, video: true },
+        _ => FlvHeader { audio, video },
+    };
+    header.serialize()
+}
+
+pub(super) fn tag(message: &MediaMessage) -> Vec<u8> {
+    let tag = FlvTag {
+        tag_type: message.message_type_id,
+        timestamp: message.timestamp,
+        data: message.data.to_vec(),
+    };
+    tag.to_bytes()
+}
+
+async fn stream(
+    server: &HttpServer,
+    events: &mut broadcast::Receiver<StreamEvent>,
+    messages: Vec<MediaMessage>,
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<(), &'static str> {
+    let head = server.head(200, "OK", &[
+        ("Content-Type", "video/x-flv"),
+        ("Transfer-Encoding", "chunked"),
+        ("Cache-Control", "no-cache"),
+        ("Connection", "close"),
+    ]);
+    write(writer, head.as_bytes()).await?;
+
+    write_chunk(writer, &header(&messages)?).await?;
+    for message in &messages {
+        write_chunk(writer, &tag(message)).await?;
+    }
+
+    let mut keyframe_gate = KeyframeGate::default();
+    let mut buf = [0u8; 512];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(StreamEvent::Media(message)) => {
+                    if keyframe_gate.admit(&message) {
+                        write_chunk(writer, &tag(&message)).await?;
+                    }
+                }
+                Ok(StreamEvent::Ended) | Err(broadcast::error::RecvError::Closed) => break,
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    println!("HTTP-FLV player fell behind, dropped {} messages", skipped);
+                    keyframe_gate.lagged();
+                }
+            },
+            // Players don't send anything after the request, so this only returns once they're gone
+            read = reader.read(&mut buf) => match read {
+                Ok(0) | Err(_) => Err("Player disconnected")?,
+                Ok(_) => {}
+            },
+        }
+    }
+
+    // The last chunk, the stream is over
+    write(writer, b"0\r\n\r\n").await
+}
+
+async fn write_chunk(writer: &mut OwnedWriteHalf, data: &[u8]) -> Result<(), &'static str> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    write(writer, &chunk).await
+}
+
+async fn write(writer: &mut OwnedWriteHalf, data: &[u8]) -> Result<(), &'static str> {
+    match writer.write_all(data).await {
+        Ok(()) => Ok(()),
+        Err(_) => Err("Error writing to player"),
+    }
+}
@@ -0,0 +1,93 @@
+use media_core::TrackKind;
+use tokio::net::tcp::OwnedWriteHalf;
+use crate::hls::{parse_rendition, PlaylistKind, WaitError};
+use crate::http::{HttpServer, Request};
+
+// HLS over plain GETs: the playlist at /{app}/{stream}.m3u8, the DASH manifest at
+// /{app}/{stream}.mpd, and everything they point to under /{app}/{stream}/. MPEG-TS segments
+// are {sequence}.ts, listed in media.m3u8. With fMP4 the rendition playlists are video.m3u8 and
+// audio.m3u8, whose files start with video- or audio-: segments as
+// {sequence}.m4s, parts as {sequence}.{part}.m4s and init segments as init{version}.mp4. The
+// playlist at /{app}/{stream}.m3u8 is a multivariant one for either. URIs in the manifests are
+// relative, so they resolve to those. Manifest polls don't go through the play
+// hooks, there'd be one every few seconds.
+
+// The request path after /{app}/
+pub(super) async fn serve(server: &HttpServer, writer: &mut OwnedWriteHalf, request: &Request, path: &str, keep_alive: bool) -> std::io::Result<()> {
+    let hls = match &server.hls {
+        Some(hls) => hls,
+        None => return server.respond(writer, 404, "Not Found", keep_alive).await,
+    };
+
+    if let Some(name) = path.strip_suffix(".m3u8") {
+        let (name, kind) = hls.playlist_name(name);
+        // Blocking reload: hold the request until the playlist has the segment or part asked for
+        let sequence = request.param("_HLS_msn").and_then(|sequence| sequence.parse::<u64>().ok());
+        let part = request.param("_HLS_part").and_then(|part| part.parse::<usize>().ok());
+        if let Some(sequence) = sequence {
+            match hls.wait(name, sequence, part).await {
+                Ok(()) => {}
+                Err(WaitError::TooFarAhead) => return server.respond(writer, 400, "Bad Request", keep_alive).await,
+                Err(WaitError::TimedOut) => return server.respond(writer, 503, "Service Unavailable", keep_alive).await,
+                Err(WaitError::NoSuchStream) => return server.respond(writer, 404, "Not Found", keep_alive).await,
+            }
+        }
+
+        // Relative to the playlist, so only the last part of the name. Rendition playlists are
+        // next to their files already.
+        let prefix = match kind {
+            PlaylistKind::Media(_) => String::new(),
+            PlaylistKind::Multivariant => format!("{}/", name.rsplit('/').next().unwrap_or(name)),
+        };
+        let skip = request.param("_HLS_skip").is_some_and(|skip| skip == "YES" || skip == "v2");
+        return match hls.playlist(name, kind, &prefix, skip) {
+            Some(playlist) => server.send(writer, "application/vnd.apple.mpegurl", "no-cache", playlist.as_bytes(), keep_alive).await,
+            None => server.respond(writer, 404, "Not Found", keep_alive).await,
+        };
+    }
+
+    if let Some(name) = path.strip_suffix(".mpd") {
+        let prefix = format!("{}/", name.rsplit('/').next().unwrap_or(name));
+        return match hls.mpd(name, &prefix) {
+            Some(mpd) => server.send(writer, "application/dash+xml", "no-cache", mpd.as_bytes(), keep_alive).await,
+            None => server.respond(writer, 404, "Not Found", keep_alive).await,
+        };
+    }
+
+    let (name, file) = match path.rsplit_once('/') {
+        Some(split) => split,
+        None => return server.respond(writer, 404, "Not Found", keep_alive).await,
+    };
+
+    let (rendition, file) = match file.split_once('-') {
+        Some((rendition, file)) => (parse_rendition(rendition), file),
+        None => (None, file),
+    };
+    let data = if let Some(version) = file.strip_prefix("init").and_then(|file| file.strip_suffix(".mp4")) {
+        version.parse().ok().and_then(|version| hls.init(name, version, rendition))
+    } else if let Some(file) = file.strip_suffix(&format!(".{}", hls.extension())) {
+        let mut numbers = file.split('.').map(|number| number.parse::<u64>().ok());
+        match (numbers.next().flatten(), numbers.next(), numbers.next()) {
+            (Some(sequence), None, _) => hls.segment(name, sequence, rendition),
+            // Parts named in a preload hint get asked for before they're there
+            (Some(sequence), Some(Some(part)), None) => match hls.wait(name, sequence, Some(part as usize)).await {
+                Ok(()) => hls.part(name, sequence, part as usize, rendition),
+                Err(_) => None,
+            },
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let content_type = match rendition {
+        _ if file.ends_with(".ts") => "video/mp2t",
+        Some(TrackKind::Audio) => "audio/mp4",
+        _ => "video/mp4",
+    };
+    match data {
+        // Nothing changes once it's in the playlist
+        Some(data) => server.send(writer, content_type, "max-age=3600", &data, keep_alive).await,
+        None => server.respond(writer, 404, "Not Found", keep_alive).await,
+    }
+}
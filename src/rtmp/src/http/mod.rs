@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::config::RtmpConfig;
+use crate::hls::HlsStreams;
+use crate::hooks::{ClientInfo, Hooks};
+use crate::registry::StreamRegistry;
+use crate::relay::{PullRelays, PushRelays};
+use crate::server::NEXT_CLIENT_ID;
+
+mod flv;
+mod hls;
+mod rtmpt;
+mod websocket;
+
+// Just enough HTTP/1.1 to serve streams to players: GET requests without bodies, plus the CORS
+// preflight, WebSocket upgrades, and the POSTs of RTMPT, whose bodies need a Content-Length.
+// Responses other than HTTP-FLV and WebSocket-FLV streams keep the connection open for the next
+// request.
+
+// Request heads bigger than this are refused, nothing we serve needs more
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    // The value of a query string parameter, as it is without any percent decoding
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| value)
+    }
+
+    fn keep_alive(&self) -> bool {
+        !self.header("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+pub(crate) struct HttpServer {
+    config: Arc<RtmpConfig>,
+    hooks: Arc<Hooks>,
+    registry: Arc<StreamRegistry>,
+    pushes: Arc<PushRelays>,
+    pulls: Arc<PullRelays>,
+    // None when HLS is disabled
+    hls: Option<Arc<HlsStreams>>,
+    rtmpt_sessions: rtmpt::Sessions,
+}
+
+impl HttpServer {
+    pub fn new(config: Arc<RtmpConfig>, hooks: Arc<Hooks>, registry: Arc<StreamRegistry>, pushes: Arc<PushRelays>, pulls: Arc<PullRelays>, hls: Option<Arc<HlsStreams>>) -> Self {
+        Self {
+            config,
+            hooks,
+            registry,
+            pushes,
+            pulls,
+            hls,
+            rtmpt_sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(self: Arc<Self>) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.config.http.listen).await?;
+        println!("Serving HTTP on {}", self.config.http.listen);
+
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                server.handle_connection(socket, addr).await;
+            });
+        }
+    }
+
+    async fn handle_connection(&self, socket: TcpStream, addr: SocketAddr) {
+        let (reader, mut writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let request = match read_request(&mut reader).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(err) => {
+                    eprintln!("Bad HTTP request from {}: {}", addr, err);
+                    let _ = self.respond(&mut writer, 400, "Bad Request", false).await;
+                    return;
+                }
+            };
+
+            // /{app}/{path}, where stream names in the path can have slashes of their own
+            let target = request.path.strip_prefix('/').and_then(|path| path.split_once('/'));
+
+            let keep_alive = request.keep_alive();
+            let result = match (request.method.as_str(), target) {
+                ("OPTIONS", _) => self.respond(&mut writer, 204, "No Content", keep_alive).await,
+                ("GET", Some((app, path))) if path.len() > 4 && path.ends_with(".flv") => {
+                    let client = self.client_info(&request, addr, app);
+                    let name = &path[..path.len() - 4];
+                    let upgrade = request.header("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+                    if upgrade && self.config.websocket.enabled {
+                        websocket::serve(self, &client, &request, name, reader, writer).await;
+                    } else {
+                        flv::serve(self, &client, name, reader, writer).await;
+                    }
+                    return;
+                }
+                ("GET", Some((_, path))) => hls::serve(self, &mut writer, &request, path, keep_alive).await,
+                ("GET", None) => self.respond(&mut writer, 404, "Not Found", keep_alive).await,
+                ("POST", _) if self.config.rtmpt.enabled => rtmpt::serve(self, &mut reader, &mut writer, &request, addr, keep_alive).await,
+                _ => self.respond(&mut writer, 405, "Method Not Allowed", keep_alive).await,
+            };
+            if result.is_err() || !keep_alive {
+                return;
+            }
+        }
+    }
+
+    fn client_info(&self, request: &Request, addr: SocketAddr, app: &str) -> ClientInfo {
+        ClientInfo {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr: addr.ip().to_string(),
+            app: app.to_string(),
+            flash_ver: request.header("User-Agent").unwrap_or_default().to_string(),
+            tc_url: format!("http://{}/{}", request.header("Host").unwrap_or_default(), app),
+        }
+    }
+
+    // The status line and headers, including CORS ones when configured
+    fn head(&self, status: u16, reason: &str, headers: &[(&str, &str)]) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let origin = &self.config.http.allow_origin;
+        if !origin.is_empty() {
+            head.push_str(&format!("Access-Control-Allow-Origin: {}\r\n", origin));
+            head.push_str("Access-Control-Allow-Methods: GET, OPTIONS\r\n");
+            head.push_str("Access-Control-Allow-Headers: *\r\n");
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    async fn respond(&self, writer: &mut OwnedWriteHalf, status: u16, reason: &str, keep_alive: bool) -> io::Result<()> {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let head = self.head(status, reason, &[("Content-Length", "0"), ("Connection", connection)]);
+        writer.write_all(head.as_bytes()).await
+    }
+
+    async fn send(&self, writer: &mut OwnedWriteHalf, content_type: &str, cache_control: &str, body: &[u8], keep_alive: bool) -> io::Result<()> {
+        let length = body.len().to_string();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let head = self.head(200, "OK", &[
+            ("Content-Type", content_type),
+            ("Content-Length", &length),
+            ("Cache-Control", cache_control),
+            ("Connection", connection),
+        ]);
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(body).await
+    }
+}
+
+// The body of a request that has one, which has to come with a Content-Length
+async fn read_body(reader: &mut BufReader<OwnedReadHalf>, request: &Request, max_size: usize) -> Result<Vec<u8>, &'static str> {
+    if request.header("Transfer-Encoding").is_some() {
+        Err("Chunked request bodies aren't supported")?
+    }
+    let length = match request.header("Content-Length").map(str::parse::<usize>) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => Err("Invalid Content-Length")?,
+        None => 0,
+    };
+    if length > max_size {
+        Err("Request body too large")?
+    }
+
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).await.is_err() {
+        Err("Connection closed mid-request")?
+    }
+    Ok(body)
+}
+
+// None when the client closed the connection between requests
+async fn read_request(reader: &mut BufReader<OwnedReadHalf>) -> Result<Option<Request>, &'static str> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = match (&mut *reader).take((MAX_REQUEST_SIZE - size) as u64 + 1).read_line(&mut line).await {
+            Ok(read) => read,
+            Err(_) => Err("Error reading request")?,
+        };
+        if read == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            Err("Connection closed mid-request")?
+        }
+        size += read;
+        if size > MAX_REQUEST_SIZE {
+            Err("Request too large")?
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            if lines.is_empty() {
+                // Stray blank line between requests
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines[0].split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => Err("Invalid request line")?,
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let headers = lines[1..].iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(Some(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    }))
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use crate::http::{read_body, HttpServer, Request};
+use crate::server::RtmpConnection;
+
+// RTMPT: RTMP tunneled through HTTP POSTs. POST /open/1 starts a session and answers with its id,
+// then the client sends its RTMP bytes in the bodies of POST /send/{id}/{sequence} and polls with
+// POST /idle/{id}/{sequence}. Every answer starts with a byte hinting how long to wait before the
+// next poll, followed by whatever the server has for the client. POST /close/{id}/{sequence} ends
+// the session. Behind every session is an RtmpConnection like the ones on the RTMP listener, over
+// an in-memory stream instead of a socket.
+
+pub(super) type Sessions = Arc<Mutex<HashMap<String, Arc<Session>>>>;
+
+// Of the in-memory stream between the tunnel and the connection, in either direction
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// The connection doesn't get read from while this much waits for the client to poll for it
+const MAX_PENDING: usize = 1024 * 1024;
+
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// The polling interval hint doubles up to this while neither side has anything to send
+const MAX_POLLING_INTERVAL: u8 = 0x21;
+
+// How often a session checks whether its client is gone
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const CONTENT_TYPE: &str = "application/x-fcs";
+
+pub(super) struct Session {
+    // The client's bytes go to the connection through here
+    input: tokio::sync::Mutex<WriteHalf<DuplexStream>>,
+    output: Mutex<Output>,
+    // Wakes the session up once the client picked up what was pending
+    drained: Notify,
+    last_request: Mutex<Instant>,
+}
+
+struct Output {
+    // What the connection wrote since the last poll
+    data: Vec<u8>,
+    polling_interval: u8,
+    // Set once the connection is done
+    closed: bool,
+}
+
+pub(super) async fn serve(
+    server: &HttpServer,
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+    request: &Request,
+    addr: SocketAddr,
+    keep_alive: bool,
+) -> io::Result<()> {
+    // Read even when it's going to be refused, the next request comes after it
+    let body = match read_body(reader, request, MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(err) => {
+            eprintln!("Bad RTMPT request from {}: {}", addr, err);
+            server.respond(writer, 400, "Bad Request", false).await?;
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+    };
+
+    // The sequence numbers after the session id only matter to proxies, so they don't cache
+    let mut segments = request.path.trim_start_matches('/').split('/');
+    let (command, id) = (segments.next().unwrap_or_default(), segments.next());
+    let session = match (command, id) {
+        ("open", _) => {
+            let id = open(server, addr);
+            return server.send(writer, CONTENT_TYPE, "no-cache", format!("{}\n", id).as_bytes(), keep_alive).await;
+        }
+        ("send" | "idle" | "close", Some(id)) => server.rtmpt_sessions.lock().unwrap().get(id).cloned(),
+        // Including /fcs/ident2, which clients try before opening a session
+        _ => None,
+    };
+    let (session, id) = match (session, id) {
+        (Some(session), Some(id)) => (session, id),
+        _ => return server.respond(writer, 404, "Not Found", keep_alive).await,
+    };
+    *session.last_request.lock().unwrap() = Instant::now();
+
+    if command == "close" {
+        server.rtmpt_sessions.lock().unwrap().remove(id);
+        // Like the client closing its socket, the connection gets to clean up
+        let _ = session.input.lock().await.shutdown().await;
+        println!("RTMPT session {} closed", id);
+        return server.send(writer, CONTENT_TYPE, "no-cache", &[0], keep_alive).await;
+    }
+
+    // Idle polls have a body too, a single byte that doesn't mean anything
+    let sent = command == "send" && !body.is_empty();
+    if sent {
+        // A connection that's gone shows in the poll
+        let _ = session.input.lock().await.write_all(&body).await;
+    }
+
+    match session.poll(sent) {
+        Some(body) => server.send(writer, CONTENT_TYPE, "no-cache", &body, keep_alive).await,
+        None => {
+            server.rtmpt_sessions.lock().unwrap().remove(id);
+            server.respond(writer, 404, "Not Found", keep_alive).await
+        }
+    }
+}
+
+// Starts the connection of a new session, returning the session id
+fn open(server: &HttpServer, addr: SocketAddr) -> String {
+    let id = format!("{:016x}", rand::random::<u64>());
+    let (tunnel, stream) = tokio::io::duplex(BUFFER_SIZE);
+    let (output, input) = tokio::io::split(tunnel);
+    let session = Arc::new(Session {
+        input: tokio::sync::Mutex::new(input),
+        output: Mutex::new(Output {
+            data: Vec::new(),
+            polling_interval: 1,
+            closed: false,
+        }),
+        drained: Notify::new(),
+        last_request: Mutex::new(Instant::now()),
+    });
+    server.rtmpt_sessions.lock().unwrap().insert(id.clone(), session.clone());
+    println!("RTMPT session {} opened for {}", id, addr);
+
+    let mut connection = RtmpConnection::new(
+        stream,
+        addr,
+        server.config.clone(),
+        server.hooks.clone(),
+        server.registry.clone(),
+        server.pushes.clone(),
+        server.pulls.clone(),
+    );
+    tokio::spawn(async move {
+        connection.handle_connection().await;
+    });
+
+    let sessions = server.rtmpt_sessions.clone();
+    let timeout = Duration::from_millis(server.config.rtmpt.session_timeout_ms);
+    let session_id = id.clone();
+    tokio::spawn(async move {
+        session.run(&sessions, &session_id, output, timeout).await;
+    });
+    id
+}
+
+impl Session {
+    // Collects what the connection writes for the next poll. Once the session is closed or its
+    // client stopped polling, dropping it closes the connection's stream.
+    async fn run(&self, sessions: &Sessions, id: &str, mut output: ReadHalf<DuplexStream>, timeout: Duration) {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut expiry_check = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        let mut done = false;
+        loop {
+            let full = self.output.lock().unwrap().data.len() >= MAX_PENDING;
+            tokio::select! {
+                read = output.read(&mut buffer), if !done && !full => match read {
+                    Ok(0) | Err(_) => {
+                        // The client still gets what's left on its next poll
+                        self.output.lock().unwrap().closed = true;
+                        done = true;
+                    }
+                    Ok(read) => self.output.lock().unwrap().data.extend_from_slice(&buffer[..read]),
+                },
+                _ = self.drained.notified(), if !done && full => {}
+                _ = expiry_check.tick() => {
+                    if !sessions.lock().unwrap().contains_key(id) {
+                        return;
+                    }
+                    if self.last_request.lock().unwrap().elapsed() >= timeout {
+                        println!("RTMPT session {} timed out", id);
+                        sessions.lock().unwrap().remove(id);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // The answer to a request: the polling interval hint, then everything pending. None once the
+    // connection is done and the client got all of it.
+    fn poll(&self, sent: bool) -> Option<Vec<u8>> {
+        let mut output = self.output.lock().unwrap();
+        if output.closed && output.data.is_empty() {
+            return None;
+        }
+        output.polling_interval = match sent || !output.data.is_empty() {
+            true => 1,
+            false => (output.polling_interval * 2).min(MAX_POLLING_INTERVAL),
+        };
+
+        let mut body = vec![output.polling_interval];
+        body.append(&mut output.data);
+        drop(output);
+        self.drained.notify_one();
+        Some(body)
+    }
+}
@@ -0,0 +1,351 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::codec::base64_encode;
+use crate::config::SlowPlayerPolicy;
+use crate::hooks::ClientInfo;
+use crate::http::{HttpServer, Request, flv};
+use crate::registry::{KeyframeGate, MediaMessage, StreamEvent};
+
+// WebSocket-FLV: the HTTP-FLV byte stream over a WebSocket (RFC 6455), for places that cut off
+// long chunked responses but let WebSockets through. The FLV header goes in the first binary
+// message, then one tag per message. Every player has a queue of its own in front of the socket,
+// and what happens when it fills up is configurable.
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Players don't send us anything but control frames, which are 125 bytes at most
+const MAX_FRAME_SIZE: u64 = 4096;
+
+// Close status codes
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+
+const FELL_BEHIND: &str = "Player fell behind";
+
+pub(super) async fn serve(server: &HttpServer, client: &ClientInfo, request: &Request, name: &str, reader: BufReader<OwnedReadHalf>, mut writer: OwnedWriteHalf) {
+    let key = match (request.header("Sec-WebSocket-Key"), request.header("Sec-WebSocket-Version")) {
+        (Some(key), Some("13")) => key,
+        (Some(_), _) => {
+            let head = server.head(426, "Upgrade Required", &[("Sec-WebSocket-Version", "13"), ("Content-Length", "0"), ("Connection", "close")]);
+            let _ = writer.write_all(head.as_bytes()).await;
+            return;
+        }
+        (None, _) => {
+            let _ = server.respond(&mut writer, 400, "Bad Request", false).await;
+            return;
+        }
+    };
+
+    let (mut events, messages, _pull) = match flv::subscribe(server, client, name, &mut writer).await {
+        Some(subscription) => subscription,
+        None => return,
+    };
+
+    let head = server.head(101, "Switching Protocols", &[
+        ("Upgrade", "websocket"),
+        ("Connection", "Upgrade"),
+        ("Sec-WebSocket-Accept", &accept_key(key)),
+    ]);
+    if writer.write_all(head.as_bytes()).await.is_ok() {
+        println!("WebSocket-FLV client {} is playing {}", client.id, name);
+        let result = stream(server, &mut events, messages, reader, writer).await;
+        if let Err(err) = result {
+            println!("WebSocket-FLV client {} stopped playing {}: {}", client.id, name, err);
+        }
+    }
+
+    drop(events);
+    server.registry.unsubscribe(name);
+    server.hooks.on_play_done(client, name);
+}
+
+async fn stream(
+    server: &HttpServer,
+    events: &mut broadcast::Receiver<StreamEvent>,
+    messages: Vec<MediaMessage>,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+) -> Result<(), &'static str> {
+    let config = &server.config.websocket;
+    let (queue, outgoing) = mpsc::channel(config.queue_size.max(1));
+    let write_task = tokio::spawn(write_frames(outgoing, writer));
+    // Frames get read on a task of their own, reading one can't be interrupted halfway
+    let (incoming_sender, mut incoming) = mpsc::channel(8);
+    let read_task = tokio::spawn(read_frames(reader, incoming_sender));
+
+    let mut player = Player {
+        queue,
+        policy: config.slow_player,
+        keyframe_gate: KeyframeGate::default(),
+        dropped: 0,
+    };
+    let result = player.play(events, messages, &mut incoming).await;
+    read_task.abort();
+    // Otherwise whatever is still queued goes out first, the close frame last
+    if result == Err(FELL_BEHIND) {
+        write_task.abort();
+    }
+    result
+}
+
+struct Player {
+    queue: mpsc::Sender<Vec<u8>>,
+    policy: SlowPlayerPolicy,
+    keyframe_gate: KeyframeGate,
+    // Tags dropped since the queue last had room
+    dropped: u64,
+}
+
+impl Player {
+    async fn play(
+        &mut self,
+        events: &mut broadcast::Receiver<StreamEvent>,
+        messages: Vec<MediaMessage>,
+        incoming: &mut mpsc::Receiver<(u8, Vec<u8>)>,
+    ) -> Result<(), &'static str> {
+        // The start waits for room rather than counting as falling behind, the GOP alone can
+        // be more than the queue holds
+        self.send(frame(OPCODE_BINARY, &flv::header(&messages)?)).await?;
+        for message in &messages {
+            self.send(frame(OPCODE_BINARY, &flv::tag(message))).await?;
+        }
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(StreamEvent::Media(message)) => self.queue_tag(&message)?,
+                    Ok(StreamEvent::Ended) | Err(broadcast::error::RecvError::Closed) => break,
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        println!("WebSocket-FLV player fell behind, dropped {} messages", skipped);
+                        self.keyframe_gate.lagged();
+                    }
+                },
+                received = incoming.recv() => match received {
+                    Some((OPCODE_CLOSE, payload)) => {
+                        // Echoing the status code back finishes the closing handshake
+                        let _ = self.queue.try_send(frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]));
+                        return Ok(());
+                    }
+                    Some((OPCODE_PING, payload)) => {
+                        let _ = self.queue.try_send(frame(OPCODE_PONG, &payload));
+                    }
+                    // Pongs and anything else players send mean nothing to us
+                    Some(_) => {}
+                    None => Err("Player disconnected")?,
+                },
+            }
+        }
+
+        // The stream is over
+        let _ = self.queue.try_send(frame(OPCODE_CLOSE, &GOING_AWAY.to_be_bytes()));
+        Ok(())
+    }
+
+    async fn send(&mut self, frame: Vec<u8>) -> Result<(), &'static str> {
+        match self.queue.send(frame).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err("Player disconnected"),
+        }
+    }
+
+    fn queue_tag(&mut self, message: &MediaMessage) -> Result<(), &'static str> {
+        if !self.keyframe_gate.admit(message) {
+            self.dropped += 1;
+            return Ok(());
+        }
+
+        match self.queue.try_send(frame(OPCODE_BINARY, &flv::tag(message))) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    println!("WebSocket-FLV player fell behind, dropped {} tags", self.dropped);
+                    self.dropped = 0;
+                }
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => match self.policy {
+                SlowPlayerPolicy::DropFrames => {
+                    self.keyframe_gate.lagged();
+                    self.dropped += 1;
+                    Ok(())
+                }
+                // A player that far behind wouldn't get to a close frame any time soon
+                SlowPlayerPolicy::Disconnect => Err(FELL_BEHIND),
+            },
+            Err(TrySendError::Closed(_)) => Err("Player disconnected"),
+        }
+    }
+}
+
+// Until the queue closes or the player goes away
+async fn write_frames(mut outgoing: mpsc::Receiver<Vec<u8>>, mut writer: OwnedWriteHalf) {
+    while let Some(frame) = outgoing.recv().await {
+        let close = frame[0] & 0x0F == OPCODE_CLOSE;
+        if writer.write_all(&frame).await.is_err() || close {
+            return;
+        }
+    }
+}
+
+// Complete messages with their opcodes, until the connection closes or something's wrong with it
+async fn read_frames(mut reader: BufReader<OwnedReadHalf>, incoming: mpsc::Sender<(u8, Vec<u8>)>) {
+    loop {
+        let received = match read_frame(&mut reader).await {
+            Ok(received) => received,
+            Err(err) => {
+                if err != "Connection closed" {
+                    eprintln!("Bad WebSocket frame from player: {}", err);
+                    // Handled as if the player closed the connection, which gets it a close frame
+                    let _ = incoming.send((OPCODE_CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())).await;
+                }
+                return;
+            }
+        };
+        if incoming.send(received).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Players send control frames whole, so fragments are taken as they come
+async fn read_frame(reader: &mut BufReader<OwnedReadHalf>) -> Result<(u8, Vec<u8>), &'static str> {
+    let mut head = [0u8; 2];
+    if reader.read_exact(&mut head).await.is_err() {
+        Err("Connection closed")?
+    }
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        Err("Frame from the player isn't masked")?
+    }
+
+    let length = match head[1] & 0x7F {
+        126 => read_bytes::<2>(reader).await.map(|bytes| u16::from_be_bytes(bytes) as u64)?,
+        127 => read_bytes::<8>(reader).await.map(u64::from_be_bytes)?,
+        length => length as u64,
+    };
+    if length > MAX_FRAME_SIZE {
+        Err("Frame too large")?
+    }
+
+    let mask = read_bytes::<4>(reader).await?;
+    let mut payload = vec![0u8; length as usize];
+    if reader.read_exact(&mut payload).await.is_err() {
+        Err("Connection closed mid-frame")?
+    }
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+async fn read_bytes<const N: usize>(reader: &mut BufReader<OwnedReadHalf>) -> Result<[u8; N], &'static str> {
+    let mut bytes = [0u8; N];
+    match reader.read_exact(&mut bytes).await {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err("Connection closed mid-frame"),
+    }
+}
+
+// A single unmasked frame, which is what servers send
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Sec-WebSocket-Accept, proof that we read the key
+fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+// FIPS 180-4 SHA-1, which the handshake needs and nothing else does
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    // Padded with a one bit, zeros and the length in bits to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, value) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha1_known_answers() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Two blocks once padded
+        assert_eq!(
+            hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+        );
+    }
+
+    // The sample handshake of RFC 6455 section 1.3
+    #[test]
+    fn accept_key_rfc_sample() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}
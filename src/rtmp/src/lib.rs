@@ -1,9 +1,9 @@
-use std::io::Read;
 use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc::{channel};
-use std::sync::{Arc, Mutex};
-use crate::chunk::chunk_router::ChunkRouter;
+use tokio_rustls::TlsAcceptor;
+use std::sync::Arc;
 
 mod server;
 mod handshake;
@@ -11,35 +11,154 @@ mod chunk;
 mod control_message;
 mod command_message;
 mod socket;
+mod config;
+mod hooks;
+mod registry;
+mod group;
+mod recorder;
+mod file_publisher;
+mod vod;
+mod http;
+mod hls;
+mod mp4;
+mod timestamp;
+mod media;
+mod client;
+mod relay;
+mod tls;
+pub mod flv;
+pub mod codec;
 
-pub trait Serializable {
-    fn serialize(&self) -> Result<Vec<u8>, &'static str>;
-    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str>
-        where
-            R: Read,
-            Self: Sized;
-}
+pub use config::{DuplicatePublisherPolicy, HlsConfig, CertificateConfig, HookConfig, HttpConfig, PublishConfig, PushConfig, RecordConfig, RelayConfig, RtmpsConfig, RtmptConfig, RtmpConfig, RtspConfig, SlowPlayerPolicy, StreamGroupConfig, TimeoutConfig, VodConfig, WebSocketConfig};
+pub use hooks::{ClientInfo, Hooks};
+pub use file_publisher::FilePublisher;
+pub use client::{ClientEvent, RtmpClient, RtmpUrl};
+pub use relay::{PullRelays, PushRelays, PushState, PushStatus};
+pub use server::PublishingType;
+pub use group::{GroupEvent, MemberStats};
+pub use registry::{MediaMessage, StreamEvent, StreamInfo, StreamRegistry, TimecodeAnchor};
+
+pub use media_core::Serializable;
 
+#[derive(Clone)]
 pub struct RtmpServer {
-    pub chunk_router: Arc<Mutex<ChunkRouter>>,
+    pub registry: Arc<StreamRegistry>,
+    pub config: Arc<RtmpConfig>,
+    // Where published streams get pushed on to, which can be started and stopped at runtime
+    pub pushes: Arc<PushRelays>,
+    // Streams played here that get pulled from origins
+    pub pulls: Arc<PullRelays>,
+    hooks: Arc<Hooks>,
 }
 
 impl RtmpServer {
     pub fn new() -> RtmpServer {
+        Self::with_config(RtmpConfig::default())
+    }
+
+    pub fn with_config(config: RtmpConfig) -> RtmpServer {
+        let registry = Arc::new(StreamRegistry::new(config.publish.clone(), config.groups.clone()));
         RtmpServer {
-            chunk_router: Arc::new(Mutex::new(ChunkRouter::new())),
+            pushes: Arc::new(PushRelays::new(config.relay.clone(), registry.clone())),
+            pulls: Arc::new(PullRelays::new(config.relay.clone(), registry.clone())),
+            registry,
+            hooks: Arc::new(Hooks::new(config.hooks.clone())),
+            config: Arc::new(config),
         }
     }
 
     pub async fn start(&self) -> io::Result<()> {
-        // Start a TCP server
-        let listener = TcpListener::bind("127.0.0.1:1935").await?;
+        let listener = TcpListener::bind(&self.config.listen).await?;
+        self.start_on(listener).await
+    }
+
+    // Like start, with RTMP on a listener that's bound already, e.g. to an ephemeral port
+    pub async fn start_on(&self, listener: TcpListener) -> io::Result<()> {
+        self.registry.start_group_reports();
+
+        let hls = self.config.hls.enabled.then(|| Arc::new(hls::HlsStreams::new(self.config.hls.clone())));
+        if let Some(hls) = &hls {
+            tokio::spawn(hls.clone().start(self.registry.clone()));
+        }
+
+        if self.config.http.enabled {
+            let http = Arc::new(http::HttpServer::new(self.config.clone(), self.hooks.clone(), self.registry.clone(), self.pushes.clone(), self.pulls.clone(), hls));
+            tokio::spawn(async move {
+                if let Err(err) = http.start().await {
+                    eprintln!("HTTP server stopped: {}", err);
+                }
+            });
+        }
+
+        // RTMPS next to plain RTMP, when it's on
+        let rtmps = match self.config.rtmps.enabled {
+            true => {
+                let acceptor = tls::acceptor(&self.config.rtmps).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let listener = TcpListener::bind(&self.config.rtmps.listen).await?;
+                println!("Serving RTMPS on {}", self.config.rtmps.listen);
+                Some((listener, acceptor))
+            }
+            false => None,
+        };
+
+        println!("Serving RTMP on {}", listener.local_addr()?);
 
         loop {
-            let (socket, _) = listener.accept().await?;
-            let (tx, rx) = channel(9999);
-            let mut connection = server::RtmpConnection::new(socket, tx);
-            connection.handle_connection().await;
+            tokio::select! {
+                // A failed accept is about that one connection, the listener carries on
+                accepted = listener.accept() => {
+                    let (socket, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            eprintln!("Failed to accept an RTMP connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        server.serve(socket, addr).await;
+                    });
+                }
+                accepted = accept_tls(&rtmps) => {
+                    let (socket, addr, acceptor) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            eprintln!("Failed to accept an RTMPS connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        // The TLS handshake counts towards the handshake timeout too
+                        let handshake = acceptor.accept(socket);
+                        let stream = match server.config.timeouts.handshake() {
+                            Some(timeout) => tokio::time::timeout(timeout, handshake).await.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                            None => handshake.await,
+                        };
+                        match stream {
+                            Ok(stream) => server.serve(stream, addr).await,
+                            Err(err) => eprintln!("TLS handshake with {} failed: {}", addr, err),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    // Runs the connection to the end, over whatever stream the client came in on
+    async fn serve<S: AsyncRead + AsyncWrite + Send + 'static>(&self, stream: S, addr: SocketAddr) {
+        let mut connection = server::RtmpConnection::new(stream, addr, self.config.clone(), self.hooks.clone(), self.registry.clone(), self.pushes.clone(), self.pulls.clone());
+        connection.handle_connection().await;
+    }
+}
+
+// Never returns while RTMPS is off
+async fn accept_tls(rtmps: &Option<(TcpListener, TlsAcceptor)>) -> io::Result<(tokio::net::TcpStream, SocketAddr, TlsAcceptor)> {
+    match rtmps {
+        Some((listener, acceptor)) => {
+            let (socket, addr) = listener.accept().await?;
+            Ok((socket, addr, acceptor.clone()))
         }
+        None => std::future::pending().await,
     }
 }
@@ -0,0 +1,419 @@
+use std::sync::Arc;
+use amf::amf0::Value;
+use media_core::{Bytes, Codec, FrameEvent, FrameReceiver, FrameSender, MediaFrame, TrackInfo, TrackKind};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, oneshot};
+use crate::Serializable;
+use crate::flv::audio::{AudioCodec, AudioConfiguration, AudioPacket, AudioTagParser};
+use crate::flv::metadata::Metadata;
+use crate::flv::video::{DecoderConfiguration, VideoCodec, VideoPacket, VideoTagParser, split_annex_b};
+use crate::registry::{MediaMessage, StreamEvent, StreamInfo, StreamRegistry};
+use crate::server::wait_for_kick;
+
+// Translates between RTMP messages carrying FLV tags and the protocol neutral frames of media-core,
+// so streams published over RTMP can be played over anything else and the other way around.
+
+// Track ids as MP4 would number them
+pub const VIDEO_TRACK: u32 = 1;
+pub const AUDIO_TRACK: u32 = 2;
+
+fn video_codec(codec: VideoCodec) -> Option<Codec> {
+    match codec {
+        VideoCodec::Avc => Some(Codec::H264),
+        VideoCodec::Hevc => Some(Codec::H265),
+        VideoCodec::Av1 => Some(Codec::Av1),
+        VideoCodec::Vp9 => Some(Codec::Vp9),
+        _ => None,
+    }
+}
+
+fn audio_codec(codec: AudioCodec) -> Option<Codec> {
+    match codec {
+        AudioCodec::Aac => Some(Codec::Aac),
+        AudioCodec::Mp3 => Some(Codec::Mp3),
+        AudioCodec::Opus => Some(Codec::Opus),
+        AudioCodec::Flac => Some(Codec::Flac),
+        AudioCodec::Ac3 => Some(Codec::Ac3),
+        AudioCodec::Eac3 => Some(Codec::Eac3),
+        AudioCodec::G711ALaw => Some(Codec::G711ALaw),
+        AudioCodec::G711MuLaw => Some(Codec::G711MuLaw),
+        _ => None,
+    }
+}
+
+// The tracks described by a publisher's sequence headers, plus what we know about audio codecs
+// that don't send one
+pub fn tracks(video_header: Option<&MediaMessage>, audio_header: Option<&MediaMessage>, info: &StreamInfo) -> Vec<TrackInfo> {
+    let mut tracks = Vec::new();
+
+    let video = video_header.and_then(|message| message.video.as_ref());
+    if let (Some(video), Some(properties)) = (video, &info.video) {
+        let config = match &video.packet {
+            VideoPacket::SequenceHeader(DecoderConfiguration::Avc(record)) => record.serialize().ok(),
+            VideoPacket::SequenceHeader(DecoderConfiguration::Hevc(record)) => record.serialize().ok(),
+            VideoPacket::SequenceHeader(DecoderConfiguration::Av1(record)) => Some(record.clone()),
+            VideoPacket::SequenceHeader(DecoderConfiguration::Vp9(record)) => Some(record.clone()),
+            _ => None,
+        };
+        if let (Some(codec), Some(config)) = (video_codec(video.codec), config) {
+            let mut track = TrackInfo::new(VIDEO_TRACK, codec);
+            track.config = Bytes::from(config);
+            track.width = Some(properties.width);
+            track.height = Some(properties.height);
+            track.frame_rate = info.frame_rate();
+            track.projection = info.projection();
+            track.codecs = Some(properties.codecs.clone());
+            track.fmtp = Some(properties.fmtp.clone());
+            tracks.push(track);
+        }
+    }
+
+    if let Some(properties) = &info.audio {
+        let config = match audio_header.and_then(|message| message.audio.as_ref()).map(|audio| &audio.packet) {
+            Some(AudioPacket::SequenceHeader(AudioConfiguration::Aac(config))) => config.data.clone(),
+            Some(AudioPacket::SequenceHeader(AudioConfiguration::Opus(config))) => config.clone(),
+            Some(AudioPacket::SequenceHeader(AudioConfiguration::Flac(config))) => config.clone(),
+            _ => Vec::new(),
+        };
+        if let Some(codec) = audio_codec(properties.codec) {
+            let mut track = TrackInfo::new(AUDIO_TRACK, codec);
+            track.config = Bytes::from(config);
+            track.sample_rate = Some(properties.sample_rate);
+            track.channels = Some(properties.channels);
+            track.codecs = properties.codecs.clone();
+            tracks.push(track);
+        }
+    }
+
+    tracks
+}
+
+// None for sequence headers, metadata and codecs media-core doesn't know about
+pub fn to_frame(message: &MediaMessage) -> Option<MediaFrame> {
+    let dts = message.timestamp as i64;
+
+    if let Some(video) = &message.video {
+        let payload = match &video.packet {
+            VideoPacket::Nalus(_) => video.to_annex_b()?,
+            VideoPacket::Frame(frame) => frame.clone(),
+            _ => return None,
+        };
+        return Some(MediaFrame {
+            track: VIDEO_TRACK,
+            codec: video_codec(video.codec)?,
+            pts: dts + video.composition_time as i64,
+            dts,
+            keyframe: video.is_keyframe(),
+            payload: Bytes::from(payload),
+        });
+    }
+
+    if let Some(audio) = &message.audio {
+        let payload = match &audio.packet {
+            AudioPacket::Frame(frame) => frame.clone(),
+            _ => return None,
+        };
+        return Some(MediaFrame {
+            track: AUDIO_TRACK,
+            codec: audio_codec(audio.codec)?,
+            pts: dts,
+            dts,
+            keyframe: true,
+            payload: Bytes::from(payload),
+        });
+    }
+
+    None
+}
+
+// The first byte of an enhanced RTMP video tag plus its FourCC
+fn enhanced_video_header(codec: Codec, keyframe: bool, packet_type: u8) -> Vec<u8> {
+    let fourcc: &[u8; 4] = match codec {
+        Codec::H265 => b"hvc1",
+        Codec::Av1 => b"av01",
+        _ => b"vp09",
+    };
+    let frame_type = if keyframe { 1 } else { 2 };
+    let mut tag = vec![0x80 | (frame_type << 4) | packet_type];
+    tag.extend_from_slice(fourcc);
+    tag
+}
+
+// The first byte of an enhanced RTMP audio tag plus its FourCC
+fn enhanced_audio_header(codec: Codec, packet_type: u8) -> Vec<u8> {
+    let fourcc: &[u8; 4] = match codec {
+        Codec::Opus => b"Opus",
+        Codec::Flac => b"fLaC",
+        Codec::Ac3 => b"ac-3",
+        _ => b"ec-3",
+    };
+    let mut tag = vec![0x90 | packet_type];
+    tag.extend_from_slice(fourcc);
+    tag
+}
+
+// The FLV tag carrying a track's decoder configuration, None if the codec doesn't have one
+pub fn sequence_header_tag(track: &TrackInfo) -> Option<Vec<u8>> {
+    if track.config.is_empty() {
+        return None;
+    }
+
+    let mut tag = match track.codec {
+        Codec::H264 => vec![0x17, 0, 0, 0, 0],
+        Codec::H265 | Codec::Av1 | Codec::Vp9 => enhanced_video_header(track.codec, true, 0),
+        Codec::Aac => vec![0xAF, 0],
+        Codec::Opus | Codec::Flac => enhanced_audio_header(track.codec, 0),
+        _ => return None,
+    };
+    tag.extend_from_slice(&track.config);
+
+    // frame_tag always writes 4 byte NALU lengths, whatever the original record said
+    let length_size_position = match track.codec {
+        Codec::H264 => Some(tag.len() - track.config.len() + 4),
+        Codec::H265 => Some(tag.len() - track.config.len() + 21),
+        _ => None,
+    };
+    if let Some(position) = length_size_position {
+        if position < tag.len() {
+            tag[position] |= 0b11;
+        }
+    }
+    Some(tag)
+}
+
+// The FLV tag carrying a frame
+pub fn frame_tag(frame: &MediaFrame) -> Vec<u8> {
+    let frame_type = if frame.keyframe { 1 } else { 2 };
+    let composition_time = &(frame.composition_time() as i32).to_be_bytes()[1..4];
+
+    // Back from Annex B to NAL units with 4 byte length prefixes
+    let payload = if frame.codec.uses_nalus() {
+        let mut payload = Vec::with_capacity(frame.payload.len());
+        for nalu in split_annex_b(&frame.payload) {
+            payload.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+            payload.extend_from_slice(nalu);
+        }
+        payload
+    } else {
+        frame.payload.to_vec()
+    };
+
+    let mut tag = match frame.codec {
+        Codec::H264 => {
+            let mut tag = vec![(frame_type << 4) | 7, 1];
+            tag.extend_from_slice(composition_time);
+            tag
+        }
+        Codec::H265 => {
+            let mut tag = enhanced_video_header(frame.codec, frame.keyframe, 1);
+            tag.extend_from_slice(composition_time);
+            tag
+        }
+        // CodedFrames without a composition time offset
+        Codec::Av1 | Codec::Vp9 => enhanced_video_header(frame.codec, frame.keyframe, 1),
+        Codec::Aac => vec![0xAF, 1],
+        // 44kHz 16 bit stereo, the frame headers say what it really is
+        Codec::Mp3 => vec![0x2F],
+        // G.711 is 8kHz mono, which the flags can't express anyway
+        Codec::G711ALaw => vec![0x72],
+        Codec::G711MuLaw => vec![0x82],
+        Codec::Opus | Codec::Flac | Codec::Ac3 | Codec::Eac3 => enhanced_audio_header(frame.codec, 1),
+    };
+    tag.extend_from_slice(&payload);
+    tag
+}
+
+// An onMetaData message for RTMP players of a stream that wasn't published over RTMP
+pub fn metadata(tracks: &[TrackInfo]) -> Vec<u8> {
+    let mut metadata = Metadata::default();
+    for track in tracks {
+        match track.kind() {
+            TrackKind::Video => {
+                if let (Some(width), Some(height)) = (track.width, track.height) {
+                    metadata.set("width", Value::Number(width as f64));
+                    metadata.set("height", Value::Number(height as f64));
+                }
+                if let Some(frame_rate) = track.frame_rate {
+                    metadata.set("framerate", Value::Number(frame_rate));
+                }
+                if track.codec == Codec::H264 {
+                    metadata.set("videocodecid", Value::Number(7.0));
+                }
+                if let Some(projection) = &track.projection {
+                    metadata.set_projection(projection);
+                }
+            }
+            TrackKind::Audio => {
+                if let Some(sample_rate) = track.sample_rate {
+                    metadata.set("audiosamplerate", Value::Number(sample_rate as f64));
+                }
+                if let Some(channels) = track.channels {
+                    metadata.set("stereo", Value::Boolean(channels > 1));
+                }
+                if track.codec == Codec::Aac {
+                    metadata.set("audiocodecid", Value::Number(10.0));
+                }
+            }
+        }
+    }
+    metadata.serialize()
+}
+
+// Feeds a frame subscriber from the stream's RTMP subscription until either side goes away
+pub(crate) async fn forward_frames(registry: &StreamRegistry, name: &str, mut events: broadcast::Receiver<StreamEvent>, frames: FrameSender) {
+    // Decoding has to start on a keyframe, which is also where we pick up after falling behind
+    let mut waiting_for_keyframe = true;
+
+    if let Some(tracks) = registry.stream_tracks(name) {
+        if !tracks.is_empty() && frames.send(FrameEvent::Tracks(tracks)).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = frames.closed() => return,
+        };
+
+        match event {
+            Ok(StreamEvent::Media(message)) => {
+                // Metadata can change the projection of the video track
+                if message.is_sequence_header() || message.message_type_id == 18 {
+                    if let Some(tracks) = registry.stream_tracks(name) {
+                        if frames.send(FrameEvent::Tracks(tracks)).await.is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+
+                let frame = match to_frame(&message) {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if frame.codec.kind() == TrackKind::Video && waiting_for_keyframe {
+                    if !frame.keyframe {
+                        continue;
+                    }
+                    waiting_for_keyframe = false;
+                }
+
+                match frames.try_send(FrameEvent::Frame(frame)) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => {
+                        println!("Frame subscriber of {} is falling behind, skipping to the next keyframe", name);
+                        waiting_for_keyframe = true;
+                    }
+                    Err(TrySendError::Closed(_)) => return,
+                }
+            }
+            Ok(StreamEvent::Ended) => {
+                let _ = frames.send(FrameEvent::Ended).await;
+                return;
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => waiting_for_keyframe = true,
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+// Publishes frames to the stream as though they came from an RTMP publisher, until the sender
+// is dropped, the frames end or another publisher takes over
+pub(crate) async fn receive_frames(registry: &StreamRegistry, name: &str, publisher_id: u64, mut frames: FrameReceiver, kicked: oneshot::Receiver<()>) {
+    let mut kicked = Some(kicked);
+    let mut parsers = TagParsers::new();
+    let mut last_dts = 0u32;
+
+    loop {
+        let event = tokio::select! {
+            event = frames.recv() => event,
+            _ = wait_for_kick(&mut kicked) => return,
+        };
+
+        match event {
+            Some(FrameEvent::Tracks(tracks)) => {
+                registry.send(name, publisher_id, MediaMessage {
+                    message_type_id: 18,
+                    timestamp: last_dts,
+                    data: Arc::new(metadata(&tracks)),
+                    video: None,
+                    audio: None,
+                });
+
+                for track in &tracks {
+                    if let Some(tag) = sequence_header_tag(track) {
+                        registry.send(name, publisher_id, parsers.message(name, track.kind(), last_dts, tag));
+                    }
+                }
+            }
+            Some(FrameEvent::Frame(frame)) => {
+                last_dts = frame.dts as u32;
+                registry.send(name, publisher_id, parsers.message(name, frame.codec.kind(), last_dts, frame_tag(&frame)));
+            }
+            Some(FrameEvent::Ended) | None => return,
+        }
+    }
+}
+
+// How every ingest path parses what gets published. It keeps state: AVC frames depend on the NALU
+// length size and AAC frames on the sequence header that came before them.
+pub(crate) struct TagParsers {
+    video: VideoTagParser,
+    audio: AudioTagParser,
+}
+
+impl TagParsers {
+    pub(crate) fn new() -> Self {
+        Self {
+            video: VideoTagParser::new(),
+            audio: AudioTagParser::new(),
+        }
+    }
+
+    // A message that doesn't parse still gets published as it is, just without the parsed tag
+    pub(crate) fn parse(&mut self, name: &str, message_type_id: u8, timestamp: u32, data: Vec<u8>) -> MediaMessage {
+        let video = if message_type_id == 9 {
+            match self.video.parse(&data) {
+                Ok(video) => Some(Arc::new(video)),
+                Err(err) => {
+                    eprintln!("Error parsing video message from {}: {}", name, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let audio = if message_type_id == 8 {
+            match self.audio.parse(&data) {
+                Ok(audio) => Some(Arc::new(audio)),
+                Err(err) => {
+                    eprintln!("Error parsing audio message from {}: {}", name, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        MediaMessage {
+            message_type_id,
+            timestamp,
+            data: Arc::new(data),
+            video,
+            audio,
+        }
+    }
+
+    // Tags we made ourselves still get parsed, everything downstream of the registry relies on it
+    fn message(&mut self, name: &str, kind: TrackKind, timestamp: u32, tag: Vec<u8>) -> MediaMessage {
+        let message_type_id = match kind {
+            TrackKind::Video => 9,
+            TrackKind::Audio => 8,
+        };
+        self.parse(name, message_type_id, timestamp, tag)
+    }
+}
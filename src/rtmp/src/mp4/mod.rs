@@ -0,0 +1,434 @@
+use media_core::{Codec, MediaFrame, Projection, ProjectionKind, StereoMode, TrackInfo, TrackKind};
+use crate::Serializable;
+use crate::flv::audio::AudioSpecificConfig;
+use crate::flv::video::{AvcDecoderConfigurationRecord, HevcDecoderConfigurationRecord, split_annex_b};
+
+// Fragmented MP4 (ISO/IEC 14496-12): an init segment with the sample entries of every track,
+// then fragments of one moof and one mdat each. CMAF wants a single track in each, so HLS and
+// DASH packaging use a muxer per track. Video is H.264 or H.265 with 4 byte NALU lengths, audio
+// is AAC.
+
+const VIDEO_TIMESCALE: u32 = 90000;
+
+// Sample flags: sync samples don't depend on anything, the rest depend on other samples
+const SYNC_SAMPLE: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE: u32 = 0x0101_0000;
+
+pub(crate) struct Sample {
+    pub track: u32,
+    // Milliseconds on the stream's timeline
+    pub dts: i64,
+    pub pts: i64,
+    pub duration: i64,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+struct Mp4Track {
+    info: TrackInfo,
+    timescale: u32,
+    // The avcC, hvcC or AudioSpecificConfig as it goes into the sample entry
+    config: Vec<u8>,
+}
+
+impl Mp4Track {
+    fn to_timescale(&self, milliseconds: i64) -> i64 {
+        milliseconds * self.timescale as i64 / 1000
+    }
+}
+
+pub(crate) struct Fmp4Muxer {
+    tracks: Vec<Mp4Track>,
+    sequence: u32,
+}
+
+impl Fmp4Muxer {
+    // Tracks that can't go in fragmented MP4 are left out
+    pub(crate) fn new(name: &str, tracks: &[TrackInfo]) -> Self {
+        let mut muxer = Fmp4Muxer {
+            tracks: Vec::new(),
+            sequence: 0,
+        };
+
+        for track in tracks {
+            if muxer.tracks.iter().any(|other| other.info.kind() == track.kind()) {
+                continue;
+            }
+            match mp4_track(track) {
+                Ok(track) => muxer.tracks.push(track),
+                Err(err) => println!("fMP4 of {} leaves out track {}: {}", name, track.id, err),
+            }
+        }
+        muxer
+    }
+
+    pub(crate) fn has_video(&self) -> bool {
+        self.tracks.iter().any(|track| track.info.kind() == TrackKind::Video)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub(crate) fn carries(&self, frame: &MediaFrame) -> bool {
+        self.tracks.iter().any(|track| track.info.id == frame.track)
+    }
+
+    // The tracks that made it in, for manifests to describe
+    pub(crate) fn tracks(&self) -> Vec<TrackInfo> {
+        self.tracks.iter().map(|track| track.info.clone()).collect()
+    }
+
+    // NAL units get 4 byte lengths rather than start codes, everything else goes as it is
+    pub(crate) fn sample(frame: &MediaFrame, duration: i64) -> Sample {
+        let data = if frame.codec.uses_nalus() {
+            let mut data = Vec::with_capacity(frame.payload.len());
+            for nalu in split_annex_b(&frame.payload) {
+                data.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+                data.extend_from_slice(nalu);
+            }
+            data
+        } else {
+            frame.payload.to_vec()
+        };
+
+        Sample {
+            track: frame.track,
+            dts: frame.dts,
+            pts: frame.pts,
+            duration,
+            keyframe: frame.keyframe,
+            data,
+        }
+    }
+
+    pub(crate) fn init_segment(&self) -> Vec<u8> {
+        let mut ftyp = b"iso6".to_vec();
+        ftyp.extend_from_slice(&0u32.to_be_bytes());
+        for brand in [b"iso6", b"cmfc", b"mp41"] {
+            ftyp.extend_from_slice(brand);
+        }
+
+        let next_track_id = self.tracks.iter().map(|track| track.info.id).max().unwrap_or(0) + 1;
+        let mut moov = mvhd(next_track_id);
+        for track in &self.tracks {
+            moov.extend_from_slice(&trak(track));
+        }
+        let mut mvex = Vec::new();
+        for track in &self.tracks {
+            let mut trex = full_box_header(0, 0);
+            trex.extend_from_slice(&track.info.id.to_be_bytes());
+            // Default sample description index, duration, size and flags
+            trex.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            mvex.extend_from_slice(&mp4_box(b"trex", &trex));
+        }
+        moov.extend_from_slice(&mp4_box(b"mvex", &mvex));
+
+        let mut init = mp4_box(b"ftyp", &ftyp);
+        init.extend_from_slice(&mp4_box(b"moov", &moov));
+        init
+    }
+
+    // One moof and mdat with the samples of every track, in decode order within each track.
+    // Tracks without samples still get a traf starting at start, in milliseconds, so fragments
+    // of a track line up with those of the others.
+    pub(crate) fn fragment(&mut self, samples: &[Sample], start: i64) -> Vec<u8> {
+        self.sequence += 1;
+        let tracks: Vec<(&Mp4Track, Vec<&Sample>)> = self.tracks.iter()
+            .map(|track| (track, samples.iter().filter(|sample| sample.track == track.info.id).collect::<Vec<_>>()))
+            .collect();
+
+        // The data offsets in the truns depend on the size of the moof they're in, which doesn't
+        // depend on the offsets
+        let moof_size = self.moof(&tracks, start, 0).len();
+        let moof = self.moof(&tracks, start, moof_size);
+
+        let mut mdat = Vec::new();
+        for (_, samples) in &tracks {
+            for sample in samples {
+                mdat.extend_from_slice(&sample.data);
+            }
+        }
+
+        let mut fragment = moof;
+        fragment.extend_from_slice(&mp4_box(b"mdat", &mdat));
+        fragment
+    }
+
+    fn moof(&self, tracks: &[(&Mp4Track, Vec<&Sample>)], start: i64, moof_size: usize) -> Vec<u8> {
+        let mut mfhd = full_box_header(0, 0);
+        mfhd.extend_from_slice(&self.sequence.to_be_bytes());
+        let mut moof = mp4_box(b"mfhd", &mfhd);
+
+        // Sample data starts after the mdat header
+        let mut data_offset = moof_size + 8;
+        for (track, samples) in tracks {
+            // Default base is moof, so data offsets count from the start of the moof
+            let mut tfhd = full_box_header(0, 0x02_0000);
+            tfhd.extend_from_slice(&track.info.id.to_be_bytes());
+
+            let mut tfdt = full_box_header(1, 0);
+            let base_dts = samples.first().map_or(start, |sample| sample.dts);
+            tfdt.extend_from_slice(&(track.to_timescale(base_dts) as u64).to_be_bytes());
+
+            // Data offset, then duration, size, flags and composition time offset for every sample.
+            // Version 1 makes the offsets signed.
+            let video = track.info.kind() == TrackKind::Video;
+            let flags = if video { 0x000F01 } else { 0x000701 };
+            let mut trun = full_box_header(1, flags);
+            trun.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&(data_offset as u32).to_be_bytes());
+            for sample in samples {
+                let dts = track.to_timescale(sample.dts);
+                let duration = track.to_timescale(sample.dts + sample.duration) - dts;
+                trun.extend_from_slice(&(duration.max(0) as u32).to_be_bytes());
+                trun.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                let sample_flags = if sample.keyframe || !video { SYNC_SAMPLE } else { NON_SYNC_SAMPLE };
+                trun.extend_from_slice(&sample_flags.to_be_bytes());
+                if video {
+                    let offset = track.to_timescale(sample.pts) - dts;
+                    trun.extend_from_slice(&(offset as i32).to_be_bytes());
+                }
+                data_offset += sample.data.len();
+            }
+
+            let mut traf = mp4_box(b"tfhd", &tfhd);
+            traf.extend_from_slice(&mp4_box(b"tfdt", &tfdt));
+            traf.extend_from_slice(&mp4_box(b"trun", &trun));
+            moof.extend_from_slice(&mp4_box(b"traf", &traf));
+        }
+        mp4_box(b"moof", &moof)
+    }
+}
+
+fn mp4_track(track: &TrackInfo) -> Result<Mp4Track, &'static str> {
+    // Samples get 4 byte NALU lengths whatever the publisher used, so the records have to say so
+    let (timescale, config) = match track.codec {
+        Codec::H264 => {
+            let mut record = AvcDecoderConfigurationRecord::deserialize(&mut &track.config[..])?;
+            record.nalu_length_size = 4;
+            (VIDEO_TIMESCALE, record.serialize()?)
+        }
+        Codec::H265 => {
+            let mut record = HevcDecoderConfigurationRecord::deserialize(&mut &track.config[..])?;
+            record.nalu_length_size = 4;
+            (VIDEO_TIMESCALE, record.serialize()?)
+        }
+        Codec::Aac => (AudioSpecificConfig::parse(&track.config)?.sample_rate, track.config.to_vec()),
+        _ => Err("Codec isn't supported in fMP4")?,
+    };
+
+    Ok(Mp4Track {
+        info: track.clone(),
+        timescale,
+        config,
+    })
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + body.len());
+    buf.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn full_box_header(version: u8, flags: u32) -> Vec<u8> {
+    let mut buf = vec![version];
+    buf.extend_from_slice(&flags.to_be_bytes()[1..]);
+    buf
+}
+
+// The unity matrix, for mvhd and tkhd
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn mvhd(next_track_id: u32) -> Vec<u8> {
+    let mut mvhd = full_box_header(0, 0);
+    // Creation and modification time, timescale and an unknown duration
+    mvhd.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+    mvhd.extend_from_slice(&1000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0u32.to_be_bytes());
+    // Rate 1.0, volume 1.0, reserved
+    mvhd.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+    mvhd.extend_from_slice(&[0; 10]);
+    for value in MATRIX {
+        mvhd.extend_from_slice(&value.to_be_bytes());
+    }
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&next_track_id.to_be_bytes());
+    mp4_box(b"mvhd", &mvhd)
+}
+
+fn trak(track: &Mp4Track) -> Vec<u8> {
+    let video = track.info.kind() == TrackKind::Video;
+
+    // Enabled and in the movie
+    let mut tkhd = full_box_header(0, 3);
+    tkhd.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+    tkhd.extend_from_slice(&track.info.id.to_be_bytes());
+    // Reserved, duration, reserved, layer and alternate group
+    tkhd.extend_from_slice(&[0; 4 + 4 + 8 + 4]);
+    tkhd.extend_from_slice(if video { &[0, 0] } else { &[1, 0] });
+    tkhd.extend_from_slice(&[0, 0]);
+    for value in MATRIX {
+        tkhd.extend_from_slice(&value.to_be_bytes());
+    }
+    tkhd.extend_from_slice(&(track.info.width.unwrap_or(0) << 16).to_be_bytes());
+    tkhd.extend_from_slice(&(track.info.height.unwrap_or(0) << 16).to_be_bytes());
+
+    let mut mdhd = full_box_header(0, 0);
+    mdhd.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+    mdhd.extend_from_slice(&track.timescale.to_be_bytes());
+    mdhd.extend_from_slice(&0u32.to_be_bytes());
+    // Language und, packed into 15 bits
+    mdhd.extend_from_slice(&[0x55, 0xC4, 0, 0]);
+
+    let (handler, handler_name): (&[u8; 4], &[u8]) = if video { (b"vide", b"VideoHandler\0") } else { (b"soun", b"SoundHandler\0") };
+    let mut hdlr = full_box_header(0, 0);
+    hdlr.extend_from_slice(&[0, 0, 0, 0]);
+    hdlr.extend_from_slice(handler);
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(handler_name);
+
+    let media_header = if video {
+        let mut vmhd = full_box_header(0, 1);
+        vmhd.extend_from_slice(&[0; 8]);
+        mp4_box(b"vmhd", &vmhd)
+    } else {
+        let mut smhd = full_box_header(0, 0);
+        smhd.extend_from_slice(&[0; 4]);
+        mp4_box(b"smhd", &smhd)
+    };
+
+    // A single self contained data reference
+    let mut dref = full_box_header(0, 0);
+    dref.extend_from_slice(&1u32.to_be_bytes());
+    dref.extend_from_slice(&mp4_box(b"url ", &full_box_header(0, 1)));
+    let dinf = mp4_box(b"dinf", &mp4_box(b"dref", &dref));
+
+    // The sample tables are all empty, the samples are in the fragments
+    let mut stsd = full_box_header(0, 0);
+    stsd.extend_from_slice(&1u32.to_be_bytes());
+    stsd.extend_from_slice(&sample_entry(track));
+    let mut stbl = mp4_box(b"stsd", &stsd);
+    for kind in [b"stts", b"stsc", b"stco"] {
+        let mut table = full_box_header(0, 0);
+        table.extend_from_slice(&0u32.to_be_bytes());
+        stbl.extend_from_slice(&mp4_box(kind, &table));
+    }
+    let mut stsz = full_box_header(0, 0);
+    stsz.extend_from_slice(&[0; 8]);
+    stbl.extend_from_slice(&mp4_box(b"stsz", &stsz));
+
+    let mut minf = media_header;
+    minf.extend_from_slice(&dinf);
+    minf.extend_from_slice(&mp4_box(b"stbl", &stbl));
+
+    let mut mdia = mp4_box(b"mdhd", &mdhd);
+    mdia.extend_from_slice(&mp4_box(b"hdlr", &hdlr));
+    mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+    let mut trak = mp4_box(b"tkhd", &tkhd);
+    trak.extend_from_slice(&mp4_box(b"mdia", &mdia));
+    mp4_box(b"trak", &trak)
+}
+
+fn sample_entry(track: &Mp4Track) -> Vec<u8> {
+    // Reserved, then data reference index 1
+    let mut entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+
+    if track.info.kind() == TrackKind::Video {
+        entry.extend_from_slice(&[0; 16]);
+        entry.extend_from_slice(&(track.info.width.unwrap_or(0) as u16).to_be_bytes());
+        entry.extend_from_slice(&(track.info.height.unwrap_or(0) as u16).to_be_bytes());
+        // 72 dpi both ways, reserved, one frame per sample, no compressor name, 24 bit depth
+        entry.extend_from_slice(&[0, 0x48, 0, 0, 0, 0x48, 0, 0, 0, 0, 0, 0, 0, 1]);
+        entry.extend_from_slice(&[0; 32]);
+        entry.extend_from_slice(&[0, 0x18, 0xFF, 0xFF]);
+
+        let (kind, config_kind) = match track.info.codec {
+            Codec::H265 => (b"hvc1", b"hvcC"),
+            _ => (b"avc1", b"avcC"),
+        };
+        entry.extend_from_slice(&mp4_box(config_kind, &track.config));
+        if let Some(projection) = &track.info.projection {
+            entry.extend_from_slice(&spherical(projection));
+        }
+        return mp4_box(kind, &entry);
+    }
+
+    entry.extend_from_slice(&[0; 8]);
+    entry.extend_from_slice(&(track.info.channels.unwrap_or(2) as u16).to_be_bytes());
+    // 16 bit samples, then pre-defined and reserved
+    entry.extend_from_slice(&[0, 16, 0, 0, 0, 0]);
+    // 16.16 fixed point, which only fits rates below 65536
+    entry.extend_from_slice(&((track.timescale.min(0xFFFF)) << 16).to_be_bytes());
+    entry.extend_from_slice(&mp4_box(b"esds", &esds(&track.config)));
+    mp4_box(b"mp4a", &entry)
+}
+
+// ISO/IEC 14496-1 descriptors around the AudioSpecificConfig
+fn esds(config: &[u8]) -> Vec<u8> {
+    let descriptor = |tag: u8, body: &[u8]| {
+        let mut buf = vec![tag, body.len() as u8];
+        buf.extend_from_slice(body);
+        buf
+    };
+
+    // MPEG-4 audio, an audio stream, no buffer size or bitrates
+    let mut decoder_config = vec![0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    decoder_config.extend_from_slice(&descriptor(0x05, config));
+
+    // ES id 0 and no flags
+    let mut es = vec![0, 0, 0];
+    es.extend_from_slice(&descriptor(0x04, &decoder_config));
+    es.extend_from_slice(&descriptor(0x06, &[0x02]));
+
+    let mut esds = full_box_header(0, 0);
+    esds.extend_from_slice(&descriptor(0x03, &es));
+    esds
+}
+
+// Spherical Video V2: st3d for the stereo layout and sv3d for the projection, which is what
+// VR players look at in the sample entry
+fn spherical(projection: &Projection) -> Vec<u8> {
+    let mut st3d = full_box_header(0, 0);
+    st3d.push(match projection.stereo {
+        StereoMode::Mono => 0,
+        StereoMode::TopBottom => 1,
+        StereoMode::LeftRight => 2,
+    });
+
+    let mut svhd = full_box_header(0, 0);
+    svhd.extend_from_slice(b"rtmp\0");
+
+    // No yaw, pitch or roll
+    let mut prhd = full_box_header(0, 0);
+    prhd.extend_from_slice(&[0; 12]);
+    let mut proj = mp4_box(b"prhd", &prhd);
+    match projection.kind {
+        ProjectionKind::Equirectangular | ProjectionKind::HalfEquirectangular => {
+            // Top, bottom, left and right bounds as 0.32 fixed point fractions of the frame.
+            // VR180 is the middle half of the sphere.
+            let side = if projection.kind == ProjectionKind::HalfEquirectangular { 0x4000_0000u32 } else { 0 };
+            let mut equi = full_box_header(0, 0);
+            for bound in [0, 0, side, side] {
+                equi.extend_from_slice(&bound.to_be_bytes());
+            }
+            proj.extend_from_slice(&mp4_box(b"equi", &equi));
+        }
+        ProjectionKind::Cubemap => {
+            // The 3x2 layout, no padding
+            let mut cbmp = full_box_header(0, 0);
+            cbmp.extend_from_slice(&[0; 8]);
+            proj.extend_from_slice(&mp4_box(b"cbmp", &cbmp));
+        }
+    }
+
+    let mut sv3d = mp4_box(b"svhd", &svhd);
+    sv3d.extend_from_slice(&mp4_box(b"proj", &proj));
+
+    let mut boxes = mp4_box(b"st3d", &st3d);
+    boxes.extend_from_slice(&mp4_box(b"sv3d", &sv3d));
+    boxes
+}
@@ -0,0 +1,437 @@
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use amf::amf0::Value;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, oneshot};
+use crate::Serializable;
+use crate::config::RecordConfig;
+use crate::flv::file::{FlvHeader, FlvReader, FlvTag, FLV_BODY_OFFSET};
+use crate::flv::metadata::{KeyframeIndex, Metadata};
+use crate::hooks::{ClientInfo, Hooks};
+use crate::registry::{MediaMessage, StreamEvent, StreamRegistry};
+
+// Writes a stream to an FLV file as it's published. The recorder is just another subscriber of
+// the stream, so it sees the same timeline players do.
+//
+// The file starts with an empty onMetaData which gets replaced once the recording is done, by
+// the latest metadata the publisher sent plus the duration, file size and keyframe index. Until
+// then the file is still playable, just not seekable.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordMode {
+    // Start the file over
+    Record,
+    // Carry on at the end of the file, if there is one
+    Append,
+}
+
+// What a publisher wants its stream recorded as, once it's the active publisher
+#[derive(Clone)]
+pub struct RecordRequest {
+    pub path: PathBuf,
+    pub mode: RecordMode,
+    pub hooks: Arc<Hooks>,
+    pub client: ClientInfo,
+}
+
+// The stream gets recorded for as long as this is kept around. The registry keeps the one
+// recording of each stream, for its active publisher.
+pub struct Recording {
+    path: PathBuf,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Recording {
+    // Takes a receiver subscribed already, so nothing published from here on is missed
+    pub(crate) fn start(registry: Arc<StreamRegistry>, request: RecordRequest, name: &str, events: broadcast::Receiver<StreamEvent>) -> Self {
+        let (stop, stopped) = oneshot::channel();
+        let path = request.path.clone();
+        let name = name.to_string();
+
+        tokio::spawn(async move {
+            if record(&registry, &name, &request.path, request.mode, events, stopped).await {
+                request.hooks.on_record_done(&request.client, &name, &request.path.to_string_lossy());
+            }
+        });
+
+        Recording { path, _stop: stop }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn recording_path(config: &RecordConfig, name: &str, live: bool) -> PathBuf {
+    let mut file_name = file_name(name);
+    if live {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        file_name = format!("{}-{}", file_name, started);
+    }
+    Path::new(&config.path).join(format!("{}.flv", file_name))
+}
+
+// Stream names come from clients, they don't get to pick where in the file system we go
+pub(crate) fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+// Returns whether the file got finished properly
+async fn record(
+    registry: &StreamRegistry,
+    name: &str,
+    path: &Path,
+    mode: RecordMode,
+    mut events: broadcast::Receiver<StreamEvent>,
+    mut stopped: oneshot::Receiver<()>,
+) -> bool {
+    // A recording of the same file that was stopped may still be finishing it
+    let file = tokio::select! {
+        file = registry.lock_recording_file(path) => file,
+        _ = &mut stopped => {
+            drop(events);
+            registry.unsubscribe(name);
+            return false;
+        }
+    };
+    let finished = record_file(name, path, mode, &mut events, &mut stopped).await;
+    registry.unlock_recording_file(path, file);
+
+    drop(events);
+    registry.unsubscribe(name);
+    finished
+}
+
+async fn record_file(
+    name: &str,
+    path: &Path,
+    mode: RecordMode,
+    events: &mut broadcast::Receiver<StreamEvent>,
+    stopped: &mut oneshot::Receiver<()>,
+) -> bool {
+    let mut writer = match FlvWriter::open(path, mode).await {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Error recording {} to {}: {}", name, path.display(), err);
+            return false;
+        }
+    };
+    println!("Recording {} to {}", name, path.display());
+
+    let mut result = Ok(());
+    while result.is_ok() {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(StreamEvent::Media(message)) => result = writer.write(message).await,
+                Ok(StreamEvent::Ended) | Err(broadcast::error::RecvError::Closed) => break,
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    println!("Recording of {} fell behind, dropped {} messages", name, skipped);
+                    writer.waiting_for_keyframe = true;
+                }
+            },
+            _ = &mut *stopped => {
+                // Whatever the publisher sent before it stopped still goes in the file
+                loop {
+                    match events.try_recv() {
+                        Ok(StreamEvent::Media(message)) => result = result.and(writer.write(message).await),
+                        Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                        Err(_) => break,
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    if let Err(err) = result {
+        eprintln!("Error writing recording of {} to {}: {}", name, path.display(), err);
+    }
+    match writer.finish().await {
+        Ok(()) => {
+            println!("Finished recording {} to {}", name, path.display());
+            true
+        }
+        Err(err) => {
+            eprintln!("Error finishing recording of {} to {}: {}", name, path.display(), err);
+            false
+        }
+    }
+}
+
+struct FlvWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    // Where the next tag goes
+    position: u64,
+    // The onMetaData tag at the start of the file that gets replaced at the end, zero if the
+    // file we appended to didn't have one
+    metadata_size: u64,
+    metadata: Metadata,
+    keyframes: KeyframeIndex,
+    audio: bool,
+    video: bool,
+    // Where this recording starts in the file, past whatever was there already when appending
+    base_timestamp: u32,
+    // The stream timestamp the recording started at
+    first_timestamp: Option<u32>,
+    last_timestamp: u32,
+    // Frames before the first keyframe can't be decoded
+    waiting_for_keyframe: bool,
+}
+
+impl FlvWriter {
+    async fn open(path: &Path, mode: RecordMode) -> Result<Self, &'static str> {
+        if let Some(dir) = path.parent() {
+            if tokio::fs::create_dir_all(dir).await.is_err() {
+                Err("Error creating recording directory")?
+            }
+        }
+
+        let existing_size = tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or(0);
+        if mode == RecordMode::Append && existing_size > 0 {
+            return Self::append(path).await;
+        }
+
+        let file = match File::create(path).await {
+            Ok(file) => file,
+            Err(_) => Err("Error creating recording file")?,
+        };
+        let mut writer = FlvWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            position: FLV_BODY_OFFSET,
+            metadata_size: 0,
+            metadata: Metadata::default(),
+            keyframes: KeyframeIndex::default(),
+            audio: false,
+            video: false,
+            base_timestamp: 0,
+            first_timestamp: None,
+            last_timestamp: 0,
+            waiting_for_keyframe: true,
+        };
+
+        let header = FlvHeader { audio: true, video: true }.serialize()?;
+        writer.write_bytes(&header).await?;
+        let metadata = FlvTag { tag_type: 18, timestamp: 0, data: Metadata::default().serialize() };
+        writer.metadata_size = metadata.size();
+        writer.write_bytes(&metadata.to_bytes()).await?;
+        writer.position = FLV_BODY_OFFSET + writer.metadata_size;
+        Ok(writer)
+    }
+
+    async fn append(path: &Path) -> Result<Self, &'static str> {
+        let scan_path = path.to_path_buf();
+        let existing = match tokio::task::spawn_blocking(move || scan(&scan_path)).await {
+            Ok(existing) => existing?,
+            Err(_) => Err("Error reading existing recording")?,
+        };
+
+        let mut file = match OpenOptions::new().write(true).open(path).await {
+            Ok(file) => file,
+            Err(_) => Err("Error opening recording file")?,
+        };
+        // A tag that got cut off the last time goes, the new ones start where it did
+        if file.set_len(existing.end).await.is_err() || file.seek(SeekFrom::Start(existing.end)).await.is_err() {
+            Err("Error opening recording file")?
+        }
+
+        println!("Appending to {} at {}ms", path.display(), existing.last_timestamp);
+        Ok(FlvWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            position: existing.end,
+            metadata_size: existing.metadata_size,
+            metadata: existing.metadata,
+            keyframes: existing.keyframes,
+            audio: existing.audio,
+            video: existing.video,
+            base_timestamp: existing.last_timestamp,
+            first_timestamp: None,
+            last_timestamp: existing.last_timestamp,
+            waiting_for_keyframe: true,
+        })
+    }
+
+    async fn write(&mut self, message: MediaMessage) -> Result<(), &'static str> {
+        // Metadata goes in the onMetaData at the start of the file once we're done
+        if message.message_type_id == 18 {
+            if let Ok(metadata) = Metadata::parse(&message.data) {
+                for (key, value) in metadata.properties {
+                    self.metadata.set(&key, value);
+                }
+                return Ok(());
+            }
+        }
+
+        let is_frame = matches!(message.message_type_id, 8 | 9) && !message.is_sequence_header();
+        if message.is_video() && is_frame {
+            if self.waiting_for_keyframe && !message.is_keyframe() {
+                return Ok(());
+            }
+            self.waiting_for_keyframe = false;
+        }
+        if is_frame && self.first_timestamp.is_none() {
+            self.first_timestamp = Some(message.timestamp);
+        }
+
+        // Headers and data messages before the first frame go at the start
+        let offset = self.first_timestamp.map_or(0, |first| message.timestamp.saturating_sub(first));
+        let timestamp = self.base_timestamp.wrapping_add(offset);
+
+        match message.message_type_id {
+            8 => self.audio = true,
+            9 => self.video = true,
+            _ => {}
+        }
+        if message.is_video() && is_frame && message.is_keyframe() {
+            self.keyframes.push(timestamp as f64 / 1000.0, self.position);
+        }
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+
+        let tag = FlvTag {
+            tag_type: message.message_type_id,
+            timestamp,
+            data: message.data.to_vec(),
+        };
+        self.write_bytes(&tag.to_bytes()).await?;
+        self.position += tag.size();
+        Ok(())
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        match self.file.write_all(bytes).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err("Error writing recording file"),
+        }
+    }
+
+    // Puts the final onMetaData at the start of the file. It's a different size than the one
+    // that's there, so everything after it has to move.
+    async fn finish(mut self) -> Result<(), &'static str> {
+        if self.file.flush().await.is_err() {
+            Err("Error writing recording file")?
+        }
+        drop(self.file);
+
+        let mut metadata = self.metadata;
+        let duration = self.last_timestamp as f64 / 1000.0;
+        metadata.set("duration", Value::Number(duration));
+        metadata.set("lasttimestamp", Value::Number(duration));
+        metadata.set("lastkeyframetimestamp", Value::Number(self.keyframes.times.last().copied().unwrap_or(0.0)));
+        metadata.set("hasVideo", Value::Boolean(self.video));
+        metadata.set("hasAudio", Value::Boolean(self.audio));
+        metadata.set("hasKeyframes", Value::Boolean(!self.keyframes.times.is_empty()));
+        metadata.set("hasMetadata", Value::Boolean(true));
+
+        // Numbers always take up the same space in AMF, so the size doesn't depend on the values
+        metadata.set("filesize", Value::Number(0.0));
+        metadata.set_keyframes(&self.keyframes);
+        let metadata_size = FlvTag { tag_type: 18, timestamp: 0, data: metadata.serialize() }.size();
+
+        let shift = metadata_size as i64 - self.metadata_size as i64;
+        let mut keyframes = self.keyframes;
+        for position in keyframes.positions.iter_mut() {
+            *position = (*position as i64 + shift) as u64;
+        }
+        metadata.set("filesize", Value::Number((self.position as i64 + shift) as f64));
+        metadata.set_keyframes(&keyframes);
+
+        let header = FlvHeader { audio: self.audio, video: self.video };
+        let tag = FlvTag { tag_type: 18, timestamp: 0, data: metadata.serialize() };
+        let old_metadata_size = self.metadata_size;
+        let path = self.path;
+        match tokio::task::spawn_blocking(move || rewrite(&path, header, &tag, old_metadata_size)).await {
+            Ok(result) => result,
+            Err(_) => Err("Error rewriting recording file"),
+        }
+    }
+}
+
+// What's in a file we're about to append to
+struct ExistingFile {
+    // Where the last complete tag ends
+    end: u64,
+    metadata: Metadata,
+    metadata_size: u64,
+    keyframes: KeyframeIndex,
+    audio: bool,
+    video: bool,
+    last_timestamp: u32,
+}
+
+fn scan(path: &Path) -> Result<ExistingFile, &'static str> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => Err("Error opening existing recording")?,
+    };
+    let mut reader = FlvReader::new(BufReader::new(file))?;
+
+    let mut existing = ExistingFile {
+        end: reader.position(),
+        metadata: Metadata::default(),
+        metadata_size: 0,
+        keyframes: KeyframeIndex::default(),
+        audio: false,
+        video: false,
+        last_timestamp: 0,
+    };
+    while let Some(tag) = reader.next_tag()? {
+        if existing.end == FLV_BODY_OFFSET && tag.tag_type == 18 {
+            if let Ok(metadata) = Metadata::parse(&tag.data) {
+                existing.metadata = metadata;
+                existing.metadata_size = tag.size();
+            }
+        }
+        match tag.tag_type {
+            8 => existing.audio = true,
+            9 => existing.video = true,
+            _ => {}
+        }
+        if tag.is_keyframe() {
+            existing.keyframes.push(tag.timestamp as f64 / 1000.0, existing.end);
+        }
+        existing.last_timestamp = existing.last_timestamp.max(tag.timestamp);
+        existing.end = reader.position();
+    }
+    Ok(existing)
+}
+
+// Writes the new header and onMetaData followed by the rest of the file to a temporary file,
+// which then replaces the recording
+fn rewrite(path: &Path, header: FlvHeader, metadata: &FlvTag, old_metadata_size: u64) -> Result<(), &'static str> {
+    let mut source = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => Err("Error opening recording file")?,
+    };
+    if source.seek(SeekFrom::Start(FLV_BODY_OFFSET + old_metadata_size)).is_err() {
+        Err("Error reading recording file")?
+    }
+
+    let temp_path = path.with_extension("flv.tmp");
+    let mut temp = match std::fs::File::create(&temp_path) {
+        Ok(file) => std::io::BufWriter::new(file),
+        Err(_) => Err("Error creating temporary recording file")?,
+    };
+
+    let written = temp.write_all(&header.serialize()?)
+        .and_then(|_| temp.write_all(&metadata.to_bytes()))
+        .and_then(|_| std::io::copy(&mut source, &mut temp))
+        .and_then(|_| temp.flush());
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+        Err("Error writing temporary recording file")?
+    }
+
+    drop(temp);
+    if std::fs::rename(&temp_path, path).is_err() {
+        Err("Error replacing recording file")?
+    }
+    Ok(())
+}
@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, OwnedMutexGuard};
+use media_core::{FRAME_QUEUE_SIZE, FrameReceiver, FrameSender, MediaRegistry, Projection, TrackInfo};
+use tokio::sync::mpsc;
+use crate::codec::{AudioProperties, Timecode, VideoProperties};
+use crate::codec::sei::{self, SeiMessage};
+use crate::media;
+use crate::recorder::{RecordRequest, Recording};
+use crate::config::{DuplicatePublisherPolicy, PublishConfig, StreamGroupConfig};
+use crate::group::StreamGroups;
+use crate::flv::audio::AudioTag;
+use crate::flv::metadata::Metadata;
+use crate::flv::video::{VideoCodec, VideoPacket, VideoTag};
+use crate::server::NEXT_CLIENT_ID;
+use crate::timestamp::TimestampNormalizer;
+
+// How many messages a subscriber can fall behind before it starts missing them
+const SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+// Most messages we keep for the GOP cache, about 10 seconds of 30fps video with its audio
+const GOP_CACHE_SIZE: usize = 1024;
+// How many stream announcements can queue up for a slow listener
+const ANNOUNCEMENT_QUEUE_SIZE: usize = 64;
+
+// An audio, video or data message as it was published, ready to be forwarded to subscribers
+// The raw message is what gets forwarded over RTMP, the parsed tag is for everything else.
+#[derive(Debug, Clone)]
+pub struct MediaMessage {
+    pub message_type_id: u8,
+    pub timestamp: u32,
+    pub data: Arc<Vec<u8>>,
+    // Only set for video messages we managed to parse
+    pub video: Option<Arc<VideoTag>>,
+    // Likewise for audio messages
+    pub audio: Option<Arc<AudioTag>>,
+}
+
+impl MediaMessage {
+    pub fn is_video(&self) -> bool {
+        self.message_type_id == 9
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        match &self.video {
+            Some(video) => video.is_keyframe(),
+            // The frame type sits below the enhanced RTMP IsExHeader bit
+            None => self.is_video() && !self.data.is_empty() && (self.data[0] >> 4) & 0b0111 == 1,
+        }
+    }
+
+    // Decoder configuration for either track: a legacy AVC or AAC sequence header or an enhanced SequenceStart
+    pub fn is_sequence_header(&self) -> bool {
+        if let Some(video) = &self.video {
+            return video.is_sequence_header();
+        }
+        if let Some(audio) = &self.audio {
+            return audio.is_sequence_header();
+        }
+        if self.data.len() < 2 {
+            return false;
+        }
+        match self.message_type_id {
+            8 if self.data[0] >> 4 == 9 => self.data[0] & 0x0F == 0,
+            8 => self.data[0] >> 4 == 10 && self.data[1] == 0,
+            9 if self.data[0] & 0x80 != 0 => self.data[0] & 0x0F == 0,
+            9 => self.data[0] & 0x0F == 7 && self.data[1] == 0,
+            _ => false,
+        }
+    }
+}
+
+// What players do when they fall behind: drop video until the next keyframe, so that whatever
+// they get next decodes
+#[derive(Debug, Default)]
+pub(crate) struct KeyframeGate {
+    waiting: bool,
+}
+
+impl KeyframeGate {
+    pub(crate) fn lagged(&mut self) {
+        self.waiting = true;
+    }
+
+    pub(crate) fn admit(&mut self, message: &MediaMessage) -> bool {
+        if self.waiting && message.is_video() && !message.is_sequence_header() {
+            if !message.is_keyframe() {
+                return false;
+            }
+            self.waiting = false;
+        }
+        true
+    }
+}
+
+// What subscribers of a stream get sent
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Media(MediaMessage),
+    // A publisher went live on the stream
+    Published,
+    // The publisher left, it has the grace period to come back before the stream ends
+    Unpublished,
+    // Nobody came back in time, the stream is gone
+    Ended,
+}
+
+// Everything a subscriber needs before it can start decoding mid-stream
+#[derive(Debug, Clone, Default)]
+struct SequenceHeaders {
+    metadata: Option<MediaMessage>,
+    video: Option<MediaMessage>,
+    audio: Option<MediaMessage>,
+}
+
+impl SequenceHeaders {
+    fn update(&mut self, message: &MediaMessage) {
+        match message.message_type_id {
+            18 => self.metadata = Some(message.clone()),
+            8 if message.is_sequence_header() => self.audio = Some(message.clone()),
+            9 if message.is_sequence_header() => self.video = Some(message.clone()),
+            _ => {}
+        }
+    }
+
+    fn to_vec(&self) -> Vec<MediaMessage> {
+        [&self.metadata, &self.video, &self.audio].into_iter().flatten().cloned().collect()
+    }
+}
+
+// The frames since the last keyframe, so players that can take a burst start with a picture right
+// away rather than on the next keyframe. Long GOPs don't get cached at all.
+#[derive(Debug, Default)]
+struct GopCache {
+    messages: Vec<MediaMessage>,
+    overflowed: bool,
+}
+
+impl GopCache {
+    fn update(&mut self, message: &MediaMessage) {
+        if !matches!(message.message_type_id, 8 | 9) || message.is_sequence_header() {
+            return;
+        }
+        if message.is_keyframe() {
+            self.messages.clear();
+            self.overflowed = false;
+        } else if self.messages.is_empty() || self.overflowed {
+            return;
+        }
+
+        if self.messages.len() >= GOP_CACHE_SIZE {
+            self.messages.clear();
+            self.overflowed = true;
+            return;
+        }
+        self.messages.push(message.clone());
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.overflowed = false;
+    }
+}
+
+// What we know about a stream's tracks. The parameter sets win over whatever onMetaData claims.
+#[derive(Debug, Clone, Default)]
+pub struct StreamInfo {
+    pub video: Option<VideoProperties>,
+    pub audio: Option<AudioProperties>,
+    pub metadata: Option<Metadata>,
+    // From the SEI messages on keyframes
+    pub sei_projection: Option<Projection>,
+    // The latest timecode from either onMetaData or the SEI messages
+    pub timecode: Option<TimecodeAnchor>,
+}
+
+// Ties the stream's timeline to the time of day its frames were shot at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimecodeAnchor {
+    pub time_of_day_ms: i64,
+    // Where on the stream's timeline that was
+    pub timestamp: u32,
+}
+
+impl TimecodeAnchor {
+    // The time of day at timestamp 0
+    pub fn origin(&self) -> i64 {
+        self.time_of_day_ms - self.timestamp as i64
+    }
+}
+
+impl StreamInfo {
+    pub fn width(&self) -> Option<u32> {
+        match &self.video {
+            Some(video) => Some(video.width),
+            None => self.metadata.as_ref().and_then(|metadata| metadata.number("width")).map(|width| width as u32),
+        }
+    }
+
+    pub fn height(&self) -> Option<u32> {
+        match &self.video {
+            Some(video) => Some(video.height),
+            None => self.metadata.as_ref().and_then(|metadata| metadata.number("height")).map(|height| height as u32),
+        }
+    }
+
+    pub fn frame_rate(&self) -> Option<f64> {
+        match self.video.as_ref().and_then(|video| video.frame_rate) {
+            Some(frame_rate) => Some(frame_rate),
+            None => self.metadata.as_ref().and_then(|metadata| metadata.number("framerate")),
+        }
+    }
+
+    // onMetaData can tell 180° from 360° video, the SEI messages can't
+    pub fn projection(&self) -> Option<Projection> {
+        self.metadata.as_ref().and_then(|metadata| metadata.projection()).or(self.sei_projection)
+    }
+
+    // Returns true when the SEI messages revealed a projection onMetaData doesn't mention
+    fn update(&mut self, name: &str, message: &MediaMessage) -> bool {
+        match message.message_type_id {
+            18 => match Metadata::parse(&message.data) {
+                Ok(metadata) => {
+                    if let Some(timecode) = metadata.timecode() {
+                        self.set_timecode(timecode, message.timestamp);
+                    }
+                    self.metadata = Some(metadata);
+                    self.check_metadata(name);
+                }
+                Err(err) => eprintln!("Error parsing metadata on stream {}: {}", name, err),
+            },
+            8 => {
+                if let Some(audio) = &message.audio {
+                    let properties = AudioProperties::from_tag(audio);
+                    if self.audio.as_ref() != Some(&properties) && (audio.is_sequence_header() || self.audio.is_none()) {
+                        println!("Stream {} audio: {:?} {}Hz, {} channels", name, properties.codec, properties.sample_rate, properties.channels);
+                        self.audio = Some(properties);
+                    }
+                }
+            }
+            9 => {
+                let config = match message.video.as_ref().map(|video| &video.packet) {
+                    Some(VideoPacket::SequenceHeader(config)) => config,
+                    Some(_) => return self.update_sei(name, message.timestamp, message.video.as_ref().unwrap()),
+                    None => return false,
+                };
+                match VideoProperties::from_config(config) {
+                    Ok(Some(properties)) => {
+                        println!(
+                            "Stream {} video: {:?} {} {:.1}, {}x{}, {} fps, {} {} bit",
+                            name, properties.codec, properties.profile, properties.level, properties.width, properties.height,
+                            properties.frame_rate.map_or("unknown".to_string(), |frame_rate| format!("{:.2}", frame_rate)),
+                            properties.chroma_format(), properties.bit_depth,
+                        );
+                        self.video = Some(properties);
+                        self.check_metadata(name);
+                    }
+                    Ok(None) => self.video = None,
+                    Err(err) => eprintln!("Error parsing video parameter sets on stream {}: {}", name, err),
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn set_timecode(&mut self, timecode: Timecode, timestamp: u32) {
+        self.timecode = Some(TimecodeAnchor {
+            time_of_day_ms: timecode.to_millis(self.frame_rate().unwrap_or(30.0)),
+            timestamp,
+        });
+    }
+
+    fn update_sei(&mut self, name: &str, timestamp: u32, video: &VideoTag) -> bool {
+        let nalus = match &video.packet {
+            VideoPacket::Nalus(nalus) if video.is_keyframe() => nalus,
+            _ => return false,
+        };
+
+        let hevc = video.codec == VideoCodec::Hevc;
+        let messages: Vec<SeiMessage> = nalus.iter()
+            .filter(|nalu| match (hevc, nalu.first()) {
+                (false, Some(header)) => header & 0x1F == 6,
+                (true, Some(header)) => (header >> 1) & 0x3F == 39,
+                _ => false,
+            })
+            .flat_map(|nalu| sei::parse_sei(nalu, hevc))
+            .collect();
+
+        for message in &messages {
+            if let SeiMessage::TimeCode(timecode) = message {
+                self.set_timecode(*timecode, timestamp);
+            }
+        }
+
+        let projection = match sei::projection(&messages) {
+            Some(projection) => projection,
+            None => return false,
+        };
+        if self.sei_projection == Some(projection) {
+            return false;
+        }
+
+        println!("Stream {} is {} {} video according to its SEI", name, projection.projection_name(), projection.stereo_mode_name());
+        self.sei_projection = Some(projection);
+        self.metadata.as_ref().is_none_or(|metadata| metadata.projection().is_none())
+    }
+
+    // Encoders get onMetaData wrong often enough that it's worth knowing about
+    fn check_metadata(&self, name: &str) {
+        let (video, metadata) = match (&self.video, &self.metadata) {
+            (Some(video), Some(metadata)) => (video, metadata),
+            _ => return,
+        };
+        if let (Some(width), Some(height)) = (metadata.number("width"), metadata.number("height")) {
+            if width as u32 != video.width || height as u32 != video.height {
+                println!("Stream {}: onMetaData says {}x{} but the SPS says {}x{}, going with the SPS", name, width, height, video.width, video.height);
+            }
+        }
+    }
+}
+
+struct Publisher {
+    id: u64,
+    // Fired when another publisher takes the stream over
+    kick: oneshot::Sender<()>,
+    headers: SequenceHeaders,
+    // What it wants the stream recorded as while it's the active publisher
+    record: Option<RecordRequest>,
+}
+
+struct StreamEntry {
+    sender: broadcast::Sender<StreamEvent>,
+    // The first publisher is the one being broadcast, the rest are on standby
+    publishers: Vec<Publisher>,
+    // Bumped whenever the stream loses its last publisher, so a grace timer can tell whether
+    // the stream has been republished (and maybe dropped again) since it was started
+    generation: u64,
+    // Every publisher starts its own timeline. Subscribers see a single one.
+    normalizer: TimestampNormalizer,
+    gop: GopCache,
+    name: String,
+    info: StreamInfo,
+    // The one recording of the stream, the active publisher's
+    recording: Option<Recording>,
+}
+
+impl StreamEntry {
+    fn new(name: &str, timestamp_tolerance_ms: u32) -> Self {
+        Self {
+            sender: broadcast::channel(SUBSCRIBER_QUEUE_SIZE).0,
+            publishers: Vec::new(),
+            generation: 0,
+            normalizer: TimestampNormalizer::new(name, timestamp_tolerance_ms),
+            gop: GopCache::default(),
+            name: name.to_string(),
+            info: StreamInfo::default(),
+            recording: None,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.publishers.is_empty() && self.sender.receiver_count() == 0
+    }
+
+    // Called whenever a different publisher becomes the active one
+    fn switch_publisher(&mut self, registry: &Arc<StreamRegistry>) {
+        self.normalizer.restart();
+        // The new publisher's frames don't follow on from the old one's
+        self.gop.clear();
+        self.update_recording(registry);
+    }
+
+    // Records the stream the way the active publisher asked for, if at all. A recording of the
+    // same file just keeps going, whoever publishes.
+    fn update_recording(&mut self, registry: &Arc<StreamRegistry>) {
+        let request = self.publishers.first().and_then(|publisher| publisher.record.clone());
+        if self.recording.as_ref().map(Recording::path) == request.as_ref().map(|request| request.path.as_path()) {
+            return;
+        }
+        self.recording = request.map(|request| Recording::start(registry.clone(), request, &self.name, self.sender.subscribe()));
+    }
+
+    // Sends a message from the active publisher out to every subscriber. Returns the timestamp
+    // it went out with.
+    fn broadcast(&mut self, mut message: MediaMessage) -> u32 {
+        message.timestamp = self.normalizer.normalize(message.message_type_id, message.timestamp);
+        let timestamp = message.timestamp;
+        if let Some(publisher) = self.publishers.first_mut() {
+            publisher.headers.update(&message);
+        }
+        // Players need to hear about it through onMetaData, including the ones that join later
+        if self.info.update(&self.name, &message) {
+            let mut metadata = self.info.metadata.clone().unwrap_or_default();
+            metadata.set_projection(&self.info.sei_projection.unwrap());
+            let metadata = MediaMessage {
+                message_type_id: 18,
+                timestamp: message.timestamp,
+                data: Arc::new(metadata.serialize()),
+                video: None,
+                audio: None,
+            };
+            if let Some(publisher) = self.publishers.first_mut() {
+                publisher.headers.update(&metadata);
+            }
+            self.info.update(&self.name, &metadata);
+            let _ = self.sender.send(StreamEvent::Media(metadata));
+        }
+        self.gop.update(&message);
+        let _ = self.sender.send(StreamEvent::Media(message));
+        timestamp
+    }
+}
+
+pub struct StreamRegistry {
+    policy: DuplicatePublisherPolicy,
+    reconnect_grace: Duration,
+    timestamp_tolerance_ms: u32,
+    streams: Mutex<HashMap<String, StreamEntry>>,
+    pub(crate) groups: StreamGroups,
+    // Held by whichever recording is writing the file, so the next one waits until it's done
+    recording_files: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    // The name of every stream that goes from having no publisher to having one
+    published: broadcast::Sender<String>,
+}
+
+impl StreamRegistry {
+    pub fn new(config: PublishConfig, groups: Vec<StreamGroupConfig>) -> Self {
+        Self {
+            policy: config.duplicate_policy,
+            reconnect_grace: Duration::from_millis(config.reconnect_grace_ms),
+            timestamp_tolerance_ms: config.timestamp_tolerance_ms,
+            streams: Mutex::new(HashMap::new()),
+            groups: StreamGroups::new(groups),
+            recording_files: Mutex::new(HashMap::new()),
+            published: broadcast::channel(ANNOUNCEMENT_QUEUE_SIZE).0,
+        }
+    }
+
+    // The stream's timeline ends with it, so it's also gone from its groups
+    fn remove_stream(&self, streams: &mut HashMap<String, StreamEntry>, name: &str) {
+        streams.remove(name);
+        self.groups.reset(name);
+    }
+
+    // Registers a publisher for the stream, which gets it recorded as asked once it's the active
+    // publisher. The returned receiver fires if the publisher gets kicked.
+    pub fn publish(self: &Arc<Self>, name: &str, publisher_id: u64, record: Option<RecordRequest>) -> Result<oneshot::Receiver<()>, &'static str> {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = streams.entry(name.to_string()).or_insert_with(|| StreamEntry::new(name, self.timestamp_tolerance_ms));
+
+        let (kick, kicked) = oneshot::channel();
+        let publisher = Publisher {
+            id: publisher_id,
+            kick,
+            headers: SequenceHeaders::default(),
+            record,
+        };
+
+        if entry.publishers.is_empty() {
+            entry.publishers.push(publisher);
+            entry.switch_publisher(self);
+            let _ = entry.sender.send(StreamEvent::Published);
+            let _ = self.published.send(name.to_string());
+            return Ok(kicked);
+        }
+
+        match self.policy {
+            DuplicatePublisherPolicy::Reject => Err("Stream is already being published"),
+            DuplicatePublisherPolicy::Takeover => {
+                println!("Publisher {} is taking over stream {}", publisher_id, name);
+                let previous = entry.publishers.remove(0);
+                let _ = previous.kick.send(());
+                entry.publishers.insert(0, publisher);
+                entry.switch_publisher(self);
+                Ok(kicked)
+            }
+            DuplicatePublisherPolicy::KeepFirst => {
+                println!("Publisher {} is on standby for stream {}", publisher_id, name);
+                entry.publishers.push(publisher);
+                Ok(kicked)
+            }
+        }
+    }
+
+    pub fn unpublish(self: &Arc<Self>, name: &str, publisher_id: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = match streams.get_mut(name) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let position = match entry.publishers.iter().position(|p| p.id == publisher_id) {
+            Some(position) => position,
+            None => return,
+        };
+        entry.publishers.remove(position);
+
+        if position != 0 {
+            return;
+        }
+
+        // If the active publisher left, the next one on standby goes live. Subscribers need its
+        // sequence headers before any of its frames will decode.
+        if let Some(next) = entry.publishers.first() {
+            println!("Publisher {} is now live on stream {}", next.id, name);
+            let headers = next.headers.to_vec();
+            entry.switch_publisher(self);
+            for message in headers {
+                entry.broadcast(message);
+            }
+            return;
+        }
+
+        entry.update_recording(self);
+        if entry.sender.receiver_count() == 0 {
+            self.remove_stream(&mut streams, name);
+            return;
+        }
+
+        // Keep the subscribers around for a while in case the publisher reconnects
+        entry.generation += 1;
+        let _ = entry.sender.send(StreamEvent::Unpublished);
+        if self.reconnect_grace.is_zero() {
+            let _ = entry.sender.send(StreamEvent::Ended);
+            self.remove_stream(&mut streams, name);
+            return;
+        }
+
+        let registry = self.clone();
+        let name = name.to_string();
+        let generation = entry.generation;
+        tokio::spawn(async move {
+            tokio::time::sleep(registry.reconnect_grace).await;
+            registry.end_stream(&name, generation);
+        });
+    }
+
+    fn end_stream(&self, name: &str, generation: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = match streams.get(name) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if entry.generation != generation || !entry.publishers.is_empty() {
+            return;
+        }
+
+        println!("Publisher of {} didn't come back, ending the stream", name);
+        let _ = entry.sender.send(StreamEvent::Ended);
+        self.remove_stream(&mut streams, name);
+    }
+
+    pub fn send(&self, name: &str, publisher_id: u64, message: MediaMessage) {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = match streams.get_mut(name) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let position = match entry.publishers.iter().position(|p| p.id == publisher_id) {
+            Some(position) => position,
+            None => return,
+        };
+        // Standby publishers only keep their headers up to date
+        if position == 0 {
+            let is_media = matches!(message.message_type_id, 8 | 9);
+            let timestamp = entry.broadcast(message);
+            if is_media {
+                self.groups.ingest(name, timestamp, entry.info.timecode);
+            }
+        } else {
+            entry.publishers[position].headers.update(&message);
+        }
+    }
+
+    // Subscribing to a stream that isn't published yet is fine, frames start flowing once it is.
+    // Returns the receiver along with the headers the subscriber has to be sent first.
+    pub fn subscribe(&self, name: &str) -> (broadcast::Receiver<StreamEvent>, Vec<MediaMessage>) {
+        let mut streams = self.streams.lock().unwrap();
+        let entry = streams.entry(name.to_string()).or_insert_with(|| StreamEntry::new(name, self.timestamp_tolerance_ms));
+
+        let headers = match entry.publishers.first() {
+            Some(publisher) => publisher.headers.to_vec(),
+            None => Vec::new(),
+        };
+
+        (entry.sender.subscribe(), headers)
+    }
+
+    // Like subscribe, but the headers are followed by the frames since the last keyframe. None
+    // while nobody is publishing the stream.
+    pub fn subscribe_with_gop(&self, name: &str) -> Option<(broadcast::Receiver<StreamEvent>, Vec<MediaMessage>)> {
+        let streams = self.streams.lock().unwrap();
+        let entry = streams.get(name)?;
+        let publisher = entry.publishers.first()?;
+
+        let mut messages = publisher.headers.to_vec();
+        messages.extend(entry.gop.messages.iter().cloned());
+        Some((entry.sender.subscribe(), messages))
+    }
+
+    // For outputs that package every stream, e.g. HLS. A stream the publisher comes back to
+    // within the reconnect grace period gets announced again.
+    pub fn subscribe_published(&self) -> broadcast::Receiver<String> {
+        self.published.subscribe()
+    }
+
+    pub fn stream_info(&self, name: &str) -> Option<StreamInfo> {
+        self.streams.lock().unwrap().get(name).map(|entry| entry.info.clone())
+    }
+
+    // None while nobody is publishing the stream
+    pub fn stream_tracks(&self, name: &str) -> Option<Vec<TrackInfo>> {
+        let streams = self.streams.lock().unwrap();
+        let entry = streams.get(name)?;
+        let publisher = entry.publishers.first()?;
+        Some(media::tracks(publisher.headers.video.as_ref(), publisher.headers.audio.as_ref(), &entry.info))
+    }
+
+    // Waits for any other recording of the file to be done with it
+    pub(crate) async fn lock_recording_file(&self, path: &Path) -> OwnedMutexGuard<()> {
+        let file = self.recording_files.lock().unwrap().entry(path.to_path_buf()).or_default().clone();
+        file.lock_owned().await
+    }
+
+    pub(crate) fn unlock_recording_file(&self, path: &Path, guard: OwnedMutexGuard<()>) {
+        let mut files = self.recording_files.lock().unwrap();
+        drop(guard);
+        // Unless another recording is waiting for it
+        if files.get(path).is_some_and(|file| Arc::strong_count(file) == 1) {
+            files.remove(path);
+        }
+    }
+
+    // Must be called after the subscriber's receiver has been dropped
+    pub fn unsubscribe(&self, name: &str) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.get(name).is_some_and(|entry| entry.is_idle()) {
+            self.remove_stream(&mut streams, name);
+        }
+    }
+}
+
+// Lets any other protocol play streams published over RTMP, and publish streams RTMP players can play
+impl MediaRegistry for StreamRegistry {
+    fn tracks(&self, name: &str) -> Option<Vec<TrackInfo>> {
+        self.stream_tracks(name)
+    }
+
+    fn subscribe_frames(self: Arc<Self>, name: &str) -> FrameReceiver {
+        let (events, _) = self.subscribe(name);
+        let (sender, receiver) = mpsc::channel(FRAME_QUEUE_SIZE);
+
+        let name = name.to_string();
+        tokio::spawn(async move {
+            media::forward_frames(&self, &name, events, sender).await;
+            self.unsubscribe(&name);
+        });
+        receiver
+    }
+
+    fn publish_frames(self: Arc<Self>, name: &str) -> Result<FrameSender, &'static str> {
+        let publisher_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let kicked = self.publish(name, publisher_id, None)?;
+        let (sender, receiver) = mpsc::channel(FRAME_QUEUE_SIZE);
+
+        let name = name.to_string();
+        tokio::spawn(async move {
+            media::receive_frames(&self, &name, publisher_id, receiver, kicked).await;
+            self.unpublish(&name, publisher_id);
+        });
+        Ok(sender)
+    }
+}
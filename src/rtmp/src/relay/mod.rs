@@ -0,0 +1,52 @@
+use std::time::Duration;
+use crate::client::RtmpUrl;
+use crate::config::RelayConfig;
+
+mod pull;
+mod push;
+
+pub use pull::PullRelays;
+pub(crate) use pull::PullPlayer;
+pub use push::{PushRelays, PushState, PushStatus};
+
+// Relays move streams between this server and others over RTMP, through the client. Pushes send
+// streams published here on to other servers, pulls get streams played here from origins.
+
+// How long a relay waits before it tries again: the minimum, doubling with every failure in a row
+// up to the maximum
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: &RelayConfig) -> Self {
+        let min = Duration::from_millis(config.reconnect_min_ms);
+        Self {
+            min,
+            max: Duration::from_millis(config.reconnect_max_ms).max(min),
+            next: min,
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    // After a connection that worked out
+    pub(crate) fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+// The URL with the stream name added when it doesn't have one
+pub(crate) fn stream_url(url: &str, name: &str) -> Result<String, &'static str> {
+    let parsed = RtmpUrl::parse(url)?;
+    match parsed.stream.is_empty() {
+        true => Ok(format!("{}/{}", url.trim_end_matches('/'), name)),
+        false => Ok(url.to_string()),
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::Instant;
+use crate::client::{ClientEvent, RtmpClient};
+use crate::config::RelayConfig;
+use crate::registry::StreamRegistry;
+use crate::relay::{stream_url, Backoff};
+use crate::server::{wait_for_kick, NEXT_CLIENT_ID};
+
+// Edge mode: a player asking for a stream nobody publishes here gets it pulled from an origin,
+// trying the configured ones in order. The pulled stream is published in the registry like any
+// other, so every player here shares the one pull, and HLS and RTSP get it too. RTMP, HTTP-FLV
+// and WebSocket-FLV players keep it going, once the last of them left for the idle timeout the
+// pull stops.
+
+// How often a pull checks whether its players are all gone
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long HTTP players wait for a pull to get going, they can't wait around like RTMP players
+const PULL_WAIT: Duration = Duration::from_secs(5);
+
+// Somebody published the stream here after all, or took it over
+const TAKEN_OVER: &str = "Stream is published here by somebody else";
+
+struct Pull {
+    // Which pull of the stream it is, so players of one that ended leave the next one alone
+    run: u64,
+    players: usize,
+    // When the last player left
+    idle_since: Instant,
+}
+
+pub struct PullRelays {
+    config: RelayConfig,
+    registry: Arc<StreamRegistry>,
+    pulls: Mutex<HashMap<String, Pull>>,
+    next_run: AtomicU64,
+    // Woken whenever a pull is done, for players waiting on one that didn't get anywhere
+    finished: Notify,
+}
+
+// Keeps the pull going for as long as the player it was requested for is around
+pub(crate) struct PullPlayer {
+    pulls: Arc<PullRelays>,
+    name: String,
+    run: u64,
+}
+
+impl Drop for PullPlayer {
+    fn drop(&mut self) {
+        self.pulls.left(&self.name, self.run);
+    }
+}
+
+impl PullRelays {
+    pub(crate) fn new(config: RelayConfig, registry: Arc<StreamRegistry>) -> Self {
+        Self {
+            config,
+            registry,
+            pulls: Mutex::new(HashMap::new()),
+            next_run: AtomicU64::new(1),
+            finished: Notify::new(),
+        }
+    }
+
+    // Pulls the stream for a player, unless it's being pulled already. None when there are no
+    // origins, or when the stream is published here.
+    pub(crate) fn request(self: &Arc<Self>, name: &str) -> Option<PullPlayer> {
+        if self.config.origins.is_empty() {
+            return None;
+        }
+
+        let mut pulls = self.pulls.lock().unwrap();
+        let run = match pulls.get_mut(name) {
+            Some(pull) => {
+                pull.players += 1;
+                pull.run
+            }
+            None => {
+                if self.registry.stream_tracks(name).is_some() {
+                    return None;
+                }
+                let run = self.next_run.fetch_add(1, Ordering::Relaxed);
+                pulls.insert(name.to_string(), Pull {
+                    run,
+                    players: 1,
+                    idle_since: Instant::now(),
+                });
+
+                let relays = self.clone();
+                let name = name.to_string();
+                tokio::spawn(async move {
+                    relays.run(&name, run).await;
+                });
+                run
+            }
+        };
+
+        Some(PullPlayer {
+            pulls: self.clone(),
+            name: name.to_string(),
+            run,
+        })
+    }
+
+    // Waits a while for a stream that's being pulled to go live
+    pub(crate) async fn wait(&self, name: &str) {
+        let mut published = self.registry.subscribe_published();
+        let deadline = Instant::now() + PULL_WAIT;
+        loop {
+            // Registered before checking, so a pull finishing in between isn't missed
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            if self.registry.stream_tracks(name).is_some() || !self.pulls.lock().unwrap().contains_key(name) {
+                return;
+            }
+            tokio::select! {
+                result = published.recv() => {
+                    if let Err(RecvError::Closed) = result {
+                        return;
+                    }
+                }
+                _ = finished => {}
+                _ = tokio::time::sleep_until(deadline) => return,
+            }
+        }
+    }
+
+    fn left(&self, name: &str, run: u64) {
+        let mut pulls = self.pulls.lock().unwrap();
+        if let Some(pull) = pulls.get_mut(name).filter(|pull| pull.run == run) {
+            pull.players -= 1;
+            if pull.players == 0 {
+                pull.idle_since = Instant::now();
+            }
+        }
+    }
+
+    // Done with the pull, checked under the lock so no player joins a pull that's going away
+    fn finish(&self, name: &str, run: u64, only_if_idle: bool) -> bool {
+        let mut pulls = self.pulls.lock().unwrap();
+        let pull = match pulls.get(name).filter(|pull| pull.run == run) {
+            Some(pull) => pull,
+            None => return true,
+        };
+        let timeout = Duration::from_millis(self.config.pull_idle_timeout_ms);
+        if only_if_idle && (pull.players > 0 || pull.idle_since.elapsed() < timeout) {
+            return false;
+        }
+        pulls.remove(name);
+        self.finished.notify_waiters();
+        true
+    }
+
+    async fn wait_idle(&self, name: &str, run: u64) {
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            if self.finish(name, run, true) {
+                return;
+            }
+        }
+    }
+
+    // Goes through the origins until one has the stream. Once it was live, a lost connection
+    // is retried with a backoff for as long as there are players.
+    async fn run(&self, name: &str, run: u64) {
+        let publisher_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        // Set while the stream is published here
+        let mut kicked = None;
+        let mut live = false;
+        let mut backoff = Backoff::new(&self.config);
+
+        'pull: loop {
+            for origin in &self.config.origins {
+                let url = match stream_url(origin, name) {
+                    Ok(url) => url,
+                    Err(err) => {
+                        eprintln!("Not pulling {} from {}: {}", name, origin, err);
+                        continue;
+                    }
+                };
+
+                let result = tokio::select! {
+                    result = self.pull(name, &url, publisher_id, &mut kicked, &mut live, &mut backoff) => Some(result),
+                    _ = self.wait_idle(name, run) => None,
+                };
+                match result {
+                    Some(Ok(())) => {
+                        println!("{} ended on {}", name, url);
+                        break 'pull;
+                    }
+                    Some(Err(TAKEN_OVER)) => {
+                        println!("Stopped pulling {}, it's published here", name);
+                        break 'pull;
+                    }
+                    Some(Err(err)) => eprintln!("Error pulling {} from {}: {}", name, url, err),
+                    None => {
+                        println!("Nobody is playing {} anymore, stopped pulling it", name);
+                        break 'pull;
+                    }
+                }
+            }
+
+            if !live {
+                eprintln!("No origin has {}", name);
+                break;
+            }
+            let delay = backoff.next();
+            eprintln!("Pulling {} again in {}ms", name, delay.as_millis());
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.wait_idle(name, run) => break,
+            }
+        }
+
+        self.finish(name, run, false);
+        if kicked.is_some() {
+            self.registry.unpublish(name, publisher_id);
+        }
+    }
+
+    // Plays the stream from the origin, publishing it here once media comes in. Returns once the
+    // origin ended it.
+    async fn pull(
+        &self,
+        name: &str,
+        url: &str,
+        publisher_id: u64,
+        kicked: &mut Option<oneshot::Receiver<()>>,
+        live: &mut bool,
+        backoff: &mut Backoff,
+    ) -> Result<(), &'static str> {
+        let mut client = RtmpClient::connect(url).await?;
+        client.play().await?;
+        println!("Pulling {} from {}", name, url);
+
+        loop {
+            let event = tokio::select! {
+                event = client.next_event() => event,
+                _ = wait_for_kick(kicked) => Err(TAKEN_OVER)?,
+            };
+            match event {
+                Some(ClientEvent::Media(message)) => {
+                    if kicked.is_none() {
+                        *kicked = Some(self.registry.publish(name, publisher_id, None).map_err(|_| TAKEN_OVER)?);
+                        *live = true;
+                        backoff.reset();
+                    }
+                    self.registry.send(name, publisher_id, message);
+                }
+                Some(ClientEvent::Status { level, code, .. }) => match code.as_str() {
+                    // The origin's publisher dropped, players here get to wait for it like there
+                    "NetStream.Play.UnpublishNotify" if kicked.is_some() => {
+                        *kicked = None;
+                        self.registry.unpublish(name, publisher_id);
+                    }
+                    "NetStream.Play.Stop" | "NetStream.Play.Complete" => {
+                        client.close().await;
+                        return Ok(());
+                    }
+                    _ if level == "error" => {
+                        eprintln!("{} refused {}: {}", url, name, code);
+                        Err("Refused by the origin")?
+                    }
+                    _ => {}
+                },
+                Some(_) => {}
+                None => Err("Origin closed the connection")?,
+            }
+        }
+    }
+}
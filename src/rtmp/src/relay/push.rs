@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tokio::sync::{broadcast, oneshot};
+use tokio::sync::broadcast::error::RecvError;
+use crate::client::{ClientEvent, RtmpClient};
+use crate::config::RelayConfig;
+use crate::registry::{KeyframeGate, MediaMessage, StreamEvent, StreamRegistry};
+use crate::relay::{stream_url, Backoff};
+use crate::server::PublishingType;
+
+// Pushes published streams on to other RTMP servers, each to any number of URLs. Every push has
+// a connection of its own that gets retried with a backoff until the stream ends or the push is
+// stopped. Pushes start on their own when a stream matching a [[relay.push]] rule is published,
+// or at runtime through start.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushState {
+    Connecting,
+    Pushing,
+    // Waiting to try again after the connection failed or was lost
+    Retrying,
+}
+
+#[derive(Debug, Clone)]
+pub struct PushStatus {
+    pub id: u64,
+    pub stream: String,
+    pub url: String,
+    pub state: PushState,
+    // When it got into that state
+    pub since: SystemTime,
+    // Attempts that failed since the last one that worked
+    pub failures: u32,
+    pub last_error: Option<&'static str>,
+}
+
+struct Push {
+    status: PushStatus,
+    // Dropping it stops the push too
+    stop: oneshot::Sender<()>,
+}
+
+pub struct PushRelays {
+    config: RelayConfig,
+    registry: Arc<StreamRegistry>,
+    pushes: Mutex<HashMap<u64, Push>>,
+    next_id: AtomicU64,
+}
+
+impl PushRelays {
+    pub(crate) fn new(config: RelayConfig, registry: Arc<StreamRegistry>) -> Self {
+        Self {
+            config,
+            registry,
+            pushes: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    // Starts the configured pushes of a stream that just got published to the app. Ones that
+    // are still going, e.g. when the publisher came back within the grace period, carry on.
+    pub(crate) fn published(self: &Arc<Self>, app: &str, name: &str) {
+        let rules = self.config.push.iter().filter(|rule| rule.matches(app, name));
+        for url in rules.flat_map(|rule| &rule.urls) {
+            match stream_url(url, name) {
+                Ok(url) if !self.is_pushing(name, &url) => {
+                    self.spawn(name, url);
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("Not pushing {} to {}: {}", name, url, err),
+            }
+        }
+    }
+
+    // Pushes a stream that's being published to the URL, returning the id of the push
+    pub fn start(self: &Arc<Self>, name: &str, url: &str) -> Result<u64, &'static str> {
+        let url = stream_url(url, name)?;
+        if self.registry.stream_tracks(name).is_none() {
+            Err("Stream is not being published")?
+        }
+        if self.is_pushing(name, &url) {
+            Err("Stream is already being pushed there")?
+        }
+        Ok(self.spawn(name, url))
+    }
+
+    // A configured push that gets stopped starts again the next time its stream is published
+    pub fn stop(&self, id: u64) -> Result<(), &'static str> {
+        match self.pushes.lock().unwrap().remove(&id) {
+            Some(push) => {
+                let _ = push.stop.send(());
+                Ok(())
+            }
+            None => Err("No such push"),
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<PushStatus> {
+        let mut statuses: Vec<PushStatus> = self.pushes.lock().unwrap().values().map(|push| push.status.clone()).collect();
+        statuses.sort_by_key(|status| status.id);
+        statuses
+    }
+
+    fn is_pushing(&self, name: &str, url: &str) -> bool {
+        self.pushes.lock().unwrap().values().any(|push| push.status.stream == name && push.status.url == url)
+    }
+
+    fn spawn(self: &Arc<Self>, name: &str, url: String) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (stop, stopped) = oneshot::channel();
+        let status = PushStatus {
+            id,
+            stream: name.to_string(),
+            url,
+            state: PushState::Connecting,
+            since: SystemTime::now(),
+            failures: 0,
+            last_error: None,
+        };
+        self.pushes.lock().unwrap().insert(id, Push { status, stop });
+
+        let relays = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            relays.run(id, &name, stopped).await;
+            relays.pushes.lock().unwrap().remove(&id);
+        });
+        id
+    }
+
+    fn update(&self, id: u64, state: PushState, error: Option<&'static str>) {
+        let mut pushes = self.pushes.lock().unwrap();
+        let status = match pushes.get_mut(&id) {
+            Some(push) => &mut push.status,
+            None => return,
+        };
+        status.state = state;
+        status.since = SystemTime::now();
+        match error {
+            Some(error) => {
+                status.failures += 1;
+                status.last_error = Some(error);
+            }
+            None if state == PushState::Pushing => status.failures = 0,
+            None => {}
+        }
+    }
+
+    async fn run(&self, id: u64, name: &str, mut stopped: oneshot::Receiver<()>) {
+        let url = match self.pushes.lock().unwrap().get(&id) {
+            Some(push) => push.status.url.clone(),
+            None => return,
+        };
+        let mut backoff = Backoff::new(&self.config);
+
+        loop {
+            // Subscribed anew every time, a new connection needs the headers again
+            let (events, messages) = match self.registry.subscribe_with_gop(name) {
+                Some(subscription) => subscription,
+                None => break,
+            };
+            self.update(id, PushState::Connecting, None);
+
+            let result = tokio::select! {
+                result = self.push(id, name, &url, events, messages, &mut backoff) => Some(result),
+                _ = &mut stopped => None,
+            };
+            self.registry.unsubscribe(name);
+            let err = match result {
+                Some(Ok(())) => break,
+                Some(Err(err)) => err,
+                None => {
+                    println!("Stopped pushing {} to {}", name, url);
+                    return;
+                }
+            };
+
+            let delay = backoff.next();
+            eprintln!("Error pushing {} to {}: {}, retrying in {}ms", name, url, err, delay.as_millis());
+            self.update(id, PushState::Retrying, Some(err));
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = &mut stopped => {
+                    println!("Stopped pushing {} to {}", name, url);
+                    return;
+                }
+            }
+        }
+        println!("Done pushing {} to {}", name, url);
+    }
+
+    // Returns once the stream ended, or with an error when the connection failed
+    async fn push(&self, id: u64, name: &str, url: &str, mut events: broadcast::Receiver<StreamEvent>, messages: Vec<MediaMessage>, backoff: &mut Backoff) -> Result<(), &'static str> {
+        let mut client = RtmpClient::connect(url).await?;
+        client.publish(PublishingType::Live).await?;
+        println!("Pushing {} to {}", name, url);
+        backoff.reset();
+        self.update(id, PushState::Pushing, None);
+
+        for message in messages {
+            client.send_media(&message).await?;
+        }
+
+        let mut keyframe_gate = KeyframeGate::default();
+        loop {
+            let message = match events.recv().await {
+                Ok(StreamEvent::Media(message)) => message,
+                Ok(StreamEvent::Ended) | Err(RecvError::Closed) => break,
+                // The publisher dropped, the stream goes on if it comes back in time
+                Ok(StreamEvent::Published | StreamEvent::Unpublished) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Push of {} to {} fell behind, skipped {} messages", name, url, skipped);
+                    keyframe_gate.lagged();
+                    continue;
+                }
+            };
+            if !keyframe_gate.admit(&message) {
+                continue;
+            }
+            client.send_media(&message).await?;
+
+            // The upstream server can end it any time, e.g. when it drops the stream
+            while let Some(event) = client.try_next_event().await? {
+                if let ClientEvent::Status { level, code, .. } = event {
+                    if level == "error" {
+                        eprintln!("{} refused {}: {}", url, name, code);
+                        Err("Refused by the server")?
+                    }
+                }
+            }
+        }
+
+        client.close().await;
+        Ok(())
+    }
+}
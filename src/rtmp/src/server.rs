@@ -1,46 +1,135 @@
-use std::io::{Cursor, ErrorKind, Read, Write};
-use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc::Sender;
-use tokio::net::TcpStream;
+use std::io::{Cursor, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, oneshot};
 use crate::Serializable;
-use crate::command_message::{AMFCall, AMFMessage, PlayMessage};
-use amf::amf0::Value::{String, Number};
+use crate::command_message::{AMFCall, AMFMessage, PauseMessage, PlayMessage, SeekMessage};
+use amf::Pair;
+use amf::amf0::Value::{Array, String, Number, Null, Object};
 use crate::chunk::chunk_headers::ChunkHeader;
 use crate::chunk::chunk_wrangler::ChunkWrangler;
-use crate::control_message::{SetChunkSize, SetPeerBandwidth, WindowAcknowledgementSize};
+use crate::config::RtmpConfig;
+use crate::flv::video::SUPPORTED_FOURCCS;
+use crate::control_message::{SetChunkSize, SetPeerBandwidth, UserControlMessage, WindowAcknowledgementSize};
 use crate::socket::RtmpSocket;
 use crate::handshake::{CS0, CS1};
+use crate::hooks::{ClientInfo, Hooks};
+use crate::media::TagParsers;
+use crate::recorder::{recording_path, RecordMode, RecordRequest};
+use crate::registry::{KeyframeGate, MediaMessage, StreamEvent, StreamRegistry};
+use crate::relay::{PullPlayer, PullRelays, PushRelays};
+use crate::vod::{vod_path, VodPlayback, VodStep};
 use crate::server::PublishingType::Live;
 use tokio::io::AsyncWriteExt;
 
-#[derive(Debug, PartialEq)]
+pub(crate) static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// We only ever hand out a single stream per connection
+const STREAM_ID: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PublishingType {
     Live,
-    Play
+    Play,
+    // Live, and written to a file that starts over
+    Record,
+    // Live, and added to the end of the file
+    Append,
+}
+
+impl PublishingType {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Live => "live",
+            PublishingType::Play => "play",
+            PublishingType::Record => "record",
+            PublishingType::Append => "append",
+        }
+    }
 }
 
-pub struct RtmpConnection {
-    pub socket: RtmpSocket,
-    pub multiplexer: ChunkWrangler,
-    pub sender: Sender<Vec<u8>>,
-    pub publishing_type: Option<PublishingType>
+// One client over whatever carries its bytes: plain TCP, TLS, or a tunnel
+pub struct RtmpConnection<S> {
+    pub socket: RtmpSocket<WriteHalf<S>>,
+    // Handed over to the reader task once the handshake is done
+    reader: Option<ReadHalf<S>>,
+    pub publishing_type: Option<PublishingType>,
+    pub publishing_name: Option<std::string::String>,
+    pub playing_name: Option<std::string::String>,
+    pub client: ClientInfo,
+    config: Arc<RtmpConfig>,
+    hooks: Arc<Hooks>,
+    registry: Arc<StreamRegistry>,
+    pushes: Arc<PushRelays>,
+    pulls: Arc<PullRelays>,
+    // Fires when another publisher takes over our stream
+    kicked: Option<oneshot::Receiver<()>>,
+    subscription: Option<broadcast::Receiver<StreamEvent>>,
+    // Keeps the stream we play coming from the origin, on edges
+    pull: Option<PullPlayer>,
+    // Set instead of the subscription when playing a file
+    vod: Option<VodPlayback>,
+    // Drops video after we fell behind, until the next keyframe
+    keyframe_gate: KeyframeGate,
+    parsers: TagParsers,
+    // For the idle timeouts and ping round trips
+    started: Instant,
+    last_activity: Instant,
+    last_media: Instant,
+    last_ping: Instant,
+    pub rtt: Option<Duration>,
 }
 
-impl RtmpConnection {
-    pub fn new(stream: TcpStream, sender: Sender<Vec<u8>>) -> Self {
-        let socket = RtmpSocket::new(stream);
+impl<S: AsyncRead + AsyncWrite + Send + 'static> RtmpConnection<S> {
+    pub fn new(stream: S, addr: SocketAddr, config: Arc<RtmpConfig>, hooks: Arc<Hooks>, registry: Arc<StreamRegistry>, pushes: Arc<PushRelays>, pulls: Arc<PullRelays>) -> Self {
+        let client = ClientInfo {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr: addr.ip().to_string(),
+            ..ClientInfo::default()
+        };
+
+        let (reader, writer) = tokio::io::split(stream);
+        let mut socket = RtmpSocket::new(writer);
+        socket.write_timeout = config.timeouts.write();
         RtmpConnection {
             socket,
-            multiplexer: ChunkWrangler::new(),
-            sender,
-            publishing_type: None
+            reader: Some(reader),
+            publishing_type: None,
+            publishing_name: None,
+            playing_name: None,
+            client,
+            config,
+            hooks,
+            registry,
+            pushes,
+            pulls,
+            kicked: None,
+            subscription: None,
+            pull: None,
+            vod: None,
+            keyframe_gate: KeyframeGate::default(),
+            parsers: TagParsers::new(),
+            started: Instant::now(),
+            last_activity: Instant::now(),
+            last_media: Instant::now(),
+            last_ping: Instant::now(),
+            rtt: None,
         }
     }
 
     pub async fn handshake(&mut self) -> Result<u8, &'static str> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return Err("Handshake already done"),
+        };
+
         // Read 1 byte for the CS0 version
         let mut buf = [0; 1];
-        let c0 = match self.socket.socket.read_exact(&mut buf).await {
+        let c0 = match reader.read_exact(&mut buf).await {
             Ok(_) => {
                 let c0 = CS0::deserialize(&mut buf.as_ref()).unwrap();
 
@@ -54,7 +143,7 @@ impl RtmpConnection {
 
         // Now read 1536 bytes for the CS1 chunk_headers
         let mut buf2 = vec![0; 1536];
-        let c1: CS1 = match self.socket.socket.read_exact(&mut buf2).await {
+        let c1: CS1 = match reader.read_exact(&mut buf2).await {
             Ok(_) =>
             // Pass the buf2 as a mut reference to deserialize
                 match CS1::deserialize(&mut buf2.as_slice()) {
@@ -90,13 +179,14 @@ impl RtmpConnection {
         };
 
         // Send our own S0, S1 and S2
-        self.socket.socket.write_all(&s0.serialize().unwrap()).await;
-        self.socket.socket.write_all(&s1.serialize().unwrap()).await;
-        self.socket.socket.write_all(&s2.serialize().unwrap()).await;
+        let response = [s0.serialize().unwrap(), s1.serialize().unwrap(), s2.serialize().unwrap()].concat();
+        if self.socket.socket.write_all(&response).await.is_err() {
+            return Err("Error sending S0, S1 and S2");
+        }
 
         // Now we wait for the client to send their CS2
         let mut buf3 = vec![0; 1536];
-        match self.socket.socket.read_exact(&mut buf3).await {
+        match reader.read_exact(&mut buf3).await {
             Ok(_) =>
                 match CS1::deserialize(&mut buf3.as_slice()) {
                     Ok(c2) => c2,
@@ -118,29 +208,45 @@ impl RtmpConnection {
         Ok(3)
     }
 
-    async fn handle_command_message(&mut self, mut cursor: Cursor<&Vec<u8>>) {
+    // Returning an error closes the connection
+    async fn handle_command_message(&mut self, mut cursor: Cursor<&Vec<u8>>) -> Result<(), &'static str> {
         // First, we get the command name as a str
         let message = match AMFMessage::deserialize(&mut cursor) {
             Ok(message) => message,
             Err(err) => {
                 eprintln!("Error reading AMF message: {}", err);
-                return;
+                return Ok(());
             }
         };
 
         match message.command_name.as_str() {
             "connect" => {
-                match AMFCall::deserialize(&mut cursor) {
+                let amf_call = match AMFCall::deserialize(&mut cursor) {
                     Ok(amf_call) => amf_call,
                     Err(err) => {
                         eprintln!("Error reading AMF call: {}", err);
-                        return;
+                        return Ok(());
                     }
                 };
 
+                for (key, value) in amf_call.command_object {
+                    let value = match value {
+                        String(value) => value,
+                        _ => continue,
+                    };
+                    match key.as_str() {
+                        "app" => self.client.app = value,
+                        "flashVer" => self.client.flash_ver = value,
+                        "tcUrl" => self.client.tc_url = value,
+                        _ => {}
+                    }
+                }
+                self.hooks.on_connect(&self.client);
+
                 self.socket.send_message(WindowAcknowledgementSize { window_acknowledgement_size: 5000000 }, 2, 5, 0).await;
                 self.socket.send_message(SetPeerBandwidth { window_acknowledgement_size: 5000000, limit_type: 1 }, 2, 6, 0).await;
                 self.socket.send_message(SetChunkSize { chunk_size: 5000 }, 2, 1, 0).await;
+                self.socket.chunk_size = 5000;
 
                 let response_header = AMFMessage {
                     transaction_id: 1.0,
@@ -153,6 +259,8 @@ impl RtmpConnection {
                         ("capabilities".to_string(), Number(31.0)),
                         ("mode".to_string(), String("live".to_string())),
                         ("objectEncoding".to_string(), Number(0.0)),
+                        // Enhanced RTMP, the codecs we take beyond what the legacy codec ids cover
+                        ("fourCcList".to_string(), Array { entries: SUPPORTED_FOURCCS.iter().map(|fourcc| String(fourcc.to_string())).collect() }),
                     ],
                     additional_args: vec![
                         ("level".to_string(), String("status".to_string())),
@@ -172,12 +280,12 @@ impl RtmpConnection {
                     command_name: "_result".to_string(),
                 };
 
-                let response_body = AMFCall {
-                    command_object: Vec::new(),
-                    additional_args: Vec::new(),
-                };
+                // No command object, just the id of the stream we created
+                let mut response_body = Vec::new();
+                Null.write_to(&mut response_body).expect("Failed to serialize null command object");
+                Number(STREAM_ID as f64).write_to(&mut response_body).expect("Failed to serialize stream id");
 
-                self.socket.send_bytes([response_header.serialize().unwrap(), response_body.serialize().unwrap()].concat(), 3, 20, 0).await;
+                self.socket.send_bytes([response_header.serialize().unwrap(), response_body].concat(), 3, 20, 0).await;
             }
             "publish" => {
                 // expect null amf object first
@@ -185,7 +293,7 @@ impl RtmpConnection {
                     Ok(amf::Value::Amf0(amf::Amf0Value::Null)) => {}
                     _ => {
                         eprintln!("Error reading NULL AMF object");
-                        return;
+                        return Ok(());
                     }
                 };
 
@@ -194,70 +302,311 @@ impl RtmpConnection {
                     Ok(amf::Value::Amf0(amf::Amf0Value::String(string))) => string,
                     _ => {
                         eprintln!("Error reading publishing name");
-                        return;
+                        return Ok(());
                     }
                 };
 
-                self.publishing_type = match amf::Value::read_from(&mut cursor, amf::Version::Amf0) {
+                // The type is optional and defaults to live
+                let publishing_type = match amf::Value::read_from(&mut cursor, amf::Version::Amf0) {
                     Ok(amf::Value::Amf0(amf::Amf0Value::String(string))) => {
                         match string.to_lowercase().as_str() {
-                            "live" => Option::Some(PublishingType::Live),
-                            "play" => Option::Some(PublishingType::Play),
-                            _ => Option::None
+                            "play" => PublishingType::Play,
+                            "record" => PublishingType::Record,
+                            "append" => PublishingType::Append,
+                            _ => Live,
                         }
                     },
-                    _ => {
-                        eprintln!("Error reading publishing type");
-                        return;
-                    }
+                    _ => Live,
                 };
+                self.publishing_type = Some(publishing_type);
 
                 println!("Publishing name: {}", publishing_name);
                 println!("Publishing type: {:?}", self.publishing_type);
 
-                // If the publishing type is "live" then we need to open a tx rx pair
-                if self.publishing_type == Some(Live) {
-                    let response_header = AMFMessage {
-                        transaction_id: 0.0,
-                        command_name: "onStatus".to_string(),
-                    };
+                if !self.hooks.on_publish(&self.client, &publishing_name, publishing_type.name()).await {
+                    self.send_status("error", "NetStream.Publish.Denied", "Publishing denied.").await;
+                    return Err("Publish denied by on_publish hook");
+                }
 
-                    let response_body = AMFCall {
-                        command_object: Vec::new(),
-                        additional_args: vec![
-                            ("code".to_string(), (String("NetStream.Publish.Start".to_string()))),
-                            ("level".to_string(), (String("status".to_string()))),
-                            ("description".to_string(), (String("Started publishing stream.".to_string()))),
-                        ]
-                    };
+                let record = &self.config.record;
+                let recording = match publishing_type {
+                    PublishingType::Record => Some((recording_path(record, &publishing_name, false), RecordMode::Record)),
+                    PublishingType::Append => Some((recording_path(record, &publishing_name, false), RecordMode::Append)),
+                    _ if record.live => Some((recording_path(record, &publishing_name, true), RecordMode::Record)),
+                    _ => None,
+                };
+                // The registry starts it once we're the active publisher
+                let request = recording.map(|(path, mode)| RecordRequest {
+                    path,
+                    mode,
+                    hooks: self.hooks.clone(),
+                    client: self.client.clone(),
+                });
 
-                    self.socket.send_bytes([response_header.serialize().unwrap(), response_body.serialize().unwrap()].concat(), 3, 20, 0).await;
+                match self.registry.publish(&publishing_name, self.client.id, request) {
+                    Ok(kicked) => {
+                        self.kicked = Some(kicked);
+                        self.last_media = Instant::now();
+                    }
+                    Err(err) => {
+                        eprintln!("Refusing to publish {}: {}", publishing_name, err);
+                        self.send_status("error", "NetStream.Publish.BadName", "Stream is already being published.").await;
+                        return Ok(());
+                    }
+                }
+                self.send_status("status", "NetStream.Publish.Start", "Started publishing stream.").await;
+                if publishing_type != Live {
+                    self.send_status("status", "NetStream.Record.Start", "Started recording stream.").await;
                 }
+                self.pushes.published(&self.client.app, &publishing_name);
+                self.publishing_name = Some(publishing_name);
             }
             "play" => {
                 match PlayMessage::deserialize(&mut cursor) {
                     Ok(msg) => {
                         println!("{:?}", msg);
-                        // First, we set our chunk_headers size to the max chunk_headers size
+                        if !self.hooks.on_play(&self.client, &msg).await {
+                            self.send_status("error", "NetStream.Play.Failed", "Playback denied.").await;
+                            return Err("Play denied by on_play hook");
+                        }
+                        match vod_path(&self.config.vod, &msg.stream_name) {
+                            Some(path) => self.start_vod(msg.stream_name, path, msg.start).await,
+                            None => self.start_playing(msg.stream_name).await,
+                        }
                     }
                     Err(err) => {
                         eprintln!("Error deserializing play message: {}", err);
                     }
                 }
             }
+            "seek" => {
+                match SeekMessage::deserialize(&mut cursor) {
+                    Ok(msg) => self.seek_vod(msg.milliseconds).await,
+                    Err(err) => eprintln!("Error deserializing seek message: {}", err),
+                }
+            }
+            "pause" | "pauseRaw" => {
+                match PauseMessage::deserialize(&mut cursor) {
+                    Ok(msg) => self.pause_vod(msg).await,
+                    Err(err) => eprintln!("Error deserializing pause message: {}", err),
+                }
+            }
+            "FCUnpublish" | "deleteStream" | "closeStream" => {
+                self.close_stream();
+            }
             _ => {
                 println!("Unsupported command: {}", message.command_name);
             }
         }
+
+        Ok(())
+    }
+
+    async fn send_status(&mut self, level: &str, code: &str, description: &str) {
+        let response_header = AMFMessage {
+            transaction_id: 0.0,
+            command_name: "onStatus".to_string(),
+        };
+
+        let response_body = AMFCall {
+            command_object: Vec::new(),
+            additional_args: vec![
+                ("code".to_string(), String(code.to_string())),
+                ("level".to_string(), String(level.to_string())),
+                ("description".to_string(), String(description.to_string())),
+            ]
+        };
+
+        self.socket.send_bytes([response_header.serialize().unwrap(), response_body.serialize().unwrap()].concat(), 3, 20, STREAM_ID).await;
+    }
+
+    async fn start_playing(&mut self, name: std::string::String) {
+        self.pull = self.pulls.request(&name);
+        let (subscription, headers) = self.registry.subscribe(&name);
+        self.subscription = Some(subscription);
+        self.playing_name = Some(name);
+
+        self.socket.send_message(UserControlMessage::stream_begin(STREAM_ID), 2, 4, 0).await;
+        self.send_status("status", "NetStream.Play.Reset", "Playing and resetting stream.").await;
+        self.send_status("status", "NetStream.Play.Start", "Started playing stream.").await;
+
+        // Whatever the publisher sent so far that we need to be able to decode the next frame
+        for message in headers {
+            self.forward_media(message).await;
+        }
+    }
+
+    // Plays a file from the VOD directory, from the keyframe at or before the start offset
+    async fn start_vod(&mut self, name: std::string::String, path: PathBuf, start: f64) {
+        let mut vod = match VodPlayback::open(path).await {
+            Ok(vod) => vod,
+            Err(err) => {
+                eprintln!("Error playing {}: {}", name, err);
+                self.send_status("error", "NetStream.Play.StreamNotFound", "Error opening file.").await;
+                return;
+            }
+        };
+        // Anything negative means from the start for recorded content
+        let headers = match vod.seek(start.max(0.0) as u32).await {
+            Ok(headers) => headers,
+            Err(err) => {
+                eprintln!("Error playing {}: {}", name, err);
+                self.send_status("error", "NetStream.Play.Failed", "Error reading file.").await;
+                return;
+            }
+        };
+        println!("Playing file {} from {}ms", name, start.max(0.0));
+        self.playing_name = Some(name);
+
+        self.socket.send_message(UserControlMessage::stream_begin(STREAM_ID), 2, 4, 0).await;
+        self.send_status("status", "NetStream.Play.Reset", "Playing and resetting file.").await;
+        self.send_status("status", "NetStream.Play.Start", "Started playing file.").await;
+        for message in headers {
+            self.forward_media(message).await;
+        }
+        self.vod = Some(vod);
+    }
+
+    async fn seek_vod(&mut self, milliseconds: f64) {
+        // Live streams can't seek
+        let result = match self.vod.as_mut() {
+            Some(vod) => vod.seek(milliseconds.max(0.0) as u32).await,
+            None => Err("Not playing a file"),
+        };
+        let headers = match result {
+            Ok(headers) => headers,
+            Err(err) => {
+                eprintln!("Client {} can't seek: {}", self.client.id, err);
+                self.send_status("error", "NetStream.Seek.Failed", "Seek failed.").await;
+                return;
+            }
+        };
+
+        self.socket.send_message(UserControlMessage::stream_begin(STREAM_ID), 2, 4, 0).await;
+        self.send_status("status", "NetStream.Seek.Notify", "Seeking.").await;
+        self.send_status("status", "NetStream.Play.Start", "Started playing file.").await;
+        for message in headers {
+            self.forward_media(message).await;
+        }
     }
 
-    pub fn handle_control_stream_msg(&mut self, header: ChunkHeader, buf: &Vec<u8>) {
+    // Playback carries on from where it stopped, whatever position the player says it's at
+    async fn pause_vod(&mut self, msg: PauseMessage) {
+        let vod = match self.vod.as_mut() {
+            Some(vod) => vod,
+            None => return,
+        };
+        println!("Client {} {} at {}ms", self.client.id, if msg.pause { "paused" } else { "unpaused" }, msg.milliseconds);
+        vod.pause(msg.pause);
+        if msg.pause {
+            self.send_status("status", "NetStream.Pause.Notify", "Paused file.").await;
+        } else {
+            self.socket.send_message(UserControlMessage::stream_begin(STREAM_ID), 2, 4, 0).await;
+            self.send_status("status", "NetStream.Unpause.Notify", "Unpaused file.").await;
+        }
+    }
+
+    async fn handle_vod_step(&mut self, step: VodStep) {
+        match step {
+            VodStep::Media(message) => self.forward_media(message).await,
+            VodStep::Complete => {
+                let (duration, bytes) = match &self.vod {
+                    Some(vod) => (vod.duration, vod.bytes),
+                    None => return,
+                };
+
+                let mut status = Vec::new();
+                String("onPlayStatus".to_string()).write_to(&mut status).unwrap();
+                Object {
+                    class_name: None,
+                    entries: vec![
+                        Pair { key: "code".to_string(), value: String("NetStream.Play.Complete".to_string()) },
+                        Pair { key: "level".to_string(), value: String("status".to_string()) },
+                        Pair { key: "duration".to_string(), value: Number(duration) },
+                        Pair { key: "bytes".to_string(), value: Number(bytes as f64) },
+                    ],
+                }.write_to(&mut status).unwrap();
+                self.socket.send_bytes(status, 5, 18, STREAM_ID).await;
+
+                self.socket.send_message(UserControlMessage::stream_eof(STREAM_ID), 2, 4, 0).await;
+                self.send_status("status", "NetStream.Play.Stop", "Stopped playing file.").await;
+            }
+        }
+    }
+
+    // Audio, video and data messages from a publisher go straight to the registry
+    fn handle_media_message(&mut self, header: ChunkHeader, data: Vec<u8>) {
+        let name = match &self.publishing_name {
+            Some(name) => name,
+            None => return,
+        };
+        self.last_media = Instant::now();
+
+        // Encoders wrap their metadata in @setDataFrame, players expect to get the bare onMetaData
+        let data = if header.message_type_id == 18 {
+            strip_set_data_frame(data)
+        } else {
+            data
+        };
+
+        let message = self.parsers.parse(name, header.message_type_id, header.timestamp, data);
+        self.registry.send(name, self.client.id, message);
+    }
+
+    async fn handle_stream_event(&mut self, event: StreamEvent) {
+        match event {
+            StreamEvent::Media(message) => self.forward_media(message).await,
+            StreamEvent::Published => {
+                self.socket.send_message(UserControlMessage::stream_begin(STREAM_ID), 2, 4, 0).await;
+                self.send_status("status", "NetStream.Play.PublishNotify", "Stream is now published.").await;
+            }
+            StreamEvent::Unpublished => {
+                self.socket.send_message(UserControlMessage::stream_eof(STREAM_ID), 2, 4, 0).await;
+                self.send_status("status", "NetStream.Play.UnpublishNotify", "Stream is now unpublished.").await;
+            }
+            StreamEvent::Ended => {
+                self.send_status("status", "NetStream.Play.Stop", "Stopped playing stream.").await;
+                self.close_stream();
+            }
+        }
+    }
+
+    async fn forward_media(&mut self, message: MediaMessage) {
+        if !self.keyframe_gate.admit(&message) {
+            return;
+        }
+
+        let csid = match message.message_type_id {
+            8 => 4,
+            9 => 6,
+            _ => 5,
+        };
+        self.socket.send_bytes_at(message.data.to_vec(), csid, message.message_type_id, STREAM_ID, message.timestamp).await;
+    }
+
+    // Called when the client stops publishing or playing, either explicitly or by disconnecting
+    fn close_stream(&mut self) {
+        if let Some(name) = self.publishing_name.take() {
+            self.kicked = None;
+            self.registry.unpublish(&name, self.client.id);
+            self.hooks.on_publish_done(&self.client, &name);
+        }
+        if let Some(name) = self.playing_name.take() {
+            self.subscription = None;
+            self.vod = None;
+            self.registry.unsubscribe(&name);
+            self.pull = None;
+            self.hooks.on_play_done(&self.client, &name);
+        }
+    }
+
+    pub async fn handle_control_stream_msg(&mut self, header: ChunkHeader, buf: &Vec<u8>) {
         println!("Received control stream message");
         match header.message_type_id {
             1 => {
+                // Already applied by the reader task, see ChunkWrangler::spawn
                 println!("Set chunk_headers size");
-                self.multiplexer.max_chunk_size = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
-                println!("New max chunk_headers size: {}", self.multiplexer.max_chunk_size);
+                println!("New max chunk_headers size: {}", u32::from_be_bytes(buf[0..4].try_into().unwrap()));
             }
             2 => {
                 // Used to signify to the peer that further processing is not necessary and that the stream is probably about to close.
@@ -272,6 +621,14 @@ impl RtmpConnection {
                 println!("Peer has recv'd {} bytes total thus far", sequence_number);
 
             }
+            4 => {
+                match UserControlMessage::deserialize(&mut Cursor::new(&buf)) {
+                    Ok(msg) => self.handle_user_control_msg(msg).await,
+                    Err(err) => {
+                        eprintln!("Error deserializing user control message: {}", err);
+                    }
+                }
+            }
             5 => {
                 match WindowAcknowledgementSize::deserialize(&mut Cursor::new(&buf)) {
                     Ok(msg) => {
@@ -299,8 +656,66 @@ impl RtmpConnection {
         }
     }
 
+    async fn handle_user_control_msg(&mut self, msg: UserControlMessage) {
+        let timestamp = match msg.event_data.get(0..4) {
+            Some(bytes) => u32::from_be_bytes(bytes.try_into().unwrap()),
+            None => {
+                eprintln!("User control event {} is missing its data", msg.event_type);
+                return;
+            }
+        };
+
+        match msg.event_type {
+            3 => {
+                println!("Set Buffer Length");
+            }
+            6 => {
+                // Clients can ping us too
+                self.socket.send_message(UserControlMessage::ping_response(timestamp), 2, 4, 0).await;
+            }
+            7 => {
+                let rtt = Duration::from_millis(self.connection_time().wrapping_sub(timestamp) as u64);
+                println!("Round trip time for client {}: {}ms", self.client.id, rtt.as_millis());
+                self.rtt = Some(rtt);
+            }
+            _ => {
+                println!("Unsupported user control event: {}", msg.event_type);
+            }
+        }
+    }
+
+    // Milliseconds since the connection was accepted, which is what we stamp our pings with
+    fn connection_time(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    // Sends a ping when one is due and checks whether the peer has gone quiet for too long
+    async fn check_liveness(&mut self) -> Result<(), &'static str> {
+        let timeouts = &self.config.timeouts;
+        if self.publishing_name.is_some() {
+            if timeouts.publisher_idle().is_some_and(|timeout| self.last_media.elapsed() > timeout) {
+                return Err("Publisher stopped sending media");
+            }
+        } else if timeouts.player_idle().is_some_and(|timeout| self.last_activity.elapsed() > timeout) {
+            return Err("Peer stopped responding");
+        }
+
+        if timeouts.ping_interval().is_some_and(|interval| self.last_ping.elapsed() >= interval) {
+            self.last_ping = Instant::now();
+            let timestamp = self.connection_time();
+            self.socket.send_message(UserControlMessage::ping_request(timestamp), 2, 4, 0).await;
+        }
+
+        Ok(())
+    }
+
     pub async fn handle_connection(&mut self) {
-        match self.handshake().await {
+        let handshake = match self.config.timeouts.handshake() {
+            Some(timeout) => tokio::time::timeout(timeout, self.handshake()).await.unwrap_or(Err("Handshake timed out")),
+            None => self.handshake().await,
+        };
+
+        match handshake {
             Ok(_) => {
                 println!("Shook the fuk outta that hand");
             }
@@ -310,25 +725,118 @@ impl RtmpConnection {
             }
         }
 
-        // Now while our connection is open, we read chunk_headers by chunk_headers
-        // and print the data we receive
+        let mut incoming = match self.reader.take() {
+            Some(reader) => ChunkWrangler::new().spawn(reader),
+            None => return,
+        };
+
+        let mut liveness_check = tokio::time::interval(Duration::from_millis(250));
+        self.last_activity = Instant::now();
+        self.last_ping = Instant::now();
+
+        // Now while our connection is open, we handle messages as they come in, along with
+        // anything the registry has for us
         loop {
-            let (header, data) = match self.multiplexer.read_chunk(&mut self.socket).await {
-                Ok((header, data)) => (header, data),
-                Err(_) => {
-                    continue;
-                }
-            };
+            tokio::select! {
+                message = incoming.recv() => {
+                    let (header, data) = match message {
+                        Some(message) => message,
+                        None => {
+                            println!("Connection closed");
+                            break;
+                        }
+                    };
+                    self.last_activity = Instant::now();
 
-            if header.message_type_id == 20 {
-                self.handle_command_message(Cursor::new(&data)).await;
-                continue;
+                    if let Err(err) = self.handle_message(header, data).await {
+                        eprintln!("Closing connection: {}", err);
+                        break;
+                    }
+                }
+                _ = wait_for_kick(&mut self.kicked) => {
+                    println!("Publisher {} was replaced by a newer publisher", self.client.id);
+                    break;
+                }
+                event = next_event(&mut self.subscription) => {
+                    match event {
+                        Ok(event) => self.handle_stream_event(event).await,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            println!("Player {} fell behind, dropped {} messages", self.client.id, skipped);
+                            self.keyframe_gate.lagged();
+                        }
+                        Err(broadcast::error::RecvError::Closed) => self.subscription = None,
+                    }
+                }
+                step = next_vod_step(&mut self.vod) => self.handle_vod_step(step).await,
+                _ = liveness_check.tick() => {
+                    if let Err(err) = self.check_liveness().await {
+                        eprintln!("Disconnecting client {}: {}", self.client.id, err);
+                        break;
+                    }
+                }
             }
-            // If this message is targeting the control stream, we need to parse it properly
-            if header.message_stream_id == 0 {
-                self.handle_control_stream_msg(header, &data);
-                continue;
+
+            // Nothing we send gets through anymore
+            if self.socket.broken {
+                eprintln!("Disconnecting client {}: Connection broken", self.client.id);
+                break;
             }
         }
+
+        self.close_stream();
     }
-}
\ No newline at end of file
+
+    async fn handle_message(&mut self, header: ChunkHeader, data: Vec<u8>) -> Result<(), &'static str> {
+        if header.message_type_id == 20 {
+            return self.handle_command_message(Cursor::new(&data)).await;
+        }
+        // If this message is targeting the control stream, we need to parse it properly
+        if header.message_stream_id == 0 {
+            self.handle_control_stream_msg(header, &data).await;
+            return Ok(());
+        }
+        if matches!(header.message_type_id, 8 | 9 | 18) {
+            self.handle_media_message(header, data);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn wait_for_kick(kicked: &mut Option<oneshot::Receiver<()>>) {
+    if let Some(receiver) = kicked {
+        if receiver.await.is_ok() {
+            return;
+        }
+        // The registry let go of us without kicking us, don't poll this receiver again
+        *kicked = None;
+    }
+    std::future::pending().await
+}
+
+async fn next_event(subscription: &mut Option<broadcast::Receiver<StreamEvent>>) -> Result<StreamEvent, broadcast::error::RecvError> {
+    match subscription {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_vod_step(vod: &mut Option<VodPlayback>) -> VodStep {
+    match vod {
+        Some(vod) => vod.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn strip_set_data_frame(data: Vec<u8>) -> Vec<u8> {
+    let mut cursor = Cursor::new(&data);
+    let is_set_data_frame = match amf::Value::read_from(&mut cursor, amf::Version::Amf0) {
+        Ok(value) => value.try_as_str() == Some("@setDataFrame"),
+        Err(_) => false,
+    };
+
+    if is_set_data_frame {
+        data[cursor.position() as usize..].to_vec()
+    } else {
+        data
+    }
+}
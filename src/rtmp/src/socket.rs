@@ -1,41 +1,74 @@
-use std::io;
+use std::time::Duration;
 use crate::Serializable;
-use std::io::{Write};
-use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use crate::chunk::chunk_headers::{ChunkBasicHeader, ChunkHeader};
 
-pub struct RtmpSocket {
-    pub socket: TcpStream,
+// The sending half of the connection, a TCP or TLS stream or whatever else carries the bytes.
+// Reading happens on its own task, see ChunkWrangler.
+pub struct RtmpSocket<W> {
+    pub socket: W,
+    // The chunk size we announced to the peer with SetChunkSize
+    pub chunk_size: usize,
+    // Set once a write failed or timed out, after which nothing gets through anymore
+    pub broken: bool,
+    // How long a write may take, None waits for as long as it takes
+    pub write_timeout: Option<Duration>,
 }
 
-impl RtmpSocket {
-    pub fn new(socket: TcpStream) -> Self {
-        Self { socket }
+impl<W: AsyncWrite + Unpin> RtmpSocket<W> {
+    pub fn new(socket: W) -> Self {
+        Self { socket, chunk_size: 128, broken: false, write_timeout: None }
     }
 
     pub async fn send_bytes(&mut self, msg: Vec<u8>, chunk_stream_id: u8, type_id: u8, message_stream_id: u32) {
+        self.send_bytes_at(msg, chunk_stream_id, type_id, message_stream_id, 0).await;
+    }
+
+    // Splits the message into chunks no bigger than our chunk size. Every chunk after the first
+    // only carries a fmt 3 basic header (plus the extended timestamp if there is one).
+    pub async fn send_bytes_at(&mut self, msg: Vec<u8>, chunk_stream_id: u8, type_id: u8, message_stream_id: u32, timestamp: u32) {
+        if self.broken {
+            return;
+        }
         let header = ChunkHeader {
             basic_header: ChunkBasicHeader {
                 fmt: 0,
                 csid: chunk_stream_id,
             },
-            timestamp: 0,
+            timestamp,
             message_length: msg.len() as u32,
             message_type_id: type_id,
             message_stream_id,
+            timestamp_delta: timestamp,
+            extended_timestamp: timestamp >= 0xFFFFFF,
         };
 
-        match header.serialize() {
-            Ok(buf) => {
-                self.socket.write_all(&buf).await;
-                self.socket.write_all(&msg).await;
-            }
+        let mut buf = match header.serialize() {
+            Ok(buf) => buf,
             Err(err) => {
                 eprintln!("Error serializing chunk_headers header: {}", err);
+                return;
+            }
+        };
+
+        let continuation = ChunkBasicHeader { fmt: 3, csid: chunk_stream_id }.serialize().unwrap();
+        for (i, chunk) in msg.chunks(self.chunk_size).enumerate() {
+            if i > 0 {
+                buf.extend_from_slice(&continuation);
+                if timestamp >= 0xFFFFFF {
+                    buf.extend_from_slice(&timestamp.to_be_bytes());
+                }
             }
+            buf.extend_from_slice(chunk);
+        }
+
+        let result = match self.write_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.socket.write_all(&buf)).await.unwrap_or(Err(std::io::ErrorKind::TimedOut.into())),
+            None => self.socket.write_all(&buf).await,
+        };
+        if let Err(err) = result {
+            eprintln!("Error writing message: {}", err);
+            self.broken = true;
         }
     }
 
@@ -50,4 +83,4 @@ impl RtmpSocket {
 
         self.send_bytes(data, chunk_stream_id, type_id, message_stream_id).await;
     }
-}
\ No newline at end of file
+}
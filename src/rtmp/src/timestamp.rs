@@ -0,0 +1,188 @@
+// Maps whatever timestamps the publisher sends onto a clean timeline for the stream: starting at
+// zero, never going backwards on a track, and without jumps when the encoder restarts its clock
+// or a different publisher takes over.
+//
+// All arithmetic wraps, so a publisher running past the 32 bit timestamp limit keeps working.
+
+pub struct TimestampNormalizer {
+    name: String,
+    // How far a timestamp can stray from the rest of the stream before it counts as a discontinuity
+    tolerance: u32,
+    // Added to every incoming timestamp
+    offset: u32,
+    // The furthest we've got on any track
+    last_output: Option<u32>,
+    last_audio: Option<u32>,
+    last_video: Option<u32>,
+    // Set when the next timestamp starts a new timeline which should carry on from the current one
+    restart_pending: bool,
+}
+
+impl TimestampNormalizer {
+    pub fn new(name: &str, tolerance_ms: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            tolerance: tolerance_ms,
+            offset: 0,
+            last_output: None,
+            last_audio: None,
+            last_video: None,
+            restart_pending: false,
+        }
+    }
+
+    // The timestamps that follow come from a new clock, e.g. a reconnected publisher
+    pub fn restart(&mut self) {
+        self.restart_pending = self.last_output.is_some();
+    }
+
+    pub fn normalize(&mut self, message_type_id: u8, timestamp: u32) -> u32 {
+        // Data messages such as onMetaData are usually stamped 0 no matter where the stream is,
+        // so they just get the current stream time
+        if message_type_id != 8 && message_type_id != 9 {
+            return self.last_output.unwrap_or(0);
+        }
+
+        let last_output = match self.last_output {
+            Some(last_output) => last_output,
+            None => {
+                // The very first frame defines zero
+                if timestamp != 0 {
+                    println!("Stream {} starts at timestamp {}, rebasing to 0", self.name, timestamp);
+                }
+                self.offset = 0u32.wrapping_sub(timestamp);
+                self.last_output = Some(0);
+                // Neither track may start before zero, even if the other one is skewed slightly behind
+                self.last_audio = Some(0);
+                self.last_video = Some(0);
+                return 0;
+            }
+        };
+
+        if self.restart_pending {
+            self.restart_pending = false;
+            println!("Stream {} restarted at timestamp {}, continuing from {}", self.name, timestamp, last_output.wrapping_add(1));
+            self.offset = last_output.wrapping_add(1).wrapping_sub(timestamp);
+        }
+
+        let mut output = timestamp.wrapping_add(self.offset);
+
+        // A big jump either way means the encoder's clock was reset or skipped ahead. Normal
+        // audio/video interleaving stays well within the tolerance.
+        let jump = output.wrapping_sub(last_output) as i32;
+        if jump.unsigned_abs() > self.tolerance {
+            println!("Timestamp discontinuity on stream {}: jumped {}ms at timestamp {}, rebasing to {}", self.name, jump, timestamp, last_output.wrapping_add(1));
+            self.offset = last_output.wrapping_add(1).wrapping_sub(timestamp);
+            output = last_output.wrapping_add(1);
+        }
+
+        // Small steps backwards on the same track would still confuse every muxer downstream
+        if let Some(track_last) = self.track_last(message_type_id) {
+            if (output.wrapping_sub(track_last) as i32) < 0 {
+                println!("Non-monotonic {} timestamp on stream {}: {} after {}, clamping", track_name(message_type_id), self.name, output, track_last);
+                output = track_last;
+            }
+        }
+
+        self.set_track_last(message_type_id, output);
+        if (output.wrapping_sub(last_output) as i32) > 0 {
+            self.last_output = Some(output);
+        }
+        output
+    }
+
+    fn track_last(&self, message_type_id: u8) -> Option<u32> {
+        match message_type_id {
+            8 => self.last_audio,
+            _ => self.last_video,
+        }
+    }
+
+    fn set_track_last(&mut self, message_type_id: u8, timestamp: u32) {
+        match message_type_id {
+            8 => self.last_audio = Some(timestamp),
+            _ => self.last_video = Some(timestamp),
+        }
+    }
+}
+
+fn track_name(message_type_id: u8) -> &'static str {
+    match message_type_id {
+        8 => "audio",
+        _ => "video",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: u8 = 8;
+    const VIDEO: u8 = 9;
+
+    #[test]
+    fn rebases_to_zero() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        assert_eq!(normalizer.normalize(VIDEO, 5000), 0);
+        assert_eq!(normalizer.normalize(AUDIO, 5023), 23);
+        assert_eq!(normalizer.normalize(VIDEO, 5040), 40);
+        // onMetaData and the like get the current stream time
+        assert_eq!(normalizer.normalize(18, 0), 40);
+    }
+
+    #[test]
+    fn rebases_across_the_32_bit_limit() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        assert_eq!(normalizer.normalize(VIDEO, u32::MAX - 10), 0);
+        assert_eq!(normalizer.normalize(VIDEO, 29), 40);
+    }
+
+    #[test]
+    fn continues_after_a_discontinuity() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        normalizer.normalize(VIDEO, 0);
+        assert_eq!(normalizer.normalize(VIDEO, 40), 40);
+        // Ahead and back beyond the tolerance
+        assert_eq!(normalizer.normalize(VIDEO, 90000), 41);
+        assert_eq!(normalizer.normalize(VIDEO, 90040), 81);
+        assert_eq!(normalizer.normalize(VIDEO, 100), 82);
+        assert_eq!(normalizer.normalize(AUDIO, 120), 102);
+        // Within the tolerance is just a gap
+        assert_eq!(normalizer.normalize(VIDEO, 1000), 982);
+    }
+
+    #[test]
+    fn clamps_each_track_separately() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        normalizer.normalize(VIDEO, 0);
+        assert_eq!(normalizer.normalize(VIDEO, 40), 40);
+        // Audio can be behind video, as long as it doesn't go back on itself
+        assert_eq!(normalizer.normalize(AUDIO, 30), 30);
+        assert_eq!(normalizer.normalize(AUDIO, 25), 30);
+        assert_eq!(normalizer.normalize(VIDEO, 20), 40);
+        assert_eq!(normalizer.normalize(VIDEO, 80), 80);
+        assert_eq!(normalizer.normalize(AUDIO, 53), 53);
+    }
+
+    #[test]
+    fn restarts_after_a_new_publisher() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        normalizer.normalize(VIDEO, 1000);
+        assert_eq!(normalizer.normalize(VIDEO, 1400), 400);
+        assert_eq!(normalizer.normalize(AUDIO, 1380), 380);
+
+        // The new publisher's clock starts over, the stream carries on
+        normalizer.restart();
+        assert_eq!(normalizer.normalize(VIDEO, 0), 401);
+        assert_eq!(normalizer.normalize(AUDIO, 10), 411);
+        assert_eq!(normalizer.normalize(VIDEO, 40), 441);
+    }
+
+    #[test]
+    fn restart_before_any_frames_starts_at_zero() {
+        let mut normalizer = TimestampNormalizer::new("test", 1000);
+        normalizer.restart();
+        assert_eq!(normalizer.normalize(VIDEO, 3000), 0);
+        assert_eq!(normalizer.normalize(VIDEO, 3040), 40);
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use crate::config::RtmpsConfig;
+
+// TLS for RTMPS. The certificate is picked by the server name the client asks for (SNI), an exact
+// match before a wildcard one, falling back to the default certificate. Certificates get reloaded
+// when their files change.
+
+#[derive(Debug)]
+struct Certificate {
+    // None for the default certificate
+    server_name: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    // When either file last changed, as of the last load
+    modified: Option<SystemTime>,
+    key: Arc<CertifiedKey>,
+}
+
+#[derive(Debug)]
+struct Certificates {
+    certificates: Mutex<Vec<Certificate>>,
+}
+
+impl Certificates {
+    fn load(config: &RtmpsConfig) -> Result<Self, &'static str> {
+        let mut files = Vec::new();
+        match (&config.cert_path, &config.key_path) {
+            (Some(cert_path), Some(key_path)) => files.push((None, cert_path, key_path)),
+            (None, None) => {}
+            _ => Err("RTMPS needs both a certificate and a key")?,
+        }
+        for certificate in &config.certificates {
+            files.push((Some(certificate.server_name.to_lowercase()), &certificate.cert_path, &certificate.key_path));
+        }
+        if files.is_empty() {
+            Err("RTMPS has no certificates")?
+        }
+
+        let mut certificates = Vec::new();
+        for (server_name, cert_path, key_path) in files {
+            let (cert_path, key_path) = (PathBuf::from(cert_path), PathBuf::from(key_path));
+            certificates.push(Certificate {
+                server_name,
+                modified: modified(&cert_path, &key_path),
+                key: load_key(&cert_path, &key_path)?,
+                cert_path,
+                key_path,
+            });
+        }
+        Ok(Self { certificates: Mutex::new(certificates) })
+    }
+
+    // A certificate that doesn't load, e.g. because it's halfway written, keeps the one it had and
+    // gets tried again next time
+    fn reload(&self) {
+        let mut certificates = self.certificates.lock().unwrap();
+        for certificate in certificates.iter_mut() {
+            let modified = modified(&certificate.cert_path, &certificate.key_path);
+            if modified == certificate.modified {
+                continue;
+            }
+            match load_key(&certificate.cert_path, &certificate.key_path) {
+                Ok(key) => {
+                    println!("Reloaded certificate {}", certificate.cert_path.display());
+                    certificate.key = key;
+                    certificate.modified = modified;
+                }
+                Err(err) => eprintln!("Error reloading certificate {}: {}", certificate.cert_path.display(), err),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.lock().unwrap();
+        let name = client_hello.server_name().map(str::to_lowercase);
+        let named = |exact: bool| {
+            let name = name.as_deref()?;
+            certificates.iter().find(|certificate| match certificate.server_name.as_deref() {
+                Some(server_name) if exact => server_name == name,
+                Some(server_name) => server_name.strip_prefix("*.").is_some_and(|domain| name.split_once('.').is_some_and(|(_, rest)| rest == domain)),
+                None => false,
+            })
+        };
+        named(true)
+            .or_else(|| named(false))
+            .or_else(|| certificates.iter().find(|certificate| certificate.server_name.is_none()))
+            .map(|certificate| certificate.key.clone())
+    }
+}
+
+// The acceptor for the RTMPS listener. Its certificates get checked for changes from then on.
+pub(crate) fn acceptor(config: &RtmpsConfig) -> Result<TlsAcceptor, &'static str> {
+    let certificates = Arc::new(Certificates::load(config)?);
+    if config.reload_interval_ms > 0 {
+        let certificates = certificates.clone();
+        let interval = Duration::from_millis(config.reload_interval_ms);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                certificates.reload();
+            }
+        });
+    }
+
+    let server_config = match ServerConfig::builder_with_provider(Arc::new(ring::default_provider())).with_safe_default_protocol_versions() {
+        Ok(builder) => builder.with_no_client_auth().with_cert_resolver(certificates),
+        Err(_) => Err("Error setting up TLS")?,
+    };
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// The certificate chain and its private key, which have to go together
+fn load_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, &'static str> {
+    let chain = match CertificateDer::pem_file_iter(cert_path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>()) {
+        Ok(chain) if !chain.is_empty() => chain,
+        Ok(_) => Err("No certificates in the certificate file")?,
+        Err(err) => {
+            eprintln!("Error reading certificate {}: {:?}", cert_path.display(), err);
+            Err("Error reading certificate")?
+        }
+    };
+    let key = match PrivateKeyDer::from_pem_file(key_path) {
+        Ok(key) => key,
+        Err(err) => {
+            eprintln!("Error reading private key {}: {:?}", key_path.display(), err);
+            Err("Error reading private key")?
+        }
+    };
+
+    let key = match ring::sign::any_supported_type(&key) {
+        Ok(key) => key,
+        Err(_) => Err("Unsupported private key")?,
+    };
+    let certified = CertifiedKey::new(chain, key);
+    if certified.keys_match().is_err() {
+        Err("Certificate and private key don't match")?
+    }
+    Ok(Arc::new(certified))
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    modified(cert_path).max(modified(key_path))
+}
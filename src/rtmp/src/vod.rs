@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::config::VodConfig;
+use crate::flv::file::{FlvReader, FlvTag};
+use crate::flv::metadata::{KeyframeIndex, Metadata};
+use crate::recorder::file_name;
+use crate::registry::MediaMessage;
+
+// Plays an FLV file to an RTMP player. Tags go out as their timestamps come due, a little ahead
+// so the player can fill its buffer. Seeking goes to the last keyframe before the requested
+// time, found through the keyframe index in the file's onMetaData, or by reading through the
+// file for files that don't have one.
+
+// How far ahead of the clock tags get sent
+const BUFFER_AHEAD_MS: u32 = 1000;
+// Tags read from the file in one go
+const READ_BATCH: usize = 64;
+
+type Reader = FlvReader<BufReader<File>>;
+type BatchRead = JoinHandle<(Reader, Result<Vec<FlvTag>, &'static str>)>;
+
+// Players can ask for flv:name or name.flv too
+pub(crate) fn vod_path(config: &VodConfig, name: &str) -> Option<PathBuf> {
+    let dir = config.path.as_ref()?;
+    let name = name.strip_prefix("flv:").unwrap_or(name);
+    let name = name.strip_suffix(".flv").unwrap_or(name);
+    let path = Path::new(dir).join(format!("{}.flv", file_name(name)));
+    path.is_file().then_some(path)
+}
+
+pub(crate) enum VodStep {
+    Media(MediaMessage),
+    // The end of the file. Playback stays there, the player can still seek back.
+    Complete,
+}
+
+pub(crate) struct VodPlayback {
+    // Away while a batch read has it
+    reader: Option<Reader>,
+    read: Option<BatchRead>,
+    buffer: VecDeque<FlvTag>,
+    end_of_file: bool,
+    complete: bool,
+    paused: bool,
+    metadata: Option<FlvTag>,
+    // The sequence headers, which have to be sent again after every seek
+    headers: Vec<FlvTag>,
+    // Where the first frame is, for playing from the start
+    first_frame: u64,
+    keyframes: KeyframeIndex,
+    pub duration: f64,
+    // The file timestamp that's due at the instant, set by the first tag after starting or resuming
+    clock: Option<(Instant, u32)>,
+    pub bytes: u64,
+}
+
+impl VodPlayback {
+    pub(crate) async fn open(path: PathBuf) -> Result<Self, &'static str> {
+        match tokio::task::spawn_blocking(move || Self::load(&path)).await {
+            Ok(result) => result,
+            Err(_) => Err("Error opening VOD file"),
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self, &'static str> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => Err("Error opening VOD file")?,
+        };
+        let mut reader = FlvReader::new(BufReader::new(file))?;
+
+        // Everything before the first frame is metadata and sequence headers
+        let mut metadata: Option<FlvTag> = None;
+        let mut headers: Vec<FlvTag> = Vec::new();
+        let mut first_frame = reader.position();
+        while let Some(tag) = reader.next_tag()? {
+            if tag.tag_type == 18 && metadata.is_none() && Metadata::parse(&tag.data).is_ok() {
+                metadata = Some(tag);
+            } else if tag.is_sequence_header() {
+                headers.retain(|header| header.tag_type != tag.tag_type);
+                headers.push(tag);
+            } else if matches!(tag.tag_type, 8 | 9) {
+                break;
+            }
+            first_frame = reader.position();
+        }
+
+        let parsed = metadata.as_ref().and_then(|tag| Metadata::parse(&tag.data).ok());
+        let index = parsed.as_ref().and_then(|metadata| metadata.keyframes());
+        let mut duration = parsed.as_ref().and_then(|metadata| metadata.number("duration")).unwrap_or(0.0);
+
+        let keyframes = match index {
+            Some(keyframes) => keyframes,
+            None => {
+                // No index in the file, so we make our own
+                let mut keyframes = KeyframeIndex::default();
+                reader.seek(first_frame)?;
+                let mut last_timestamp = 0;
+                loop {
+                    let position = reader.position();
+                    let tag = match reader.next_tag()? {
+                        Some(tag) => tag,
+                        None => break,
+                    };
+                    if tag.is_keyframe() {
+                        keyframes.push(tag.timestamp as f64 / 1000.0, position);
+                    }
+                    last_timestamp = last_timestamp.max(tag.timestamp);
+                }
+                if duration <= 0.0 {
+                    duration = last_timestamp as f64 / 1000.0;
+                }
+                keyframes
+            }
+        };
+
+        Ok(VodPlayback {
+            reader: Some(reader),
+            read: None,
+            buffer: VecDeque::new(),
+            end_of_file: false,
+            complete: false,
+            paused: false,
+            metadata,
+            headers,
+            first_frame,
+            keyframes,
+            duration,
+            clock: None,
+            bytes: 0,
+        })
+    }
+
+    // Jumps to the last keyframe at or before the time. Returns the metadata and sequence headers
+    // the player needs before it can carry on from there.
+    pub(crate) async fn seek(&mut self, milliseconds: u32) -> Result<Vec<MediaMessage>, &'static str> {
+        // A read that's underway has the reader
+        if let Some(read) = self.read.take() {
+            if let Ok((reader, _)) = read.await {
+                self.reader = Some(reader);
+            }
+        }
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => Err("VOD file is gone")?,
+        };
+
+        let (time, position) = match self.keyframes.seek(milliseconds as f64 / 1000.0) {
+            Some((time, position)) if milliseconds > 0 => ((time * 1000.0) as u32, position),
+            _ => (0, self.first_frame),
+        };
+        reader.seek(position)?;
+
+        self.buffer.clear();
+        self.end_of_file = false;
+        self.complete = false;
+        self.clock = None;
+
+        Ok(self.metadata.iter().chain(self.headers.iter()).map(|tag| message(tag.clone(), time)).collect())
+    }
+
+    pub(crate) fn pause(&mut self, pause: bool) {
+        self.paused = pause;
+        // Whatever comes next is due right away, not when it would have been without the pause
+        self.clock = None;
+    }
+
+    pub(crate) async fn next(&mut self) -> VodStep {
+        loop {
+            if self.paused || self.complete {
+                return std::future::pending().await;
+            }
+
+            if let Some(tag) = self.buffer.front() {
+                let (started, base) = *self.clock.get_or_insert((Instant::now(), tag.timestamp));
+                let wait = tag.timestamp.saturating_sub(base).saturating_sub(BUFFER_AHEAD_MS);
+                tokio::time::sleep_until(started + Duration::from_millis(wait as u64)).await;
+
+                let tag = self.buffer.pop_front().unwrap();
+                self.bytes += tag.data.len() as u64;
+                let timestamp = tag.timestamp;
+                return VodStep::Media(message(tag, timestamp));
+            }
+
+            if self.end_of_file {
+                self.complete = true;
+                return VodStep::Complete;
+            }
+
+            if self.read.is_none() {
+                let mut reader = match self.reader.take() {
+                    Some(reader) => reader,
+                    None => {
+                        self.end_of_file = true;
+                        continue;
+                    }
+                };
+                self.read = Some(tokio::task::spawn_blocking(move || {
+                    let tags = read_batch(&mut reader);
+                    (reader, tags)
+                }));
+            }
+
+            // Awaiting the handle rather than taking it keeps the read around if we get cancelled
+            let result = self.read.as_mut().unwrap().await;
+            self.read = None;
+            match result {
+                Ok((reader, Ok(tags))) => {
+                    self.reader = Some(reader);
+                    self.end_of_file = tags.len() < READ_BATCH;
+                    self.buffer.extend(tags);
+                }
+                Ok((reader, Err(err))) => {
+                    eprintln!("Error reading VOD file: {}", err);
+                    self.reader = Some(reader);
+                    self.end_of_file = true;
+                }
+                Err(_) => self.end_of_file = true,
+            }
+        }
+    }
+}
+
+fn read_batch(reader: &mut Reader) -> Result<Vec<FlvTag>, &'static str> {
+    let mut tags = Vec::with_capacity(READ_BATCH);
+    while tags.len() < READ_BATCH {
+        match reader.next_tag()? {
+            Some(tag) if matches!(tag.tag_type, 8 | 9 | 18) => tags.push(tag),
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(tags)
+}
+
+// Players don't need the parsed tags, the messages go straight out over RTMP
+fn message(tag: FlvTag, timestamp: u32) -> MediaMessage {
+    MediaMessage {
+        message_type_id: tag.tag_type,
+        timestamp,
+        data: Arc::new(tag.data),
+        video: None,
+        audio: None,
+    }
+}
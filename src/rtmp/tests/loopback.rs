@@ -0,0 +1,100 @@
+// Publishes to a server on a loopback port with one client and plays the stream back with another
+use std::sync::Arc;
+use std::time::Duration;
+use rtmp::{ClientEvent, MediaMessage, PublishingType, RtmpClient, RtmpConfig, RtmpServer};
+use tokio::net::TcpListener;
+
+// From an x264 encode, High 3.1 1280x720 at 30 fps
+const SPS_720P: [u8; 26] = [
+    0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x10, 0x00,
+    0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60,
+];
+const PPS: [u8; 4] = [0x68, 0xeb, 0xe3, 0xcb];
+
+fn message(message_type_id: u8, timestamp: u32, data: Vec<u8>) -> MediaMessage {
+    MediaMessage { message_type_id, timestamp, data: Arc::new(data), video: None, audio: None }
+}
+
+fn video_header() -> MediaMessage {
+    let mut data = vec![0x17, 0, 0, 0, 0, 1, SPS_720P[1], SPS_720P[2], SPS_720P[3], 0xff, 0xe1];
+    data.extend_from_slice(&(SPS_720P.len() as u16).to_be_bytes());
+    data.extend_from_slice(&SPS_720P);
+    data.push(1);
+    data.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+    data.extend_from_slice(&PPS);
+    message(9, 0, data)
+}
+
+// One slice NAL unit, numbered so every frame is different
+fn video_frame(timestamp: u32, keyframe: bool) -> MediaMessage {
+    let nal_type = if keyframe { 0x65 } else { 0x41 };
+    let frame_type = if keyframe { 0x17 } else { 0x27 };
+    let mut data = vec![frame_type, 1, 0, 0, 0, 0, 0, 0, 5, nal_type];
+    data.extend_from_slice(&timestamp.to_be_bytes());
+    message(9, timestamp, data)
+}
+
+fn audio_header() -> MediaMessage {
+    // AAC LC, 44.1kHz stereo
+    message(8, 0, vec![0xaf, 0, 0x12, 0x10])
+}
+
+fn audio_frame(timestamp: u32) -> MediaMessage {
+    let mut data = vec![0xaf, 1];
+    data.extend_from_slice(&timestamp.to_be_bytes());
+    message(8, timestamp, data)
+}
+
+#[tokio::test]
+async fn plays_what_gets_published() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("rtmp://{}/live/loopback", listener.local_addr().unwrap());
+    let server = RtmpServer::with_config(RtmpConfig::default());
+    tokio::spawn(async move { server.start_on(listener).await });
+
+    // Both return once the server said NetStream.Publish.Start and NetStream.Play.Start
+    let mut publisher = RtmpClient::connect(&url).await.unwrap();
+    publisher.publish(PublishingType::Live).await.unwrap();
+    for message in [video_header(), audio_header(), video_frame(0, true), audio_frame(0)] {
+        publisher.send_media(&message).await.unwrap();
+    }
+
+    let mut player = RtmpClient::connect(&url).await.unwrap();
+    player.play().await.unwrap();
+
+    let mut sent = Vec::new();
+    for i in 1..=10 {
+        let frames = [video_frame(i * 40, i % 5 == 0), audio_frame(i * 40)];
+        for frame in frames {
+            publisher.send_media(&frame).await.unwrap();
+            sent.push(frame);
+        }
+    }
+
+    let mut received = Vec::new();
+    while received.last().map(|message: &MediaMessage| (message.message_type_id, message.timestamp)) != Some((8, 400)) {
+        match tokio::time::timeout(Duration::from_secs(5), player.next_event()).await {
+            Ok(Some(ClientEvent::Media(message))) => received.push(message),
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("Server closed the connection"),
+            Err(_) => panic!("Timed out waiting for media, got {} messages", received.len()),
+        }
+    }
+
+    // The sequence headers come before any frames
+    let media: Vec<&MediaMessage> = received.iter().filter(|message| message.message_type_id != 18).collect();
+    assert_eq!(media[0].data, video_header().data);
+    assert_eq!(media[1].data, audio_header().data);
+
+    // And the frames published while playing come through as they were sent
+    let live: Vec<(u8, u32, &[u8])> = media[media.len() - sent.len()..].iter()
+        .map(|message| (message.message_type_id, message.timestamp, message.data.as_slice()))
+        .collect();
+    let expected: Vec<(u8, u32, &[u8])> = sent.iter()
+        .map(|message| (message.message_type_id, message.timestamp, message.data.as_slice()))
+        .collect();
+    assert_eq!(live, expected);
+
+    publisher.close().await;
+    player.close().await;
+}
@@ -1,35 +1,58 @@
-use std::io::Read;
+use std::io;
 use std::net::TcpListener;
+use std::sync::Arc;
+use media_core::MediaRegistry;
+use tokio::runtime::Handle;
 
+mod rtp;
 mod server;
+mod sdp;
 
-pub trait Serializable {
-    fn serialize(&self) -> Result<Vec<u8>, &'static str>;
-    fn deserialize<R>(reader: &mut R) -> Result<Self, &'static str> where R : Read, Self: Sized;
-}
+pub use media_core::Serializable;
 
-pub struct RtspServer {
+const DEFAULT_LISTEN: &str = "127.0.0.1:554";
 
+pub struct RtspServer {
+    // Where the streams come from, without one every stream is unknown
+    registry: Option<Arc<dyn MediaRegistry>>,
+    // Frame channels are fed by tasks on the runtime the registry lives on, which connection
+    // threads have to enter to get them
+    runtime: Option<Handle>,
+    pub listen: String,
 }
 
 impl RtspServer {
     pub fn new() -> RtspServer {
         RtspServer {
+            registry: None,
+            runtime: None,
+            listen: DEFAULT_LISTEN.to_string(),
+        }
+    }
+
+    // Called from within the registry's runtime
+    pub fn with_registry(registry: Arc<dyn MediaRegistry>) -> RtspServer {
+        RtspServer {
+            registry: Some(registry),
+            runtime: Handle::try_current().ok(),
+            listen: DEFAULT_LISTEN.to_string(),
         }
     }
 
-    pub fn start(&mut self) {
+    pub fn start(&mut self) -> io::Result<()> {
         // Start a TCP server
-        let listener = TcpListener::bind("127.0.0.1:554").unwrap();
+        let listener = TcpListener::bind(&self.listen)?;
+        println!("Serving RTSP on {}", self.listen);
 
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
+            let stream = stream?;
             println!("Connection established!");
-            let mut connection = server::RtspConnection::new(stream);
+            let mut connection = server::RtspConnection::new(stream, self.registry.clone(), self.runtime.clone());
             // Start a thread to handle the connection, and pass a reference to ourselves
             std::thread::spawn(move || {
                 connection.handle_connection();
             });
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,402 @@
+use media_core::{Bytes, Codec, MediaFrame, TrackInfo};
+
+// RTP (RFC 3550) for PLAY and RECORD. H.264 (RFC 6184) and H.265 (RFC 7798) go as NAL units,
+// fragmented when they don't fit in a packet, AAC as RFC 3640 AAC-hbr access units and
+// everything else a frame per packet.
+
+// Keeps packets within a typical MTU, so they'd also make it over UDP
+const MAX_PAYLOAD_SIZE: usize = 1400;
+
+// RTCP sender reports go out every this many milliseconds of media
+const SENDER_REPORT_INTERVAL: i64 = 5000;
+
+const H264_STAP_A: u8 = 24;
+const H264_FU_A: u8 = 28;
+const HEVC_AP: u8 = 48;
+const HEVC_FU: u8 = 49;
+
+pub fn clock_rate(track: &TrackInfo) -> u32 {
+    match track.codec {
+        Codec::H264 | Codec::H265 => 90000,
+        Codec::Opus => 48000,
+        Codec::G711MuLaw | Codec::G711ALaw => 8000,
+        _ => track.sample_rate.unwrap_or(44100),
+    }
+}
+
+pub struct Packetizer {
+    codec: Codec,
+    payload_type: u8,
+    clock_rate: u32,
+    ssrc: u32,
+    sequence: u16,
+    packet_count: u32,
+    octet_count: u32,
+    // The pts of the last sender report
+    last_report: Option<i64>,
+}
+
+impl Packetizer {
+    pub fn new(track: &TrackInfo, payload_type: u8, ssrc: u32) -> Self {
+        Self {
+            codec: track.codec,
+            payload_type,
+            clock_rate: clock_rate(track),
+            ssrc,
+            sequence: 0,
+            packet_count: 0,
+            octet_count: 0,
+            last_report: None,
+        }
+    }
+
+    // The RTP timestamp of a time on the stream's timeline
+    pub fn timestamp(&self, milliseconds: i64) -> u32 {
+        (milliseconds * self.clock_rate as i64 / 1000) as u32
+    }
+
+    // The RTP packets of a frame, the last one with the marker bit
+    pub fn packetize(&mut self, frame: &MediaFrame) -> Vec<Vec<u8>> {
+        let payloads = match self.codec {
+            Codec::H264 => split_annex_b(&frame.payload).into_iter().flat_map(fragment_h264).collect(),
+            Codec::H265 => split_annex_b(&frame.payload).into_iter().flat_map(fragment_hevc).collect(),
+            Codec::Aac => vec![aac_payload(&frame.payload)],
+            _ => vec![frame.payload.to_vec()],
+        };
+
+        let timestamp = self.timestamp(frame.pts);
+        let last = payloads.len().saturating_sub(1);
+        payloads.into_iter().enumerate().map(|(index, payload)| self.packet(timestamp, index == last, &payload)).collect()
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80);
+        packet.push((marker as u8) << 7 | self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packet_count = self.packet_count.wrapping_add(1);
+        self.octet_count = self.octet_count.wrapping_add(payload.len() as u32);
+        packet
+    }
+
+    // An RTCP sender report for the frame at this time, when one is due. Its NTP timestamp is the
+    // time on the stream's timeline too, which is all players need to line the tracks up.
+    pub fn sender_report(&mut self, milliseconds: i64) -> Option<Vec<u8>> {
+        if self.last_report.is_some_and(|last| milliseconds - last < SENDER_REPORT_INTERVAL) {
+            return None;
+        }
+        self.last_report = Some(milliseconds);
+
+        let milliseconds = milliseconds.max(0) as u64;
+        let fraction = ((milliseconds % 1000) << 32) / 1000;
+        let mut report = vec![0x80, 200, 0, 6];
+        report.extend_from_slice(&self.ssrc.to_be_bytes());
+        report.extend_from_slice(&((milliseconds / 1000) as u32).to_be_bytes());
+        report.extend_from_slice(&(fraction as u32).to_be_bytes());
+        report.extend_from_slice(&self.timestamp(milliseconds as i64).to_be_bytes());
+        report.extend_from_slice(&self.packet_count.to_be_bytes());
+        report.extend_from_slice(&self.octet_count.to_be_bytes());
+        Some(report)
+    }
+}
+
+// A NAL unit as it is, or split into FU-A fragments
+fn fragment_h264(nalu: &[u8]) -> Vec<Vec<u8>> {
+    if nalu.len() <= MAX_PAYLOAD_SIZE {
+        return vec![nalu.to_vec()];
+    }
+    let indicator = (nalu[0] & 0xE0) | H264_FU_A;
+    fragment(&nalu[1..], &[indicator], nalu[0] & 0x1F)
+}
+
+// A NAL unit as it is, or split into fragmentation units
+fn fragment_hevc(nalu: &[u8]) -> Vec<Vec<u8>> {
+    if nalu.len() <= MAX_PAYLOAD_SIZE || nalu.len() < 3 {
+        return vec![nalu.to_vec()];
+    }
+    let header = [(nalu[0] & 0x81) | (HEVC_FU << 1), nalu[1]];
+    fragment(&nalu[2..], &header, (nalu[0] >> 1) & 0x3F)
+}
+
+// The payload header, then the FU header with the start and end bits and the NAL unit type
+fn fragment(data: &[u8], header: &[u8], nalu_type: u8) -> Vec<Vec<u8>> {
+    let chunk_size = MAX_PAYLOAD_SIZE - header.len() - 1;
+    let count = data.len().div_ceil(chunk_size);
+    data.chunks(chunk_size).enumerate().map(|(index, chunk)| {
+        let mut fu_header = nalu_type;
+        if index == 0 {
+            fu_header |= 0x80;
+        }
+        if index == count - 1 {
+            fu_header |= 0x40;
+        }
+        let mut payload = header.to_vec();
+        payload.push(fu_header);
+        payload.extend_from_slice(chunk);
+        payload
+    }).collect()
+}
+
+// One access unit behind its AU header: 13 bits of size and 3 of index
+fn aac_payload(frame: &[u8]) -> Vec<u8> {
+    let size = frame.len().min(0x1FFF);
+    let mut payload = vec![0x00, 0x10, (size >> 5) as u8, ((size & 0x1F) << 3) as u8];
+    payload.extend_from_slice(&frame[..size]);
+    payload
+}
+
+// NAL units delimited by 3 or 4 byte start codes
+fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut start = None;
+    let mut position = 0;
+    while position + 3 <= data.len() {
+        if data[position] == 0 && data[position + 1] == 0 && data[position + 2] == 1 {
+            if let Some(start) = start {
+                // A zero before the start code belongs to a 4 byte start code
+                let mut end = position;
+                if end > start && data[end - 1] == 0 {
+                    end -= 1;
+                }
+                if end > start {
+                    nalus.push(&data[start..end]);
+                }
+            }
+            position += 3;
+            start = Some(position);
+        } else {
+            position += 1;
+        }
+    }
+    if let Some(start) = start.filter(|start| *start < data.len()) {
+        nalus.push(&data[start..]);
+    }
+    nalus
+}
+
+// Puts the frames of one track back together from its RTP packets
+pub struct Depacketizer {
+    track: u32,
+    codec: Codec,
+    payload_type: u8,
+    clock_rate: u32,
+    // The RTP timestamp the track started at, which is zero on the stream's timeline
+    first_timestamp: Option<u32>,
+    // The access unit in progress, in Annex B format, and its timestamp
+    access_unit: Vec<u8>,
+    timestamp: u32,
+    keyframe: bool,
+    // The NAL unit being put back together from fragments
+    fragment: Option<Vec<u8>>,
+}
+
+impl Depacketizer {
+    pub fn new(track: &TrackInfo, payload_type: u8, clock_rate: u32) -> Self {
+        Self {
+            track: track.id,
+            codec: track.codec,
+            payload_type,
+            clock_rate,
+            first_timestamp: None,
+            access_unit: Vec::new(),
+            timestamp: 0,
+            keyframe: false,
+            fragment: None,
+        }
+    }
+
+    // The frames the packet completes
+    pub fn push(&mut self, packet: &[u8]) -> Vec<MediaFrame> {
+        let mut frames = Vec::new();
+        let (marker, timestamp, payload) = match parse_packet(packet) {
+            Some((payload_type, marker, timestamp, payload)) if payload_type == self.payload_type => (marker, timestamp, payload),
+            _ => return frames,
+        };
+        self.first_timestamp.get_or_insert(timestamp);
+
+        match self.codec {
+            Codec::H264 | Codec::H265 => {
+                // Packets of the next access unit finish the one before, in case its marker got lost
+                if timestamp != self.timestamp {
+                    frames.extend(self.finish_access_unit());
+                }
+                self.timestamp = timestamp;
+                match self.codec {
+                    Codec::H264 => self.push_h264(payload),
+                    _ => self.push_hevc(payload),
+                }
+                if marker {
+                    frames.extend(self.finish_access_unit());
+                }
+            }
+            Codec::Aac => {
+                // The AU headers, their length in bits, then the access units one after the other
+                let headers_length = match payload.get(..2) {
+                    Some(length) => (u16::from_be_bytes([length[0], length[1]]) as usize).div_ceil(8),
+                    None => return frames,
+                };
+                let mut data = match payload.get(2 + headers_length..) {
+                    Some(data) => data,
+                    None => return frames,
+                };
+                for (index, header) in payload[2..2 + headers_length].chunks_exact(2).enumerate() {
+                    let size = (u16::from_be_bytes([header[0], header[1]]) >> 3) as usize;
+                    if size > data.len() {
+                        break;
+                    }
+                    let timestamp = timestamp.wrapping_add(index as u32 * 1024);
+                    frames.push(self.frame(timestamp, true, Bytes::copy_from_slice(&data[..size])));
+                    data = &data[size..];
+                }
+            }
+            _ => frames.push(self.frame(timestamp, true, Bytes::copy_from_slice(payload))),
+        }
+        frames
+    }
+
+    fn push_h264(&mut self, payload: &[u8]) {
+        let header = match payload.first() {
+            Some(header) => *header,
+            None => return,
+        };
+        match header & 0x1F {
+            1..=23 => self.push_nalu(payload),
+            H264_STAP_A => {
+                let mut data = &payload[1..];
+                while let Some(length) = data.get(..2) {
+                    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+                    let nalu = match data.get(2..2 + length) {
+                        Some(nalu) => nalu,
+                        None => break,
+                    };
+                    self.push_nalu(nalu);
+                    data = &data[2 + length..];
+                }
+            }
+            H264_FU_A if payload.len() > 2 => {
+                let fu_header = payload[1];
+                if fu_header & 0x80 != 0 {
+                    self.fragment = Some(vec![(header & 0xE0) | (fu_header & 0x1F)]);
+                }
+                // A fragment whose start got lost is of no use
+                if let Some(fragment) = &mut self.fragment {
+                    fragment.extend_from_slice(&payload[2..]);
+                }
+                if fu_header & 0x40 != 0 {
+                    if let Some(nalu) = self.fragment.take() {
+                        self.push_nalu(&nalu);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push_hevc(&mut self, payload: &[u8]) {
+        if payload.len() < 3 {
+            return;
+        }
+        match (payload[0] >> 1) & 0x3F {
+            HEVC_AP => {
+                let mut data = &payload[2..];
+                while let Some(length) = data.get(..2) {
+                    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+                    let nalu = match data.get(2..2 + length) {
+                        Some(nalu) => nalu,
+                        None => break,
+                    };
+                    self.push_nalu(nalu);
+                    data = &data[2 + length..];
+                }
+            }
+            HEVC_FU => {
+                let fu_header = payload[2];
+                if fu_header & 0x80 != 0 {
+                    self.fragment = Some(vec![(payload[0] & 0x81) | ((fu_header & 0x3F) << 1), payload[1]]);
+                }
+                if let Some(fragment) = &mut self.fragment {
+                    fragment.extend_from_slice(&payload[3..]);
+                }
+                if fu_header & 0x40 != 0 {
+                    if let Some(nalu) = self.fragment.take() {
+                        self.push_nalu(&nalu);
+                    }
+                }
+            }
+            _ => self.push_nalu(payload),
+        }
+    }
+
+    // Parameter sets and access unit delimiters stay out, the track's config has what it needs
+    fn push_nalu(&mut self, nalu: &[u8]) {
+        let header = match nalu.first() {
+            Some(header) => *header,
+            None => return,
+        };
+        let (skip, keyframe) = match self.codec {
+            Codec::H264 => {
+                let nalu_type = header & 0x1F;
+                (matches!(nalu_type, 7..=9), nalu_type == 5)
+            }
+            _ => {
+                let nalu_type = (header >> 1) & 0x3F;
+                (matches!(nalu_type, 32..=35), (16..=21).contains(&nalu_type))
+            }
+        };
+        if skip {
+            return;
+        }
+        self.keyframe |= keyframe;
+        self.access_unit.extend_from_slice(&[0, 0, 0, 1]);
+        self.access_unit.extend_from_slice(nalu);
+    }
+
+    fn finish_access_unit(&mut self) -> Option<MediaFrame> {
+        if self.access_unit.is_empty() {
+            return None;
+        }
+        let payload = Bytes::from(std::mem::take(&mut self.access_unit));
+        let keyframe = std::mem::take(&mut self.keyframe);
+        Some(self.frame(self.timestamp, keyframe, payload))
+    }
+
+    // RTP only has presentation times, which have to do for decoding too
+    fn frame(&self, timestamp: u32, keyframe: bool, payload: Bytes) -> MediaFrame {
+        let elapsed = timestamp.wrapping_sub(self.first_timestamp.unwrap_or(timestamp)) as i64;
+        let time = elapsed * 1000 / self.clock_rate as i64;
+        MediaFrame {
+            track: self.track,
+            codec: self.codec,
+            pts: time,
+            dts: time,
+            keyframe,
+            payload,
+        }
+    }
+}
+
+// The payload type, marker bit, timestamp and payload of an RTP packet
+fn parse_packet(packet: &[u8]) -> Option<(u8, bool, u32, &[u8])> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let mut start = 12 + csrc_count * 4;
+    // Header extension: 2 bytes of profile, 2 of length in 32 bit words, then the extension
+    if packet[0] & 0x10 != 0 {
+        let length = packet.get(start + 2..start + 4)?;
+        start += 4 + u16::from_be_bytes([length[0], length[1]]) as usize * 4;
+    }
+    let mut end = packet.len();
+    // Padding, its length in the last byte
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    let payload = packet.get(start..end).filter(|payload| !payload.is_empty())?;
+    Some((packet[1] & 0x7F, packet[1] & 0x80 != 0, u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]), payload))
+}
@@ -0,0 +1,234 @@
+use media_core::{Codec, TrackInfo, TrackKind};
+
+// RFC 8866 session description for the tracks of a stream. Every track is its own media section,
+// controlled as trackID=<track id>.
+pub fn describe(name: &str, tracks: &[TrackInfo]) -> String {
+    let mut sdp = String::new();
+    sdp.push_str("v=0\r\n");
+    sdp.push_str("o=- 0 0 IN IP4 127.0.0.1\r\n");
+    sdp.push_str(&format!("s={}\r\n", name));
+    sdp.push_str("c=IN IP4 0.0.0.0\r\n");
+    sdp.push_str("t=0 0\r\n");
+
+    for track in tracks {
+        let payload_type = match payload_type(track.codec) {
+            Some(payload_type) => payload_type,
+            None => {
+                println!("Leaving {:?} track of {} out of the SDP", track.codec, name);
+                continue;
+            }
+        };
+        let (rtpmap, fmtp) = match track.codec {
+            Codec::H264 => ("H264/90000".to_string(), track.fmtp.clone()),
+            Codec::H265 => ("H265/90000".to_string(), track.fmtp.clone()),
+            // RFC 3640 AAC-hbr
+            Codec::Aac => (
+                format!("MPEG4-GENERIC/{}/{}", track.sample_rate.unwrap_or(44100), track.channels.unwrap_or(2)),
+                Some(format!("streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config={}", hex(&track.config))),
+            ),
+            // RFC 7587, always advertised as 48kHz stereo
+            Codec::Opus => ("opus/48000/2".to_string(), None),
+            Codec::G711MuLaw => ("PCMU/8000".to_string(), None),
+            _ => ("PCMA/8000".to_string(), None),
+        };
+
+        let media = match track.kind() {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+        };
+        sdp.push_str(&format!("m={} 0 RTP/AVP {}\r\n", media, payload_type));
+        sdp.push_str(&format!("a=rtpmap:{} {}\r\n", payload_type, rtpmap));
+        if let Some(fmtp) = fmtp {
+            sdp.push_str(&format!("a=fmtp:{} {}\r\n", payload_type, fmtp));
+        }
+        sdp.push_str(&format!("a=control:trackID={}\r\n", track.id));
+    }
+
+    sdp
+}
+
+// The payload type a codec goes out with, None for codecs we don't packetize
+pub fn payload_type(codec: Codec) -> Option<u8> {
+    match codec {
+        Codec::H264 | Codec::H265 => Some(96),
+        Codec::Aac | Codec::Opus => Some(97),
+        // Static payload types
+        Codec::G711MuLaw => Some(0),
+        Codec::G711ALaw => Some(8),
+        _ => None,
+    }
+}
+
+// A media section of an ANNOUNCE, for RECORD
+pub struct AnnouncedTrack {
+    // What SETUP asks for the track by
+    pub control: String,
+    pub payload_type: u8,
+    pub clock_rate: u32,
+    pub info: TrackInfo,
+}
+
+// The media sections of a session description that we can take frames from. Track ids go by
+// the order of the sections.
+pub fn parse(sdp: &str) -> Vec<AnnouncedTrack> {
+    struct Media {
+        payload_type: u8,
+        rtpmap: Option<String>,
+        fmtp: Option<String>,
+        control: Option<String>,
+    }
+
+    let mut sections: Vec<Media> = Vec::new();
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            // m=<media> <port> <proto> <payload type>, only the first payload type is used
+            let payload_type = media.split_whitespace().nth(3).and_then(|payload_type| payload_type.parse().ok());
+            if let Some(payload_type) = payload_type {
+                sections.push(Media { payload_type, rtpmap: None, fmtp: None, control: None });
+            }
+            continue;
+        }
+        let media = match sections.last_mut() {
+            Some(media) => media,
+            None => continue,
+        };
+        if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            if let Some((payload_type, rtpmap)) = rtpmap.split_once(' ') {
+                if payload_type.parse() == Ok(media.payload_type) {
+                    media.rtpmap = Some(rtpmap.trim().to_string());
+                }
+            }
+        } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+            if let Some((payload_type, fmtp)) = fmtp.split_once(' ') {
+                if payload_type.parse() == Ok(media.payload_type) {
+                    media.fmtp = Some(fmtp.trim().to_string());
+                }
+            }
+        } else if let Some(control) = line.strip_prefix("a=control:") {
+            media.control = Some(control.to_string());
+        }
+    }
+
+    let mut tracks = Vec::new();
+    for media in sections {
+        let control = match media.control {
+            Some(control) => control,
+            None => continue,
+        };
+        // Static payload types don't need an rtpmap
+        let rtpmap = media.rtpmap.unwrap_or_else(|| match media.payload_type {
+            0 => "PCMU/8000".to_string(),
+            8 => "PCMA/8000".to_string(),
+            _ => String::new(),
+        });
+        let mut rtpmap = rtpmap.split('/');
+        let encoding = rtpmap.next().unwrap_or_default().to_ascii_uppercase();
+        let clock_rate = rtpmap.next().and_then(|clock_rate| clock_rate.parse().ok()).unwrap_or(0);
+        let channels = rtpmap.next().and_then(|channels| channels.parse().ok());
+        let parameter = |name: &str| {
+            media.fmtp.as_deref().unwrap_or_default().split(';')
+                .filter_map(|parameter| parameter.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string())
+        };
+
+        let id = tracks.len() as u32 + 1;
+        let info = match encoding.as_str() {
+            "H264" => {
+                let parameter_sets: Vec<Vec<u8>> = parameter("sprop-parameter-sets").unwrap_or_default().split(',').filter_map(base64_decode).collect();
+                let config = match avc_config(&parameter_sets) {
+                    Some(config) => config,
+                    None => {
+                        println!("Leaving H.264 track {} out, its SDP has no parameter sets", control);
+                        continue;
+                    }
+                };
+                let mut info = TrackInfo::new(id, Codec::H264);
+                info.config = config.into();
+                info
+            }
+            "MPEG4-GENERIC" if parameter("mode").is_some_and(|mode| mode.eq_ignore_ascii_case("AAC-hbr")) => {
+                let mut info = TrackInfo::new(id, Codec::Aac);
+                info.config = parameter("config").and_then(|config| hex_decode(&config)).unwrap_or_default().into();
+                info.sample_rate = Some(clock_rate);
+                info.channels = channels;
+                info
+            }
+            "OPUS" => TrackInfo::new(id, Codec::Opus),
+            "PCMU" => TrackInfo::new(id, Codec::G711MuLaw),
+            "PCMA" => TrackInfo::new(id, Codec::G711ALaw),
+            _ => {
+                println!("Leaving {} track {} out, we can't record it", encoding, control);
+                continue;
+            }
+        };
+        if clock_rate == 0 {
+            continue;
+        }
+        tracks.push(AnnouncedTrack {
+            control,
+            payload_type: media.payload_type,
+            clock_rate,
+            info,
+        });
+    }
+    tracks
+}
+
+// An AVCDecoderConfigurationRecord (ISO/IEC 14496-15) from the SPS and PPS of sprop-parameter-sets
+fn avc_config(parameter_sets: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let sps: Vec<&Vec<u8>> = parameter_sets.iter().filter(|nalu| nalu.first().is_some_and(|header| header & 0x1F == 7)).collect();
+    let pps: Vec<&Vec<u8>> = parameter_sets.iter().filter(|nalu| nalu.first().is_some_and(|header| header & 0x1F == 8)).collect();
+    let first = sps.first().filter(|sps| sps.len() >= 4)?;
+    if pps.is_empty() {
+        return None;
+    }
+
+    // Version, profile, compatibility, level, then 4 byte NALU lengths
+    let mut config = vec![1, first[1], first[2], first[3], 0xFF, 0xE0 | sps.len() as u8];
+    for nalu in sps {
+        config.extend_from_slice(&(nalu.len() as u16).to_be_bytes());
+        config.extend_from_slice(nalu);
+    }
+    config.push(pps.len() as u8);
+    for nalu in pps {
+        config.extend_from_slice(&(nalu.len() as u16).to_be_bytes());
+        config.extend_from_slice(nalu);
+    }
+    Some(config)
+}
+
+// RFC 4648 base64, padding optional
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data.trim().bytes().take_while(|byte| *byte != b'=') {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    (!decoded.is_empty()).then_some(decoded)
+}
+
+fn hex_decode(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len()).step_by(2).map(|index| u8::from_str_radix(data.get(index..index + 2)?, 16).ok()).collect()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,21 +1,418 @@
-use std::io::Read;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use media_core::{FrameEvent, FrameReceiver, FrameSender, MediaRegistry};
+use tokio::runtime::Handle;
+use crate::rtp::{Depacketizer, Packetizer};
+use crate::sdp::{self, AnnouncedTrack};
+
+// RTSP 1.0 (RFC 2326) with RTP interleaved on the connection: DESCRIBE, SETUP and PLAY to play a
+// stream, ANNOUNCE, SETUP and RECORD to publish one. Frames go through the registry either way,
+// so RTSP plays what other protocols publish and the other way around.
 
 pub struct RtspConnection {
-    socket: TcpStream
+    // Responses go out through here, and RTP while playing
+    socket: Arc<Mutex<TcpStream>>,
+    registry: Option<Arc<dyn MediaRegistry>>,
+    runtime: Option<Handle>,
+    // The stream an ANNOUNCE is for and its tracks, until RECORD
+    announced: Option<(String, Vec<AnnouncedTrack>)>,
+    session: Option<Session>,
+}
+
+struct Session {
+    id: String,
+    name: String,
+    // The interleaved channel of the RTP of every track SETUP was done for, RTCP goes on the next
+    channels: HashMap<u32, u8>,
+    state: State,
+}
+
+enum State {
+    Ready,
+    // Cleared to stop the thread sending the frames
+    Playing(Arc<AtomicBool>),
+    Recording {
+        frames: FrameSender,
+        // By interleaved channel
+        depacketizers: HashMap<u8, Depacketizer>,
+    },
+}
+
+// We don't take any requests with a body, anything bigger than this isn't worth reading past
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+// A player that doesn't take what we send for this long is gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PUBLIC: &str = "Public: OPTIONS, DESCRIBE, SETUP, PLAY, ANNOUNCE, RECORD, TEARDOWN, GET_PARAMETER";
+
+struct Request {
+    method: String,
+    url: String,
+    cseq: String,
+    // Names in lowercase
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
 }
 
 impl RtspConnection {
-    pub fn new(socket: TcpStream) -> RtspConnection {
+    pub fn new(socket: TcpStream, registry: Option<Arc<dyn MediaRegistry>>, runtime: Option<Handle>) -> RtspConnection {
         RtspConnection {
-            socket
+            socket: Arc::new(Mutex::new(socket)),
+            registry,
+            runtime,
+            announced: None,
+            session: None,
         }
     }
 
     pub fn handle_connection(&mut self) {
-        // Read as many bytes as we can from the socket
-        let mut buffer = [0; 1024];
-        self.socket.read(&mut buffer).unwrap();
-        println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
+        let socket = self.socket.lock().unwrap();
+        let _ = socket.set_write_timeout(Some(WRITE_TIMEOUT));
+        let mut reader = match socket.try_clone() {
+            Ok(socket) => BufReader::new(socket),
+            Err(err) => {
+                eprintln!("Error cloning socket: {}", err);
+                return;
+            }
+        };
+        drop(socket);
+        // The registry's frame channels need its runtime
+        let _runtime = self.runtime.as_ref().map(Handle::enter);
+
+        loop {
+            // RTP and RTCP come in between requests
+            match reader.fill_buf() {
+                Ok(buffer) if buffer.first() == Some(&b'$') => {
+                    if let Err(err) = self.read_interleaved(&mut reader) {
+                        eprintln!("Error reading RTP: {}", err);
+                        break;
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("Error reading request: {}", err);
+                    break;
+                }
+            }
+
+            let request = match read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("Error reading request: {}", err);
+                    break;
+                }
+            };
+            println!("Request: {} {}", request.method, request.url);
+
+            let result = match request.method.as_str() {
+                "OPTIONS" => self.respond(&request, "200 OK", &[PUBLIC.to_string()], ""),
+                "DESCRIBE" => self.describe(&request),
+                "ANNOUNCE" => self.announce(&request),
+                "SETUP" => self.setup(&request),
+                "PLAY" => self.play(&request),
+                "RECORD" => self.record(&request),
+                "TEARDOWN" => {
+                    self.stop();
+                    self.session = None;
+                    self.respond(&request, "200 OK", &[], "")
+                }
+                // Clients keep their session alive with it
+                "GET_PARAMETER" => self.respond(&request, "200 OK", &[], ""),
+                _ => self.respond(&request, "501 Not Implemented", &[], ""),
+            };
+            if let Err(err) = result {
+                eprintln!("Error writing response: {}", err);
+                break;
+            }
+        }
+        self.stop();
+    }
+
+    fn describe(&mut self, request: &Request) -> std::io::Result<()> {
+        let (name, _) = split_url(&request.url);
+        let tracks = match &self.registry {
+            Some(registry) => registry.tracks(name),
+            None => None,
+        };
+
+        match tracks {
+            Some(tracks) if !tracks.is_empty() => {
+                let body = sdp::describe(name, &tracks);
+                let headers = ["Content-Type: application/sdp".to_string(), format!("Content-Base: {}/", request.url.trim_end_matches('/'))];
+                self.respond(request, "200 OK", &headers, &body)
+            }
+            _ => self.respond(request, "404 Not Found", &[], ""),
+        }
+    }
+
+    fn announce(&mut self, request: &Request) -> std::io::Result<()> {
+        let (name, _) = split_url(&request.url);
+        let tracks = sdp::parse(&request.body);
+        if tracks.is_empty() {
+            return self.respond(request, "415 Unsupported Media Type", &[], "");
+        }
+        self.announced = Some((name.to_string(), tracks));
+        self.respond(request, "200 OK", &[], "")
+    }
+
+    fn setup(&mut self, request: &Request) -> std::io::Result<()> {
+        // Only interleaved on this connection, which gets through firewalls and NAT
+        let transport = request.header("transport").unwrap_or_default();
+        if !transport.contains("RTP/AVP/TCP") {
+            return self.respond(request, "461 Unsupported Transport", &[], "");
+        }
+
+        // trackID=<id> as DESCRIBE has it, or the control of an announced track
+        let (name, control) = split_url(&request.url);
+        let track = match (&self.announced, control) {
+            (Some((announced, tracks)), Some(control)) if announced == name => tracks.iter()
+                .find(|track| track.control.rsplit('/').next() == Some(control))
+                .map(|track| track.info.id),
+            (_, Some(control)) => control.strip_prefix("trackID=").and_then(|id| id.parse().ok()),
+            (_, None) => None,
+        };
+        let track = match track {
+            Some(track) => track,
+            None => return self.respond(request, "404 Not Found", &[], ""),
+        };
+
+        let session = self.session.get_or_insert_with(|| Session {
+            id: format!("{:016x}", random()),
+            name: name.to_string(),
+            channels: HashMap::new(),
+            state: State::Ready,
+        });
+        if session.name != name || !matches!(session.state, State::Ready) {
+            return self.respond(request, "455 Method Not Valid in This State", &[], "");
+        }
+
+        let channel = transport.split(';')
+            .find_map(|parameter| parameter.trim().strip_prefix("interleaved="))
+            .and_then(|channels| channels.split('-').next()?.parse().ok())
+            .unwrap_or(session.channels.len() as u8 * 2);
+        session.channels.insert(track, channel);
+        let transport = format!("Transport: RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel.wrapping_add(1));
+        self.respond(request, "200 OK", &[transport], "")
+    }
+
+    fn play(&mut self, request: &Request) -> std::io::Result<()> {
+        let (registry, session) = match (&self.registry, &mut self.session) {
+            (Some(registry), Some(session)) if matches!(session.state, State::Ready) => (registry, session),
+            _ => return self.respond(request, "455 Method Not Valid in This State", &[], ""),
+        };
+        let tracks = match registry.tracks(&session.name) {
+            Some(tracks) => tracks,
+            None => return self.respond(request, "404 Not Found", &[], ""),
+        };
+
+        let mut packetizers = HashMap::new();
+        let mut rtp_info = Vec::new();
+        for track in &tracks {
+            if let (Some(channel), Some(payload_type)) = (session.channels.get(&track.id), sdp::payload_type(track.codec)) {
+                packetizers.insert(track.id, (Packetizer::new(track, payload_type, random() as u32), *channel));
+                rtp_info.push(format!("url={}/trackID={};seq=0", request.url.trim_end_matches('/'), track.id));
+            }
+        }
+
+        let frames = registry.clone().subscribe_frames(&session.name);
+        let playing = Arc::new(AtomicBool::new(true));
+        session.state = State::Playing(playing.clone());
+        println!("Playing {} over RTSP", session.name);
+
+        // The response has to be out before the first packet
+        let headers = ["Range: npt=0.000-".to_string(), format!("RTP-Info: {}", rtp_info.join(","))];
+        self.respond(request, "200 OK", &headers, "")?;
+        let socket = self.socket.clone();
+        std::thread::spawn(move || send_frames(frames, &socket, packetizers, &playing));
+        Ok(())
+    }
+
+    fn record(&mut self, request: &Request) -> std::io::Result<()> {
+        let (registry, session, announced) = match (&self.registry, &mut self.session, &self.announced) {
+            (Some(registry), Some(session), Some((name, tracks))) if matches!(session.state, State::Ready) && *name == session.name => (registry, session, tracks),
+            _ => return self.respond(request, "455 Method Not Valid in This State", &[], ""),
+        };
+
+        let mut tracks = Vec::new();
+        let mut depacketizers = HashMap::new();
+        for track in announced {
+            if let Some(channel) = session.channels.get(&track.info.id) {
+                depacketizers.insert(*channel, Depacketizer::new(&track.info, track.payload_type, track.clock_rate));
+                tracks.push(track.info.clone());
+            }
+        }
+
+        let frames = match registry.clone().publish_frames(&session.name) {
+            Ok(frames) => frames,
+            Err(err) => {
+                eprintln!("Refused RTSP publisher of {}: {}", session.name, err);
+                return self.respond(request, "403 Forbidden", &[], "");
+            }
+        };
+        if frames.blocking_send(FrameEvent::Tracks(tracks)).is_err() {
+            return self.respond(request, "500 Internal Server Error", &[], "");
+        }
+        println!("Recording {} over RTSP", session.name);
+        session.state = State::Recording { frames, depacketizers };
+        self.respond(request, "200 OK", &[], "")
+    }
+
+    // A $, the channel and a 2 byte length, then an RTP or RTCP packet. Only recording clients
+    // send RTP, players only send RTCP, which we don't need.
+    fn read_interleaved<R: BufRead>(&mut self, reader: &mut R) -> std::io::Result<()> {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let mut packet = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut packet)?;
+
+        if let Some(Session { state: State::Recording { frames, depacketizers }, .. }) = &mut self.session {
+            if let Some(depacketizer) = depacketizers.get_mut(&header[1]) {
+                for frame in depacketizer.push(&packet) {
+                    // Another publisher took over
+                    if frames.blocking_send(FrameEvent::Frame(frame)).is_err() {
+                        return Err(std::io::Error::other("Stream no longer published"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Stops playing or publishing
+    fn stop(&mut self) {
+        match self.session.as_mut().map(|session| std::mem::replace(&mut session.state, State::Ready)) {
+            Some(State::Playing(playing)) => playing.store(false, Ordering::Relaxed),
+            Some(State::Recording { frames, .. }) => {
+                let _ = frames.blocking_send(FrameEvent::Ended);
+            }
+            _ => {}
+        }
+    }
+
+    fn respond(&mut self, request: &Request, status: &str, headers: &[String], body: &str) -> std::io::Result<()> {
+        let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, request.cseq);
+        if let Some(session) = &self.session {
+            response.push_str(&format!("Session: {}\r\n", session.id));
+        }
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        self.socket.lock().unwrap().write_all(response.as_bytes())
+    }
+}
+
+// Sends the frames as RTP until the client stops playing, goes away or the stream ends
+fn send_frames(mut frames: FrameReceiver, writer: &Mutex<TcpStream>, mut packetizers: HashMap<u32, (Packetizer, u8)>, playing: &AtomicBool) {
+    while let Some(event) = frames.blocking_recv() {
+        if !playing.load(Ordering::Relaxed) {
+            return;
+        }
+        let frame = match event {
+            FrameEvent::Frame(frame) => frame,
+            // Players can't be told about new tracks without another DESCRIBE
+            FrameEvent::Tracks(_) => continue,
+            FrameEvent::Ended => return,
+        };
+        let (packetizer, channel) = match packetizers.get_mut(&frame.track) {
+            Some((packetizer, channel)) => (packetizer, *channel),
+            None => continue,
+        };
+
+        let mut data = Vec::new();
+        if let Some(report) = packetizer.sender_report(frame.pts) {
+            interleave(&mut data, channel.wrapping_add(1), &report);
+        }
+        for packet in packetizer.packetize(&frame) {
+            interleave(&mut data, channel, &packet);
+        }
+        if writer.lock().unwrap().write_all(&data).is_err() {
+            return;
+        }
     }
-}
\ No newline at end of file
+}
+
+fn interleave(data: &mut Vec<u8>, channel: u8, packet: &[u8]) {
+    data.push(b'$');
+    data.push(channel);
+    data.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    data.extend_from_slice(packet);
+}
+
+// rtsp://host/app/stream is the same stream as rtmp://host/app/stream. SETUP URLs have the control
+// of a track after that, which always has an = in it.
+fn split_url(url: &str) -> (&str, Option<&str>) {
+    let url = url.trim_end_matches('/');
+    let (rest, last) = url.rsplit_once('/').unwrap_or(("", url));
+    match last.contains('=') {
+        true => (rest.rsplit('/').next().unwrap_or(""), Some(last)),
+        false => (last, None),
+    }
+}
+
+// Session ids and SSRCs, which only need to be hard to guess
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64);
+    hasher.finish()
+}
+
+// None once the client has closed the connection
+fn read_request<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Request>> {
+    let mut line = String::new();
+    // Skip blank lines between requests
+    while line.trim().is_empty() {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let url = parts.next().unwrap_or("").to_string();
+
+    let mut cseq = String::new();
+    let mut content_length = 0;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim().to_string());
+            match name.as_str() {
+                "cseq" => cseq = value.clone(),
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                _ => {}
+            }
+            headers.push((name, value));
+        }
+    }
+
+    // Only ANNOUNCE has a body we use, but it mustn't be mistaken for the next request either way
+    if content_length > MAX_BODY_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request { method, url, cseq, headers, body: String::from_utf8_lossy(&body).into_owned() }))
+}